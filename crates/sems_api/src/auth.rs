@@ -0,0 +1,47 @@
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+use crate::session::{ErrorKind, ErrorResponse};
+
+/// Middleware rejecting the requests without the admin bearer token with a 401
+///
+/// Without an admin token, every request is allowed.
+pub(crate) async fn require_admin_token(
+    State(admin_token): State<Option<Arc<str>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admin_token) = admin_token else {
+        return next.run(request).await;
+    };
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => {
+            next.run(request).await
+        }
+        Some(_) => unauthorized("Invalid admin token"),
+        None => unauthorized("Missing admin bearer token"),
+    }
+}
+
+fn unauthorized(error: &str) -> Response {
+    (
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        ErrorResponse::response(ErrorKind::Unauthorized, error.to_string()),
+    )
+        .into_response()
+}
+
+/// Compare the tokens in a time that does not depend on the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,459 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+};
+use sems_core::{ChargerStatus, StationState};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::extract::ApiJson;
+use crate::session::{ErrorResponse, schedule_promoted_sessions};
+use crate::station::station_error_to_response;
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DerateRequest {
+    pub max_power: u32,
+}
+
+#[derive(Serialize, Deserialize, Default, IntoParams)]
+pub struct ChargerQuery {
+    /// Only return the chargers running this firmware version
+    pub firmware: Option<String>,
+}
+
+/// Get the status of the chargers, with their derating
+/// The chargers can be filtered by firmware version with `?firmware=`, e.g. to follow a rollout.
+#[utoipa::path(
+    get,
+    path = "/chargers",
+    tag = "chargers",
+    params(ChargerQuery),
+    responses((status = 200, description = "Charger statuses", body = Vec<ChargerStatus>))
+)]
+pub async fn get_chargers(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Query(query): Query<ChargerQuery>,
+) -> Json<Vec<ChargerStatus>> {
+    tracing::info!("Getting charger statuses");
+    let state = app_state.lock().await;
+    let mut chargers = state.charger_statuses();
+    if let Some(firmware) = &query.firmware {
+        chargers.retain(|charger| charger.firmware_version.as_ref() == Some(firmware));
+    }
+    Json(chargers)
+}
+
+/// Derate a charger below its configured max power, e.g. on thermal throttling
+/// The derating applies until it is cleared, and the sessions are reallocated immediately
+#[utoipa::path(
+    post,
+    path = "/chargers/{charger_id}/derate",
+    tag = "chargers",
+    params(("charger_id" = String, Path, description = "Id of the charger")),
+    request_body = DerateRequest,
+    responses(
+        (status = 200, description = "Charger derated", body = Vec<ChargerStatus>),
+        (status = 404, description = "Charger not found", body = ErrorResponse),
+    )
+)]
+pub async fn derate_charger(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(charger_id): Path<String>,
+    ApiJson(payload): ApiJson<DerateRequest>,
+) -> Response {
+    let mut state = app_state.lock().await;
+    let result = state.derate_charger(&charger_id, payload.max_power);
+    schedule_promoted_sessions(&app_state, &mut state);
+    match result {
+        Ok(()) => Json(state.charger_statuses()).into_response(),
+        Err(error) => station_error_to_response(error),
+    }
+}
+
+/// Clear the derating of a charger, restoring its configured max power
+#[utoipa::path(
+    delete,
+    path = "/chargers/{charger_id}/derate",
+    tag = "chargers",
+    params(("charger_id" = String, Path, description = "Id of the charger")),
+    responses(
+        (status = 200, description = "Derating cleared", body = Vec<ChargerStatus>),
+        (status = 404, description = "Charger not found", body = ErrorResponse),
+    )
+)]
+pub async fn clear_charger_derating(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(charger_id): Path<String>,
+) -> Response {
+    let mut state = app_state.lock().await;
+    let result = state.clear_charger_derating(&charger_id);
+    schedule_promoted_sessions(&app_state, &mut state);
+    match result {
+        Ok(()) => Json(state.charger_statuses()).into_response(),
+        Err(error) => station_error_to_response(error),
+    }
+}
+
+/// Suspend a charger, e.g. for a partial maintenance
+/// Its sessions stay active with no power until it is resumed, and the capacity they free goes to
+/// the other chargers
+#[utoipa::path(
+    post,
+    path = "/chargers/{charger_id}/suspend",
+    tag = "chargers",
+    params(("charger_id" = String, Path, description = "Id of the charger")),
+    responses(
+        (status = 200, description = "Charger suspended", body = Vec<ChargerStatus>),
+        (status = 404, description = "Charger not found", body = ErrorResponse),
+    )
+)]
+pub async fn suspend_charger(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(charger_id): Path<String>,
+) -> Response {
+    let mut state = app_state.lock().await;
+    let result = state.suspend_charger(&charger_id);
+    schedule_promoted_sessions(&app_state, &mut state);
+    match result {
+        Ok(()) => Json(state.charger_statuses()).into_response(),
+        Err(error) => station_error_to_response(error),
+    }
+}
+
+/// Resume a suspended charger, allocating power to its sessions again
+#[utoipa::path(
+    post,
+    path = "/chargers/{charger_id}/resume",
+    tag = "chargers",
+    params(("charger_id" = String, Path, description = "Id of the charger")),
+    responses(
+        (status = 200, description = "Charger resumed", body = Vec<ChargerStatus>),
+        (status = 404, description = "Charger not found", body = ErrorResponse),
+    )
+)]
+pub async fn resume_charger(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(charger_id): Path<String>,
+) -> Response {
+    let mut state = app_state.lock().await;
+    let result = state.resume_charger(&charger_id);
+    schedule_promoted_sessions(&app_state, &mut state);
+    match result {
+        Ok(()) => Json(state.charger_statuses()).into_response(),
+        Err(error) => station_error_to_response(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, StatusCode},
+        routing::{get, post},
+    };
+    use sems_core::{ChargerConfig, ConnectorId, StationConfig};
+    use tower::util::ServiceExt;
+
+    /// Create the application router with the charger endpoints
+    pub fn create_app(app_state: StationState) -> Router {
+        let shared_state = Arc::new(Mutex::new(app_state));
+        Router::new()
+            .route("/chargers", get(get_chargers))
+            .route(
+                "/chargers/{charger_id}/derate",
+                post(derate_charger).delete(clear_charger_derating),
+            )
+            .route("/chargers/{charger_id}/suspend", post(suspend_charger))
+            .route("/chargers/{charger_id}/resume", post(resume_charger))
+            .with_state(shared_state)
+    }
+
+    fn test_station_config() -> StationConfig {
+        StationConfig {
+            station_id: "TEST_STATION".into(),
+            grid_capacity: 400,
+            chargers: vec![ChargerConfig {
+                id: "CP001".into(),
+                max_power: 200,
+                connectors: 2,
+                ..Default::default()
+            }],
+            batteries: vec![],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_derate_charger() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        assert_eq!(session.allocated_power, 150);
+        let app = create_app(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/chargers/CP001/derate")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&DerateRequest { max_power: 100 }).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The derating is reported, and binds the allocation below the configured max power
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/chargers")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let statuses: Vec<ChargerStatus> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].configured_max_power, 200);
+        assert_eq!(statuses[0].max_power, 100);
+        assert_eq!(statuses[0].derated_max_power, Some(100));
+        assert_eq!(statuses[0].allocated_power, 100);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/chargers/CP001/derate")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let statuses: Vec<ChargerStatus> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(statuses[0].max_power, 200);
+        assert_eq!(statuses[0].derated_max_power, None);
+        assert_eq!(statuses[0].allocated_power, 150);
+    }
+
+    #[tokio::test]
+    async fn test_get_chargers_by_firmware() {
+        let config = StationConfig {
+            chargers: ["2.1.0", "2.2.0", "2.1.0"]
+                .into_iter()
+                .enumerate()
+                .map(|(i, firmware)| ChargerConfig {
+                    id: format!("CP00{}", i + 1),
+                    max_power: 100,
+                    connectors: 2,
+                    firmware_version: Some(firmware.into()),
+                    vendor: Some("Kempower".into()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..test_station_config()
+        };
+        let app = create_app(StationState::new(config));
+        let get_chargers = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<Vec<ChargerStatus>>(&body).unwrap()
+            }
+        };
+
+        let chargers = get_chargers("/chargers").await;
+        assert_eq!(chargers.len(), 3);
+        assert_eq!(chargers[1].firmware_version.as_deref(), Some("2.2.0"));
+        assert_eq!(chargers[1].vendor.as_deref(), Some("Kempower"));
+
+        let chargers = get_chargers("/chargers?firmware=2.1.0").await;
+        assert_eq!(
+            chargers
+                .iter()
+                .map(|charger| charger.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["CP001", "CP003"]
+        );
+
+        assert!(get_chargers("/chargers?firmware=3.0.0").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_derate_charger_not_found() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/chargers/CP999/derate")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&DerateRequest { max_power: 100 }).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert!(error_response.error.contains("CP999"));
+    }
+
+    #[tokio::test]
+    async fn test_suspend_charger() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        assert_eq!(session.allocated_power, 150);
+        let app = create_app(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/chargers/CP001/suspend")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let statuses: Vec<ChargerStatus> = serde_json::from_slice(&body).unwrap();
+        assert!(statuses[0].suspended);
+        assert_eq!(statuses[0].allocated_power, 0);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/chargers/CP001/resume")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let statuses: Vec<ChargerStatus> = serde_json::from_slice(&body).unwrap();
+        assert!(!statuses[0].suspended);
+        assert_eq!(statuses[0].allocated_power, 150);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/chargers/CP999/suspend")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_available_connectors() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let app = create_app(state.clone());
+        let get_statuses = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/chargers")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<Vec<ChargerStatus>>(&body).unwrap()
+        };
+        assert_eq!(get_statuses(app).await[0].available_connectors, 2);
+
+        // One of the two connectors is in use
+        state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        let app = create_app(state);
+        assert_eq!(get_statuses(app).await[0].available_connectors, 1);
+    }
+}
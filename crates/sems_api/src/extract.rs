@@ -0,0 +1,41 @@
+use axum::{
+    Json,
+    extract::{FromRequest, Request, rejection::JsonRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+use crate::session::{ErrorKind, ErrorResponse};
+
+/// JSON body extractor answering an `ErrorResponse` when the body cannot be parsed
+///
+/// Unlike `Json`, which answers 422 when the JSON does not match the expected schema, any body
+/// that cannot be parsed is a malformed request (400). The 422 status is kept for requests whose
+/// values are invalid.
+pub struct ApiJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let rejection = match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => return Ok(ApiJson(value)),
+            Err(rejection) => rejection,
+        };
+        let error = ErrorResponse {
+            error: rejection.body_text(),
+            kind: ErrorKind::Malformed,
+        };
+        let status = match rejection {
+            // A missing content type keeps its 415 status, and a body over the limit its 413 status
+            JsonRejection::MissingJsonContentType(_) => rejection.status(),
+            _ if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => rejection.status(),
+            _ => ErrorKind::Malformed.status(),
+        };
+        Err((status, Json(error)).into_response())
+    }
+}
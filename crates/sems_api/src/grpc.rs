@@ -0,0 +1,378 @@
+use sems_core::{
+    ConnectorId, QueuedSession, SessionAdmission, SessionError, SessionOptions, SessionState,
+    StationState,
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+use crate::session::{admit_session_on, apply_power_update, schedule_promoted_sessions};
+
+/// Types and service generated from `proto/sems.proto`
+pub mod proto {
+    tonic::include_proto!("sems.v1");
+}
+
+use proto::sems_server::{Sems, SemsServer};
+
+/// gRPC service backed by the same station state as the REST API
+pub struct SemsService {
+    app_state: Arc<Mutex<StationState>>,
+}
+
+/// Create the gRPC service sharing the station state with the REST API
+pub fn create_grpc_service(app_state: Arc<Mutex<StationState>>) -> SemsServer<SemsService> {
+    SemsServer::new(SemsService { app_state })
+}
+
+fn session_error_to_status(error: SessionError) -> Status {
+    match error {
+        SessionError::ConnectorAlreadyInUse { .. } => Status::already_exists(error.to_string()),
+        // Like the REST API, an index below the connector base is an invalid request rather than
+        // a missing connector
+        SessionError::InvalidConnectorIndex { .. } => Status::invalid_argument(error.to_string()),
+        SessionError::ConnectorNotFound { .. }
+        | SessionError::SessionNotFound { .. }
+        | SessionError::SessionIdNotFound { .. }
+        | SessionError::ConnectorLabelNotFound { .. }
+        | SessionError::QueuedSessionNotFound { .. }
+        // gRPC has no equivalent of the 410 Gone of the REST API
+        | SessionError::SessionGone { .. } => Status::not_found(error.to_string()),
+        SessionError::InvalidTransition { .. } | SessionError::AllocationFailed { .. } => {
+            Status::failed_precondition(error.to_string())
+        }
+        SessionError::StationDraining
+        | SessionError::EmergencyStop
+        | SessionError::InsufficientCapacity
+        | SessionError::SessionIdsExhausted { .. } => {
+            Status::unavailable(error.to_string())
+        }
+        SessionError::EmptyNote
+        | SessionError::MissingVehicleMaxPower
+        | SessionError::ExpiryInPast { .. } => {
+            Status::invalid_argument(error.to_string())
+        }
+        SessionError::TooManyNotes { .. } => Status::failed_precondition(error.to_string()),
+    }
+}
+
+impl From<sems_core::Session> for proto::Session {
+    fn from(session: sems_core::Session) -> Self {
+        let state = match session.state {
+            SessionState::Pending => proto::SessionState::Pending,
+            SessionState::Charging => proto::SessionState::Charging,
+            SessionState::Suspended => proto::SessionState::Suspended,
+            SessionState::Finished => proto::SessionState::Finished,
+        };
+        proto::Session {
+            session_id: session.session_id.to_string(),
+            connector_id: Some(proto::ConnectorId {
+                charger_id: session.connector_id.charger_id,
+                idx: session.connector_id.idx as u32,
+            }),
+            allocated_power: session.allocated_power,
+            vehicle_max_power: session.vehicle_max_power,
+            state: state as i32,
+            metadata: session.metadata,
+            short_id: session.short_id,
+        }
+    }
+}
+
+impl From<QueuedSession> for proto::Session {
+    fn from(queued_session: QueuedSession) -> Self {
+        proto::Session {
+            session_id: queued_session.session_id.to_string(),
+            connector_id: Some(proto::ConnectorId {
+                charger_id: queued_session.connector_id.charger_id,
+                idx: queued_session.connector_id.idx as u32,
+            }),
+            allocated_power: 0,
+            vehicle_max_power: queued_session.vehicle_max_power,
+            state: proto::SessionState::Queued as i32,
+            metadata: queued_session.options.metadata,
+            short_id: queued_session.short_id,
+        }
+    }
+}
+
+fn session_reply(session: sems_core::Session) -> Response<proto::SessionReply> {
+    Response::new(proto::SessionReply {
+        session: Some(session.into()),
+        queue_position: 0,
+    })
+}
+
+#[tonic::async_trait]
+impl Sems for SemsService {
+    async fn start_session(
+        &self,
+        request: Request<proto::StartSessionRequest>,
+    ) -> Result<Response<proto::SessionReply>, Status> {
+        let request = request.into_inner();
+        let connector_id = request
+            .connector_id
+            .ok_or_else(|| Status::invalid_argument("Missing connector id"))?;
+        let connector_id = ConnectorId {
+            charger_id: connector_id.charger_id,
+            idx: u8::try_from(connector_id.idx).map_err(|_| {
+                Status::invalid_argument(format!("Invalid connector index {}", connector_id.idx))
+            })?,
+        };
+        let options = SessionOptions {
+            metadata: request.metadata,
+            ..Default::default()
+        };
+        let mut state = self.app_state.lock().await;
+        let admission = admit_session_on(
+            &self.app_state,
+            &mut state,
+            connector_id,
+            Some(request.vehicle_max_power),
+            None,
+            options,
+        )
+        .map_err(session_error_to_status)?;
+        Ok(match admission {
+            SessionAdmission::Started(session) => session_reply(session),
+            SessionAdmission::Queued {
+                queued_session,
+                position,
+            } => Response::new(proto::SessionReply {
+                session: Some(queued_session.into()),
+                queue_position: u32::try_from(position).unwrap_or(u32::MAX),
+            }),
+        })
+    }
+
+    async fn stop_session(
+        &self,
+        request: Request<proto::StopSessionRequest>,
+    ) -> Result<Response<proto::SessionReply>, Status> {
+        let mut state = self.app_state.lock().await;
+        let result = state
+            .resolve_session_id(&request.into_inner().session_id)
+            .and_then(|session_id| state.stop_session(session_id));
+        schedule_promoted_sessions(&self.app_state, &mut state);
+        result.map(session_reply).map_err(session_error_to_status)
+    }
+
+    async fn power_update(
+        &self,
+        request: Request<proto::PowerUpdateRequest>,
+    ) -> Result<Response<proto::SessionReply>, Status> {
+        let request = request.into_inner();
+        let mut state = self.app_state.lock().await;
+        state
+            .resolve_session_id(&request.session_id)
+            .and_then(|session_id| {
+                apply_power_update(
+                    &self.app_state,
+                    &mut state,
+                    session_id,
+                    request.consumed_power,
+                )
+            })
+            .map(session_reply)
+            .map_err(session_error_to_status)
+    }
+
+    async fn get_status(
+        &self,
+        _request: Request<proto::GetStatusRequest>,
+    ) -> Result<Response<proto::StatusReply>, Status> {
+        let state = self.app_state.lock().await;
+        let sessions = state
+            .get_sessions()
+            .values()
+            .cloned()
+            .map(Into::into)
+            .collect();
+        Ok(Response::new(proto::StatusReply { sessions }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sems_core::{ChargerConfig, StationConfig};
+    use tonic::Code;
+    use uuid::Uuid;
+
+    fn test_service() -> SemsService {
+        test_service_with(test_config())
+    }
+
+    fn test_service_with(config: StationConfig) -> SemsService {
+        SemsService {
+            app_state: Arc::new(Mutex::new(StationState::new(config))),
+        }
+    }
+
+    fn test_config() -> StationConfig {
+        StationConfig {
+            station_id: "TEST_STATION".into(),
+            grid_capacity: 400,
+            chargers: vec![ChargerConfig {
+                id: "CP001".into(),
+                max_power: 200,
+                connectors: 2,
+                ..Default::default()
+            }],
+            batteries: vec![],
+            ..Default::default()
+        }
+    }
+
+    fn start_session_request(idx: u32, vehicle_max_power: u32) -> proto::StartSessionRequest {
+        proto::StartSessionRequest {
+            connector_id: Some(proto::ConnectorId {
+                charger_id: "CP001".into(),
+                idx,
+            }),
+            vehicle_max_power,
+            metadata: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_lifecycle() {
+        let service = test_service();
+
+        let session = service
+            .start_session(Request::new(start_session_request(1, 150)))
+            .await
+            .unwrap()
+            .into_inner()
+            .session
+            .unwrap();
+        assert_eq!(session.allocated_power, 150);
+        assert_eq!(session.state(), proto::SessionState::Pending);
+
+        let session = service
+            .power_update(Request::new(proto::PowerUpdateRequest {
+                session_id: session.session_id,
+                consumed_power: 100,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .session
+            .unwrap();
+        assert_eq!(session.allocated_power, 100);
+        assert_eq!(session.state(), proto::SessionState::Charging);
+        assert_eq!(session.short_id.len(), 8);
+
+        let status = service
+            .get_status(Request::new(proto::GetStatusRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.sessions.len(), 1);
+        assert_eq!(status.sessions[0].session_id, session.session_id);
+
+        let session = service
+            .stop_session(Request::new(proto::StopSessionRequest {
+                session_id: session.session_id,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .session
+            .unwrap();
+        assert_eq!(session.state(), proto::SessionState::Finished);
+    }
+
+    #[tokio::test]
+    async fn test_session_queue() {
+        let service = test_service_with(StationConfig {
+            queue_when_full: true,
+            ..test_config()
+        });
+
+        let first = service
+            .start_session(Request::new(start_session_request(1, 200)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(first.queue_position, 0);
+
+        // The charger has no power left, so the second session waits
+        let reply = service
+            .start_session(Request::new(start_session_request(2, 200)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.queue_position, 1);
+        let queued = reply.session.unwrap();
+        assert_eq!(queued.state(), proto::SessionState::Queued);
+        assert_eq!(queued.allocated_power, 0);
+        assert_eq!(queued.short_id.len(), 8);
+
+        service
+            .stop_session(Request::new(proto::StopSessionRequest {
+                session_id: first.session.unwrap().session_id,
+            }))
+            .await
+            .unwrap();
+        let status = service
+            .get_status(Request::new(proto::GetStatusRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.sessions.len(), 1);
+        assert_eq!(status.sessions[0].session_id, queued.session_id);
+        assert_eq!(status.sessions[0].allocated_power, 200);
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let service = test_service();
+
+        let status = service
+            .start_session(Request::new(start_session_request(3, 150)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        // Indexes above u8::MAX do not wrap around to an existing connector
+        let status = service
+            .start_session(Request::new(start_session_request(257, 150)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let status = service
+            .start_session(Request::new(start_session_request(0, 150)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        service
+            .start_session(Request::new(start_session_request(1, 150)))
+            .await
+            .unwrap();
+        let status = service
+            .start_session(Request::new(start_session_request(1, 150)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        let status = service
+            .stop_session(Request::new(proto::StopSessionRequest {
+                session_id: "not-a-uuid".into(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let status = service
+            .power_update(Request::new(proto::PowerUpdateRequest {
+                session_id: Uuid::new_v4().to_string(),
+                consumed_power: 100,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
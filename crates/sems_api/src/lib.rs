@@ -2,39 +2,399 @@
 //!
 //! This library provides the HTTP API for the Station Energy Management System.
 
+mod auth;
+mod charger;
+mod extract;
+mod grpc;
+mod openapi;
 mod session;
 mod station;
+mod units;
 
 use axum::{
-    Router,
-    routing::{get, post},
+    BoxError, Json, Router,
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    http::{HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post},
 };
 use sems_core::StationState;
-use std::sync::{Arc, Mutex};
+use std::future::{Ready, ready};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tower::ServiceBuilder;
+use tower::timeout::TimeoutLayer;
+use tower_http::cors::CorsLayer;
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::TraceLayer;
 
+use crate::session::{ErrorKind, ErrorResponse};
+
+pub use crate::grpc::create_grpc_service;
+
 /// Health check endpoint
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses((status = 200, description = "Server is up", body = String))
+)]
 pub async fn health_check() -> &'static str {
     "OK"
 }
 
+/// Fallback for the methods a route does not support
+///
+/// Answers 405 with an `ErrorResponse` and the `Allow` header listing the supported methods.
+fn method_not_allowed(
+    allow: &'static str,
+) -> impl FnOnce(Method) -> Ready<Response> + Clone + Send + Sync + 'static {
+    move |method: Method| {
+        ready(
+            (
+                [(header::ALLOW, allow)],
+                ErrorResponse::response(
+                    ErrorKind::MethodNotAllowed,
+                    format!("Method {} is not allowed, expected {}", method, allow),
+                ),
+            )
+                .into_response(),
+        )
+    }
+}
+
+/// Fallback for the paths no route matches, answered like the other errors
+async fn route_not_found(method: Method, uri: axum::http::Uri) -> Response {
+    ErrorResponse::response(
+        ErrorKind::RouteNotFound,
+        format!("No route for {} {}", method, uri.path()),
+    )
+}
+
+/// Header holding the time the server answered, in RFC 3339 with milliseconds
+pub const SERVER_TIME_HEADER: HeaderName = HeaderName::from_static("x-server-time");
+
+/// Add the server time to the response, so that clients can estimate their clock skew and order
+/// the responses
+///
+/// Unlike the `Date` header, it has a sub-second precision.
+async fn add_server_time(mut response: Response) -> Response {
+    let server_time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    if let Ok(value) = HeaderValue::from_str(&server_time) {
+        response.headers_mut().insert(SERVER_TIME_HEADER, value);
+    }
+    response
+}
+
+/// Build the CORS layer allowing browsers on the given origins to call the API
+///
+/// Without origins, no CORS header is returned, so that only same-origin requests are allowed.
+fn cors_layer(allowed_origins: Vec<HeaderValue>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(allowed_origins)
+        .allow_methods([
+            Method::GET,
+            Method::HEAD,
+            Method::POST,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            HeaderName::from_static("idempotency-key"),
+        ])
+        .expose_headers([SERVER_TIME_HEADER])
+}
+
+/// Answer the requests exceeding the request timeout with a 504
+///
+/// The timeout is the only error of the layers under the error handling.
+async fn timeout_response(method: Method, uri: axum::http::Uri, error: BoxError) -> Response {
+    tracing::warn!("{} {} failed: {}", method, uri, error);
+    ErrorResponse::response(
+        ErrorKind::Timeout,
+        format!("{} {} was not answered in time", method, uri.path()),
+    )
+}
+
+/// Answer the bodies rejected by the body limit with an `ErrorResponse`, like the other errors
+///
+/// The body limit layer answers a plain text 413 when the content length is over the limit.
+async fn body_limit_response(response: Response) -> Response {
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/json");
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE || is_json {
+        return response;
+    }
+    let error = ErrorResponse {
+        error: "The request body is over the size limit".into(),
+        kind: ErrorKind::Malformed,
+    };
+    (StatusCode::PAYLOAD_TOO_LARGE, Json(error)).into_response()
+}
+
+/// Default maximum size of the request bodies, in bytes
+pub const DEFAULT_BODY_LIMIT: usize = 1024 * 1024;
+
+/// Default time to answer a request
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options of the application router
+#[derive(Debug, Clone)]
+pub struct AppOptions {
+    /// Origins of the browsers allowed to call the API from another origin
+    pub cors_origins: Vec<HeaderValue>,
+    /// Bearer token required by the endpoints changing the station configuration or capacity
+    pub admin_token: Option<String>,
+    /// Maximum size of the request bodies in bytes, larger ones are rejected with a 413
+    pub body_limit: usize,
+    /// Time to answer a request, after which it is answered with a 504
+    pub request_timeout: Duration,
+}
+
+impl Default for AppOptions {
+    fn default() -> Self {
+        AppOptions {
+            cors_origins: Vec::new(),
+            admin_token: None,
+            body_limit: DEFAULT_BODY_LIMIT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
+
 /// Create the application router with all endpoints
-pub fn create_app(app_state: StationState) -> Router {
-    let shared_state = Arc::new(Mutex::new(app_state));
+pub fn create_app(app_state: StationState, options: AppOptions) -> Router {
+    create_app_with_state(Arc::new(Mutex::new(app_state)), options)
+}
+
+/// Create the application router on a station state shared with other servers, e.g. the gRPC one
+pub fn create_app_with_state(
+    shared_state: Arc<Mutex<StationState>>,
+    options: AppOptions,
+) -> Router {
+    let AppOptions {
+        cors_origins,
+        admin_token,
+        body_limit,
+        request_timeout,
+    } = options;
+    let admin_only = middleware::from_fn_with_state(
+        admin_token.map(Arc::<str>::from),
+        auth::require_admin_token,
+    );
     Router::new()
-        .route("/health", get(health_check))
+        .route(
+            "/health",
+            get(health_check).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/openapi.json",
+            get(openapi::get_openapi).fallback(method_not_allowed("GET, HEAD")),
+        )
         .route(
             "/station/config",
-            get(station::get_station_config).post(station::update_station_config),
+            post(station::update_station_config)
+                .route_layer(admin_only.clone())
+                .get(station::get_station_config)
+                .fallback(method_not_allowed("GET, HEAD, POST")),
+        )
+        .route(
+            "/station/config/preview",
+            post(station::preview_station_config).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/config/schema",
+            get(openapi::get_config_schema).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/station/status",
+            get(station::get_station_status).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/station/status/compact",
+            get(station::get_compact_station_status).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/station/status.ndjson",
+            get(station::get_station_status_ndjson).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/station/capacity",
+            get(station::get_station_capacity).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/station/demand-response",
+            post(station::apply_demand_response)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/power-cap",
+            post(station::set_power_cap)
+                .delete(station::clear_power_cap)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST, DELETE")),
+        )
+        .route(
+            "/station/margin",
+            post(station::set_safety_margin)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/what-if",
+            post(station::what_if_capacity).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/drain",
+            post(station::drain_station)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/undrain",
+            post(station::undrain_station)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/emergency-stop",
+            post(station::emergency_stop)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/clear-emergency",
+            post(station::clear_emergency_stop)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/reallocate",
+            post(station::reallocate_station)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/station/battery",
+            post(station::control_battery)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/chargers",
+            get(charger::get_chargers).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/chargers/{charger_id}/derate",
+            post(charger::derate_charger)
+                .delete(charger::clear_charger_derating)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST, DELETE")),
+        )
+        .route(
+            "/chargers/{charger_id}/suspend",
+            post(charger::suspend_charger)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/chargers/{charger_id}/resume",
+            post(charger::resume_charger)
+                .route_layer(admin_only.clone())
+                .fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/sessions",
+            get(session::list_sessions)
+                .post(session::create_session)
+                .fallback(method_not_allowed("GET, HEAD, POST")),
+        )
+        .route(
+            "/sessions/preview",
+            post(session::preview_session).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/sessions/import",
+            post(session::import_session).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/sessions/power-updates",
+            post(session::batch_power_update).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/sessions/by-label/{label}",
+            post(session::create_session_by_label).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/connectors/{charger_id}/{idx}/reattach",
+            post(session::reattach_session).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/sessions/{session_id}",
+            get(session::get_session).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/sessions/{session_id}/export",
+            get(session::export_session).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/sessions/{session_id}/history",
+            get(session::get_allocation_history).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/sessions/{session_id}/stop",
+            post(session::stop_session).fallback(method_not_allowed("POST")),
         )
-        .route("/station/status", get(station::get_station_status))
-        .route("/sessions", post(session::create_session))
-        .route("/sessions/{session_id}/stop", post(session::stop_session))
         .route(
             "/sessions/{session_id}/power-update",
-            post(session::power_update),
+            post(session::power_update).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/sessions/{session_id}/metadata",
+            patch(session::update_session_metadata).fallback(method_not_allowed("PATCH")),
         )
+        .route(
+            "/sessions/{session_id}/notes",
+            post(session::add_session_note).fallback(method_not_allowed("POST")),
+        )
+        .route(
+            "/sessions/{session_id}/pin",
+            post(session::pin_session)
+                .delete(session::unpin_session)
+                .route_layer(admin_only)
+                .fallback(method_not_allowed("POST, DELETE")),
+        )
+        .route(
+            "/queue",
+            get(session::list_queued_sessions).fallback(method_not_allowed("GET, HEAD")),
+        )
+        .route(
+            "/queue/{session_id}",
+            delete(session::cancel_queued_session).fallback(method_not_allowed("DELETE")),
+        )
+        .fallback(route_not_found)
+        // The station lock is an async lock never held across an await, so a request waiting for
+        // it is cancelled at the timeout, and one holding it is not
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(timeout_response))
+                .layer(TimeoutLayer::new(request_timeout)),
+        )
+        // The body limit replaces the default limit of the extractors, so that it can be raised
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(body_limit))
+        .layer(middleware::map_response(body_limit_response))
+        .layer(cors_layer(cors_origins))
         .layer(TraceLayer::new_for_http())
+        // Outermost, so that the errors of the other layers have the server time too
+        .layer(middleware::map_response(add_server_time))
         .with_state(shared_state)
 }
 
@@ -60,8 +420,10 @@ mod tests {
                 id: "CP001".into(),
                 max_power: 200,
                 connectors: 2,
+                ..Default::default()
             }],
-            battery: None,
+            batteries: vec![],
+            ..Default::default()
         }
     }
 
@@ -86,7 +448,7 @@ mod tests {
     async fn test_integration_create_and_stop_session() {
         let config = test_station_config();
         let state = StationState::new(config);
-        let app = create_app(state);
+        let app = create_app(state, AppOptions::default());
 
         // Create a session
         let create_request = session::CreateSessionRequest {
@@ -94,7 +456,9 @@ mod tests {
                 charger_id: "CP001".to_string(),
                 idx: 1,
             },
-            vehicle_max_power: 150,
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: Default::default(),
         };
 
         let response = app
@@ -137,7 +501,7 @@ mod tests {
     async fn test_integration_session_with_power_update() {
         let config = test_station_config();
         let state = StationState::new(config);
-        let app = create_app(state);
+        let app = create_app(state, AppOptions::default());
 
         // Create a session
         let create_request = session::CreateSessionRequest {
@@ -145,7 +509,9 @@ mod tests {
                 charger_id: "CP001".to_string(),
                 idx: 1,
             },
-            vehicle_max_power: 150,
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: Default::default(),
         };
 
         let response = app
@@ -199,4 +565,407 @@ mod tests {
         assert_eq!(updated_session_response.session.session_id, session_id);
         assert_eq!(updated_session_response.session.vehicle_max_power, 100);
     }
+
+    #[tokio::test]
+    async fn test_route_not_found() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state, AppOptions::default());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/stations/status")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().contains_key(SERVER_TIME_HEADER));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: session::ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::RouteNotFound);
+        assert_eq!(error_response.error, "No route for GET /stations/status");
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state, AppOptions::default());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("PUT")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, POST");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: session::ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert!(error_response.error.contains("PUT is not allowed"));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, POST");
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(
+            state,
+            AppOptions {
+                cors_origins: vec![HeaderValue::from_static("https://dashboard.example.com")],
+                ..Default::default()
+            },
+        );
+
+        // Preflight from an allowed origin
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("OPTIONS")
+                    .header(header::ORIGIN, "https://dashboard.example.com")
+                    .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                    .header(
+                        header::ACCESS_CONTROL_REQUEST_HEADERS,
+                        "content-type,idempotency-key",
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dashboard.example.com"
+        );
+        let allowed_headers = headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(allowed_headers.contains("content-type"));
+        assert!(allowed_headers.contains("idempotency-key"));
+        assert!(
+            headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+                .to_str()
+                .unwrap()
+                .contains("PATCH")
+        );
+
+        // Other origins are not allowed
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .header(header::ORIGIN, "https://evil.example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cors_same_origin_by_default() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state, AppOptions::default());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .header(header::ORIGIN, "https://dashboard.example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let config = test_station_config();
+        let state = StationState::new(config.clone());
+        let app = create_app(
+            state,
+            AppOptions {
+                body_limit: 1024,
+                ..Default::default()
+            },
+        );
+        // A valid configuration, padded over the limit
+        let mut body = serde_json::to_value(&config).unwrap();
+        body["padding"] = "x".repeat(2048).into();
+        let body = serde_json::to_string(&body).unwrap();
+
+        // Rejected from its content length
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("POST")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::CONTENT_LENGTH, body.len())
+                    .body(Body::from(body.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let error_body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: session::ErrorResponse = serde_json::from_slice(&error_body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Malformed);
+
+        // Rejected while reading it without content length
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("POST")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: session::ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Malformed);
+
+        // A body under the limit is accepted
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("POST")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::to_string(&config).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout_on_locked_station() {
+        let shared_state = Arc::new(Mutex::new(StationState::new(test_station_config())));
+        let app = create_app_with_state(
+            shared_state.clone(),
+            AppOptions {
+                request_timeout: Duration::from_millis(50),
+                ..Default::default()
+            },
+        );
+
+        // A request waiting for the station lock is answered at the timeout
+        let state = shared_state.lock().await;
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: session::ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Timeout);
+
+        drop(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_server_time_header() {
+        let state = StationState::new(test_station_config());
+        let app = create_app(state, AppOptions::default());
+        let before = chrono::Utc::now();
+
+        for request in [
+            Request::builder()
+                .uri("/station/status")
+                .body(Body::empty())
+                .unwrap(),
+            // Errors have it too
+            Request::builder()
+                .uri("/sessions/unknown")
+                .body(Body::empty())
+                .unwrap(),
+        ] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            let server_time = response.headers()[SERVER_TIME_HEADER].to_str().unwrap();
+            let server_time = chrono::DateTime::parse_from_rfc3339(server_time).unwrap();
+            assert!(server_time >= before - chrono::Duration::milliseconds(1));
+            assert!(server_time <= chrono::Utc::now());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_token() {
+        let config = test_station_config();
+        let state = StationState::new(config.clone());
+        let app = create_app(
+            state,
+            AppOptions {
+                admin_token: Some("secret".into()),
+                ..Default::default()
+            },
+        );
+        let config_request = |authorization: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("/station/config")
+                .method("POST")
+                .header("content-type", "application/json");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            request
+                .body(Body::from(serde_json::to_string(&config).unwrap()))
+                .unwrap()
+        };
+
+        // Without a token, or with a bad one, the admin endpoints are denied
+        let response = app.clone().oneshot(config_request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: session::ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Unauthorized);
+
+        let response = app
+            .clone()
+            .oneshot(config_request(Some("Bearer wrong")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/chargers/CP001/derate")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // With the token, they are allowed
+        let response = app
+            .clone()
+            .oneshot(config_request(Some("Bearer secret")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The read and session endpoints stay open
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Unsupported methods are still answered with a 405
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
 }
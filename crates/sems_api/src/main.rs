@@ -1,7 +1,25 @@
-use clap::Parser;
-use sems_api::create_app;
-use sems_core::{StationConfig, StationState};
-use std::path::PathBuf;
+use axum::http::HeaderValue;
+use axum_server::tls_rustls::RustlsConfig;
+use clap::{Parser, ValueEnum};
+use sems_api::{
+    AppOptions, DEFAULT_BODY_LIMIT, DEFAULT_REQUEST_TIMEOUT, create_app_with_state,
+    create_grpc_service,
+};
+use sems_core::{ChargerConfig, ConfigProblem, StationConfig, StationState};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing_subscriber::EnvFilter;
+
+/// Output format of the logs
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    /// Human readable multi-line logs, for local development
+    Pretty,
+    /// One JSON object per line, for log aggregators
+    Json,
+}
 
 /// Command line arguments for the electra-sems server
 #[derive(Parser, Debug)]
@@ -15,6 +33,181 @@ struct Args {
     /// Port to bind the server to
     #[arg(short, long, default_value = "3000")]
     port: u16,
+
+    /// Format of the logs. The log level is set with the RUST_LOG environment variable
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+
+    /// Origin allowed to call the API from a browser, e.g. https://dashboard.example.com.
+    /// Can be repeated. Without it, only same-origin requests are allowed
+    #[arg(long = "cors-origin")]
+    cors_origins: Vec<HeaderValue>,
+
+    /// Port to bind the gRPC server to. Without it, only the REST API is served
+    #[arg(long)]
+    grpc_port: Option<u16>,
+
+    /// Bearer token required by the endpoints changing the station configuration or capacity.
+    /// Without it, these endpoints are open to anyone who can reach the API
+    #[arg(long, env = "SEMS_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Number of times to retry reading the configuration file, e.g. while a network volume is
+    /// being mounted. The delay between attempts doubles, starting at 500ms
+    #[arg(long, default_value = "3")]
+    config_retries: u32,
+
+    /// Maximum size of the request bodies in bytes. Larger requests are rejected with a 413
+    #[arg(long, default_value_t = DEFAULT_BODY_LIMIT)]
+    body_limit: usize,
+
+    /// Time in seconds to answer a request. Slower REST requests are answered with a 504, and
+    /// slower gRPC calls are cancelled
+    #[arg(long, default_value_t = DEFAULT_REQUEST_TIMEOUT.as_secs())]
+    request_timeout: u64,
+
+    /// Only check the configuration file: print its problems to stderr, one per line, and exit
+    /// with a non-zero status if it has some, without starting the servers
+    #[arg(long)]
+    check: bool,
+
+    /// PEM file of the certificate chain to serve the REST API over HTTPS, e.g. on edge
+    /// deployments without a reverse proxy. Requires --tls-key. The files are read at startup, so
+    /// a renewed certificate requires a restart
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of the certificate. Requires --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+/// Delay before the first retry of the configuration file read
+const CONFIG_RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest delay between two reads of the configuration file
+const CONFIG_RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// Read the configuration file, retrying up to `retries` times with an exponential backoff
+async fn read_config(path: &Path, retries: u32) -> Result<String, String> {
+    let mut delay = CONFIG_RETRY_INITIAL_DELAY;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match tokio::fs::read_to_string(path).await {
+            Ok(content) => return Ok(content),
+            Err(e) if attempt <= retries => {
+                tracing::warn!(
+                    "Failed to read config file '{}' (attempt {}/{}): {}, retrying in {:?}",
+                    path.display(),
+                    attempt,
+                    retries + 1,
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(CONFIG_RETRY_MAX_DELAY);
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read config file '{}' after {} attempts: {}",
+                    path.display(),
+                    attempt,
+                    e
+                ));
+            }
+        }
+    }
+}
+
+/// Parse the configuration file. For sites with many chargers, its `chargers` can be the path of
+/// a JSON file holding the array of chargers, relative to the configuration file.
+///
+/// On error, return the path of the file that cannot be read or parsed with the error.
+fn parse_config(path: &Path, content: &str) -> Result<StationConfig, (PathBuf, String)> {
+    let config_error = |e: serde_json::Error| (path.to_path_buf(), e.to_string());
+    let mut config: serde_json::Value = serde_json::from_str(content).map_err(config_error)?;
+    let Some(chargers_file) = config["chargers"].as_str() else {
+        // Parsed from the text, so that the errors give their line
+        return serde_json::from_str(content).map_err(config_error);
+    };
+
+    let chargers_path = path.parent().unwrap_or(Path::new("")).join(chargers_file);
+    let chargers_error = |e: &dyn std::fmt::Display| (chargers_path.clone(), e.to_string());
+    let chargers_content =
+        std::fs::read_to_string(&chargers_path).map_err(|e| chargers_error(&e))?;
+    // Parsed as chargers first, so that the errors point into the chargers file
+    serde_json::from_str::<Vec<ChargerConfig>>(&chargers_content)
+        .map_err(|e| chargers_error(&e))?;
+    config["chargers"] = serde_json::from_str(&chargers_content).map_err(|e| chargers_error(&e))?;
+    serde_json::from_value(config).map_err(config_error)
+}
+
+/// Parse and validate the configuration file, returning its problems and its warnings as lines
+/// of the form `<path>: <problem>: <message>`, where the problem is `parse` if the file cannot be
+/// parsed. The warnings are prefixed by `warning: ` and do not make the configuration invalid.
+/// The problems of a separate chargers file are reported with its path.
+fn check_config(path: &Path, content: &str) -> (Vec<String>, Vec<String>) {
+    let station_config = match parse_config(path, content) {
+        Ok(station_config) => station_config,
+        Err((path, e)) => {
+            return (
+                vec![format!("{}: parse: {}", path.display(), e)],
+                Vec::new(),
+            );
+        }
+    };
+    let problem_line = |prefix: &str, problem: &ConfigProblem| {
+        let kind = serde_json::to_value(problem)
+            .ok()
+            .and_then(|value| value["problem"].as_str().map(str::to_string))
+            .unwrap_or_default();
+        format!("{}: {}{}: {}", path.display(), prefix, kind, problem)
+    };
+    let problems = station_config
+        .validate()
+        .err()
+        .unwrap_or_default()
+        .iter()
+        .map(|problem| problem_line("", problem))
+        .collect();
+    let warnings = station_config
+        .warnings()
+        .iter()
+        .map(|warning| problem_line("warning: ", warning))
+        .collect();
+    (problems, warnings)
+}
+
+/// Station and server settings logged once at startup, for field diagnosis
+#[derive(Debug, PartialEq, Eq)]
+struct StartupSummary {
+    station_id: String,
+    /// Grid capacity in kW
+    grid_capacity: u32,
+    chargers: usize,
+    /// Combined max power of the chargers in kW
+    charger_capacity: u32,
+    batteries: usize,
+    bind_addr: String,
+    tls: bool,
+}
+
+/// Build the startup summary of the station configuration and the REST server address
+fn startup_summary(config: &StationConfig, bind_addr: &str, tls: bool) -> StartupSummary {
+    StartupSummary {
+        station_id: config.station_id.clone(),
+        grid_capacity: config.grid_capacity,
+        chargers: config.chargers.len(),
+        charger_capacity: config
+            .chargers
+            .iter()
+            .map(ChargerConfig::total_max_power)
+            .fold(0u32, u32::saturating_add),
+        batteries: config.batteries.len(),
+        bind_addr: bind_addr.to_string(),
+        tls,
+    }
 }
 
 #[tokio::main]
@@ -23,48 +216,189 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
     // Initialize tracing
-    tracing_subscriber::fmt().pretty().init();
+    let subscriber = tracing_subscriber::fmt().with_env_filter(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    );
+    match args.log_format {
+        LogFormat::Pretty => subscriber.pretty().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 
     // Load station configuration from JSON file
-    let config_content = tokio::fs::read_to_string(&args.config).await.map_err(|e| {
-        format!(
-            "Failed to read config file '{}': {}",
-            args.config.display(),
-            e
-        )
-    })?;
+    let config_content = read_config(&args.config, args.config_retries).await?;
 
-    let station_config: StationConfig = serde_json::from_str(&config_content).map_err(|e| {
+    if args.check {
+        let (problems, warnings) = check_config(&args.config, &config_content);
+        for warning in warnings {
+            eprintln!("{}", warning);
+        }
+        if problems.is_empty() {
+            println!("{}: OK", args.config.display());
+            return Ok(());
+        }
+        for problem in problems {
+            eprintln!("{}", problem);
+        }
+        std::process::exit(1);
+    }
+
+    let station_config = parse_config(&args.config, &config_content)
+        .map_err(|(path, e)| format!("Failed to parse config file '{}': {}", path.display(), e))?;
+
+    station_config.validate().map_err(|problems| {
         format!(
-            "Failed to parse config file '{}': {}",
+            "Invalid config file '{}': {}",
             args.config.display(),
-            e
+            problems
+                .iter()
+                .map(|problem| problem.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
         )
     })?;
 
+    for warning in station_config.warnings() {
+        tracing::warn!("Config file '{}': {}", args.config.display(), warning);
+    }
+
     tracing::info!(
         "Loaded station config from {}: {}",
         args.config.display(),
         station_config.station_id
     );
 
-    // Create application state
-    let app_state = StationState::new(station_config);
+    // Load the certificate before binding, so that a bad certificate fails fast
+    let tls_config = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            // Explicit, as other crates may enable another provider of rustls
+            let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+            Some(RustlsConfig::from_pem_file(cert, key).await.map_err(|e| {
+                format!(
+                    "Failed to load TLS certificate '{}' and key '{}': {}",
+                    cert.display(),
+                    key.display(),
+                    e
+                )
+            })?)
+        }
+        _ => None,
+    };
+
+    let bind_addr = format!("0.0.0.0:{}", args.port);
+    let summary = startup_summary(&station_config, &bind_addr, tls_config.is_some());
+
+    // Create application state, shared by the REST and gRPC servers
+    let app_state = Arc::new(Mutex::new(StationState::new(station_config)));
 
     // Build our application with routes
-    let app = create_app(app_state);
+    if args.admin_token.is_none() {
+        tracing::warn!("No admin token set, the admin endpoints are open to anyone");
+    }
+    let request_timeout = Duration::from_secs(args.request_timeout);
+    let app = create_app_with_state(
+        app_state.clone(),
+        AppOptions {
+            cors_origins: args.cors_origins,
+            admin_token: args.admin_token,
+            body_limit: args.body_limit,
+            request_timeout,
+        },
+    );
 
     // Run our app with hyper
-    let bind_addr = format!("0.0.0.0:{}", args.port);
-    tracing::info!("Starting server on {}", bind_addr);
+    tracing::info!(
+        station_id = summary.station_id,
+        grid_capacity_kw = summary.grid_capacity,
+        chargers = summary.chargers,
+        charger_capacity_kw = summary.charger_capacity,
+        battery = summary.batteries > 0,
+        batteries = summary.batteries,
+        bind_addr = summary.bind_addr,
+        "Starting {} server",
+        if summary.tls { "HTTPS" } else { "HTTP" }
+    );
 
     let listener = tokio::net::TcpListener::bind(&bind_addr)
         .await
         .map_err(|e| format!("Failed to bind to {}: {}", bind_addr, e))?;
 
-    axum::serve(listener, app)
-        .await
-        .map_err(|e| format!("Server error: {}", e))?;
+    let rest_server = async {
+        match tls_config {
+            Some(tls_config) => {
+                let listener = listener
+                    .into_std()
+                    .map_err(|e| format!("Failed to bind to {}: {}", bind_addr, e))?;
+                axum_server::from_tcp_rustls(listener, tls_config)
+                    .serve(app.into_make_service())
+                    .await
+            }
+            None => axum::serve(listener, app).await,
+        }
+        .map_err(|e| format!("Server error: {}", e))
+    };
+
+    // Run the gRPC server alongside, if enabled
+    let grpc_server = async {
+        let Some(grpc_port) = args.grpc_port else {
+            return Ok(());
+        };
+        let grpc_addr = ([0, 0, 0, 0], grpc_port).into();
+        tracing::info!("Starting gRPC server on {}", grpc_addr);
+        tonic::transport::Server::builder()
+            .timeout(request_timeout)
+            .add_service(create_grpc_service(app_state))
+            .serve(grpc_addr)
+            .await
+            .map_err(|e| format!("gRPC server error on {}: {}", grpc_addr, e))
+    };
+
+    tokio::try_join!(rest_server, grpc_server)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sems_core::{Bess, SharingMode};
+
+    #[test]
+    fn test_startup_summary() {
+        let config = StationConfig {
+            station_id: "TEST_STATION".into(),
+            grid_capacity: 400,
+            chargers: vec![
+                ChargerConfig {
+                    id: "CP001".into(),
+                    max_power: 150,
+                    connectors: 2,
+                    sharing: SharingMode::Dedicated,
+                    ..Default::default()
+                },
+                ChargerConfig {
+                    id: "CP002".into(),
+                    max_power: 200,
+                    connectors: 2,
+                    sharing: SharingMode::Shared,
+                    ..Default::default()
+                },
+            ],
+            batteries: vec![Bess::new(100, 50)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            startup_summary(&config, "0.0.0.0:3000", false),
+            StartupSummary {
+                station_id: "TEST_STATION".into(),
+                grid_capacity: 400,
+                chargers: 2,
+                // The connectors of a dedicated charger each get its max power
+                charger_capacity: 500,
+                batteries: 1,
+                bind_addr: "0.0.0.0:3000".into(),
+                tls: false,
+            }
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use axum::Json;
+use serde_json::Value;
+use utoipa::OpenApi;
+
+use crate::{charger, session, station};
+
+/// OpenAPI description of the endpoints and their DTOs
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Electra SEMS",
+        description = "Station Energy Management System"
+    ),
+    paths(
+        crate::health_check,
+        station::get_station_config,
+        get_config_schema,
+        station::update_station_config,
+        station::preview_station_config,
+        station::get_station_status,
+        station::get_compact_station_status,
+        station::get_station_status_ndjson,
+        station::get_station_capacity,
+        station::apply_demand_response,
+        station::set_power_cap,
+        station::clear_power_cap,
+        station::set_safety_margin,
+        station::what_if_capacity,
+        station::control_battery,
+        station::drain_station,
+        station::undrain_station,
+        station::emergency_stop,
+        station::clear_emergency_stop,
+        station::reallocate_station,
+        charger::get_chargers,
+        charger::derate_charger,
+        charger::clear_charger_derating,
+        charger::suspend_charger,
+        charger::resume_charger,
+        session::list_sessions,
+        session::create_session,
+        session::create_session_by_label,
+        session::reattach_session,
+        session::get_session,
+        session::export_session,
+        session::import_session,
+        session::get_allocation_history,
+        session::preview_session,
+        session::stop_session,
+        session::power_update,
+        session::batch_power_update,
+        session::update_session_metadata,
+        session::add_session_note,
+        session::pin_session,
+        session::unpin_session,
+        session::list_queued_sessions,
+        session::cancel_queued_session,
+    )
+)]
+pub struct ApiDoc;
+
+/// Serve the OpenAPI specification
+pub async fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Get the JSON Schema of the station configuration
+/// The schema is derived from the configuration types, so that config UIs can validate a
+/// configuration before posting it.
+#[utoipa::path(
+    get,
+    path = "/station/config/schema",
+    tag = "station",
+    responses((status = 200, description = "JSON Schema of the station configuration", body = Object))
+)]
+pub async fn get_config_schema() -> Json<Value> {
+    Json(component_json_schema("StationConfig"))
+}
+
+/// Return a standalone JSON Schema of a component of the OpenAPI specification
+///
+/// OpenAPI 3.1 schemas are JSON Schemas, so the component is kept as is, with every component
+/// in its `$defs` for its references to resolve.
+fn component_json_schema(name: &str) -> Value {
+    let openapi = serde_json::to_value(ApiDoc::openapi()).expect("The OpenAPI spec is JSON");
+    let mut defs = openapi["components"]["schemas"].clone();
+    rewrite_component_refs(&mut defs);
+
+    let mut schema = defs[name].clone();
+    if let Value::Object(object) = &mut schema {
+        object.insert(
+            "$schema".to_string(),
+            "https://json-schema.org/draft/2020-12/schema".into(),
+        );
+        object.insert("title".to_string(), name.into());
+        object.insert("$defs".to_string(), defs);
+    }
+    schema
+}
+
+/// Point the references to the OpenAPI components to the `$defs` of the JSON Schema
+fn rewrite_component_refs(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::String(reference) if key == "$ref" => {
+                        *reference = reference.replace("#/components/schemas/", "#/$defs/");
+                    }
+                    value => rewrite_component_refs(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(rewrite_component_refs),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+    };
+    use tower::util::ServiceExt;
+
+    #[tokio::test]
+    async fn test_openapi_endpoint() {
+        let app = Router::new().route("/openapi.json", get(get_openapi));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/openapi.json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let create_session = &spec["paths"]["/sessions"]["post"];
+        assert!(create_session["responses"]["200"].is_object());
+        assert!(create_session["responses"]["409"].is_object());
+        assert!(spec["paths"]["/station/config"]["get"].is_object());
+        assert!(spec["paths"]["/station/config"]["post"].is_object());
+
+        let schemas = &spec["components"]["schemas"];
+        for schema in [
+            "CreateSessionRequest",
+            "SessionResponse",
+            "PowerUpdateRequest",
+            "ErrorResponse",
+            "StationConfig",
+            "StationStatus",
+        ] {
+            assert!(schemas[schema].is_object(), "Missing schema {}", schema);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config_schema_endpoint() {
+        let app = Router::new().route("/station/config/schema", get(get_config_schema));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config/schema")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let schema: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(schema["title"], "StationConfig");
+        assert_eq!(schema["type"], "object");
+        for property in ["stationId", "gridCapacity", "chargers", "batteries"] {
+            assert!(
+                schema["properties"][property].is_object(),
+                "Missing property {}",
+                property
+            );
+        }
+        assert!(schema["$defs"]["ChargerConfig"]["properties"]["maxPower"].is_object());
+
+        // The references point to the definitions of the schema
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("#/$defs/ChargerConfig"));
+        assert!(!body.contains("#/components/schemas/"));
+    }
+}
@@ -1,103 +1,1416 @@
 use axum::{
     Json,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use sems_core::{
+    AllocationSample, ConnectorId, Feasibility, QueuedSession, Session, SessionAdmission,
+    SessionError, SessionNote, SessionOptions, StationState,
 };
-use sems_core::{ConnectorId, Session, SessionError, StationState};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize)]
+use crate::extract::ApiJson;
+use crate::units::{PowerFields, kw_to_w};
+
+#[derive(Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateSessionRequest {
     pub connector_id: ConnectorId,
+    /// Max power of the vehicle in kW, required unless `vehicle_max_current` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle_max_power: Option<u32>,
+    /// Max current per phase of the vehicle in A, used instead of `vehicle_max_power` if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle_max_current: Option<u32>,
+    #[serde(flatten)]
+    pub options: SessionOptions,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateSessionByLabelRequest {
+    /// Max power of the vehicle in kW, required unless `vehicle_max_current` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle_max_power: Option<u32>,
+    /// Max current per phase of the vehicle in A, used instead of `vehicle_max_power` if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle_max_current: Option<u32>,
+    #[serde(flatten)]
+    pub options: SessionOptions,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReattachSessionRequest {
+    /// Max power of the vehicle in kW, required to create a session unless
+    /// `vehicle_max_current` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle_max_power: Option<u32>,
+    /// Max current per phase of the vehicle in A, used instead of `vehicle_max_power` if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle_max_current: Option<u32>,
+    /// Options of the session, only used if a new session is created
+    #[serde(flatten)]
+    pub options: SessionOptions,
+}
+
+/// Whether a reattach found the active session of the connector or created a new one
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ReattachOutcome {
+    /// The connector already had an active session, which is returned unchanged
+    Reattached,
+    /// The connector was free, and a new session was started
+    Created,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReattachSessionResponse {
+    pub outcome: ReattachOutcome,
+    #[serde(flatten)]
+    pub response: SessionResponse,
+}
+
+/// Portable description of a session, to re-create it on another connector or station
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionExport {
+    /// Id of the exported session, which the imported session does not keep
+    pub session_id: Uuid,
+    /// Connector of the exported session
+    pub connector_id: ConnectorId,
+    /// Max power of the vehicle in kW given when the session started
     pub vehicle_max_power: u32,
+    #[serde(flatten)]
+    pub options: SessionOptions,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportSessionRequest {
+    /// Connector to re-create the session on, which must exist and be free
+    pub connector_id: ConnectorId,
+    /// Session returned by `GET /sessions/{session_id}/export`
+    pub session: SessionExport,
+}
+
+/// Unit of the power fields in the responses
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum PowerUnit {
+    /// Power in kW
+    #[default]
+    Kw,
+    /// Power in W
+    W,
+    /// Current per phase in A, given the station voltage and the connector phases
+    Amps,
+}
+
+#[derive(Serialize, Deserialize, Default, IntoParams)]
+pub struct UnitQuery {
+    #[serde(default)]
+    pub unit: PowerUnit,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct SessionResponse {
     pub session: Session,
+    #[serde(default)]
+    pub unit: PowerUnit,
+    /// Share of the vehicle max power that is allocated, between 0 and 1
+    pub allocation_ratio: f32,
+    /// Notes left by operators on the session, from the oldest
+    #[serde(default)]
+    pub notes: Vec<SessionNote>,
+    /// Seconds left before the expiry set by the client, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<i64>,
+    /// Allocated power smoothed over the last allocations, for display only, if the station sets
+    /// a display smoothing factor. The chargers must apply `allocated_power`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_power: Option<u32>,
+}
+
+/// Return the share of the vehicle max power allocated to the session, clamped to [0, 1]
+///
+/// A vehicle that does not take any power is considered fully served.
+fn allocation_ratio(session: &Session) -> f32 {
+    if session.vehicle_max_power == 0 {
+        return 1.0;
+    }
+    (session.allocated_power as f32 / session.vehicle_max_power as f32).clamp(0.0, 1.0)
+}
+
+/// Build the response for a session, converting its power fields to the requested unit
+fn session_response(
+    state: &StationState,
+    mut session: Session,
+    unit: PowerUnit,
+) -> Result<SessionResponse, SessionError> {
+    let allocation_ratio = allocation_ratio(&session);
+    let notes = state.session_notes(session.session_id).to_vec();
+    let expires_in_secs = session
+        .expires_at
+        .map(|expires_at| (expires_at - chrono::Utc::now()).num_seconds().max(0));
+    let mut display_power = state.display_power(session.session_id);
+    match unit {
+        PowerUnit::Kw => {}
+        PowerUnit::W => {
+            session.map_powers(&kw_to_w);
+            display_power = display_power.map(kw_to_w);
+        }
+        PowerUnit::Amps => {
+            display_power = display_power
+                .map(|power| state.power_to_current(&session.connector_id, power))
+                .transpose()?;
+            session.allocated_power =
+                state.power_to_current(&session.connector_id, session.allocated_power)?;
+            session.vehicle_max_power =
+                state.power_to_current(&session.connector_id, session.vehicle_max_power)?;
+            session.requested_max_power =
+                state.power_to_current(&session.connector_id, session.requested_max_power)?;
+            session.consumed_power =
+                state.power_to_current(&session.connector_id, session.consumed_power)?;
+            session.pinned_power = session
+                .pinned_power
+                .map(|power| state.power_to_current(&session.connector_id, power))
+                .transpose()?;
+        }
+    }
+    Ok(SessionResponse {
+        session,
+        unit,
+        allocation_ratio,
+        notes,
+        expires_in_secs,
+        display_power,
+    })
+}
+
+/// Session waiting for the station to have capacity, with its powers in kW
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedSessionResponse {
+    #[serde(flatten)]
+    pub queued_session: QueuedSession,
+    /// Position of the session in the queue, from 1 for the next one to start
+    pub position: usize,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PowerUpdateRequest {
     pub consumed_power: u32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateMetadataRequest {
+    /// Key-values merged into the session metadata, overwriting existing keys
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PinSessionRequest {
+    /// Power to hold the session at in kW, exempt from the fair share
+    pub power: u32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AddNoteRequest {
+    /// Text of the note, e.g. "customer reported slow charging, investigated"
+    pub text: String,
+}
+
+/// Category of an error, so that clients can handle it without parsing the message
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    /// The request body is not valid JSON, does not match the expected schema, or is an
+    /// inconsistent station configuration (400)
+    Malformed,
+    /// The request is well-formed, but its values are invalid (422)
+    Validation,
+    /// The requested resource does not exist (404)
+    NotFound,
+    /// No route matches the path of the request (404)
+    RouteNotFound,
+    /// The session was stopped, and is no longer retained (410)
+    Gone,
+    /// The request conflicts with the current state of the station (409)
+    Conflict,
+    /// The route does not support the method (405)
+    MethodNotAllowed,
+    /// The admin bearer token is missing or invalid (401)
+    Unauthorized,
+    /// The station does not accept the request for now, e.g. while it is draining or emergency
+    /// stopped (503)
+    Unavailable,
+    /// The request was not answered within the request timeout (504)
+    Timeout,
+}
+
+impl ErrorKind {
+    /// Return the status code of the errors of this kind
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorKind::Malformed => StatusCode::BAD_REQUEST,
+            ErrorKind::Validation => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::NotFound | ErrorKind::RouteNotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Gone => StatusCode::GONE,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct ErrorResponse {
     pub error: String,
+    pub kind: ErrorKind,
+}
+
+impl ErrorResponse {
+    /// Build the response for an error of the given kind, with its status code
+    pub fn response(kind: ErrorKind, error: String) -> Response {
+        (kind.status(), Json(ErrorResponse { error, kind })).into_response()
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionGoneResponse {
+    pub error: String,
+    pub kind: ErrorKind,
+    /// When the session was stopped
+    pub stopped_at: chrono::DateTime<chrono::Utc>,
+}
+
+fn session_error_to_response(error: SessionError) -> Response {
+    let stopped_at = match error {
+        SessionError::SessionGone { stopped_at, .. } => Some(stopped_at),
+        _ => None,
+    };
+    let ErrorResponse { error, kind } = session_error_to_error_response(error);
+    match stopped_at {
+        Some(stopped_at) => (
+            kind.status(),
+            Json(SessionGoneResponse {
+                error,
+                kind,
+                stopped_at,
+            }),
+        )
+            .into_response(),
+        None => ErrorResponse::response(kind, error),
+    }
 }
 
-fn session_error_to_response(error: SessionError) -> impl IntoResponse {
-    let (status, message) = match error {
+fn session_error_to_error_response(error: SessionError) -> ErrorResponse {
+    let (kind, message) = match error {
         SessionError::ConnectorAlreadyInUse { connector_id } => (
-            StatusCode::CONFLICT,
+            ErrorKind::Conflict,
             format!(
                 "Connector {}:{} is already in use",
                 connector_id.charger_id, connector_id.idx
             ),
         ),
+        // An index below the connector base is an invalid request rather than a missing connector
+        SessionError::InvalidConnectorIndex {
+            connector_id,
+            connector_base,
+        } => (
+            ErrorKind::Validation,
+            format!(
+                "Connector {}:{} is invalid, connector indexes start at {}",
+                connector_id.charger_id, connector_id.idx, connector_base
+            ),
+        ),
         SessionError::ConnectorNotFound { connector_id } => (
-            StatusCode::NOT_FOUND,
+            ErrorKind::NotFound,
             format!(
                 "Connector {}:{} not found",
                 connector_id.charger_id, connector_id.idx
             ),
         ),
         SessionError::SessionNotFound { session_id } => (
-            StatusCode::NOT_FOUND,
+            ErrorKind::NotFound,
             format!("Session {} not found", session_id),
         ),
+        SessionError::SessionIdNotFound { .. }
+        | SessionError::ConnectorLabelNotFound { .. }
+        | SessionError::QueuedSessionNotFound { .. } => (ErrorKind::NotFound, error.to_string()),
+        SessionError::SessionGone {
+            session_id,
+            stopped_at,
+        } => (
+            ErrorKind::Gone,
+            format!(
+                "Session {} was stopped at {}",
+                session_id,
+                stopped_at.to_rfc3339()
+            ),
+        ),
+        SessionError::InvalidTransition { .. } | SessionError::AllocationFailed { .. } => {
+            (ErrorKind::Conflict, error.to_string())
+        }
+        SessionError::StationDraining
+        | SessionError::EmergencyStop
+        | SessionError::InsufficientCapacity
+        | SessionError::SessionIdsExhausted { .. } => (ErrorKind::Unavailable, error.to_string()),
+        SessionError::EmptyNote
+        | SessionError::MissingVehicleMaxPower
+        | SessionError::ExpiryInPast { .. } => (ErrorKind::Validation, error.to_string()),
+        SessionError::TooManyNotes { .. } => (ErrorKind::Conflict, error.to_string()),
     };
 
-    (status, Json(ErrorResponse { error: message }))
+    ErrorResponse {
+        error: message,
+        kind,
+    }
 }
 
 /// Create a new charging session
+#[utoipa::path(
+    post,
+    path = "/sessions",
+    tag = "sessions",
+    params(UnitQuery),
+    request_body = CreateSessionRequest,
+    responses(
+        (
+            status = 200,
+            description = "Session started",
+            body = SessionResponse,
+            headers(("Location" = String, description = "Path of the created session")),
+        ),
+        (status = 202, description = "Station full, session queued", body = QueuedSessionResponse),
+        (status = 404, description = "Connector not found", body = ErrorResponse),
+        (status = 409, description = "Connector already in use", body = ErrorResponse),
+        (status = 422, description = "Invalid connector index", body = ErrorResponse),
+        (status = 503, description = "Station draining, emergency stopped or without capacity", body = ErrorResponse),
+    )
+)]
 pub async fn create_session(
     State(app_state): State<Arc<Mutex<StationState>>>,
-    Json(payload): Json<CreateSessionRequest>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<CreateSessionRequest>,
+) -> impl IntoResponse {
+    start_session(
+        &app_state,
+        Ok(payload.connector_id),
+        payload.vehicle_max_power,
+        payload.vehicle_max_current,
+        payload.options,
+        query.unit,
+    )
+    .await
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionImpact {
+    pub session_id: Uuid,
+    /// Change of the allocated power of the session in kW, negative when it is reduced
+    pub power_change: i64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewResponse {
+    /// Impact on every active session, from the largest reduction
+    pub impacts: Vec<SessionImpact>,
+    /// Power the session would get, and the limit bounding it
+    pub feasibility: Feasibility,
+}
+
+/// Preview the impact of starting a session on the active sessions, without starting it
+/// The impacts are the changes of a full reallocation, which the sessions get at their next power
+/// update once the session started. The feasibility tells the power the session would get and the
+/// limit bounding it, e.g. for driver apps to set expectations before plugging in.
+#[utoipa::path(
+    post,
+    path = "/sessions/preview",
+    tag = "sessions",
+    request_body = CreateSessionRequest,
+    responses(
+        (status = 200, description = "Impact on the active sessions", body = PreviewResponse),
+        (status = 404, description = "Connector not found", body = ErrorResponse),
+        (status = 409, description = "Connector already in use", body = ErrorResponse),
+        (status = 422, description = "Invalid connector index", body = ErrorResponse),
+        (status = 503, description = "Station draining, emergency stopped or without capacity", body = ErrorResponse),
+    )
+)]
+pub async fn preview_session(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    ApiJson(payload): ApiJson<CreateSessionRequest>,
+) -> impl IntoResponse {
+    let state = app_state.lock().await;
+    match vehicle_max_power_of(
+        &state,
+        &payload.connector_id,
+        payload.vehicle_max_power,
+        payload.vehicle_max_current,
+    )
+    .and_then(|vehicle_max_power| {
+        check_expiry(&payload.options)?;
+        let impacts = state.marginal_impact(
+            payload.connector_id.clone(),
+            vehicle_max_power,
+            payload.options.clone(),
+        )?;
+        let feasibility =
+            state.feasibility(payload.connector_id, vehicle_max_power, payload.options)?;
+        Ok((impacts, feasibility))
+    }) {
+        Ok((impacts, feasibility)) => Json(PreviewResponse {
+            impacts: impacts
+                .into_iter()
+                .map(|(session_id, power_change)| SessionImpact {
+                    session_id,
+                    power_change,
+                })
+                .collect(),
+            feasibility,
+        })
+        .into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Prefix of the query parameters filtering the sessions by metadata
+const TAG_QUERY_PREFIX: &str = "tag.";
+
+/// Return whether the metadata of the session has all the `tag.`-prefixed key-values of the query
+fn matches_tags(session: &Session, query: &HashMap<String, String>) -> bool {
+    query.iter().all(|(param, value)| {
+        param
+            .strip_prefix(TAG_QUERY_PREFIX)
+            .is_none_or(|key| session.metadata.get(key) == Some(value))
+    })
+}
+
+/// List the active sessions, filtered by metadata
+/// Every `tag.<key>=<value>` query parameter must match the session metadata, e.g.
+/// `?tag.fleetId=F1` returns the sessions of the fleet F1
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "sessions",
+    params(
+        UnitQuery,
+        ("tag.<key>" = Option<String>, Query, description = "Value of the metadata key"),
+    ),
+    responses((status = 200, description = "Matching sessions", body = Vec<SessionResponse>))
+)]
+pub async fn list_sessions(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Query(unit_query): Query<UnitQuery>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let state = app_state.lock().await;
+    let mut sessions = state
+        .get_sessions()
+        .values()
+        .filter(|session| matches_tags(session, &query))
+        .cloned()
+        .collect::<Vec<_>>();
+    sessions.sort_by(|a, b| {
+        (&a.connector_id.charger_id, a.connector_id.idx)
+            .cmp(&(&b.connector_id.charger_id, b.connector_id.idx))
+    });
+    match sessions
+        .into_iter()
+        .map(|session| session_response(&state, session, unit_query.unit))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(responses) => Json(responses).into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Create a new charging session on the connector with the given label
+#[utoipa::path(
+    post,
+    path = "/sessions/by-label/{label}",
+    tag = "sessions",
+    params(
+        ("label" = String, Path, description = "Label of the connector"),
+        UnitQuery,
+    ),
+    request_body = CreateSessionByLabelRequest,
+    responses(
+        (
+            status = 200,
+            description = "Session started",
+            body = SessionResponse,
+            headers(("Location" = String, description = "Path of the created session")),
+        ),
+        (status = 202, description = "Station full, session queued", body = QueuedSessionResponse),
+        (status = 404, description = "Connector or label not found", body = ErrorResponse),
+        (status = 409, description = "Connector already in use", body = ErrorResponse),
+        (status = 422, description = "Invalid connector index", body = ErrorResponse),
+        (status = 503, description = "Station draining, emergency stopped or without capacity", body = ErrorResponse),
+    )
+)]
+pub async fn create_session_by_label(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(label): Path<String>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<CreateSessionByLabelRequest>,
+) -> impl IntoResponse {
+    let connector_id = app_state.lock().await.resolve_label(&label);
+    start_session(
+        &app_state,
+        connector_id,
+        payload.vehicle_max_power,
+        payload.vehicle_max_current,
+        payload.options,
+        query.unit,
+    )
+    .await
+}
+
+/// Start a session on the connector, converting the vehicle max current if given
+async fn start_session(
+    app_state: &Arc<Mutex<StationState>>,
+    connector_id: Result<ConnectorId, SessionError>,
+    vehicle_max_power: Option<u32>,
+    vehicle_max_current: Option<u32>,
+    options: SessionOptions,
+    unit: PowerUnit,
+) -> Response {
+    let mut state = app_state.lock().await;
+    let admission = connector_id.and_then(|connector_id| {
+        admit_session_on(
+            app_state,
+            &mut state,
+            connector_id,
+            vehicle_max_power,
+            vehicle_max_current,
+            options,
+        )
+    });
+    let session = match admission {
+        Ok(SessionAdmission::Started(session)) => session,
+        Ok(SessionAdmission::Queued {
+            queued_session,
+            position,
+        }) => {
+            return (
+                StatusCode::ACCEPTED,
+                Json(QueuedSessionResponse {
+                    queued_session,
+                    position,
+                }),
+            )
+                .into_response();
+        }
+        Err(error) => return session_error_to_response(error),
+    };
+    match session_response(&state, session, unit) {
+        Ok(response) => (
+            StatusCode::OK,
+            [(
+                header::LOCATION,
+                format!("/sessions/{}", response.session.session_id),
+            )],
+            Json(response),
+        )
+            .into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Return the vehicle max power of a new session, converted from its max current if given
+///
+/// One of them is required, so that a forgotten field does not start a session with no power.
+fn vehicle_max_power_of(
+    state: &StationState,
+    connector_id: &ConnectorId,
+    vehicle_max_power: Option<u32>,
+    vehicle_max_current: Option<u32>,
+) -> Result<u32, SessionError> {
+    match (vehicle_max_current, vehicle_max_power) {
+        (Some(current), _) => state.current_to_power(connector_id, current),
+        (None, Some(power)) => Ok(power),
+        (None, None) => Err(SessionError::MissingVehicleMaxPower),
+    }
+}
+
+/// Refuse a session whose expiry set by the client already passed, which would stop right away
+fn check_expiry(options: &SessionOptions) -> Result<(), SessionError> {
+    match options.expires_at {
+        Some(expires_at) if expires_at < chrono::Utc::now() => {
+            Err(SessionError::ExpiryInPast { expires_at })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Start a session on the connector, with the vehicle max current converted to a power if given,
+/// or queue it if the station is full
+///
+/// A started session is stopped in the background when it reaches the expiry set by the client,
+/// and the sessions are reallocated when its warm-up ends. A queued session is dropped from the
+/// queue in the background if it reaches the expiry set by the client before it started.
+pub(crate) fn admit_session_on(
+    app_state: &Arc<Mutex<StationState>>,
+    state: &mut StationState,
+    connector_id: ConnectorId,
+    vehicle_max_power: Option<u32>,
+    vehicle_max_current: Option<u32>,
+    options: SessionOptions,
+) -> Result<SessionAdmission, SessionError> {
+    let vehicle_max_power =
+        vehicle_max_power_of(state, &connector_id, vehicle_max_power, vehicle_max_current)?;
+    check_expiry(&options)?;
+    let admission = state.admit_session(connector_id, vehicle_max_power, options)?;
+    match &admission {
+        SessionAdmission::Started(session) => schedule_session_tasks(app_state, state, session),
+        SessionAdmission::Queued { queued_session, .. } => {
+            if let Some(expires_at) = queued_session.options.expires_at {
+                schedule_expiry(app_state, queued_session.session_id, expires_at);
+            }
+        }
+    }
+    Ok(admission)
+}
+
+/// Stop the session in the background at the expiry set by the client
+///
+/// The timer and the wall clock can drift apart, so the expiry is checked again for the time left
+/// if the timer fires before it.
+fn schedule_expiry(
+    app_state: &Arc<Mutex<StationState>>,
+    session_id: Uuid,
+    expires_at: chrono::DateTime<chrono::Utc>,
+) {
+    let app_state = app_state.clone();
+    let time_left = |expires_at: chrono::DateTime<chrono::Utc>| {
+        (expires_at - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default()
+    };
+    let mut delay = time_left(expires_at);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(delay).await;
+            let mut state = app_state.lock().await;
+            state.expire_session(session_id);
+            schedule_promoted_sessions(&app_state, &mut state);
+            // Still active or queued: the expiry has not been reached yet
+            match state.expires_at(session_id) {
+                Some(expires_at) => delay = time_left(expires_at),
+                None => break,
+            }
+        }
+    });
+}
+
+/// Reallocate the sessions in the background once the warm-up of the session ends
+fn schedule_warmup_end(
+    app_state: &Arc<Mutex<StationState>>,
+    state: &StationState,
+    session_id: Uuid,
+) {
+    if let Some(warmup_ends_at) = state.warmup_ends_at(session_id) {
+        let app_state = app_state.clone();
+        let delay = (warmup_ends_at - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let mut state = app_state.lock().await;
+            state.end_warmup(session_id);
+            schedule_promoted_sessions(&app_state, &mut state);
+        });
+    }
+}
+
+/// Schedule the expiry of a started session, and its reallocation once its warm-up ends
+fn schedule_session_tasks(
+    app_state: &Arc<Mutex<StationState>>,
+    state: &StationState,
+    session: &Session,
+) {
+    if let Some(expires_at) = session.expires_at {
+        schedule_expiry(app_state, session.session_id, expires_at);
+    }
+    schedule_warmup_end(app_state, state, session.session_id);
+}
+
+/// Schedule the end of the warm-up of the queued sessions started since the last call
+///
+/// Meant to be called after every change to the station: any of them may free capacity. The
+/// expiry of a queued session was already scheduled when it was queued.
+pub(crate) fn schedule_promoted_sessions(
+    app_state: &Arc<Mutex<StationState>>,
+    state: &mut StationState,
+) {
+    for session in state.take_promoted_sessions() {
+        schedule_warmup_end(app_state, state, session.session_id);
+    }
+}
+
+/// Return the active session on the connector, or start one if the connector is free
+///
+/// Meant for chargers recovering from a reboot: they may have lost the session id while the
+/// vehicle is still plugged in, and can call this again safely. A new session is queued like
+/// with `POST /sessions` when the station is full, and a session already queued on the connector
+/// is returned with its position.
+#[utoipa::path(
+    post,
+    path = "/connectors/{charger_id}/{idx}/reattach",
+    tag = "sessions",
+    params(
+        ("charger_id" = String, Path, description = "Id of the charger"),
+        ("idx" = u8, Path, description = "Index of the connector on the charger, from 1"),
+        UnitQuery,
+    ),
+    request_body = ReattachSessionRequest,
+    responses(
+        (
+            status = 200,
+            description = "Session reattached or started",
+            body = ReattachSessionResponse,
+            headers(("Location" = String, description = "Path of the session")),
+        ),
+        (status = 202, description = "Station full, session queued or already queued", body = QueuedSessionResponse),
+        (status = 404, description = "Charger not found", body = ErrorResponse),
+        (status = 422, description = "Invalid connector index", body = ErrorResponse),
+        (status = 503, description = "Station draining, emergency stopped or without capacity", body = ErrorResponse),
+    )
+)]
+pub async fn reattach_session(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path((charger_id, idx)): Path<(String, u8)>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<ReattachSessionRequest>,
+) -> impl IntoResponse {
+    let mut state = app_state.lock().await;
+    let connector_id = ConnectorId { charger_id, idx };
+    let queued = |queued_session, position| {
+        (
+            StatusCode::ACCEPTED,
+            Json(QueuedSessionResponse {
+                queued_session,
+                position,
+            }),
+        )
+            .into_response()
+    };
+    // A session queued on the connector is found like an active one, so reattaching stays safe
+    if let Some((queued_session, position)) = state
+        .queued_sessions()
+        .zip(1..)
+        .find(|(queued_session, _)| queued_session.connector_id == connector_id)
+    {
+        return queued(queued_session.clone(), position);
+    }
+    let (outcome, session) = match state.session_on_connector(&connector_id).cloned() {
+        Some(session) => (ReattachOutcome::Reattached, session),
+        // A new session waits behind the queued ones like any other
+        None => match admit_session_on(
+            &app_state,
+            &mut state,
+            connector_id,
+            payload.vehicle_max_power,
+            payload.vehicle_max_current,
+            payload.options,
+        ) {
+            Ok(SessionAdmission::Started(session)) => (ReattachOutcome::Created, session),
+            Ok(SessionAdmission::Queued {
+                queued_session,
+                position,
+            }) => return queued(queued_session, position),
+            Err(error) => return session_error_to_response(error),
+        },
+    };
+    match session_response(&state, session, query.unit) {
+        Ok(response) => (
+            StatusCode::OK,
+            [(
+                header::LOCATION,
+                format!("/sessions/{}", response.session.session_id),
+            )],
+            Json(ReattachSessionResponse { outcome, response }),
+        )
+            .into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Return an active or recently finished charging session
+#[utoipa::path(
+    get,
+    path = "/sessions/{session_id}",
+    tag = "sessions",
+    params(
+        ("session_id" = String, Path, description = "Id or short id of the session"),
+        UnitQuery,
+    ),
+    responses(
+        (status = 200, description = "The session", body = SessionResponse),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+    )
+)]
+pub async fn get_session(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<UnitQuery>,
+) -> impl IntoResponse {
+    let state = app_state.lock().await;
+    match state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| state.get_session(session_id).cloned())
+        .and_then(|session| session_response(&state, session, query.unit))
+    {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Export a session, to import it on another connector or station, e.g. when its charger is moved
+/// The export holds the vehicle max power the session started with and its options. The session
+/// is not stopped.
+#[utoipa::path(
+    get,
+    path = "/sessions/{session_id}/export",
+    tag = "sessions",
+    params(("session_id" = String, Path, description = "Id or short id of the session")),
+    responses(
+        (status = 200, description = "The exported session", body = SessionExport),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+    )
+)]
+pub async fn export_session(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(session_id): Path<String>,
+) -> impl IntoResponse {
+    let state = app_state.lock().await;
+    match state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| state.get_session(session_id).cloned())
+    {
+        Ok(session) => Json(SessionExport {
+            session_id: session.session_id,
+            connector_id: session.connector_id,
+            vehicle_max_power: session.requested_max_power,
+            options: SessionOptions {
+                depart_by: session.depart_by,
+                energy_needed_wh: session.energy_needed_wh,
+                expires_at: session.expires_at,
+                metadata: session.metadata,
+            },
+        })
+        .into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Re-create an exported session on a connector, which is allocated or queued like a new session
+#[utoipa::path(
+    post,
+    path = "/sessions/import",
+    tag = "sessions",
+    params(UnitQuery),
+    request_body = ImportSessionRequest,
+    responses(
+        (
+            status = 200,
+            description = "Session imported",
+            body = SessionResponse,
+            headers(("Location" = String, description = "Path of the imported session")),
+        ),
+        (status = 202, description = "Station full, session queued", body = QueuedSessionResponse),
+        (status = 404, description = "Connector not found", body = ErrorResponse),
+        (status = 409, description = "Connector already in use", body = ErrorResponse),
+        (status = 422, description = "Invalid connector index", body = ErrorResponse),
+        (status = 503, description = "Station draining, emergency stopped or without capacity", body = ErrorResponse),
+    )
+)]
+pub async fn import_session(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<ImportSessionRequest>,
+) -> impl IntoResponse {
+    tracing::info!(
+        exported_session_id = %payload.session.session_id,
+        "Importing session"
+    );
+    start_session(
+        &app_state,
+        Ok(payload.connector_id),
+        Some(payload.session.vehicle_max_power),
+        None,
+        payload.session.options,
+        query.unit,
+    )
+    .await
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationHistoryResponse {
+    pub session_id: uuid::Uuid,
+    /// Allocated power of the session over time in kW, from the oldest change
+    pub history: Vec<AllocationSample>,
+}
+
+/// Return the trace of the power allocated to a session
+/// A sample is recorded every time an allocation changes the power of the session, and only the
+/// last 100 are kept. This is the allocation, not the energy delivered to the vehicle.
+#[utoipa::path(
+    get,
+    path = "/sessions/{session_id}/history",
+    tag = "sessions",
+    params(("session_id" = String, Path, description = "Id or short id of the session")),
+    responses(
+        (status = 200, description = "Allocation history", body = AllocationHistoryResponse),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+    )
+)]
+pub async fn get_allocation_history(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(session_id): Path<String>,
 ) -> impl IntoResponse {
-    let mut state = app_state.lock().unwrap();
-    match state.start_session(payload.connector_id, payload.vehicle_max_power) {
-        Ok(session) => (StatusCode::OK, Json(SessionResponse { session })).into_response(),
-        Err(error) => session_error_to_response(error).into_response(),
+    let state = app_state.lock().await;
+    match state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| {
+            state
+                .allocation_history(session_id)
+                .map(|history| AllocationHistoryResponse {
+                    session_id,
+                    history,
+                })
+        }) {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(error) => session_error_to_response(error),
     }
 }
 
 /// Stop an existing charging session
+#[utoipa::path(
+    post,
+    path = "/sessions/{session_id}/stop",
+    tag = "sessions",
+    params(("session_id" = String, Path, description = "Id or short id of the session")),
+    responses(
+        (status = 204, description = "Session stopped"),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+        (status = 409, description = "Session already finished", body = ErrorResponse),
+    )
+)]
 pub async fn stop_session(
     State(app_state): State<Arc<Mutex<StationState>>>,
-    Path(session_id): Path<Uuid>,
+    Path(session_id): Path<String>,
+) -> impl IntoResponse {
+    let mut state = app_state.lock().await;
+    let result = state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| state.stop_session(session_id));
+    // The queued sessions may have started in place of the stopped one
+    schedule_promoted_sessions(&app_state, &mut state);
+    match result {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// List the sessions waiting for capacity, from the next one to start
+#[utoipa::path(
+    get,
+    path = "/queue",
+    tag = "sessions",
+    responses((status = 200, description = "Queued sessions", body = Vec<QueuedSessionResponse>))
+)]
+pub async fn list_queued_sessions(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> impl IntoResponse {
+    let state = app_state.lock().await;
+    let queue = state
+        .queued_sessions()
+        .cloned()
+        .zip(1..)
+        .map(|(queued_session, position)| QueuedSessionResponse {
+            queued_session,
+            position,
+        })
+        .collect::<Vec<_>>();
+    Json(queue)
+}
+
+/// Remove a session from the queue before it started, e.g. when the driver leaves
+#[utoipa::path(
+    delete,
+    path = "/queue/{session_id}",
+    tag = "sessions",
+    params(("session_id" = String, Path, description = "Id or short id of the queued session")),
+    responses(
+        (status = 200, description = "Session removed from the queue", body = QueuedSession),
+        (status = 404, description = "Session not in the queue", body = ErrorResponse),
+    )
+)]
+pub async fn cancel_queued_session(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(session_id): Path<String>,
 ) -> impl IntoResponse {
-    let mut state = app_state.lock().unwrap();
-    state.stop_session(session_id);
-    StatusCode::NO_CONTENT
+    let mut state = app_state.lock().await;
+    match state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| state.cancel_queued_session(session_id))
+    {
+        Ok(queued_session) => {
+            schedule_promoted_sessions(&app_state, &mut state);
+            (StatusCode::OK, Json(queued_session)).into_response()
+        }
+        Err(error) => session_error_to_response(error),
+    }
 }
 
 /// Update the power consumption for an existing session
+/// If the station coalesces power updates, the allocation of the session is only updated once
+/// the coalescing window elapsed, by a background task reallocating all the pending updates.
+#[utoipa::path(
+    post,
+    path = "/sessions/{session_id}/power-update",
+    tag = "sessions",
+    params(
+        ("session_id" = String, Path, description = "Id or short id of the session"),
+        UnitQuery,
+    ),
+    request_body = PowerUpdateRequest,
+    responses(
+        (status = 200, description = "Power reallocated", body = SessionResponse),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+        (status = 409, description = "Session already finished", body = ErrorResponse),
+    )
+)]
 pub async fn power_update(
     State(app_state): State<Arc<Mutex<StationState>>>,
-    Path(session_id): Path<Uuid>,
-    Json(payload): Json<PowerUpdateRequest>,
+    Path(session_id): Path<String>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<PowerUpdateRequest>,
+) -> impl IntoResponse {
+    let mut state = app_state.lock().await;
+    match state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| {
+            apply_power_update(&app_state, &mut state, session_id, payload.consumed_power)
+        })
+        .and_then(|session| session_response(&state, session, query.unit))
+    {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchPowerUpdateRequest {
+    pub updates: Vec<BatchPowerUpdate>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchPowerUpdate {
+    /// Id or short id of the session
+    pub session_id: String,
+    pub consumed_power: u32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchResult {
+    /// Result of every update, in the order of the request
+    pub results: Vec<BatchItemResult>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchItemResult {
+    /// Id of the session, as given in the request
+    pub session_id: String,
+    /// Status code the update would have had if sent alone
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<SessionResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+/// Update the power consumption of several sessions at once
+/// The valid updates are applied with a single reallocation, even if the station coalesces power
+/// updates, and the invalid ones are reported without failing the whole batch.
+#[utoipa::path(
+    post,
+    path = "/sessions/power-updates",
+    tag = "sessions",
+    params(UnitQuery),
+    request_body = BatchPowerUpdateRequest,
+    responses(
+        (status = 207, description = "Result of every update", body = BatchResult),
+    )
+)]
+pub async fn batch_power_update(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<BatchPowerUpdateRequest>,
+) -> impl IntoResponse {
+    let mut state = app_state.lock().await;
+    let session_ids = payload
+        .updates
+        .iter()
+        .map(|update| state.resolve_session_id(&update.session_id))
+        .collect::<Vec<_>>();
+    let valid_updates = payload
+        .updates
+        .iter()
+        .zip(&session_ids)
+        .filter_map(|(update, session_id)| {
+            Some((*session_id.as_ref().ok()?, update.consumed_power))
+        })
+        .collect::<Vec<_>>();
+    let mut update_results = state.batch_power_update(&valid_updates).into_iter();
+    schedule_promoted_sessions(&app_state, &mut state);
+
+    let results = payload
+        .updates
+        .into_iter()
+        .zip(session_ids)
+        .map(|(update, session_id)| {
+            let result = session_id
+                .and_then(|_| {
+                    update_results
+                        .next()
+                        .expect("Every valid update has a result")
+                })
+                .and_then(|session| session_response(&state, session, query.unit));
+            match result {
+                Ok(session) => BatchItemResult {
+                    session_id: update.session_id,
+                    status: StatusCode::OK.as_u16(),
+                    session: Some(session),
+                    error: None,
+                },
+                Err(error) => {
+                    let error = session_error_to_error_response(error);
+                    BatchItemResult {
+                        session_id: update.session_id,
+                        status: error.kind.status().as_u16(),
+                        session: None,
+                        error: Some(error),
+                    }
+                }
+            }
+        })
+        .collect();
+    (StatusCode::MULTI_STATUS, Json(BatchResult { results }))
+}
+
+/// Apply a power update to the locked `state`, coalescing it if the station has a coalescing window
+///
+/// A background task flushes the coalesced updates of `app_state` once the window elapsed.
+pub(crate) fn apply_power_update(
+    app_state: &Arc<Mutex<StationState>>,
+    state: &mut StationState,
+    session_id: Uuid,
+    consumed_power: u32,
+) -> Result<Session, SessionError> {
+    match state.get_config().coalesce_window_ms {
+        Some(window_ms) => {
+            let schedule_flush = !state.has_pending_power_updates();
+            let result = state.record_power_update(session_id, consumed_power);
+            if result.is_ok() && schedule_flush {
+                let app_state = app_state.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(window_ms)).await;
+                    let mut state = app_state.lock().await;
+                    if let Err(error) = state.flush_power_updates() {
+                        tracing::warn!("Could not flush the power updates: {}", error);
+                    }
+                    schedule_promoted_sessions(&app_state, &mut state);
+                });
+            }
+            result
+        }
+        None => {
+            let result = state.power_update(session_id, consumed_power);
+            schedule_promoted_sessions(app_state, state);
+            result
+        }
+    }
+}
+
+/// Update the metadata of an existing session
+#[utoipa::path(
+    patch,
+    path = "/sessions/{session_id}/metadata",
+    tag = "sessions",
+    params(
+        ("session_id" = String, Path, description = "Id or short id of the session"),
+        UnitQuery,
+    ),
+    request_body = UpdateMetadataRequest,
+    responses(
+        (status = 200, description = "Metadata updated", body = SessionResponse),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+    )
+)]
+pub async fn update_session_metadata(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<UpdateMetadataRequest>,
+) -> impl IntoResponse {
+    let mut state = app_state.lock().await;
+    match state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| state.update_session_metadata(session_id, payload.metadata))
+        .and_then(|session| session_response(&state, session, query.unit))
+    {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Append a timestamped note to an active or recently finished session
+/// The notes are returned with the session, and do not affect the allocation. A session holds at
+/// most 50 notes.
+#[utoipa::path(
+    post,
+    path = "/sessions/{session_id}/notes",
+    tag = "sessions",
+    params(
+        ("session_id" = String, Path, description = "Id or short id of the session"),
+        UnitQuery,
+    ),
+    request_body = AddNoteRequest,
+    responses(
+        (status = 200, description = "Note added", body = SessionResponse),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+        (status = 409, description = "Too many notes", body = ErrorResponse),
+        (status = 422, description = "Empty note", body = ErrorResponse),
+    )
+)]
+pub async fn add_session_note(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<AddNoteRequest>,
+) -> impl IntoResponse {
+    let mut state = app_state.lock().await;
+    match state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| {
+            state.add_session_note(session_id, payload.text)?;
+            state.get_session(session_id).cloned()
+        })
+        .and_then(|session| session_response(&state, session, query.unit))
+    {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Pin an active session at a fixed power, e.g. to test a charger or honor a contractual rate
+/// The other sessions share the rest of the capacity. The pin is still bounded by the vehicle max
+/// power and the station, charger and group limits.
+#[utoipa::path(
+    post,
+    path = "/sessions/{session_id}/pin",
+    tag = "sessions",
+    params(
+        ("session_id" = String, Path, description = "Id or short id of the session"),
+        UnitQuery,
+    ),
+    request_body = PinSessionRequest,
+    responses(
+        (status = 200, description = "Session pinned", body = SessionResponse),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+    )
+)]
+pub async fn pin_session(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<UnitQuery>,
+    ApiJson(payload): ApiJson<PinSessionRequest>,
+) -> impl IntoResponse {
+    let mut state = app_state.lock().await;
+    let session = state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| state.pin_session(session_id, payload.power));
+    schedule_promoted_sessions(&app_state, &mut state);
+    match session.and_then(|session| session_response(&state, session, query.unit)) {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(error) => session_error_to_response(error),
+    }
+}
+
+/// Remove the pin of a session, which gets its fair share again
+#[utoipa::path(
+    delete,
+    path = "/sessions/{session_id}/pin",
+    tag = "sessions",
+    params(
+        ("session_id" = String, Path, description = "Id or short id of the session"),
+        UnitQuery,
+    ),
+    responses(
+        (status = 200, description = "Session unpinned", body = SessionResponse),
+        (status = 404, description = "Session not found", body = ErrorResponse),
+        (status = 410, description = "Session stopped, no longer retained", body = SessionGoneResponse),
+    )
+)]
+pub async fn unpin_session(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    Path(session_id): Path<String>,
+    Query(query): Query<UnitQuery>,
 ) -> impl IntoResponse {
-    let mut state = app_state.lock().unwrap();
-    match state.power_update(session_id, payload.consumed_power) {
-        Ok(session) => (StatusCode::OK, Json(SessionResponse { session })).into_response(),
-        Err(error) => session_error_to_response(error).into_response(),
+    let mut state = app_state.lock().await;
+    let session = state
+        .resolve_session_id(&session_id)
+        .and_then(|session_id| state.unpin_session(session_id));
+    schedule_promoted_sessions(&app_state, &mut state);
+    match session.and_then(|session| session_response(&state, session, query.unit)) {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(error) => session_error_to_response(error),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::{Router, routing::post};
+    use axum::{
+        Router,
+        routing::{delete, get, patch, post},
+    };
     use axum::{
         body::Body,
         http::{Request, StatusCode},
@@ -109,9 +1422,34 @@ mod tests {
     pub fn create_app(app_state: StationState) -> Router {
         let shared_state = Arc::new(Mutex::new(app_state));
         Router::new()
-            .route("/sessions", post(create_session))
+            .route("/sessions", get(list_sessions).post(create_session))
+            .route("/sessions/by-label/{label}", post(create_session_by_label))
+            .route("/sessions/preview", post(preview_session))
+            .route("/sessions/import", post(import_session))
+            .route("/sessions/power-updates", post(batch_power_update))
+            .route("/sessions/{session_id}", get(get_session))
+            .route("/sessions/{session_id}/export", get(export_session))
+            .route(
+                "/sessions/{session_id}/history",
+                get(get_allocation_history),
+            )
             .route("/sessions/{session_id}/stop", post(stop_session))
             .route("/sessions/{session_id}/power-update", post(power_update))
+            .route(
+                "/sessions/{session_id}/metadata",
+                patch(update_session_metadata),
+            )
+            .route("/sessions/{session_id}/notes", post(add_session_note))
+            .route(
+                "/sessions/{session_id}/pin",
+                post(pin_session).delete(unpin_session),
+            )
+            .route(
+                "/connectors/{charger_id}/{idx}/reattach",
+                post(reattach_session),
+            )
+            .route("/queue", get(list_queued_sessions))
+            .route("/queue/{session_id}", delete(cancel_queued_session))
             .with_state(shared_state)
     }
 
@@ -124,14 +1462,19 @@ mod tests {
                     id: "CP001".into(),
                     max_power: 200,
                     connectors: 2,
+                    labels: HashMap::from([(1, "A1".into()), (2, "A2".into())]),
+                    ..Default::default()
                 },
                 ChargerConfig {
                     id: "CP002".into(),
                     max_power: 150,
                     connectors: 1,
+                    phases: Some(1),
+                    ..Default::default()
                 },
             ],
-            battery: None,
+            batteries: vec![],
+            ..Default::default()
         }
     }
 
@@ -146,7 +1489,9 @@ mod tests {
                 charger_id: "CP001".to_string(),
                 idx: 1,
             },
-            vehicle_max_power: 150,
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
         };
 
         let response = app
@@ -175,20 +1520,23 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_create_session_connector_not_found() {
+    async fn test_create_session_location() {
         let config = test_station_config();
         let state = StationState::new(config);
         let app = create_app(state);
 
         let create_request = CreateSessionRequest {
             connector_id: ConnectorId {
-                charger_id: "CP999".to_string(), // Non-existent charger
+                charger_id: "CP001".to_string(),
                 idx: 1,
             },
-            vehicle_max_power: 150,
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
         };
 
         let response = app
+            .clone()
             .oneshot(
                 Request::builder()
                     .uri("/sessions")
@@ -200,41 +1548,264 @@ mod tests {
             .await
             .unwrap();
 
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.status(), StatusCode::OK);
+        let location = response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
 
         let body = axum::body::to_bytes(response.into_body(), usize::MAX)
             .await
             .unwrap();
-        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
-        assert!(error_response.error.contains("Connector CP999:1 not found"));
-    }
-
-    #[tokio::test]
-    async fn test_create_session_connector_already_in_use() {
-        let config = test_station_config();
-        let mut state = StationState::new(config);
-
-        // Start a session first
-        let connector_id = ConnectorId {
-            charger_id: "CP001".to_string(),
-            idx: 1,
-        };
-        state.start_session(connector_id.clone(), 100).unwrap();
-
-        let app = create_app(state);
-
-        let create_request = CreateSessionRequest {
-            connector_id,
-            vehicle_max_power: 150,
-        };
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            location,
+            format!("/sessions/{}", session_response.session.session_id)
+        );
 
+        // The location points to the created session
         let response = app
             .oneshot(
                 Request::builder()
-                    .uri("/sessions")
-                    .method("POST")
-                    .header("content-type", "application/json")
-                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .uri(&location)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let get_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            get_response.session.session_id,
+            session_response.session.session_id
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_session_connector_not_found() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP999".to_string(), // Non-existent charger
+                idx: 1,
+            },
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
+        };
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert!(error_response.error.contains("Connector CP999:1 not found"));
+        assert_eq!(error_response.kind, ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_create_session_connector_index_zero() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP001".to_string(),
+                idx: 0,
+            },
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
+        };
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        // The index 0 is an invalid value, not a missing connector
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Validation);
+    }
+
+    #[tokio::test]
+    async fn test_create_session_malformed_request() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        for body in [
+            // Invalid JSON
+            r#"{"connectorId": {"chargerId": "CP001", "idx": 1"#,
+            // Valid JSON not matching the schema
+            r#"{"vehicleMaxPower": 150}"#,
+            r#"{"connectorId": {"chargerId": "CP001", "idx": 256}}"#,
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/sessions")
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", body);
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error_response.kind, ErrorKind::Malformed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_session_without_vehicle_max_power() {
+        let app = create_app(StationState::new(test_station_config()));
+
+        // A forgotten vehicle max power does not start a session with no power
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        r#"{"connectorId": {"chargerId": "CP001", "idx": 1}}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Validation);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let sessions: Vec<SessionResponse> = serde_json::from_slice(&body).unwrap();
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_session_expired() {
+        let app = create_app(StationState::new(test_station_config()));
+
+        // An expiry in the past would stop the session as soon as it starts
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP001".to_string(),
+                idx: 1,
+            },
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions {
+                expires_at: Some(chrono::Utc::now() - chrono::Duration::minutes(1)),
+                ..Default::default()
+            },
+        };
+        for uri in ["/sessions", "/sessions/preview"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error_response.kind, ErrorKind::Validation);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_session_connector_already_in_use() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+
+        // Start a session first
+        let connector_id = ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 1,
+        };
+        state.start_session(connector_id.clone(), 100).unwrap();
+
+        let app = create_app(state);
+
+        let create_request = CreateSessionRequest {
+            connector_id,
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
+        };
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
                     .unwrap(),
             )
             .await
@@ -249,6 +1820,70 @@ mod tests {
         assert!(error_response.error.contains("is already in use"));
     }
 
+    #[tokio::test]
+    async fn test_create_session_station_draining() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 1,
+                },
+                100,
+            )
+            .unwrap();
+        state.drain();
+
+        let app = create_app(state);
+
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP001".to_string(),
+                idx: 2,
+            },
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Unavailable);
+
+        // The active session can still be stopped
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}/stop", session.session_id))
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
     #[tokio::test]
     async fn test_stop_session() {
         let config = test_station_config();
@@ -358,4 +1993,1430 @@ mod tests {
             error_response.error.contains("Session") && error_response.error.contains("not found")
         );
     }
+
+    #[tokio::test]
+    async fn test_power_update_finished_session() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+
+        let connector_id = ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 1,
+        };
+        let session = state.start_session(connector_id, 150).unwrap();
+        state.stop_session(session.session_id).unwrap();
+
+        let app = create_app(state);
+
+        let power_update_request = PowerUpdateRequest {
+            consumed_power: 100,
+        };
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}/power-update", session.session_id))
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&power_update_request).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert!(error_response.error.contains("Finished"));
+    }
+
+    #[tokio::test]
+    async fn test_get_stopped_session_gone() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+
+        let connector_id = ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 1,
+        };
+        let session = state.start_session(connector_id.clone(), 150).unwrap();
+        state.stop_session(session.session_id).unwrap();
+        // Push the session out of the 100 retained finished sessions
+        for _ in 0..100 {
+            let other_session = state.start_session(connector_id.clone(), 150).unwrap();
+            state.stop_session(other_session.session_id).unwrap();
+        }
+
+        let app = create_app(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}", session.session_id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::GONE);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let gone_response: SessionGoneResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(gone_response.kind, ErrorKind::Gone);
+        assert!(
+            gone_response
+                .error
+                .contains(&gone_response.stopped_at.to_rfc3339())
+        );
+
+        // A session that never existed is not found
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}", Uuid::new_v4()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_create_session_in_amps() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        // 32A on a single-phase 230V connector is 7kW
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP002".to_string(),
+                idx: 1,
+            },
+            vehicle_max_power: None,
+            vehicle_max_current: Some(32),
+            options: SessionOptions::default(),
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.unit, PowerUnit::Kw);
+        assert_eq!(session_response.session.vehicle_max_power, 7);
+        assert_eq!(session_response.session.allocated_power, 7);
+
+        // The allocation is reported in amps, rounded down: 7kW is 30.4A
+        let power_update_request = PowerUpdateRequest { consumed_power: 7 };
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!(
+                        "/sessions/{}/power-update?unit=amps",
+                        session_response.session.session_id
+                    ))
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&power_update_request).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.unit, PowerUnit::Amps);
+        assert_eq!(session_response.session.vehicle_max_power, 30);
+        assert_eq!(session_response.session.allocated_power, 30);
+    }
+
+    #[tokio::test]
+    async fn test_create_session_in_amps_three_phase() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        // 32A on a three-phase 230V connector is 22kW
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP001".to_string(),
+                idx: 1,
+            },
+            vehicle_max_power: None,
+            vehicle_max_current: Some(32),
+            options: SessionOptions::default(),
+        };
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions?unit=amps")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        // 22kW on a three-phase connector is 31.9A
+        assert_eq!(session_response.session.vehicle_max_power, 31);
+        assert_eq!(session_response.session.allocated_power, 31);
+    }
+
+    #[tokio::test]
+    async fn test_preview_session() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let connector_id = ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 1,
+        };
+        let session_id = state.start_session(connector_id, 200).unwrap().session_id;
+
+        let app = create_app(state);
+
+        // A second session on the same charger would take half of its power
+        let preview_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP001".to_string(),
+                idx: 2,
+            },
+            vehicle_max_power: Some(200),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
+        };
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions/preview")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&preview_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let preview_response: PreviewResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(preview_response.impacts.len(), 1);
+        assert_eq!(preview_response.impacts[0].session_id, session_id);
+        assert_eq!(preview_response.impacts[0].power_change, -100);
+
+        // The session would get the other half, the charger being the limit
+        assert_eq!(
+            preview_response.feasibility,
+            Feasibility {
+                feasible: true,
+                max_achievable_power: 100,
+                binding_constraint: sems_core::BindingConstraint::Charger,
+            }
+        );
+
+        // The session was not started
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let sessions: Vec<SessionResponse> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sessions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_allocation_history() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let connector_id = ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 1,
+        };
+        let session = state.start_session(connector_id, 150).unwrap();
+
+        let app = create_app(state);
+        let get_history = || async {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/sessions/{}/history", session.short_id))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<AllocationHistoryResponse>(&body).unwrap()
+        };
+
+        let history_response = get_history().await;
+        assert_eq!(history_response.session_id, session.session_id);
+        assert_eq!(history_response.history.len(), 1);
+
+        // Every power update changing the allocation grows the history
+        for consumed_power in [100, 150, 60] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/sessions/{}/power-update", session.session_id))
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(format!(
+                            r#"{{"consumedPower": {consumed_power}}}"#
+                        )))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let allocated_powers = get_history()
+            .await
+            .history
+            .iter()
+            .map(|sample| sample.allocated_power)
+            .collect::<Vec<_>>();
+        assert_eq!(allocated_powers, vec![150, 100, 150, 60]);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}/history", Uuid::new_v4()))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_batch_power_update() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let session_1 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        let session_2 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP002".to_string(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        state.stop_session(session_2.session_id).unwrap();
+
+        let app = create_app(state);
+        let request = BatchPowerUpdateRequest {
+            updates: vec![
+                BatchPowerUpdate {
+                    session_id: session_1.short_id.clone(),
+                    consumed_power: 80,
+                },
+                BatchPowerUpdate {
+                    session_id: "unknown".into(),
+                    consumed_power: 50,
+                },
+                BatchPowerUpdate {
+                    session_id: session_2.session_id.to_string(),
+                    consumed_power: 50,
+                },
+                BatchPowerUpdate {
+                    session_id: Uuid::new_v4().to_string(),
+                    consumed_power: 50,
+                },
+            ],
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions/power-updates")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let batch_result: BatchResult = serde_json::from_slice(&body).unwrap();
+        let statuses = batch_result
+            .results
+            .iter()
+            .map(|result| result.status)
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, vec![200, 404, 409, 404]);
+        assert_eq!(batch_result.results[1].session_id, "unknown");
+        assert_eq!(
+            batch_result.results[2].error.as_ref().unwrap().kind,
+            ErrorKind::Conflict
+        );
+
+        // The valid update took effect
+        let session = batch_result.results[0].session.as_ref().unwrap();
+        assert_eq!(session.session.allocated_power, 80);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}", session_1.session_id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.session.consumed_power, 80);
+        assert_eq!(session_response.session.allocated_power, 80);
+    }
+
+    #[tokio::test]
+    async fn test_power_update_in_watts() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let connector_id = ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 1,
+        };
+        let session_id = state.start_session(connector_id, 150).unwrap().session_id;
+
+        let app = create_app(state);
+
+        // The request is still in kW, only the response is scaled
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}/power-update?unit=w", session_id))
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"consumedPower": 100}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.unit, PowerUnit::W);
+        assert_eq!(session_response.session.allocated_power, 100_000);
+        assert_eq!(session_response.session.vehicle_max_power, 100_000);
+        assert_eq!(session_response.session.requested_max_power, 150_000);
+        assert_eq!(session_response.session.consumed_power, 100_000);
+        assert_eq!(session_response.allocation_ratio, 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_power_updates_coalesced() {
+        let mut config = test_station_config();
+        config.coalesce_window_ms = Some(100);
+        let mut state = StationState::new(config);
+
+        let session_ids = [("CP001", 1), ("CP001", 2), ("CP002", 1)].map(|(charger_id, idx)| {
+            state
+                .start_session(
+                    ConnectorId {
+                        charger_id: charger_id.to_string(),
+                        idx,
+                    },
+                    150,
+                )
+                .unwrap()
+                .session_id
+        });
+        let reallocation_count = state.reallocation_count();
+
+        let shared_state = Arc::new(Mutex::new(state));
+        let app = Router::new()
+            .route("/sessions/{session_id}/power-update", post(power_update))
+            .with_state(shared_state.clone());
+
+        for session_id in session_ids {
+            let power_update_request = PowerUpdateRequest { consumed_power: 40 };
+
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/sessions/{}/power-update", session_id))
+                        .method("POST")
+                        .header("content-type", "application/json")
+                        .body(Body::from(
+                            serde_json::to_string(&power_update_request).unwrap(),
+                        ))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // Nothing is reallocated until the window elapsed
+        assert_eq!(
+            shared_state.lock().await.reallocation_count(),
+            reallocation_count
+        );
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // A single reallocation ran for the three updates
+        let state = shared_state.lock().await;
+        assert_eq!(state.reallocation_count(), reallocation_count + 1);
+        assert!(!state.has_pending_power_updates());
+        for session_id in session_ids {
+            assert_eq!(state.get_sessions()[&session_id].allocated_power, 40);
+        }
+    }
+
+    #[test]
+    fn test_allocation_ratio() {
+        let mut config = test_station_config();
+        config.grid_capacity = 100;
+        let mut state = StationState::new(config);
+
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 1,
+                },
+                200,
+            )
+            .unwrap();
+        assert_eq!(allocation_ratio(&session), 0.5);
+        // The ratio does not depend on the unit of the response
+        let response = session_response(&state, session, PowerUnit::W).unwrap();
+        assert_eq!(response.allocation_ratio, 0.5);
+
+        // A vehicle with a max power of 0 does not cause a division by zero
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 2,
+                },
+                0,
+            )
+            .unwrap();
+        assert_eq!(session.allocated_power, 0);
+        assert_eq!(allocation_ratio(&session), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_session_queue() {
+        let mut config = test_station_config();
+        config.grid_capacity = 200;
+        config.queue_when_full = true;
+        let mut state = StationState::new(config);
+        let first = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .unwrap();
+        let app = create_app(state);
+
+        let create_session = |charger_id: &str, idx| {
+            let create_request = CreateSessionRequest {
+                connector_id: ConnectorId {
+                    charger_id: charger_id.into(),
+                    idx,
+                },
+                vehicle_max_power: Some(150),
+                vehicle_max_current: None,
+                options: SessionOptions::default(),
+            };
+            Request::builder()
+                .uri("/sessions")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                .unwrap()
+        };
+        let get_queue = || async {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/queue").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<Vec<QueuedSessionResponse>>(&body).unwrap()
+        };
+
+        // The station is full, so the new sessions are queued with their position
+        let mut queued_sessions = Vec::new();
+        for (charger_id, idx) in [("CP002", 1), ("CP001", 2)] {
+            let response = app
+                .clone()
+                .oneshot(create_session(charger_id, idx))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let queued: QueuedSessionResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(queued.position, queued_sessions.len() + 1);
+            queued_sessions.push(queued.queued_session);
+        }
+        let queue = get_queue().await;
+        assert_eq!(queue.len(), 2);
+        assert_eq!(
+            queue[0].queued_session.session_id,
+            queued_sessions[0].session_id
+        );
+        assert_eq!(queue[1].position, 2);
+
+        // The client cancels the second session before it started, by its id or short id
+        let cancel = |session_id: String| {
+            Request::delete(format!("/queue/{}", session_id))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(cancel(queued_sessions[1].short_id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(cancel(queued_sessions[1].session_id.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(get_queue().await.len(), 1);
+
+        // Stopping a session starts the first queued one with the freed power
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(format!("/sessions/{}/stop", first.session_id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(get_queue().await.is_empty());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get(format!("/sessions/{}", queued_sessions[0].short_id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.session.allocated_power, 150);
+    }
+
+    #[tokio::test]
+    async fn test_create_session_by_label() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let create_request = CreateSessionByLabelRequest {
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions/by-label/A2")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.session.connector_id.charger_id, "CP001");
+        assert_eq!(session_response.session.connector_id.idx, 2);
+
+        // Unknown labels are not found
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions/by-label/Z9")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert!(error_response.error.contains("Z9"));
+
+        // A forgotten vehicle max power does not start a session with no power
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions/by-label/A1")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_expired_session_stopped() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP001".to_string(),
+                idx: 1,
+            },
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions {
+                expires_at: Some(chrono::Utc::now() + chrono::Duration::milliseconds(100)),
+                ..Default::default()
+            },
+        };
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let location = response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.expires_in_secs, Some(0));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(location)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            session_response.session.state,
+            sems_core::SessionState::Finished
+        );
+        assert_eq!(session_response.session.allocated_power, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pin_session() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let connector = |idx| ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx,
+        };
+        let pinned = state.start_session(connector(1), 200).unwrap();
+        let other = state.start_session(connector(2), 200).unwrap();
+        let app = create_app(state);
+        let send = |method: &str, path: String, body: Body| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(path)
+                    .method(method)
+                    .header("content-type", "application/json")
+                    .body(body)
+                    .unwrap(),
+            )
+        };
+        let get_power = |session_id: Uuid| async move {
+            let response = send("GET", format!("/sessions/{session_id}"), Body::empty())
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<SessionResponse>(&body)
+                .unwrap()
+                .session
+                .allocated_power
+        };
+
+        // The pinned session stays at its power, the other one takes the rest of the charger
+        let pin_request = PinSessionRequest { power: 150 };
+        let response = send(
+            "POST",
+            format!("/sessions/{}/pin", pinned.short_id),
+            Body::from(serde_json::to_string(&pin_request).unwrap()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.session.pinned_power, Some(150));
+        assert_eq!(session_response.session.allocated_power, 150);
+        assert_eq!(get_power(other.session_id).await, 50);
+
+        let response = send(
+            "DELETE",
+            format!("/sessions/{}/pin", pinned.session_id),
+            Body::empty(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(get_power(pinned.session_id).await, 100);
+        assert_eq!(get_power(other.session_id).await, 100);
+
+        let response = send(
+            "POST",
+            format!("/sessions/{}/pin", Uuid::new_v4()),
+            Body::from(serde_json::to_string(&pin_request).unwrap()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_export_import_session() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let exported = state
+            .start_session_with_options(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 1,
+                },
+                120,
+                SessionOptions {
+                    energy_needed_wh: Some(30_000),
+                    metadata: HashMap::from([("fleet".to_string(), "north".to_string())]),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let app = create_app(state);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}/export", exported.short_id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let export: SessionExport = serde_json::from_slice(&body).unwrap();
+        assert_eq!(export.session_id, exported.session_id);
+        assert_eq!(export.vehicle_max_power, 120);
+
+        let import = |connector_id: ConnectorId| {
+            let request = ImportSessionRequest {
+                connector_id,
+                session: serde_json::from_slice(&body).unwrap(),
+            };
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/sessions/import")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request).unwrap()))
+                    .unwrap(),
+            )
+        };
+
+        // The session is re-created on the other connector, with its options
+        let response = import(ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 2,
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let imported: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_ne!(imported.session.session_id, exported.session_id);
+        assert_eq!(imported.session.connector_id.idx, 2);
+        assert_eq!(imported.session.vehicle_max_power, 120);
+        assert_eq!(imported.session.energy_needed_wh, Some(30_000));
+        assert_eq!(imported.session.metadata["fleet"], "north");
+        // The allocation is rerun, the two sessions share the 200kW charger
+        assert_eq!(imported.session.allocated_power, 80);
+
+        // The target connector must exist and be free
+        let response = import(ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 2,
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = import(ConnectorId {
+            charger_id: "CP999".to_string(),
+            idx: 1,
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_reattach_session() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let reattach_request = ReattachSessionRequest {
+            vehicle_max_power: Some(100),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
+        };
+        let reattach = || {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/connectors/CP001/1/reattach")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&reattach_request).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+        };
+
+        // The connector is free, so a session is created
+        let response = reattach().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let created: ReattachSessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(created.outcome, ReattachOutcome::Created);
+        assert_eq!(created.response.session.connector_id.charger_id, "CP001");
+        assert_eq!(created.response.session.connector_id.idx, 1);
+        assert_eq!(created.response.session.vehicle_max_power, 100);
+
+        // The connector has an active session, which is returned
+        let response = reattach().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            format!("/sessions/{}", created.response.session.session_id)
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let reattached: ReattachSessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reattached.outcome, ReattachOutcome::Reattached);
+        assert_eq!(
+            reattached.response.session.session_id,
+            created.response.session.session_id
+        );
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let sessions: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sessions.as_array().map(Vec::len), Some(1));
+
+        // A partial body still finds the active session, but does not start one with no power
+        let reattach_partial = |uri| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from("{}"))
+                    .unwrap(),
+            )
+        };
+        let response = reattach_partial("/connectors/CP001/1/reattach")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = reattach_partial("/connectors/CP001/2/reattach")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Unknown chargers are not found
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/connectors/CP999/1/reattach")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&reattach_request).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_reattach_queued_session() {
+        let mut config = test_station_config();
+        config.grid_capacity = 200;
+        config.queue_when_full = true;
+        let mut state = StationState::new(config);
+        state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .unwrap();
+        let app = create_app(state);
+
+        // A session already waits for capacity on CP001:2
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP001".into(),
+                idx: 2,
+            },
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions::default(),
+        };
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let reattach = |uri: &str| {
+            let reattach_request = ReattachSessionRequest {
+                vehicle_max_power: Some(100),
+                vehicle_max_current: None,
+                options: SessionOptions::default(),
+            };
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&reattach_request).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+        };
+        let queued = |response: axum::response::Response| async move {
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<QueuedSessionResponse>(&body).unwrap()
+        };
+
+        // A free connector does not skip the queue: the new session waits behind the other one
+        let created = queued(reattach("/connectors/CP002/1/reattach").await.unwrap()).await;
+        assert_eq!(created.queued_session.connector_id.charger_id, "CP002");
+        assert_eq!(created.queued_session.vehicle_max_power, 100);
+        assert_eq!(created.position, 2);
+
+        // Reattaching again finds the queued session instead of refusing the connector
+        let reattached = queued(reattach("/connectors/CP002/1/reattach").await.unwrap()).await;
+        assert_eq!(
+            reattached.queued_session.session_id,
+            created.queued_session.session_id
+        );
+        assert_eq!(reattached.position, 2);
+        let first = queued(reattach("/connectors/CP001/2/reattach").await.unwrap()).await;
+        assert_eq!(first.queued_session.vehicle_max_power, 150);
+        assert_eq!(first.position, 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_session_metadata() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let create_request = CreateSessionRequest {
+            connector_id: ConnectorId {
+                charger_id: "CP001".to_string(),
+                idx: 1,
+            },
+            vehicle_max_power: Some(150),
+            vehicle_max_current: None,
+            options: SessionOptions {
+                metadata: HashMap::from([("rfid".into(), "04A1B2".into())]),
+                ..Default::default()
+            },
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/sessions")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&create_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.session.metadata["rfid"], "04A1B2");
+        let session_id = session_response.session.session_id;
+
+        let update_request = UpdateMetadataRequest {
+            metadata: HashMap::from([("fleetId".into(), "F1".into())]),
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}/metadata", session_id))
+                    .method("PATCH")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&update_request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session_response.session.metadata["rfid"], "04A1B2");
+        assert_eq!(session_response.session.metadata["fleetId"], "F1");
+
+        // Unknown sessions are not found
+        let update_metadata = |session_id: Uuid| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}/metadata", session_id))
+                    .method("PATCH")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&update_request).unwrap()))
+                    .unwrap(),
+            )
+        };
+        let response = update_metadata(Uuid::new_v4()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Stopped sessions are gone, like on the other session endpoints
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}/stop", session_id))
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = update_metadata(session_id).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn test_session_notes() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".to_string(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        let app = create_app(state);
+        let add_note = |text: &str| {
+            Request::builder()
+                .uri(format!("/sessions/{}/notes", session.short_id))
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&AddNoteRequest { text: text.into() }).unwrap(),
+                ))
+                .unwrap()
+        };
+
+        for text in [
+            "Customer reported slow charging",
+            "Investigated, vehicle tapering",
+        ] {
+            let response = app.clone().oneshot(add_note(text)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/sessions/{}", session.session_id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session_response: SessionResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            session_response
+                .notes
+                .iter()
+                .map(|note| note.text.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "Customer reported slow charging",
+                "Investigated, vehicle tapering"
+            ]
+        );
+        assert!(session_response.notes[0].timestamp <= session_response.notes[1].timestamp);
+
+        // Empty notes are rejected
+        let response = app.oneshot(add_note("")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_by_tag() {
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+        for (charger_id, idx, fleet_id) in
+            [("CP001", 1, "F1"), ("CP001", 2, "F2"), ("CP002", 1, "F1")]
+        {
+            state
+                .start_session_with_options(
+                    ConnectorId {
+                        charger_id: charger_id.to_string(),
+                        idx,
+                    },
+                    50,
+                    SessionOptions {
+                        metadata: HashMap::from([
+                            ("fleetId".into(), fleet_id.into()),
+                            ("rfid".into(), format!("{}-{}", charger_id, idx)),
+                        ]),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+        let app = create_app(state);
+
+        let list_sessions = |query: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .uri(format!("/sessions{}", query))
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<Vec<SessionResponse>>(&body)
+                    .unwrap()
+                    .into_iter()
+                    .map(|response| response.session.metadata["rfid"].clone())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            list_sessions("").await,
+            vec!["CP001-1", "CP001-2", "CP002-1"]
+        );
+        assert_eq!(
+            list_sessions("?tag.fleetId=F1").await,
+            vec!["CP001-1", "CP002-1"]
+        );
+        // All the tags must match, and the other parameters are not tags
+        assert_eq!(
+            list_sessions("?tag.fleetId=F1&tag.rfid=CP002-1&unit=kw").await,
+            vec!["CP002-1"]
+        );
+        assert!(list_sessions("?tag.fleetId=F3").await.is_empty());
+        assert!(list_sessions("?tag.userId=U1").await.is_empty());
+    }
 }
@@ -1,67 +1,865 @@
-use axum::{Json, extract::State, http::StatusCode};
-use sems_core::{Session, StationConfig, StationState};
-use serde::Serialize;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use axum::{
+    Json,
+    body::Body,
+    extract::{Query, State},
+    http::{HeaderMap, header},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use sems_core::{
+    AllocationDiff, BatteryControl, BatteryFlow, BatteryStatus, CapacitySummary, ChargerStatus,
+    ConfigProblem, Session, StationConfig, StationError, StationState,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
 
-#[derive(Serialize, serde::Deserialize)]
+use crate::extract::ApiJson;
+use crate::session::{ErrorKind, ErrorResponse, PowerUnit, UnitQuery, schedule_promoted_sessions};
+use crate::units::{InUnit, PowerFields, kw_to_w};
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct StationStatus {
-    pub sessions: HashMap<uuid::Uuid, Session>,
+    /// Active sessions, sorted by session id
+    pub sessions: Vec<Session>,
+    /// Combined power flow of the batteries, if the station has some
+    pub battery: Option<BatteryFlow>,
+    /// State of charge of every battery, and whether it reached its discharge floor
+    #[serde(default)]
+    pub batteries: Vec<BatteryStatus>,
+    /// Grid capacity of the station in kW
+    pub grid_capacity: u32,
+    /// Power allocated to the sessions in kW
+    pub allocated_power: u32,
+    /// Capacity left for new sessions in kW, including the battery boost
+    pub remaining_capacity: u32,
+    /// Allocated and max power of every charger
+    pub chargers: Vec<ChargerStatus>,
+    /// Whether the station refuses new sessions, while the active ones continue
+    #[serde(default)]
+    pub draining: bool,
+    /// Whether the station is emergency stopped, giving no power to the sessions
+    #[serde(default)]
+    pub emergency_stop: bool,
 }
 
 /// Get current station configuration
+#[utoipa::path(
+    get,
+    path = "/station/config",
+    tag = "station",
+    responses((status = 200, description = "Current configuration", body = StationConfig))
+)]
 pub async fn get_station_config(
     State(app_state): State<Arc<Mutex<StationState>>>,
 ) -> Json<StationConfig> {
     tracing::info!("Getting station configuration");
-    let state = app_state.lock().unwrap();
+    let state = app_state.lock().await;
     let config = state.get_config().clone();
     Json(config)
 }
 
+/// Representation of the station status, negotiated with the `Accept` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusFormat {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl StatusFormat {
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/json" | "application/*" | "*/*" => Some(StatusFormat::Json),
+            "text/csv" | "text/*" => Some(StatusFormat::Csv),
+            "application/x-ndjson" => Some(StatusFormat::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+/// Rank a media range by how specific it is: `*/*` below `type/*` below `type/subtype`
+fn media_range_specificity(media_type: &str) -> u8 {
+    match media_type {
+        "*/*" => 0,
+        _ if media_type.ends_with("/*") => 1,
+        _ => 2,
+    }
+}
+
+/// Pick the status format from the `Accept` header
+/// The supported media type with the highest quality wins, then the most specific media range, as
+/// in RFC 9110, then the first one listed. JSON is returned when the header is missing or lists no
+/// supported media type.
+pub fn negotiate_status_format(headers: &HeaderMap) -> StatusFormat {
+    let mut best: Option<(StatusFormat, f32, u8)> = None;
+    for accept in headers.get_all(header::ACCEPT) {
+        let Ok(accept) = accept.to_str() else {
+            continue;
+        };
+        for media_range in accept.split(',') {
+            let mut params = media_range.split(';');
+            let media_type = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let Some(format) = StatusFormat::from_media_type(&media_type) else {
+                continue;
+            };
+            let specificity = media_range_specificity(&media_type);
+            if quality > 0.0
+                && best.is_none_or(|(_, best_quality, best_specificity)| {
+                    quality > best_quality
+                        || (quality == best_quality && specificity > best_specificity)
+                })
+            {
+                best = Some((format, quality, specificity));
+            }
+        }
+    }
+    best.map_or(StatusFormat::Json, |(format, _, _)| format)
+}
+
+/// Stream the sessions as NDJSON, one session object per line
+fn sessions_ndjson(sessions: Vec<Session>) -> Response {
+    let lines = futures_util::stream::iter(sessions).map(|session| {
+        let mut line = serde_json::to_vec(&session)?;
+        line.push(b'\n');
+        Ok::<_, serde_json::Error>(line)
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Header of the CSV station status
+const STATUS_CSV_HEADER: &str =
+    "sessionId,shortId,chargerId,idx,state,allocatedPower,vehicleMaxPower,consumedPower";
+
+/// Quote a CSV field if it contains a separator, a quote or a line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Render the sessions as CSV, one session per row after a header row
+fn sessions_csv(sessions: &[Session]) -> Response {
+    let mut csv = format!("{STATUS_CSV_HEADER}\n");
+    for session in sessions {
+        let state = serde_json::to_value(session.state).unwrap_or_default();
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{}\n",
+            session.session_id,
+            csv_field(&session.short_id),
+            csv_field(&session.connector_id.charger_id),
+            session.connector_id.idx,
+            state.as_str().unwrap_or_default(),
+            session.allocated_power,
+            session.vehicle_max_power,
+            session.consumed_power,
+        ));
+    }
+    ([(header::CONTENT_TYPE, "text/csv")], csv).into_response()
+}
+
 /// Get station status with all current sessions
+/// The powers are in kW, or in W with `?unit=w`. Currents are only available on the session
+/// endpoints, as they depend on the connector.
+///
+/// The representation follows the `Accept` header: `application/json` (the default) for the full
+/// status, `text/csv` for one row per session, and `application/x-ndjson` for one session object
+/// per line. The sessions are sorted by session id, and the CSV and NDJSON powers are in the unit
+/// too.
+#[utoipa::path(
+    get,
+    path = "/station/status",
+    tag = "station",
+    params(UnitQuery),
+    responses(
+        (status = 200, description = "Current sessions", content(
+            (StationStatus = "application/json"),
+            (String = "text/csv"),
+            (Session = "application/x-ndjson"),
+        )),
+        (status = 422, description = "Unit not supported", body = ErrorResponse),
+    )
+)]
 pub async fn get_station_status(
     State(app_state): State<Arc<Mutex<StationState>>>,
-) -> Json<StationStatus> {
+    Query(query): Query<UnitQuery>,
+    headers: HeaderMap,
+) -> Response {
     tracing::info!("Getting station status");
-    let state = app_state.lock().unwrap();
-    let sessions = state.get_sessions().clone();
-    Json(StationStatus { sessions })
+    if query.unit == PowerUnit::Amps {
+        return ErrorResponse::response(
+            ErrorKind::Validation,
+            "The station status cannot be reported in amps".to_string(),
+        );
+    }
+    let format = negotiate_status_format(&headers);
+    let mut response = station_status_in(&app_state, query.unit, format).await;
+    response
+        .headers_mut()
+        .insert(header::VARY, header::ACCEPT.into());
+    response
+}
+
+/// Build the station status in the given unit and format
+async fn station_status_in(
+    app_state: &Arc<Mutex<StationState>>,
+    unit: PowerUnit,
+    format: StatusFormat,
+) -> Response {
+    let state = app_state.lock().await;
+    let mut sessions = state.get_sessions().values().cloned().collect::<Vec<_>>();
+    sessions.sort_by_key(|session| session.session_id);
+    match format {
+        StatusFormat::Json => {}
+        StatusFormat::Csv | StatusFormat::Ndjson => {
+            drop(state);
+            if unit == PowerUnit::W {
+                sessions
+                    .iter_mut()
+                    .for_each(|session| session.map_powers(&kw_to_w));
+            }
+            return match format {
+                StatusFormat::Csv => sessions_csv(&sessions),
+                _ => sessions_ndjson(sessions),
+            };
+        }
+    }
+    let battery = state.battery_flow();
+    let batteries = state.battery_statuses();
+    let capacity = state.capacity_summary();
+    Json(InUnit {
+        value: StationStatus {
+            sessions,
+            battery,
+            batteries,
+            grid_capacity: capacity.grid_capacity,
+            allocated_power: capacity.allocated_power,
+            remaining_capacity: capacity.remaining_capacity,
+            chargers: state.charger_statuses(),
+            draining: state.is_draining(),
+            emergency_stop: state.is_emergency_stopped(),
+        },
+        unit,
+    })
+    .into_response()
+}
+
+/// Power allocated on a connector, serialized as a `[chargerId, idx, allocatedKw]` array
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct CompactConnectorPower(pub String, pub u8, pub u32);
+
+/// Get the power allocated on every connector in use, as compact arrays
+/// This is a lighter variant of the station status for dashboards polling frequently over a
+/// constrained link: a flat array of `[chargerId, idx, allocatedKw]`, sorted by connector.
+#[utoipa::path(
+    get,
+    path = "/station/status/compact",
+    tag = "station",
+    responses((status = 200, description = "Power allocated per connector", body = Vec<CompactConnectorPower>))
+)]
+pub async fn get_compact_station_status(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Json<Vec<CompactConnectorPower>> {
+    let state = app_state.lock().await;
+    let mut connectors = state
+        .get_sessions()
+        .values()
+        .map(|session| {
+            CompactConnectorPower(
+                session.connector_id.charger_id.clone(),
+                session.connector_id.idx,
+                session.allocated_power,
+            )
+        })
+        .collect::<Vec<_>>();
+    connectors.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    Json(connectors)
+}
+
+/// Stream the active sessions as NDJSON, one session object per line, sorted by session id
+/// This is a variant of the station status for sites with thousands of sessions, which clients can
+/// process line by line instead of parsing a single huge document. The sessions are a snapshot
+/// taken when the request is received, and the powers are in kW.
+#[utoipa::path(
+    get,
+    path = "/station/status.ndjson",
+    tag = "station",
+    responses(
+        (status = 200, description = "One session per line", body = Session, content_type = "application/x-ndjson"),
+    )
+)]
+pub async fn get_station_status_ndjson(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Response {
+    // Only the snapshot is taken under the lock, the sessions are serialized while streaming
+    let mut sessions = {
+        let state = app_state.lock().await;
+        state.get_sessions().values().cloned().collect::<Vec<_>>()
+    };
+    sessions.sort_by_key(|session| session.session_id);
+    sessions_ndjson(sessions)
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DemandResponseRequest {
+    pub fraction: f32,
+    pub duration_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerCapRequest {
+    /// Ceiling of the grid capacity in kW
+    pub max_power: u32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SafetyMarginRequest {
+    /// Share of the grid capacity kept as headroom, from 0 to 50%
+    pub percent: u8,
+}
+
+pub(crate) fn station_error_to_response(error: StationError) -> Response {
+    let kind = match error {
+        StationError::InvalidDemandResponse { .. }
+        | StationError::InvalidDemandResponseDuration { .. } => ErrorKind::Validation,
+        StationError::ChargerNotFound { .. } | StationError::BatteryNotFound => ErrorKind::NotFound,
+        StationError::InvalidBatteryPower { .. } | StationError::InvalidSafetyMargin { .. } => {
+            ErrorKind::Validation
+        }
+    };
+
+    ErrorResponse::response(kind, error.to_string())
+}
+
+/// Get a summary of the station capacity and its usage
+#[utoipa::path(
+    get,
+    path = "/station/capacity",
+    tag = "station",
+    responses((status = 200, description = "Capacity summary", body = CapacitySummary))
+)]
+pub async fn get_station_capacity(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Json<CapacitySummary> {
+    tracing::info!("Getting station capacity");
+    let state = app_state.lock().await;
+    Json(state.capacity_summary())
+}
+
+/// Apply a demand response signal from the grid operator
+/// The grid capacity is scaled until the duration elapses, then restored by a background task
+#[utoipa::path(
+    post,
+    path = "/station/demand-response",
+    tag = "station",
+    request_body = DemandResponseRequest,
+    responses(
+        (status = 200, description = "Demand response applied", body = CapacitySummary),
+        (status = 422, description = "Invalid demand response", body = ErrorResponse),
+    )
+)]
+pub async fn apply_demand_response(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    ApiJson(payload): ApiJson<DemandResponseRequest>,
+) -> Response {
+    let duration = Duration::from_secs(payload.duration_secs);
+    let result = {
+        let mut state = app_state.lock().await;
+        state
+            .apply_demand_response(payload.fraction, duration)
+            .map(|id| (id, state.capacity_summary()))
+    };
+
+    match result {
+        Ok((id, summary)) => {
+            tokio::spawn(async move {
+                tokio::time::sleep(duration).await;
+                let mut state = app_state.lock().await;
+                state.end_demand_response(id);
+                schedule_promoted_sessions(&app_state, &mut state);
+            });
+            Json(summary).into_response()
+        }
+        Err(error) => station_error_to_response(error),
+    }
+}
+
+/// Cap the grid capacity below its configured value, e.g. on a utility request
+/// The cap applies until it is cleared, and the sessions are reallocated immediately. With a
+/// demand response, the lowest capacity wins.
+#[utoipa::path(
+    post,
+    path = "/station/power-cap",
+    tag = "station",
+    request_body = PowerCapRequest,
+    responses((status = 200, description = "Power cap set", body = CapacitySummary))
+)]
+pub async fn set_power_cap(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    ApiJson(payload): ApiJson<PowerCapRequest>,
+) -> Json<CapacitySummary> {
+    let mut state = app_state.lock().await;
+    state.set_power_cap(payload.max_power);
+    schedule_promoted_sessions(&app_state, &mut state);
+    Json(state.capacity_summary())
+}
+
+/// Keep a share of the grid capacity as headroom, without pushing a new configuration
+/// The sessions are reallocated immediately, and a margin of 0 removes it. The margin applies on
+/// top of a demand response, and a lower power cap still wins.
+#[utoipa::path(
+    post,
+    path = "/station/margin",
+    tag = "station",
+    request_body = SafetyMarginRequest,
+    responses(
+        (status = 200, description = "Safety margin set", body = CapacitySummary),
+        (status = 422, description = "Margin above 50%", body = ErrorResponse),
+    )
+)]
+pub async fn set_safety_margin(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    ApiJson(payload): ApiJson<SafetyMarginRequest>,
+) -> Response {
+    let mut state = app_state.lock().await;
+    let result = state.set_safety_margin(payload.percent);
+    schedule_promoted_sessions(&app_state, &mut state);
+    match result {
+        Ok(()) => Json(state.capacity_summary()).into_response(),
+        Err(error) => station_error_to_response(error),
+    }
+}
+
+/// Clear the power cap, restoring the grid capacity
+#[utoipa::path(
+    delete,
+    path = "/station/power-cap",
+    tag = "station",
+    responses((status = 200, description = "Power cap cleared", body = CapacitySummary))
+)]
+pub async fn clear_power_cap(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Json<CapacitySummary> {
+    let mut state = app_state.lock().await;
+    state.clear_power_cap();
+    schedule_promoted_sessions(&app_state, &mut state);
+    Json(state.capacity_summary())
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WhatIfRequest {
+    /// Hypothetical grid capacity in kW
+    pub grid_capacity: u32,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WhatIfResponse {
+    /// Hypothetical grid capacity in kW
+    pub grid_capacity: u32,
+    /// Power that would be allocated to the sessions in kW
+    pub allocated_power: u32,
+    /// Active sessions with the allocations they would get, sorted by session id
+    pub sessions: Vec<Session>,
+}
+
+/// Preview the allocations of the active sessions with another grid capacity
+/// Nothing changes in the station: this helps sizing a new grid contract. The sessions are fully
+/// reallocated at the hypothetical capacity, with the current demand response and battery.
+#[utoipa::path(
+    post,
+    path = "/station/what-if",
+    tag = "station",
+    request_body = WhatIfRequest,
+    responses((status = 200, description = "Hypothetical allocations", body = WhatIfResponse))
+)]
+pub async fn what_if_capacity(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    ApiJson(payload): ApiJson<WhatIfRequest>,
+) -> Json<WhatIfResponse> {
+    let sessions = app_state
+        .lock()
+        .await
+        .what_if_capacity(payload.grid_capacity);
+    Json(WhatIfResponse {
+        grid_capacity: payload.grid_capacity,
+        allocated_power: sessions.iter().fold(0, |total, session| {
+            total.saturating_add(session.allocated_power)
+        }),
+        sessions,
+    })
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatteryResponse {
+    /// Combined power flow of the batteries
+    pub battery: BatteryFlow,
+    /// State of charge of every battery
+    pub batteries: Vec<BatteryStatus>,
+}
+
+/// Manually charge, discharge or idle the batteries, overriding their automatic discharge
+/// Charging takes its power from the grid capacity of the sessions, discharging adds to it.
+/// The power is split among the batteries, each within its own limits. The `auto` mode restores
+/// the automatic behavior.
+#[utoipa::path(
+    post,
+    path = "/station/battery",
+    tag = "station",
+    request_body = BatteryControl,
+    responses(
+        (status = 200, description = "Battery control applied", body = BatteryResponse),
+        (status = 404, description = "The station has no battery", body = ErrorResponse),
+        (status = 422, description = "Power above the combined battery rating", body = ErrorResponse),
+    )
+)]
+pub async fn control_battery(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    ApiJson(payload): ApiJson<BatteryControl>,
+) -> Response {
+    let mut state = app_state.lock().await;
+    let result = state.set_battery_control(payload);
+    schedule_promoted_sessions(&app_state, &mut state);
+    match result {
+        Ok(()) => match state.battery_flow() {
+            Some(battery) => Json(BatteryResponse {
+                battery,
+                batteries: state.battery_statuses(),
+            })
+            .into_response(),
+            None => station_error_to_response(StationError::BatteryNotFound),
+        },
+        Err(error) => station_error_to_response(error),
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigErrorResponse {
+    pub error: String,
+    pub kind: ErrorKind,
+    /// Every problem found in the configuration
+    pub problems: Vec<ConfigProblemResponse>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigProblemResponse {
+    #[serde(flatten)]
+    pub problem: ConfigProblem,
+    pub message: String,
+}
+
+/// Describe the problems of a configuration with their messages
+fn config_problem_responses(problems: Vec<ConfigProblem>) -> Vec<ConfigProblemResponse> {
+    problems
+        .into_iter()
+        .map(|problem| ConfigProblemResponse {
+            message: problem.to_string(),
+            problem,
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigUpdateResponse {
+    /// Configuration applied
+    #[serde(flatten)]
+    pub config: StationConfig,
+    /// Problems that did not prevent the configuration from being applied, but are likely
+    /// configuration errors
+    pub warnings: Vec<ConfigProblemResponse>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigPreviewResponse {
+    /// Whether the configuration would be applied
+    pub valid: bool,
+    /// Every problem preventing the configuration from being applied
+    pub problems: Vec<ConfigProblemResponse>,
+    /// Problems that would not prevent the configuration from being applied, but are likely
+    /// configuration errors
+    pub warnings: Vec<ConfigProblemResponse>,
+    /// Changes of the sessions and their allocations if the configuration were applied, empty if
+    /// it is invalid
+    pub diff: AllocationDiff,
+    /// Capacity of the station with the configuration, if it is valid
+    pub capacity: Option<CapacitySummary>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DrainResponse {
+    /// Whether the station refuses new sessions
+    pub draining: bool,
+    /// Number of sessions still active
+    pub active_sessions: usize,
+}
+
+/// Stop accepting new sessions, e.g. before a maintenance
+/// The active sessions continue, and can still be updated and stopped. New sessions are refused
+/// with a 503 until the station is undrained.
+#[utoipa::path(
+    post,
+    path = "/station/drain",
+    tag = "station",
+    responses((status = 200, description = "Station draining", body = DrainResponse))
+)]
+pub async fn drain_station(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Json<DrainResponse> {
+    let mut state = app_state.lock().await;
+    state.drain();
+    Json(drain_response(&state))
+}
+
+/// Accept new sessions again after a drain
+#[utoipa::path(
+    post,
+    path = "/station/undrain",
+    tag = "station",
+    responses((status = 200, description = "Station accepting new sessions", body = DrainResponse))
+)]
+pub async fn undrain_station(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Json<DrainResponse> {
+    let mut state = app_state.lock().await;
+    state.undrain();
+    schedule_promoted_sessions(&app_state, &mut state);
+    Json(drain_response(&state))
+}
+
+fn drain_response(state: &StationState) -> DrainResponse {
+    DrainResponse {
+        draining: state.is_draining(),
+        active_sessions: state.get_sessions().len(),
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EmergencyStopResponse {
+    /// Whether the station is emergency stopped
+    pub emergency_stop: bool,
+    /// Number of sessions still active
+    pub active_sessions: usize,
+    /// Power allocated to the sessions in kW
+    pub allocated_power: u32,
+}
+
+/// Cut the power of every session at once, e.g. on a safety incident
+/// The sessions stay active with no power, and the charging ones are suspended. Unlike a drain,
+/// the stop is latched: new sessions are refused with a 503, and the sessions get no power until
+/// the emergency stop is cleared.
+#[utoipa::path(
+    post,
+    path = "/station/emergency-stop",
+    tag = "station",
+    responses(
+        (status = 200, description = "Station emergency stopped", body = EmergencyStopResponse),
+    )
+)]
+pub async fn emergency_stop(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Json<EmergencyStopResponse> {
+    let mut state = app_state.lock().await;
+    state.emergency_stop();
+    Json(emergency_stop_response(&state))
+}
+
+/// Release the emergency stop, and reallocate the sessions
+#[utoipa::path(
+    post,
+    path = "/station/clear-emergency",
+    tag = "station",
+    responses(
+        (status = 200, description = "Emergency stop cleared", body = EmergencyStopResponse),
+    )
+)]
+pub async fn clear_emergency_stop(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Json<EmergencyStopResponse> {
+    let mut state = app_state.lock().await;
+    state.clear_emergency_stop();
+    schedule_promoted_sessions(&app_state, &mut state);
+    Json(emergency_stop_response(&state))
+}
+
+fn emergency_stop_response(state: &StationState) -> EmergencyStopResponse {
+    EmergencyStopResponse {
+        emergency_stop: state.is_emergency_stopped(),
+        active_sessions: state.get_sessions().len(),
+        allocated_power: state.capacity_summary().allocated_power,
+    }
+}
+
+/// Reallocate the power of every active session now, and return the allocation changes
+/// The sessions are otherwise fully reallocated only when the station capacity changes, e.g. a
+/// new session gets the capacity left by the others. An empty diff means the allocations were
+/// already the ones of a full reallocation.
+#[utoipa::path(
+    post,
+    path = "/station/reallocate",
+    tag = "station",
+    responses((status = 200, description = "Allocations before and after", body = AllocationDiff))
+)]
+pub async fn reallocate_station(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+) -> Json<AllocationDiff> {
+    let diff = {
+        let mut state = app_state.lock().await;
+        let diff = state.force_reallocation();
+        schedule_promoted_sessions(&app_state, &mut state);
+        diff
+    };
+    tracing::info!(
+        changed = diff.changed.len(),
+        "Reallocated the sessions on request"
+    );
+    Json(diff)
+}
+
+/// Preview a configuration update, without applying it
+/// The configuration is validated like for `POST /station/config`, and the response tells the
+/// sessions that would be dropped, which are all the active ones as an update replaces the
+/// station, the queued sessions that would start with their allocations, and the capacity of the
+/// station with it. The runtime controls are kept as on an update. An invalid configuration is
+/// reported with its problems rather than rejected.
+#[utoipa::path(
+    post,
+    path = "/station/config/preview",
+    tag = "station",
+    request_body = StationConfig,
+    responses((status = 200, description = "Impact of the configuration", body = ConfigPreviewResponse))
+)]
+pub async fn preview_station_config(
+    State(app_state): State<Arc<Mutex<StationState>>>,
+    ApiJson(new_config): ApiJson<StationConfig>,
+) -> Json<ConfigPreviewResponse> {
+    let warnings = config_problem_responses(new_config.warnings());
+    if let Err(problems) = new_config.validate() {
+        return Json(ConfigPreviewResponse {
+            valid: false,
+            problems: config_problem_responses(problems),
+            warnings,
+            diff: AllocationDiff::default(),
+            capacity: None,
+        });
+    }
+
+    // Updated like on `POST /station/config`, on a copy of the station taken under the lock
+    let mut new_state = app_state.lock().await.clone_without_observers();
+    let diff = new_state.reconfigure(new_config);
+    Json(ConfigPreviewResponse {
+        valid: true,
+        problems: Vec::new(),
+        warnings,
+        diff,
+        capacity: Some(new_state.capacity_summary()),
+    })
 }
 
 /// Update station configuration
-/// This creates a new StationState, dropping all existing sessions
+/// This creates a new StationState, dropping all existing sessions. The runtime controls, like the
+/// emergency stop, the drain or the power cap, are kept.
+/// An inconsistent configuration is rejected with all its problems, and the current one is kept. The
+/// applied configuration is returned with its warnings.
+#[utoipa::path(
+    post,
+    path = "/station/config",
+    tag = "station",
+    request_body = StationConfig,
+    responses(
+        (status = 200, description = "Configuration updated", body = ConfigUpdateResponse),
+        (status = 400, description = "Inconsistent configuration", body = ConfigErrorResponse),
+    )
+)]
 pub async fn update_station_config(
     State(app_state): State<Arc<Mutex<StationState>>>,
-    Json(new_config): Json<StationConfig>,
-) -> Result<Json<StationConfig>, StatusCode> {
+    ApiJson(new_config): ApiJson<StationConfig>,
+) -> Response {
     tracing::info!(?new_config, "Updating station configuration");
 
-    // Create new StationState with the new config (this drops all sessions)
-    let new_state = StationState::new(new_config.clone());
+    if let Err(problems) = new_config.validate() {
+        tracing::warn!(?problems, "Rejecting inconsistent station configuration");
+        let kind = ErrorKind::Malformed;
+        let response = ConfigErrorResponse {
+            error: format!("The configuration has {} problem(s)", problems.len()),
+            kind,
+            problems: config_problem_responses(problems),
+        };
+        return (kind.status(), Json(response)).into_response();
+    }
+    let warnings = new_config.warnings();
+    if !warnings.is_empty() {
+        tracing::warn!(?warnings, "Applying a station configuration with warnings");
+    }
 
-    // Replace the current state
-    {
-        let mut state = app_state.lock().unwrap();
-        *state = new_state;
+    // Replace the current state, dropping its sessions but keeping its runtime controls
+    let diff = {
+        let mut state = app_state.lock().await;
+        let diff = state.reconfigure(new_config.clone());
+        schedule_promoted_sessions(&app_state, &mut state);
+        diff
+    };
+    if !diff.removed.is_empty() {
+        let dropped_sessions = diff
+            .removed
+            .iter()
+            .map(|session| session.session_id.to_string())
+            .collect::<Vec<_>>();
+        tracing::warn!(
+            ?dropped_sessions,
+            "Configuration change dropped the active sessions"
+        );
     }
 
     tracing::info!("Station configuration updated successfully");
-    Ok(Json(new_config))
+    Json(ConfigUpdateResponse {
+        config: new_config,
+        warnings: config_problem_responses(warnings),
+    })
+    .into_response()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    pub use axum::{Router, routing::get};
+    pub use axum::{
+        Router,
+        routing::{get, post},
+    };
     use axum::{
         body::Body,
         http::{Request, StatusCode},
     };
     use tower::util::ServiceExt;
 
-    use sems_core::ChargerConfig;
+    use sems_core::{BatteryMode, Bess, ChargerConfig, ConfigPowerUnit, SessionAdmission};
 
     /// Create the application router with all endpoints
     pub fn create_app(app_state: StationState) -> Router {
@@ -71,7 +869,24 @@ mod tests {
                 "/station/config",
                 get(get_station_config).post(update_station_config),
             )
+            .route("/station/config/preview", post(preview_station_config))
             .route("/station/status", get(get_station_status))
+            .route("/station/status/compact", get(get_compact_station_status))
+            .route("/station/status.ndjson", get(get_station_status_ndjson))
+            .route("/station/capacity", get(get_station_capacity))
+            .route("/station/demand-response", post(apply_demand_response))
+            .route(
+                "/station/power-cap",
+                post(set_power_cap).delete(clear_power_cap),
+            )
+            .route("/station/margin", post(set_safety_margin))
+            .route("/station/battery", post(control_battery))
+            .route("/station/what-if", post(what_if_capacity))
+            .route("/station/drain", post(drain_station))
+            .route("/station/undrain", post(undrain_station))
+            .route("/station/emergency-stop", post(emergency_stop))
+            .route("/station/clear-emergency", post(clear_emergency_stop))
+            .route("/station/reallocate", post(reallocate_station))
             .with_state(shared_state)
     }
 
@@ -83,8 +898,10 @@ mod tests {
                 id: "CP001".into(),
                 max_power: 200,
                 connectors: 2,
+                ..Default::default()
             }],
-            battery: None,
+            batteries: vec![],
+            ..Default::default()
         }
     }
 
@@ -137,6 +954,10 @@ mod tests {
             .unwrap();
         let status_response: StationStatus = serde_json::from_slice(&body).unwrap();
         assert!(status_response.sessions.is_empty());
+        assert!(status_response.battery.is_none());
+        assert_eq!(status_response.allocated_power, 0);
+        assert_eq!(status_response.remaining_capacity, 400);
+        assert_eq!(status_response.chargers[0].allocated_power, 0);
     }
 
     #[tokio::test]
@@ -175,112 +996,888 @@ mod tests {
 
         // Verify we have one session
         assert_eq!(status_response.sessions.len(), 1);
-        assert!(status_response.sessions.contains_key(&session_id));
 
         // Verify session details
-        let session = &status_response.sessions[&session_id];
+        let session = &status_response.sessions[0];
         assert_eq!(session.session_id, session_id);
         assert_eq!(session.connector_id.charger_id, "CP001");
         assert_eq!(session.connector_id.idx, 1);
         assert_eq!(session.vehicle_max_power, 150);
+
+        // Verify the capacity is computed server-side
+        assert_eq!(status_response.grid_capacity, 400);
+        assert_eq!(status_response.allocated_power, 150);
+        assert_eq!(status_response.remaining_capacity, 250);
+        assert_eq!(status_response.chargers.len(), 1);
+        assert_eq!(status_response.chargers[0].id, "CP001");
+        assert_eq!(status_response.chargers[0].max_power, 200);
+        assert_eq!(status_response.chargers[0].allocated_power, 150);
     }
 
     #[tokio::test]
-    async fn test_update_config_endpoint() {
-        let config = test_station_config();
-        let state = StationState::new(config);
+    async fn test_station_status_sessions_sorted() {
+        let config = StationConfig {
+            chargers: vec![ChargerConfig {
+                id: "CP001".into(),
+                max_power: 200,
+                connectors: 8,
+                ..Default::default()
+            }],
+            ..test_station_config()
+        };
+        let mut state = StationState::new(config);
+        for idx in 1..=8 {
+            state
+                .start_session(
+                    sems_core::ConnectorId {
+                        charger_id: "CP001".into(),
+                        idx,
+                    },
+                    20,
+                )
+                .unwrap();
+        }
         let app = create_app(state);
 
-        // New configuration with different values
-        let new_config = StationConfig {
-            station_id: "NEW_STATION".into(),
-            grid_capacity: 600,
-            chargers: vec![
-                ChargerConfig {
-                    id: "CP001".into(),
-                    max_power: 250,
-                    connectors: 2,
-                },
-                ChargerConfig {
-                    id: "CP002".into(),
-                    max_power: 300,
-                    connectors: 1,
-                },
-            ],
-            battery: None,
-        };
+        let mut statuses = Vec::new();
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/station/status")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            statuses.push(body);
+        }
+
+        // The sessions are serialized in the same order every time, sorted by id
+        assert_eq!(statuses[0], statuses[1]);
+        let status_response: StationStatus = serde_json::from_slice(&statuses[0]).unwrap();
+        let session_ids = status_response
+            .sessions
+            .iter()
+            .map(|session| session.session_id)
+            .collect::<Vec<_>>();
+        assert_eq!(session_ids.len(), 8);
+        assert!(session_ids.is_sorted());
+    }
+
+    #[tokio::test]
+    async fn test_compact_station_status() {
+        let mut state = StationState::new(test_station_config());
+        for idx in [2, 1] {
+            state
+                .start_session(
+                    sems_core::ConnectorId {
+                        charger_id: "CP001".into(),
+                        idx,
+                    },
+                    150,
+                )
+                .unwrap();
+        }
+        state.force_reallocation();
+        let app = create_app(state);
 
         let response = app
             .oneshot(
                 Request::builder()
-                    .uri("/station/config")
-                    .method("POST")
-                    .header("content-type", "application/json")
-                    .body(Body::from(serde_json::to_string(&new_config).unwrap()))
+                    .uri("/station/status/compact")
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        // A flat array of arrays, sorted by connector
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!([["CP001", 1, 100], ["CP001", 2, 100]])
+        );
+    }
 
+    #[tokio::test]
+    async fn test_station_status_ndjson() {
+        let mut state = StationState::new(test_station_config());
+        let mut session_ids = [1, 2]
+            .map(|idx| {
+                state
+                    .start_session(
+                        sems_core::ConnectorId {
+                            charger_id: "CP001".into(),
+                            idx,
+                        },
+                        80,
+                    )
+                    .unwrap()
+                    .session_id
+            })
+            .to_vec();
+        session_ids.sort();
+        let app = create_app(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/status.ndjson")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/x-ndjson"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        // One session per line, sorted by session id
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.ends_with('\n'));
+        let sessions = body
+            .lines()
+            .map(|line| serde_json::from_str::<Session>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sessions
+                .iter()
+                .map(|session| session.session_id)
+                .collect::<Vec<_>>(),
+            session_ids
+        );
+        assert!(sessions.iter().all(|session| session.allocated_power == 80));
+    }
+
+    /// Start two sessions of 80 kW on CP001 and return the app with their sorted ids
+    fn app_with_two_sessions() -> (Router, Vec<uuid::Uuid>) {
+        let mut state = StationState::new(test_station_config());
+        let mut session_ids = [1, 2]
+            .map(|idx| {
+                state
+                    .start_session(
+                        sems_core::ConnectorId {
+                            charger_id: "CP001".into(),
+                            idx,
+                        },
+                        80,
+                    )
+                    .unwrap()
+                    .session_id
+            })
+            .to_vec();
+        session_ids.sort();
+        (create_app(state), session_ids)
+    }
+
+    async fn get_status_accepting(
+        app: Router,
+        accept: Option<&str>,
+    ) -> (StatusCode, String, String) {
+        get_status_at_accepting(app, "/station/status", accept).await
+    }
 
+    async fn get_status_at_accepting(
+        app: Router,
+        uri: &str,
+        accept: Option<&str>,
+    ) -> (StatusCode, String, String) {
+        let mut request = Request::builder().uri(uri);
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        if status == StatusCode::OK {
+            assert_eq!(response.headers()[header::VARY], "accept");
+        }
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
         let body = axum::body::to_bytes(response.into_body(), usize::MAX)
             .await
             .unwrap();
-        let config_response: StationConfig = serde_json::from_slice(&body).unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
 
-        assert_eq!(config_response.station_id, "NEW_STATION");
-        assert_eq!(config_response.grid_capacity, 600);
-        assert_eq!(config_response.chargers.len(), 2);
-        assert_eq!(config_response.chargers[0].max_power, 250);
-        assert_eq!(config_response.chargers[1].id, "CP002");
+    #[tokio::test]
+    async fn test_station_status_accept_json() {
+        for accept in [
+            None,
+            Some("application/json"),
+            Some("*/*"),
+            Some("text/html"),
+        ] {
+            let (app, session_ids) = app_with_two_sessions();
+            let (status, content_type, body) = get_status_accepting(app, accept).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(content_type, "application/json");
+            let status: StationStatus = serde_json::from_str(&body).unwrap();
+            assert_eq!(
+                status
+                    .sessions
+                    .iter()
+                    .map(|session| session.session_id)
+                    .collect::<Vec<_>>(),
+                session_ids
+            );
+            assert_eq!(status.allocated_power, 160);
+        }
     }
 
     #[tokio::test]
-    async fn test_update_config_drops_sessions() {
+    async fn test_station_status_accept_csv() {
+        let (app, session_ids) = app_with_two_sessions();
+        let (status, content_type, body) = get_status_accepting(app, Some("text/csv")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "text/csv");
+
+        // A header row, then one row per session sorted by session id
+        let mut lines = body.lines();
+        assert_eq!(lines.next(), Some(STATUS_CSV_HEADER));
+        let rows = lines
+            .map(|line| line.split(',').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows.iter().map(|row| row[0]).collect::<Vec<_>>(),
+            session_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert!(
+            rows.iter()
+                .all(|row| row[2] == "CP001" && row[4] == "pending" && row[5] == "80")
+        );
+
+        // The powers follow the unit, and amps are refused as for JSON
+        let (app, _) = app_with_two_sessions();
+        let (status, _, body) =
+            get_status_at_accepting(app, "/station/status?unit=w", Some("text/csv")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(
+            body.lines()
+                .skip(1)
+                .all(|line| line.split(',').nth(5) == Some("80000"))
+        );
+        let (app, _) = app_with_two_sessions();
+        let (status, _, _) =
+            get_status_at_accepting(app, "/station/status?unit=amps", Some("text/csv")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_station_status_accept_ndjson() {
+        let (app, session_ids) = app_with_two_sessions();
+        let (status, content_type, body) =
+            get_status_accepting(app, Some("application/x-ndjson")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "application/x-ndjson");
+        let sessions = body
+            .lines()
+            .map(|line| serde_json::from_str::<Session>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sessions
+                .iter()
+                .map(|session| session.session_id)
+                .collect::<Vec<_>>(),
+            session_ids
+        );
+
+        // The powers follow the unit
+        let (app, _) = app_with_two_sessions();
+        let (status, _, body) =
+            get_status_at_accepting(app, "/station/status?unit=w", Some("application/x-ndjson"))
+                .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.lines().all(|line| {
+            serde_json::from_str::<Session>(line)
+                .unwrap()
+                .allocated_power
+                == 80_000
+        }));
+    }
+
+    #[test]
+    fn test_negotiate_status_format() {
+        let negotiate = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            negotiate_status_format(&headers)
+        };
+        assert_eq!(
+            negotiate_status_format(&HeaderMap::new()),
+            StatusFormat::Json
+        );
+        assert_eq!(negotiate("text/csv, application/json"), StatusFormat::Csv);
+        assert_eq!(
+            negotiate("application/json;q=0.5, application/x-ndjson"),
+            StatusFormat::Ndjson
+        );
+        assert_eq!(negotiate("TEXT/CSV; charset=utf-8"), StatusFormat::Csv);
+        assert_eq!(negotiate("text/csv;q=0, text/html"), StatusFormat::Json);
+
+        // On a quality tie, the most specific media range wins over the order
+        assert_eq!(negotiate("*/*, text/csv"), StatusFormat::Csv);
+        assert_eq!(
+            negotiate("text/*, application/x-ndjson"),
+            StatusFormat::Ndjson
+        );
+        assert_eq!(negotiate("*/*;q=0.9, text/csv;q=0.5"), StatusFormat::Json);
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("CP001"), "CP001");
+        assert_eq!(csv_field("Bay 1, north"), "\"Bay 1, north\"");
+        assert_eq!(csv_field("the \"fast\" one"), "\"the \"\"fast\"\" one\"");
+    }
+
+    #[tokio::test]
+    async fn test_station_status_in_watts() {
         use sems_core::ConnectorId;
 
         let config = test_station_config();
         let mut state = StationState::new(config);
-
-        // Start a session before updating config
         let connector_id = ConnectorId {
             charger_id: "CP001".to_string(),
             idx: 1,
         };
-        state.start_session(connector_id, 150).unwrap();
+        let session_id = state.start_session(connector_id, 150).unwrap().session_id;
 
         let app = create_app(state);
 
-        // Verify we have one session initially
         let response = app
             .clone()
             .oneshot(
                 Request::builder()
-                    .uri("/station/status")
+                    .uri("/station/status?unit=w")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
 
+        assert_eq!(response.status(), StatusCode::OK);
+
         let body = axum::body::to_bytes(response.into_body(), usize::MAX)
             .await
             .unwrap();
         let status_response: StationStatus = serde_json::from_slice(&body).unwrap();
-        assert_eq!(status_response.sessions.len(), 1);
 
-        // Update configuration
-        let new_config = StationConfig {
-            station_id: "UPDATED_STATION".into(),
-            grid_capacity: 500,
-            chargers: vec![ChargerConfig {
-                id: "CP001".into(),
-                max_power: 300,
-                connectors: 2,
-            }],
-            battery: None,
-        };
+        // Every power is scaled by 1000
+        let session = &status_response.sessions[0];
+        assert_eq!(session.session_id, session_id);
+        assert_eq!(session.allocated_power, 150_000);
+        assert_eq!(session.vehicle_max_power, 150_000);
+        assert_eq!(session.requested_max_power, 150_000);
+        assert_eq!(status_response.grid_capacity, 400_000);
+        assert_eq!(status_response.allocated_power, 150_000);
+        assert_eq!(status_response.remaining_capacity, 250_000);
+        assert_eq!(status_response.chargers[0].configured_max_power, 200_000);
+        assert_eq!(status_response.chargers[0].max_power, 200_000);
+        assert_eq!(status_response.chargers[0].allocated_power, 150_000);
+
+        // The station powers cannot be converted to currents
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/status?unit=amps")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_station_status_battery() {
+        let config = StationConfig {
+            batteries: vec![Bess::new(40, 100).with_discharge_floor(200, 20)],
+            ..test_station_config()
+        };
+        let app = create_app(StationState::new(config));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/status")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let status_response: StationStatus = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status_response.battery, Some(BatteryFlow::Idle));
+        assert_eq!(
+            status_response.batteries,
+            vec![BatteryStatus {
+                soc_percent: Some(20),
+                discharge_floor_active: true,
+                control: BatteryControl::default(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_config_in_mw() {
+        let config: StationConfig = serde_json::from_value(serde_json::json!({
+            "stationId": "TEST_STATION",
+            "powerUnit": "MW",
+            "gridCapacity": 12,
+            "chargers": [
+                { "id": "CP001", "maxPower": 1, "connectors": 2, "groupId": "FEEDER" },
+            ],
+            "groups": [{ "id": "FEEDER", "maxPower": 2 }],
+        }))
+        .unwrap();
+
+        // The powers are in kW once loaded
+        assert_eq!(config.grid_capacity, 12_000);
+        assert_eq!(config.chargers[0].max_power, 1000);
+        assert_eq!(config.groups[0].max_power, 2000);
+        assert_eq!(config.power_unit, ConfigPowerUnit::Kw);
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["gridCapacity"], 12_000);
+        assert_eq!(json["powerUnit"], "kW");
+
+        // The kW stay the default
+        let config: StationConfig = serde_json::from_value(serde_json::json!({
+            "stationId": "TEST_STATION",
+            "gridCapacity": 400,
+            "chargers": [],
+        }))
+        .unwrap();
+        assert_eq!(config.grid_capacity, 400);
+
+        let error = serde_json::from_value::<StationConfig>(serde_json::json!({
+            "stationId": "TEST_STATION",
+            "powerUnit": "MW",
+            "gridCapacity": 5_000_000,
+            "chargers": [],
+        }))
+        .unwrap_err();
+        assert!(error.to_string().contains("exceeds the maximum power"));
+    }
+
+    #[test]
+    fn test_config_single_battery_migration() {
+        // Configurations with the former single battery still parse
+        let config: StationConfig = serde_json::from_value(serde_json::json!({
+            "stationId": "TEST_STATION",
+            "gridCapacity": 400,
+            "chargers": [],
+            "battery": { "initialCapacity": 100, "power": 50 },
+        }))
+        .unwrap();
+        assert_eq!(config.batteries.len(), 1);
+        assert_eq!(config.batteries[0].power_rating(), 50);
+
+        let config: StationConfig = serde_json::from_value(serde_json::json!({
+            "stationId": "TEST_STATION",
+            "gridCapacity": 400,
+            "chargers": [],
+            "battery": null,
+        }))
+        .unwrap();
+        assert!(config.batteries.is_empty());
+
+        // They are then serialized with the list of batteries
+        let config: StationConfig = serde_json::from_value(serde_json::json!({
+            "stationId": "TEST_STATION",
+            "gridCapacity": 400,
+            "chargers": [],
+            "batteries": [
+                { "initialCapacity": 100, "power": 50 },
+                { "initialCapacity": 200, "power": 100 },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(config.battery_power_rating(), 150);
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["batteries"].as_array().unwrap().len(), 2);
+        assert!(json.get("battery").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_update_config_endpoint() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        // New configuration with different values
+        let new_config = StationConfig {
+            station_id: "NEW_STATION".into(),
+            grid_capacity: 600,
+            chargers: vec![
+                ChargerConfig {
+                    id: "CP001".into(),
+                    max_power: 250,
+                    connectors: 2,
+                    ..Default::default()
+                },
+                ChargerConfig {
+                    id: "CP002".into(),
+                    max_power: 300,
+                    connectors: 1,
+                    ..Default::default()
+                },
+            ],
+            batteries: vec![],
+            ..Default::default()
+        };
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&new_config).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let config_response: StationConfig = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(config_response.station_id, "NEW_STATION");
+        assert_eq!(config_response.grid_capacity, 600);
+        assert_eq!(config_response.chargers.len(), 2);
+        assert_eq!(config_response.chargers[0].max_power, 250);
+        assert_eq!(config_response.chargers[1].id, "CP002");
+    }
+
+    #[tokio::test]
+    async fn test_update_config_warnings() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let mut new_config = test_station_config();
+        // Its connectors cannot take its max power, which is likely a mistake
+        new_config.chargers[0].connector_max_power = Some(50);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&new_config).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let update_response: ConfigUpdateResponse = serde_json::from_slice(&body).unwrap();
+
+        // The configuration is applied with its warnings
+        assert_eq!(
+            update_response.config.chargers[0].connector_max_power,
+            Some(50)
+        );
+        assert_eq!(update_response.warnings.len(), 1);
+        assert!(matches!(
+            update_response.warnings[0].problem,
+            ConfigProblem::ConnectorsBelowChargerMaxPower { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_config_invalid_connector_base() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        // Connectors are numbered from 0 or 1 only
+        let new_config = serde_json::json!({
+            "stationId": "NEW_STATION",
+            "gridCapacity": 600,
+            "chargers": [
+                { "id": "CP001", "maxPower": 250, "connectors": 2, "connectorBase": 2 },
+            ],
+            "battery": null,
+        });
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(new_config.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert!(
+            error_response
+                .error
+                .contains("connector base must be 0 or 1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_config_reports_all_problems() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let new_config = serde_json::json!({
+            "stationId": "NEW_STATION",
+            "gridCapacity": 300,
+            "chargers": [
+                { "id": "CP001", "maxPower": 250, "connectors": 0 },
+                { "id": "CP001", "maxPower": 400, "connectors": 2 },
+            ],
+            "battery": null,
+        });
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(new_config.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ConfigErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Malformed);
+        let problems = error_response
+            .problems
+            .into_iter()
+            .map(|problem| problem.problem)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                ConfigProblem::NoConnectors {
+                    charger_id: "CP001".into(),
+                },
+                ConfigProblem::DuplicateChargerId {
+                    charger_id: "CP001".into(),
+                },
+                ConfigProblem::ChargerExceedsStationCapacity {
+                    charger_id: "CP001".into(),
+                    max_power: 400,
+                    station_capacity: 300,
+                },
+            ]
+        );
+
+        // The current configuration is kept
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let config_response: StationConfig = serde_json::from_slice(&body).unwrap();
+        assert_eq!(config_response.station_id, "TEST_STATION");
+    }
+
+    #[tokio::test]
+    async fn test_preview_config_shrink() {
+        let mut config = test_station_config();
+        config.queue_when_full = true;
+        let mut state = StationState::new(config);
+        let connector_id = |idx| sems_core::ConnectorId {
+            charger_id: "CP001".into(),
+            idx,
+        };
+        state.set_power_cap(120);
+        let session = state.start_session(connector_id(1), 150).unwrap();
+        let Ok(SessionAdmission::Queued { queued_session, .. }) =
+            state.admit_session(connector_id(2), 150, Default::default())
+        else {
+            panic!("Expected a queued session");
+        };
+        let app = create_app(state);
+        let preview = |config: StationConfig| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .uri("/station/config/preview")
+                            .method("POST")
+                            .header("content-type", "application/json")
+                            .body(Body::from(serde_json::to_string(&config).unwrap()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<ConfigPreviewResponse>(&body).unwrap()
+            }
+        };
+
+        // Shrinking the station would drop the session and start the queued one, under the power
+        // cap that is kept
+        let mut config = test_station_config();
+        config.grid_capacity = 150;
+        config.chargers[0].max_power = 100;
+        let response = preview(config.clone()).await;
+        assert!(response.valid);
+        assert!(response.problems.is_empty());
+        assert_eq!(
+            response
+                .diff
+                .removed
+                .iter()
+                .map(|session| session.session_id)
+                .collect::<Vec<_>>(),
+            vec![session.session_id]
+        );
+        assert_eq!(
+            response
+                .diff
+                .added
+                .iter()
+                .map(|session| (session.session_id, session.allocated_power))
+                .collect::<Vec<_>>(),
+            vec![(queued_session.session_id, 100)]
+        );
+        let capacity = response.capacity.unwrap();
+        assert_eq!(capacity.grid_capacity, 150);
+        assert_eq!(capacity.power_cap, Some(120));
+        assert_eq!(capacity.effective_capacity, 120);
+        assert_eq!(capacity.allocated_power, 100);
+
+        // An inconsistent shrink is reported with its problems
+        config.chargers[0].max_power = 200;
+        let response = preview(config).await;
+        assert!(!response.valid);
+        assert_eq!(
+            response.problems[0].problem,
+            ConfigProblem::ChargerExceedsStationCapacity {
+                charger_id: "CP001".into(),
+                max_power: 200,
+                station_capacity: 150,
+            }
+        );
+        assert!(response.diff.is_empty());
+        assert!(response.capacity.is_none());
+
+        // The configuration, the session and the queue are kept
+        let summary = get_capacity(&app).await;
+        assert_eq!(summary.grid_capacity, 400);
+        assert_eq!(summary.allocated_power, 120);
+    }
+
+    #[tokio::test]
+    async fn test_update_config_drops_sessions() {
+        use sems_core::ConnectorId;
+
+        let config = test_station_config();
+        let mut state = StationState::new(config);
+
+        // Start a session before updating config
+        let connector_id = ConnectorId {
+            charger_id: "CP001".to_string(),
+            idx: 1,
+        };
+        state.start_session(connector_id, 150).unwrap();
+
+        let app = create_app(state);
+
+        // Verify we have one session initially
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/status")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let status_response: StationStatus = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status_response.sessions.len(), 1);
+
+        // Update configuration
+        let new_config = StationConfig {
+            station_id: "UPDATED_STATION".into(),
+            grid_capacity: 500,
+            chargers: vec![ChargerConfig {
+                id: "CP001".into(),
+                max_power: 300,
+                connectors: 2,
+                ..Default::default()
+            }],
+            batteries: vec![],
+            ..Default::default()
+        };
 
         let response = app
             .clone()
@@ -314,4 +1911,595 @@ mod tests {
         let status_response: StationStatus = serde_json::from_slice(&body).unwrap();
         assert_eq!(status_response.sessions.len(), 0);
     }
+
+    async fn get_capacity(app: &Router) -> CapacitySummary {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/capacity")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_demand_response_reverts() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let request = DemandResponseRequest {
+            fraction: 0.5,
+            duration_secs: 900,
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/demand-response")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let summary: CapacitySummary = serde_json::from_slice(&body).unwrap();
+        assert_eq!(summary.effective_capacity, 200);
+        assert!(summary.demand_response.is_some());
+
+        // The background task restores the grid capacity once the duration elapsed
+        tokio::time::sleep(Duration::from_secs(901)).await;
+
+        let summary = get_capacity(&app).await;
+        assert_eq!(summary.effective_capacity, 400);
+        assert!(summary.demand_response.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_demand_response_too_long() {
+        let app = create_app(StationState::new(test_station_config()));
+
+        let request = DemandResponseRequest {
+            fraction: 0.5,
+            duration_secs: u64::MAX,
+        };
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/demand-response")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // The station is still usable, and its capacity unchanged
+        let summary = get_capacity(&app).await;
+        assert_eq!(summary.effective_capacity, 400);
+        assert!(summary.demand_response.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_safety_margin() {
+        let config = StationConfig {
+            grid_capacity: 200,
+            ..test_station_config()
+        };
+        let mut state = StationState::new(config);
+        let session = state
+            .start_session(
+                sems_core::ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .unwrap();
+        assert_eq!(session.allocated_power, 200);
+        let app = create_app(state);
+        let set_safety_margin = |percent| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/station/margin")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&SafetyMarginRequest { percent }).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+        };
+
+        // The session is reallocated within the margin
+        let response = set_safety_margin(25).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let summary: CapacitySummary = serde_json::from_slice(&body).unwrap();
+        assert_eq!(summary.safety_margin_percent, 25);
+        assert_eq!(summary.effective_capacity, 150);
+        assert_eq!(summary.allocated_power, 150);
+
+        // A margin above 50% is refused, and the current one kept
+        let response = set_safety_margin(60).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let summary = get_capacity(&app).await;
+        assert_eq!(summary.safety_margin_percent, 25);
+        assert_eq!(summary.allocated_power, 150);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_power_cap_with_demand_response() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+        let set_power_cap = |max_power| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/station/power-cap")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&PowerCapRequest { max_power }).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+        };
+
+        let response = set_power_cap(300).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let summary: CapacitySummary = serde_json::from_slice(&body).unwrap();
+        assert_eq!(summary.power_cap, Some(300));
+        assert_eq!(summary.effective_capacity, 300);
+
+        // The demand response curtails the grid capacity to 200kW, below the cap
+        let request = DemandResponseRequest {
+            fraction: 0.5,
+            duration_secs: 900,
+        };
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/demand-response")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(get_capacity(&app).await.effective_capacity, 200);
+
+        // The cap stays once the demand response ends
+        tokio::time::sleep(Duration::from_secs(901)).await;
+        assert_eq!(get_capacity(&app).await.effective_capacity, 300);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/power-cap")
+                    .method("DELETE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let summary = get_capacity(&app).await;
+        assert_eq!(summary.power_cap, None);
+        assert_eq!(summary.effective_capacity, 400);
+    }
+
+    #[tokio::test]
+    async fn test_demand_response_invalid_fraction() {
+        let config = test_station_config();
+        let state = StationState::new(config);
+        let app = create_app(state);
+
+        let request = DemandResponseRequest {
+            fraction: -0.2,
+            duration_secs: 900,
+        };
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/demand-response")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        // The fraction is a valid number, but out of range
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error_response: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error_response.kind, ErrorKind::Validation);
+
+        let summary = get_capacity(&app).await;
+        assert_eq!(summary.effective_capacity, 400);
+    }
+
+    #[tokio::test]
+    async fn test_control_battery() {
+        let config = StationConfig {
+            batteries: vec![Bess::new(100, 50).with_discharge_floor(200, 20)],
+            ..test_station_config()
+        };
+        let app = create_app(StationState::new(config));
+        let battery_request = |mode, power| {
+            Request::builder()
+                .uri("/station/battery")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&BatteryControl { mode, power }).unwrap(),
+                ))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(battery_request(BatteryMode::Charge, 30))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let battery_response: BatteryResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(battery_response.battery, BatteryFlow::Charging(30));
+        assert_eq!(battery_response.batteries[0].soc_percent, Some(50));
+        assert_eq!(
+            battery_response.batteries[0].control.mode,
+            BatteryMode::Charge
+        );
+
+        // The battery charge reduces the capacity of the sessions
+        let summary = get_capacity(&app).await;
+        assert_eq!(summary.effective_capacity, 370);
+
+        // The power cannot exceed the battery rating
+        let response = app
+            .clone()
+            .oneshot(battery_request(BatteryMode::Discharge, 80))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // A station without battery cannot be controlled
+        let app = create_app(StationState::new(test_station_config()));
+        let response = app
+            .oneshot(battery_request(BatteryMode::Idle, 0))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_what_if_capacity() {
+        let mut state = StationState::new(test_station_config());
+        for idx in 1..=2 {
+            state
+                .start_session(
+                    sems_core::ConnectorId {
+                        charger_id: "CP001".into(),
+                        idx,
+                    },
+                    150,
+                )
+                .unwrap();
+        }
+        let app = create_app(state);
+        let what_if = |grid_capacity| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .uri("/station/what-if")
+                            .method("POST")
+                            .header("content-type", "application/json")
+                            .body(Body::from(
+                                serde_json::to_string(&WhatIfRequest { grid_capacity }).unwrap(),
+                            ))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<WhatIfResponse>(&body).unwrap()
+            }
+        };
+
+        // The charger caps the sessions with the current capacity
+        let current = what_if(400).await;
+        assert_eq!(current.allocated_power, 200);
+
+        let smaller = what_if(120).await;
+        assert_eq!(smaller.grid_capacity, 120);
+        assert_eq!(smaller.allocated_power, 120);
+        assert_eq!(smaller.sessions.len(), 2);
+        assert!(
+            smaller
+                .sessions
+                .iter()
+                .all(|session| session.allocated_power == 60)
+        );
+
+        // The allocations of the station did not change
+        let summary = get_capacity(&app).await;
+        assert_eq!(summary.allocated_power, 200);
+    }
+
+    #[tokio::test]
+    async fn test_reallocate() {
+        let mut state = StationState::new(test_station_config());
+        let connector = |idx| sems_core::ConnectorId {
+            charger_id: "CP001".into(),
+            idx,
+        };
+        // The second session only gets the power of the charger left by the first one
+        let first = state.start_session(connector(1), 150).unwrap();
+        let second = state.start_session(connector(2), 150).unwrap();
+        assert_eq!(first.allocated_power, 150);
+        assert_eq!(second.allocated_power, 50);
+        let app = create_app(state);
+        let reallocate = || {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .uri("/station/reallocate")
+                            .method("POST")
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<AllocationDiff>(&body).unwrap()
+            }
+        };
+
+        // The full reallocation shares the charger
+        let diff = reallocate().await;
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        let mut changes = diff
+            .changed
+            .iter()
+            .map(|change| {
+                (
+                    change.session_id,
+                    change.previous_power,
+                    change.allocated_power,
+                )
+            })
+            .collect::<Vec<_>>();
+        changes.sort_by_key(|(session_id, _, _)| *session_id);
+        let mut expected = vec![(first.session_id, 150, 100), (second.session_id, 50, 100)];
+        expected.sort_by_key(|(session_id, _, _)| *session_id);
+        assert_eq!(changes, expected);
+
+        // Nothing changes the second time
+        assert!(reallocate().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let mut state = StationState::new(test_station_config());
+        state
+            .start_session(
+                sems_core::ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+            )
+            .unwrap();
+        let app = create_app(state);
+        let post = |uri| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(post("/station/drain")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let drain_response: DrainResponse = serde_json::from_slice(&body).unwrap();
+        assert!(drain_response.draining);
+        assert_eq!(drain_response.active_sessions, 1);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/status")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let status: StationStatus = serde_json::from_slice(&body).unwrap();
+        assert!(status.draining);
+
+        let response = app.oneshot(post("/station/undrain")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let drain_response: DrainResponse = serde_json::from_slice(&body).unwrap();
+        assert!(!drain_response.draining);
+    }
+
+    #[tokio::test]
+    async fn test_emergency_stop() {
+        let mut state = StationState::new(test_station_config());
+        state
+            .start_session(
+                sems_core::ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+            )
+            .unwrap();
+        let app = create_app(state);
+        let post = |uri| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let emergency_stop_response = |response: Response| async {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<EmergencyStopResponse>(&body).unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(post("/station/emergency-stop"))
+            .await
+            .unwrap();
+        let stop_response = emergency_stop_response(response).await;
+        assert!(stop_response.emergency_stop);
+        assert_eq!(stop_response.active_sessions, 1);
+        assert_eq!(stop_response.allocated_power, 0);
+
+        // The stop is latched, the station status reports it until it is cleared
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/status")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let status: StationStatus = serde_json::from_slice(&body).unwrap();
+        assert!(status.emergency_stop);
+        assert_eq!(status.allocated_power, 0);
+        assert_eq!(status.sessions[0].state, sems_core::SessionState::Pending);
+
+        let response = app
+            .clone()
+            .oneshot(post("/station/clear-emergency"))
+            .await
+            .unwrap();
+        let clear_response = emergency_stop_response(response).await;
+        assert!(!clear_response.emergency_stop);
+        assert_eq!(clear_response.active_sessions, 1);
+        assert_eq!(clear_response.allocated_power, 100);
+    }
+
+    #[tokio::test]
+    async fn test_update_config_during_emergency_stop() {
+        let app = create_app(StationState::new(test_station_config()));
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/emergency-stop")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A new configuration does not release the stop, which stays latched until it is cleared
+        let mut config = test_station_config();
+        config.grid_capacity = 300;
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/station/config")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&config).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/station/status")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let status: StationStatus = serde_json::from_slice(&body).unwrap();
+        assert!(status.emergency_stop);
+        assert_eq!(status.grid_capacity, 300);
+    }
 }
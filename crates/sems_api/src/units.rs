@@ -0,0 +1,85 @@
+use sems_core::{BatteryFlow, ChargerStatus, Session};
+use serde::{Serialize, Serializer};
+
+use crate::session::PowerUnit;
+use crate::station::StationStatus;
+
+/// Watts in a kilowatt
+const WATTS_PER_KW: u32 = 1000;
+
+/// Responses with power fields, which are stored in kW
+pub(crate) trait PowerFields {
+    /// Replace every power field by `f` applied to it
+    fn map_powers(&mut self, f: &impl Fn(u32) -> u32);
+}
+
+impl PowerFields for Session {
+    fn map_powers(&mut self, f: &impl Fn(u32) -> u32) {
+        self.allocated_power = f(self.allocated_power);
+        self.vehicle_max_power = f(self.vehicle_max_power);
+        self.requested_max_power = f(self.requested_max_power);
+        self.consumed_power = f(self.consumed_power);
+        self.pinned_power = self.pinned_power.map(f);
+    }
+}
+
+impl PowerFields for BatteryFlow {
+    fn map_powers(&mut self, f: &impl Fn(u32) -> u32) {
+        match self {
+            BatteryFlow::Idle => {}
+            BatteryFlow::Charging(power) | BatteryFlow::Discharging(power) => *power = f(*power),
+        }
+    }
+}
+
+impl PowerFields for ChargerStatus {
+    fn map_powers(&mut self, f: &impl Fn(u32) -> u32) {
+        self.configured_max_power = f(self.configured_max_power);
+        self.max_power = f(self.max_power);
+        self.derated_max_power = self.derated_max_power.map(f);
+        self.allocated_power = f(self.allocated_power);
+    }
+}
+
+impl PowerFields for StationStatus {
+    fn map_powers(&mut self, f: &impl Fn(u32) -> u32) {
+        self.sessions
+            .iter_mut()
+            .for_each(|session| session.map_powers(f));
+        if let Some(battery) = &mut self.battery {
+            battery.map_powers(f);
+        }
+        self.grid_capacity = f(self.grid_capacity);
+        self.allocated_power = f(self.allocated_power);
+        self.remaining_capacity = f(self.remaining_capacity);
+        self.chargers
+            .iter_mut()
+            .for_each(|charger| charger.map_powers(f));
+    }
+}
+
+/// Convert a power in kW to W
+pub(crate) fn kw_to_w(power: u32) -> u32 {
+    power.saturating_mul(WATTS_PER_KW)
+}
+
+/// Response serialized with its power fields in the given unit, instead of kW
+///
+/// Only the units that do not depend on the connector, kW and W, can be used.
+pub(crate) struct InUnit<T> {
+    pub value: T,
+    pub unit: PowerUnit,
+}
+
+impl<T: PowerFields + Clone + Serialize> Serialize for InUnit<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.unit {
+            PowerUnit::W => {
+                let mut value = self.value.clone();
+                value.map_powers(&kw_to_w);
+                value.serialize(serializer)
+            }
+            PowerUnit::Kw | PowerUnit::Amps => self.value.serialize(serializer),
+        }
+    }
+}
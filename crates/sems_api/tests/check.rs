@@ -0,0 +1,166 @@
+//! Black-box tests of the configuration check mode of the server binary.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Run the server binary in check mode on the configuration file
+fn check(config: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_sems_api"))
+        .args(["--check", "--config-retries", "0", "--config"])
+        .arg(config)
+        .output()
+        .expect("Could not run the server binary")
+}
+
+/// Write the configuration to a file of the temporary directory, and return its path
+fn write_config(name: &str, content: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("sems_check_{}_{}.json", name, std::process::id()));
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+#[test]
+fn test_check_valid_config() {
+    let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../examples/station_config.json");
+    let output = check(&config);
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("OK"));
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_check_invalid_config() {
+    let path = write_config(
+        "invalid",
+        r#"{
+            "stationId": "TEST_STATION",
+            "gridCapacity": 400,
+            "chargers": [
+                { "id": "CP001", "maxPower": 200, "connectors": 0 },
+                { "id": "CP001", "maxPower": 200, "connectors": 2 }
+            ]
+        }"#,
+    );
+    let output = check(&path);
+
+    // Every problem is reported on its own line
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines = stderr.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        vec![
+            format!(
+                "{}: noConnectors: Charger CP001 has no connectors",
+                path.display()
+            ),
+            format!(
+                "{}: duplicateChargerId: Charger CP001 is configured more than once",
+                path.display()
+            ),
+        ]
+    );
+
+    // A file that cannot be parsed is reported as well
+    let path = write_config("malformed", "{");
+    let output = check(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .starts_with(&format!("{}: parse: ", path.display()))
+    );
+}
+
+#[test]
+fn test_check_config_warnings() {
+    let path = write_config(
+        "warnings",
+        r#"{
+            "stationId": "TEST_STATION",
+            "gridCapacity": 400,
+            "chargers": [
+                { "id": "CP001", "maxPower": 200, "connectors": 2, "connectorMaxPower": 50 }
+            ]
+        }"#,
+    );
+    let output = check(&path);
+
+    // The warnings are reported, but the configuration is valid
+    std::fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("OK"));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .collect::<Vec<_>>(),
+        vec![format!(
+            "{}: warning: connectorsBelowChargerMaxPower: Charger CP001 connectors deliver at most \
+             100kW at 50kW each, below its 200kW max power",
+            path.display()
+        )]
+    );
+}
+
+#[test]
+fn test_check_split_config() {
+    // The chargers file is resolved relative to the configuration file
+    let dir = std::env::temp_dir().join(format!("sems_check_split_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("site")).unwrap();
+    let path = dir.join("site/station.json");
+    std::fs::write(
+        &path,
+        r#"{ "stationId": "TEST_STATION", "gridCapacity": 400, "chargers": "chargers.json" }"#,
+    )
+    .unwrap();
+    let chargers_path = dir.join("site/chargers.json");
+    std::fs::write(
+        &chargers_path,
+        r#"[
+            { "id": "CP001", "maxPower": 200, "connectors": 2 },
+            { "id": "CP002", "maxPower": 150, "connectors": 1 }
+        ]"#,
+    )
+    .unwrap();
+    let output = check(&path);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    // The problems of the chargers are reported like inline chargers
+    std::fs::write(
+        &chargers_path,
+        r#"[{ "id": "CP001", "maxPower": 200, "connectors": 0 }]"#,
+    )
+    .unwrap();
+    let output = check(&path);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr).trim_end(),
+        format!(
+            "{}: noConnectors: Charger CP001 has no connectors",
+            path.display()
+        )
+    );
+
+    // A chargers file that cannot be parsed is reported with its own path
+    std::fs::write(&chargers_path, r#"[{ "id": "CP001" }]"#).unwrap();
+    let output = check(&path);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .starts_with(&format!("{}: parse: ", chargers_path.display()))
+    );
+
+    // As is a missing one
+    std::fs::remove_file(&chargers_path).unwrap();
+    let output = check(&path);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .starts_with(&format!("{}: parse: ", chargers_path.display()))
+    );
+}
@@ -0,0 +1,209 @@
+//! Black-box tests against the real HTTP server, bound to an ephemeral port.
+
+use sems_api::{AppOptions, DEFAULT_REQUEST_TIMEOUT, create_app};
+use sems_core::{ChargerConfig, StationConfig, StationState};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn test_station_config() -> StationConfig {
+    StationConfig {
+        station_id: "TEST_STATION".into(),
+        grid_capacity: 400,
+        chargers: vec![ChargerConfig {
+            id: "CP001".into(),
+            max_power: 200,
+            connectors: 2,
+            ..Default::default()
+        }],
+        batteries: vec![],
+        ..Default::default()
+    }
+}
+
+/// Serve the application on an ephemeral port, and return its address
+async fn spawn_server() -> SocketAddr {
+    spawn_server_with_timeout(DEFAULT_REQUEST_TIMEOUT).await
+}
+
+/// Serve the application with the given request timeout, and return its address
+async fn spawn_server_with_timeout(request_timeout: Duration) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind the listener");
+    let addr = listener.local_addr().unwrap();
+    let app = create_app(
+        StationState::new(test_station_config()),
+        AppOptions {
+            request_timeout,
+            ..Default::default()
+        },
+    );
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
+
+#[tokio::test]
+async fn test_session_lifecycle() {
+    let addr = spawn_server().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("http://{}/sessions", addr))
+        .json(&json!({
+            "connectorId": { "chargerId": "CP001", "idx": 1 },
+            "vehicleMaxPower": 150,
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["session"]["allocatedPower"], 150);
+    let session_id = body["session"]["sessionId"].as_str().unwrap().to_string();
+
+    let response = client
+        .get(format!("http://{}/station/status", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["sessions"][0]["sessionId"], session_id);
+    assert_eq!(body["sessions"][0]["state"], "pending");
+
+    let response = client
+        .post(format!(
+            "http://{}/sessions/{}/power-update",
+            addr, session_id
+        ))
+        .json(&json!({ "consumedPower": 100 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["session"]["allocatedPower"], 100);
+    assert_eq!(body["session"]["state"], "charging");
+
+    let response = client
+        .post(format!("http://{}/sessions/{}/stop", addr, session_id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+    let response = client
+        .get(format!("http://{}/station/status", addr))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    assert!(body["sessions"].as_array().unwrap().is_empty());
+
+    // Stopping it again goes through the router and the error handling
+    let response = client
+        .post(format!("http://{}/sessions/{}/stop", addr, session_id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["kind"], "conflict");
+}
+
+#[tokio::test]
+async fn test_method_not_allowed() {
+    let addr = spawn_server().await;
+
+    let response = reqwest::Client::new()
+        .delete(format!("http://{}/health", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()["allow"], "GET, HEAD");
+}
+
+#[tokio::test]
+async fn test_short_id() {
+    let addr = spawn_server().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("http://{}/sessions", addr))
+        .json(&json!({
+            "connectorId": { "chargerId": "CP001", "idx": 1 },
+            "vehicleMaxPower": 150,
+        }))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let short_id = body["session"]["shortId"].as_str().unwrap().to_string();
+    assert_eq!(short_id.len(), 8);
+
+    let response = client
+        .post(format!(
+            "http://{}/sessions/{}/power-update",
+            addr,
+            short_id.to_uppercase()
+        ))
+        .json(&json!({ "consumedPower": 100 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["session"]["shortId"], short_id.as_str());
+
+    let response = client
+        .post(format!("http://{}/sessions/{}/stop", addr, short_id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+    let response = client
+        .post(format!("http://{}/sessions/unknown/stop", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["kind"], "notFound");
+}
+
+#[tokio::test]
+async fn test_request_timeout() {
+    let addr = spawn_server_with_timeout(Duration::from_millis(100)).await;
+
+    // A client announcing a body it never sends keeps the handler waiting
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            b"POST /sessions HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Content-Type: application/json\r\n\
+              Content-Length: 100\r\n\
+              \r\n\
+              {",
+        )
+        .await
+        .unwrap();
+
+    let mut response = Vec::new();
+    let mut buffer = [0; 1024];
+    while !String::from_utf8_lossy(&response).contains("\"kind\"") {
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            .await
+            .expect("The request was not answered")
+            .unwrap();
+        assert_ne!(read, 0, "The connection was closed without a response");
+        response.extend_from_slice(&buffer[..read]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 504"), "{}", response);
+    assert!(response.contains("\"kind\":\"timeout\""), "{}", response);
+}
@@ -0,0 +1,103 @@
+//! Black-box tests of the server binary, served over plain HTTP or HTTPS.
+
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Server binary running in the background, killed when dropped
+struct Server {
+    child: Child,
+    port: u16,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Start the server binary on a free port with the extra arguments, and wait until it listens
+fn spawn_server(args: &[&Path]) -> Server {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../examples/station_config.json");
+    let child = Command::new(env!("CARGO_BIN_EXE_sems_api"))
+        .arg("--config")
+        .arg(config)
+        .args(["--port", &port.to_string()])
+        .args(args)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Could not run the server binary");
+    let mut server = Server { child, port };
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if let Some(status) = server.child.try_wait().unwrap() {
+            panic!("The server exited with {}", status);
+        }
+        assert!(Instant::now() < deadline, "The server did not start");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    server
+}
+
+/// Write a self-signed certificate for localhost and its key, and return their paths
+fn write_certificate() -> (PathBuf, PathBuf) {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join(format!("sems_tls_cert_{}.pem", std::process::id()));
+    let key_path = dir.join(format!("sems_tls_key_{}.pem", std::process::id()));
+    std::fs::write(&cert_path, certificate.cert.pem()).unwrap();
+    std::fs::write(&key_path, certificate.key_pair.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+#[tokio::test]
+async fn test_plain_http_without_tls() {
+    let server = spawn_server(&[]);
+
+    let response = reqwest::get(format!("http://127.0.0.1:{}/health", server.port))
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(response.text().await.unwrap(), "OK");
+}
+
+#[tokio::test]
+async fn test_https_with_tls() {
+    let (cert_path, key_path) = write_certificate();
+    let server = spawn_server(&[
+        Path::new("--tls-cert"),
+        &cert_path,
+        Path::new("--tls-key"),
+        &key_path,
+    ]);
+    let certificate = reqwest::Certificate::from_pem(&std::fs::read(&cert_path).unwrap()).unwrap();
+    std::fs::remove_file(&cert_path).unwrap();
+    std::fs::remove_file(&key_path).unwrap();
+    let client = reqwest::Client::builder()
+        .add_root_certificate(certificate)
+        .build()
+        .unwrap();
+
+    let response = client
+        .get(format!("https://localhost:{}/health", server.port))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(response.text().await.unwrap(), "OK");
+
+    // Plain HTTP is not served on the TLS port
+    assert!(
+        reqwest::get(format!("http://127.0.0.1:{}/health", server.port))
+            .await
+            .is_err()
+    );
+}
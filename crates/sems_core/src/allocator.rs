@@ -1,155 +1,942 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
 
-use crate::{ChargerConfig, Session};
+use chrono::{DateTime, Utc};
 
+use crate::{AllocationMode, ChargerConfig, Session, SessionError, SharingMode};
+
+/// Allocate power to a new session, reallocating the current ones.
+///
+/// Return the new session, and the allocations of all the sessions in the reallocation, before
+/// the new session is capped by the hardcap capacity. Fails if the allocator could not allocate
+/// the new session, e.g. when its charger is not in the configuration.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn allocate_for_new_session(
     mut sessions: HashMap<uuid::Uuid, Session>,
     chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
     grid_capacity: u32,
+    phase_capacity: Option<u32>,
     hardcap_capacity: u32,
+    allocation_mode: AllocationMode,
     new_session: &Session,
-) -> Session {
+) -> Result<(Session, HashMap<uuid::Uuid, u32>), SessionError> {
     sessions.insert(new_session.session_id, new_session.clone());
 
-    let mut reallocated_sessions =
-        allocate_power_station(&sessions, chargers_config, grid_capacity);
-    let mut new_allocated_session = reallocated_sessions
-        .remove_entry(&new_session.session_id)
-        .expect("Could not find allocated session")
-        .1;
+    let mut reallocated_sessions = allocate_within_phases(
+        &sessions,
+        chargers_config,
+        groups,
+        grid_capacity,
+        phase_capacity,
+        allocation_mode,
+    );
+    let allocations = reallocated_sessions
+        .values()
+        .map(|session| (session.session_id, session.allocated_power))
+        .collect();
+    let mut new_allocated_session = reallocated_sessions.remove(&new_session.session_id).ok_or(
+        SessionError::AllocationFailed {
+            session_id: new_session.session_id,
+        },
+    )?;
 
     // The reallocation might lower the power for other sessions, but it will not be effective
     // immediately (not until their next call to power_update). As such we need to ensure that
     // we do not exceed the hardcap capacity.
     new_allocated_session.allocated_power =
         new_allocated_session.allocated_power.min(hardcap_capacity);
-    new_allocated_session
+    Ok((new_allocated_session, allocations))
 }
 
-fn allocate_power_station(
+/// Tolerance on the phase loads, which are not whole kW when the chargers draw from several
+/// phases.
+pub(crate) const PHASE_LOAD_TOLERANCE: f64 = 1e-6;
+
+/// Allocate the station capacity like `allocate`, keeping the load of every phase of the grid
+/// connection within `phase_capacity` if set.
+///
+/// While a phase is overloaded, the most overloaded one first, the sessions drawing from it are
+/// capped to share the phase capacity, the highest allocations being lowered first, and the
+/// station is allocated again so that the other sessions get the power they leave. A capped
+/// phase cannot be overloaded again, so this takes at most one allocation per phase.
+pub(crate) fn allocate_within_phases(
     current_sessions: &HashMap<uuid::Uuid, Session>,
     chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
     station_capacity: u32,
+    phase_capacity: Option<u32>,
+    allocation_mode: AllocationMode,
 ) -> HashMap<uuid::Uuid, Session> {
-    // Split the sessions based on their charger.
-    let mut chargers_sessions: HashMap<String, Vec<Session>> = chargers_config
-        .iter()
-        .filter_map(|(k, v)| {
-            let charger_sessions = current_sessions
-                .values()
-                .filter(|s| s.connector_id.charger_id == v.id)
-                .map(|s| {
-                    let mut out = s.clone();
-                    out.allocated_power = 0;
-                    out
-                })
-                .collect::<Vec<_>>();
-            if charger_sessions.is_empty() {
-                None
-            } else {
-                Some((k.clone(), charger_sessions))
+    let Some(phase_capacity) = phase_capacity else {
+        return allocate(
+            current_sessions,
+            chargers_config,
+            groups,
+            station_capacity,
+            allocation_mode,
+        );
+    };
+    let mut capped_sessions = current_sessions.clone();
+    loop {
+        let mut allocated_sessions = allocate(
+            &capped_sessions,
+            chargers_config,
+            groups,
+            station_capacity,
+            allocation_mode,
+        );
+        let loads = phase_loads(allocated_sessions.values(), chargers_config);
+        let overloaded_phase = (0..3)
+            .filter(|phase| loads[*phase] > phase_capacity as f64 + PHASE_LOAD_TOLERANCE)
+            .max_by(|a, b| loads[*a].total_cmp(&loads[*b]));
+        // Pinned sessions are capped like the others, their pins being bounded by the vehicle
+        // max power. The loop ends once no vehicle max power can be lowered any further
+        let capped = overloaded_phase.is_some_and(|phase| {
+            cap_phase(
+                &mut capped_sessions,
+                &allocated_sessions,
+                chargers_config,
+                phase,
+                phase_capacity,
+            )
+        });
+        if !capped {
+            // Restore the vehicle max powers capped for the phases
+            for session in allocated_sessions.values_mut() {
+                session.vehicle_max_power = current_sessions[&session.session_id].vehicle_max_power;
             }
+            return allocated_sessions;
+        }
+    }
+}
+
+/// Return the load in kW of every phase of the grid connection with these sessions.
+pub(crate) fn phase_loads<'a>(
+    sessions: impl Iterator<Item = &'a Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+) -> [f64; 3] {
+    let mut loads = [0.0; 3];
+    for session in sessions {
+        let Some(charger) = chargers_config.get(&session.connector_id.charger_id) else {
+            continue;
+        };
+        for (load, share) in loads.iter_mut().zip(charger.phase_shares()) {
+            *load += session.allocated_power as f64 * share;
+        }
+    }
+    loads
+}
+
+/// Cap the vehicle max power of the sessions drawing from the phase, so that their allocations
+/// fit in the phase capacity.
+///
+/// The allocations above a level are lowered to it, the level being the highest one keeping
+/// the load of the phase within its capacity. Return whether a vehicle max power was lowered.
+fn cap_phase(
+    sessions: &mut HashMap<uuid::Uuid, Session>,
+    allocated_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    phase: usize,
+    phase_capacity: u32,
+) -> bool {
+    let mut phase_sessions = allocated_sessions
+        .values()
+        .filter_map(|session| {
+            let share = chargers_config
+                .get(&session.connector_id.charger_id)?
+                .phase_shares()[phase];
+            (share > 0.0).then_some((session.session_id, session.allocated_power, share))
         })
-        .collect();
+        .collect::<Vec<_>>();
+    phase_sessions.sort_by_key(|(session_id, power, _)| (*power, *session_id));
 
-    loop {
-        // We compute the remaning power available for the station
-        let remaining_power = station_capacity.saturating_sub(
-            chargers_sessions
-                .values()
-                .map(|sessions| sessions.iter().map(|s| s.allocated_power).sum::<u32>())
-                .sum(),
-        );
-        // If we don't have any remaining power to share, stop the loop
-        if remaining_power == 0 {
+    let mut remaining_capacity = phase_capacity as f64;
+    let mut remaining_shares = phase_sessions
+        .iter()
+        .map(|(_, _, share)| share)
+        .sum::<f64>();
+    let mut level = u32::MAX;
+    for (_, power, share) in &phase_sessions {
+        if *power as f64 * remaining_shares <= remaining_capacity + PHASE_LOAD_TOLERANCE {
+            remaining_capacity -= *power as f64 * share;
+            remaining_shares -= share;
+        } else {
+            // Rounded down, so that the capped allocations stay within the capacity
+            level = (remaining_capacity.max(0.0) / remaining_shares + PHASE_LOAD_TOLERANCE) as u32;
             break;
         }
-        // The chargers and their associated sessions that can still take more power
-        let chargers_with_remaining_power: HashMap<String, Vec<Session>> = chargers_sessions
-            .iter()
-            // We only keep the chargers that are not at their max power
-            .filter(|(charger_id, sessions)| {
-                let vehicles_allocated = sessions.iter().map(|s| s.allocated_power).sum::<u32>();
-                let charger_capacity = chargers_config
-                    .get(*charger_id)
-                    .map(|c| c.max_power)
-                    .unwrap_or(0);
-                vehicles_allocated < charger_capacity
-            })
-            // Then we keep the chargers whose vehicles can take more power
-            .filter(|(_, sessions)| {
-                sessions
-                    .iter()
-                    .filter(|s| s.allocated_power < s.vehicle_max_power)
-                    .count()
-                    > 0
+    }
+    let mut capped = false;
+    for (session_id, power, _) in phase_sessions {
+        let session = sessions
+            .get_mut(&session_id)
+            .expect("The allocated sessions are the sessions to allocate");
+        let cap = session.vehicle_max_power.min(power.min(level));
+        capped |= cap < session.vehicle_max_power;
+        session.vehicle_max_power = cap;
+    }
+    capped
+}
+
+/// Allocate the station capacity to the sessions according to the allocation mode.
+///
+/// The sessions of the chargers in a group share the max power of the group, given by group id.
+/// The chargers without a group, or with a group missing from `groups`, are only limited by the
+/// station capacity. The pinned sessions are allocated first, and the other sessions share what
+/// they leave. A session warming up gets at least the warm-up power of its charger.
+pub(crate) fn allocate(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+    station_capacity: u32,
+    allocation_mode: AllocationMode,
+) -> HashMap<uuid::Uuid, Session> {
+    let (mut sessions, chargers_config, mut waiting_sessions) =
+        apply_sharing_modes(current_sessions, chargers_config);
+    let (pinned_sessions, mut chargers_config, mut groups, mut station_capacity) =
+        apply_pins(&mut sessions, &chargers_config, groups, station_capacity);
+    let now = Utc::now();
+    let mut warming_sessions = Vec::new();
+    let mut allocated_sessions = loop {
+        let allocated_sessions = match allocation_mode {
+            AllocationMode::Fair => {
+                allocate_power_station(&sessions, &chargers_config, &groups, station_capacity)
+            }
+            AllocationMode::Deadline => allocate_power_station_by_deadline(
+                &sessions,
+                &chargers_config,
+                &groups,
+                station_capacity,
+                now,
+            ),
+        };
+
+        // A session warming up with less than the warm-up power of its charger is given it, the
+        // first session id first, and the power it leaves is reallocated to the others
+        let warming_session = allocated_sessions
+            .values()
+            .filter(|session| {
+                chargers_config
+                    .get(&session.connector_id.charger_id)
+                    .and_then(|charger| charger.warmup_power_at(session, now))
+                    .is_some_and(|warmup_power| {
+                        session.allocated_power < warmup_power.min(session.vehicle_max_power)
+                    })
             })
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
-        // If no charger can take more power, we stop the loop
-        if chargers_with_remaining_power.is_empty() {
-            break;
+            .min_by_key(|session| session.session_id);
+        if let Some(warming_session) = warming_session {
+            let mut warming_session = sessions
+                .remove(&warming_session.session_id)
+                .expect("The allocated sessions are the sessions to allocate");
+            let warmup_power = chargers_config[&warming_session.connector_id.charger_id]
+                .warmup_power_at(&warming_session, now)
+                .unwrap_or(0);
+            reserve_power(
+                &mut warming_session,
+                warmup_power,
+                &mut chargers_config,
+                &mut groups,
+                &mut station_capacity,
+            );
+            warming_sessions.push(warming_session);
+            continue;
         }
-        // For each charger, the number of sessions that can still take
-        // more power
-        let sessions_with_remaining_power: HashMap<String, usize> = chargers_with_remaining_power
-            .iter()
-            .map(|(charger_id, sessions)| {
+
+        // Rather than sharing the power into allocations too low to be used, the session with
+        // the lowest of them waits with no power, the last started session breaking ties, and
+        // the power is reallocated to the others
+        let unusable_session = allocated_sessions
+            .values()
+            .filter(|session| is_below_usable_min_power(session, &chargers_config))
+            .min_by_key(|session| {
                 (
-                    charger_id.clone(),
-                    sessions
-                        .iter()
-                        .filter(|s| s.allocated_power < s.vehicle_max_power)
-                        .count(),
+                    session.allocated_power,
+                    Reverse((session.started_at, session.session_id)),
                 )
-            })
-            .filter(|(_k, v)| *v > 0)
-            .collect();
-        // We split the remaining power between the vehicles
-        let fair_share =
-            remaining_power / sessions_with_remaining_power.values().sum::<usize>() as u32;
+            });
+        let Some(unusable_session) = unusable_session else {
+            break allocated_sessions;
+        };
+        let mut unusable_session = sessions
+            .remove(&unusable_session.session_id)
+            .expect("The allocated sessions are the sessions to allocate");
+        unusable_session.allocated_power = 0;
+        waiting_sessions.push(unusable_session);
+    };
 
-        if fair_share == 0 {
-            // If the fair share is 0, we cannot allocate any more power
-            // That usually means that the remaining power is less than the number of sessions
-            break;
+    allocated_sessions.extend(
+        waiting_sessions
+            .into_iter()
+            .chain(pinned_sessions)
+            .chain(warming_sessions)
+            .map(|session| (session.session_id, session)),
+    );
+    // Restore the vehicle max powers capped by the connectors
+    for session in allocated_sessions.values_mut() {
+        session.vehicle_max_power = current_sessions[&session.session_id].vehicle_max_power;
+    }
+    allocated_sessions
+}
+
+/// Return whether the session is allocated some power, but less than the usable min power of its
+/// charger, or than its vehicle max power if lower.
+fn is_below_usable_min_power(
+    session: &Session,
+    chargers_config: &HashMap<String, ChargerConfig>,
+) -> bool {
+    let Some(usable_min_power) = chargers_config
+        .get(&session.connector_id.charger_id)
+        .and_then(|charger| charger.usable_min_power)
+    else {
+        return false;
+    };
+    session.allocated_power > 0
+        && session.allocated_power < usable_min_power.min(session.vehicle_max_power)
+}
+
+/// Allocate their pinned power to the pinned sessions, taking them out of the sessions to
+/// allocate.
+///
+/// A pin is still bounded by the vehicle max power and by what the station, the charger and the
+/// group have left, the pinned sessions being served by id. Return the pinned sessions, and the
+/// chargers, the groups and the station capacity left to the other sessions.
+fn apply_pins(
+    sessions: &mut HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+    station_capacity: u32,
+) -> (
+    Vec<Session>,
+    HashMap<String, ChargerConfig>,
+    HashMap<String, u32>,
+    u32,
+) {
+    let mut pinned_ids = sessions
+        .values()
+        .filter(|session| session.pinned_power.is_some())
+        .map(|session| session.session_id)
+        .collect::<Vec<_>>();
+    pinned_ids.sort();
+
+    let mut chargers_config = chargers_config.clone();
+    let mut groups = groups.clone();
+    let mut station_capacity = station_capacity;
+    let mut pinned_sessions = Vec::new();
+    for session_id in pinned_ids {
+        let mut session = sessions
+            .remove(&session_id)
+            .expect("The pinned ids are the ids of the sessions");
+        let power = session.pinned_power.unwrap_or(0);
+        if reserve_power(
+            &mut session,
+            power,
+            &mut chargers_config,
+            &mut groups,
+            &mut station_capacity,
+        ) {
+            pinned_sessions.push(session);
+        }
+    }
+    (pinned_sessions, chargers_config, groups, station_capacity)
+}
+
+/// Allocate the power to the session, bounded by its vehicle max power and by what its charger,
+/// its group and the station have left, and take it out of them.
+///
+/// Return false if the charger of the session is unknown, in which case it is not allocated.
+fn reserve_power(
+    session: &mut Session,
+    power: u32,
+    chargers_config: &mut HashMap<String, ChargerConfig>,
+    groups: &mut HashMap<String, u32>,
+    station_capacity: &mut u32,
+) -> bool {
+    let Some(charger) = chargers_config.get_mut(&session.connector_id.charger_id) else {
+        // Like the other sessions, a session of an unknown charger is not allocated
+        return false;
+    };
+    let mut group_capacity = charger
+        .group_id
+        .as_ref()
+        .and_then(|group_id| groups.get_mut(group_id));
+    session.allocated_power = power
+        .min(session.vehicle_max_power)
+        .min(charger.max_power)
+        .min(*station_capacity)
+        .min(group_capacity.as_deref().copied().unwrap_or(u32::MAX));
+    // The power is bounded by all the remaining powers, so this cannot underflow
+    charger.max_power -= session.allocated_power;
+    *station_capacity -= session.allocated_power;
+    if let Some(group_capacity) = &mut group_capacity {
+        **group_capacity -= session.allocated_power;
+    }
+    true
+}
+
+/// Adapt the sessions and the chargers to the sharing mode and the connector max powers of the
+/// chargers, so that the allocation modes only have to cap the sum of the sessions of a charger by
+/// its max power.
+///
+/// Return the sessions to allocate, the chargers to allocate them against, and the sessions
+/// waiting for their turn on a sequential charger, which get no power.
+fn apply_sharing_modes(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+) -> (
+    HashMap<uuid::Uuid, Session>,
+    HashMap<String, ChargerConfig>,
+    Vec<Session>,
+) {
+    // The first started session of each sequential charger, the session id breaking ties
+    let mut sequential_sessions: HashMap<&str, &Session> = HashMap::new();
+    for session in current_sessions.values() {
+        let charger_id = session.connector_id.charger_id.as_str();
+        if chargers_config
+            .get(charger_id)
+            .is_some_and(|c| c.sharing == SharingMode::Sequential)
+        {
+            let first = sequential_sessions.entry(charger_id).or_insert(session);
+            if (session.started_at, session.session_id) < (first.started_at, first.session_id) {
+                *first = session;
+            }
         }
+    }
 
-        // We share the power between the stations that can still take power
-        for (charger_id, charger_sessions) in chargers_sessions
-            .iter_mut()
-            .filter(|(charger_id, _)| sessions_with_remaining_power.contains_key(*charger_id))
+    let mut sessions = HashMap::new();
+    let mut waiting_sessions = Vec::new();
+    for session in current_sessions.values() {
+        let mut session = session.clone();
+        if let Some(connector_max_power) = chargers_config
+            .get(&session.connector_id.charger_id)
+            .and_then(|charger| charger.connector_max_power_of(session.connector_id.idx))
         {
-            let sessions_with_remaining_power_for_charger = *sessions_with_remaining_power
-                .get(charger_id)
-                .expect("We filtered the HashMap, so this key should exist")
-                as u32;
-            let additional_power = sessions_with_remaining_power_for_charger * fair_share;
-            let current_allocated_power: u32 =
-                charger_sessions.iter().map(|s| s.allocated_power).sum();
-            let power_to_allocate = additional_power.min(
-                chargers_config
-                    .get(charger_id)
-                    .expect("Charger config not found")
-                    .max_power
-                    - current_allocated_power,
+            session.vehicle_max_power = session.vehicle_max_power.min(connector_max_power);
+        }
+        match chargers_config.get(&session.connector_id.charger_id) {
+            Some(charger) if charger.sharing == SharingMode::Dedicated => {
+                session.vehicle_max_power = session.vehicle_max_power.min(charger.max_power);
+            }
+            Some(charger)
+                if charger.sharing == SharingMode::Sequential
+                    && sequential_sessions[session.connector_id.charger_id.as_str()].session_id
+                        != session.session_id =>
+            {
+                session.allocated_power = 0;
+                waiting_sessions.push(session);
+                continue;
+            }
+            _ => {}
+        }
+        sessions.insert(session.session_id, session);
+    }
+
+    let chargers_config = chargers_config
+        .iter()
+        .map(|(k, v)| {
+            let mut charger = v.clone();
+            charger.max_power = v.total_max_power();
+            (k.clone(), charger)
+        })
+        .collect();
+    (sessions, chargers_config, waiting_sessions)
+}
+
+/// Allocate the station capacity, serving the sessions with a deadline first.
+///
+/// Like in the fair mode, the reserved capacity of the chargers is served first. The sessions
+/// with both a departure time and an energy need are then given as much power as possible, by
+/// increasing laxity. The remaining capacity is then shared fairly among the other sessions.
+fn allocate_power_station_by_deadline(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+    station_capacity: u32,
+    now: DateTime<Utc>,
+) -> HashMap<uuid::Uuid, Session> {
+    // The laxity is computed on the full vehicle max power, not on what the reservation leaves
+    let laxities = current_sessions
+        .values()
+        .filter_map(|s| s.laxity(now).map(|laxity| (s.session_id, laxity)))
+        .collect::<HashMap<_, _>>();
+    allocate_with_reservations(
+        current_sessions,
+        chargers_config,
+        groups,
+        station_capacity,
+        |sessions, chargers_config, groups, station_capacity| {
+            allocate_deadlines_first(
+                sessions,
+                chargers_config,
+                groups,
+                station_capacity,
+                &laxities,
+            )
+        },
+    )
+}
+
+/// Give as much power as possible to the sessions with a laxity, by increasing laxity, and share
+/// the rest fairly among the other sessions.
+fn allocate_deadlines_first(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+    station_capacity: u32,
+    laxities: &HashMap<uuid::Uuid, i64>,
+) -> HashMap<uuid::Uuid, Session> {
+    let mut deadline_sessions = current_sessions
+        .values()
+        .filter(|s| chargers_config.contains_key(&s.connector_id.charger_id))
+        .filter_map(|s| laxities.get(&s.session_id).map(|laxity| (*laxity, s)))
+        .collect::<Vec<_>>();
+    // The session id breaks ties, so that the allocation is deterministic
+    deadline_sessions.sort_by_key(|(laxity, s)| (*laxity, s.session_id));
+
+    let mut chargers_remaining_power: HashMap<String, u32> = chargers_config
+        .iter()
+        .map(|(k, v)| (k.clone(), v.max_power))
+        .collect();
+    let mut groups_remaining_power = groups.clone();
+    let mut station_remaining_power = station_capacity;
+    let mut allocated_sessions = HashMap::new();
+    for (_, session) in deadline_sessions {
+        let charger_id = &session.connector_id.charger_id;
+        let charger_remaining_power = chargers_remaining_power
+            .get_mut(charger_id)
+            .expect("We filtered the sessions, so the charger should exist");
+        let mut group_remaining_power = chargers_config[charger_id]
+            .group_id
+            .as_ref()
+            .and_then(|group_id| groups_remaining_power.get_mut(group_id));
+        let power = session
+            .vehicle_max_power
+            .min(*charger_remaining_power)
+            .min(station_remaining_power)
+            .min(
+                group_remaining_power
+                    .as_deref()
+                    .copied()
+                    .unwrap_or(u32::MAX),
             );
+        // The power is bounded by all the remaining powers, so this cannot underflow
+        *charger_remaining_power -= power;
+        station_remaining_power -= power;
+        if let Some(group_remaining_power) = &mut group_remaining_power {
+            **group_remaining_power -= power;
+        }
+
+        let mut out = session.clone();
+        out.allocated_power = power;
+        allocated_sessions.insert(out.session_id, out);
+    }
+
+    // The other sessions share what is left of the chargers and the station
+    let other_sessions = current_sessions
+        .iter()
+        .filter(|(session_id, _)| !allocated_sessions.contains_key(*session_id))
+        .map(|(k, v)| (*k, v.clone()))
+        .collect();
+    let remaining_chargers_config = chargers_config
+        .iter()
+        .map(|(k, v)| {
+            let mut charger = v.clone();
+            charger.max_power = chargers_remaining_power[k];
+            (k.clone(), charger)
+        })
+        .collect();
+    allocated_sessions.extend(allocate_power_station(
+        &other_sessions,
+        &remaining_chargers_config,
+        &groups_remaining_power,
+        station_remaining_power,
+    ));
+    allocated_sessions
+}
+
+/// Allocate the station capacity, serving the reserved capacity of the chargers first.
+///
+/// Every charger with a reserved capacity shares it between its sessions, within what they can
+/// take, so the reserve of a charger without demand goes to the others. The remaining capacity is
+/// then allocated by charger priority to all the sessions, including the ones of the reserved
+/// chargers if they can take more.
+pub(crate) fn allocate_power_station(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+    station_capacity: u32,
+) -> HashMap<uuid::Uuid, Session> {
+    if let Some(charger) = single_plain_charger(chargers_config, groups) {
+        return allocate_single_charger(current_sessions, charger, station_capacity);
+    }
+    allocate_with_reservations(
+        current_sessions,
+        chargers_config,
+        groups,
+        station_capacity,
+        allocate_power_station_by_priority,
+    )
+}
+
+/// Serve the reserved capacity of the chargers, and allocate the rest with `allocate_rest`.
+///
+/// `allocate_rest` gets the sessions and the chargers with what their reservation leaves them,
+/// and chargers without reservation, and its allocations are added to the reserved ones.
+fn allocate_with_reservations(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+    station_capacity: u32,
+    allocate_rest: impl FnOnce(
+        &HashMap<uuid::Uuid, Session>,
+        &HashMap<String, ChargerConfig>,
+        &HashMap<String, u32>,
+        u32,
+    ) -> HashMap<uuid::Uuid, Session>,
+) -> HashMap<uuid::Uuid, Session> {
+    let mut reserved_chargers = chargers_config
+        .values()
+        .filter_map(|c| {
+            c.reserved_capacity
+                .map(|reserved_capacity| (c, reserved_capacity))
+        })
+        .collect::<Vec<_>>();
+    if reserved_chargers.is_empty() {
+        return allocate_rest(current_sessions, chargers_config, groups, station_capacity);
+    }
+    // The reservations exceeding the station capacity are served by charger id
+    reserved_chargers.sort_by_key(|(c, _)| &c.id);
+
+    let mut remaining_power = station_capacity;
+    let mut remaining_groups = groups.clone();
+    let mut reserved_sessions = HashMap::new();
+    for (charger, reserved_capacity) in reserved_chargers {
+        let charger_config = HashMap::from([(charger.id.clone(), charger.clone())]);
+        let charger_sessions = allocate_fair_share(
+            current_sessions,
+            &charger_config,
+            &remaining_groups,
+            reserved_capacity.min(remaining_power),
+        );
+        remaining_power = remaining_power.saturating_sub(
             charger_sessions
-                .iter_mut()
-                .filter(|session| session.allocated_power < session.vehicle_max_power)
-                .for_each(|session| {
-                    session.allocated_power = (session.allocated_power
-                        + power_to_allocate / sessions_with_remaining_power_for_charger)
-                        .min(session.vehicle_max_power)
-                })
+                .values()
+                .map(|s| s.allocated_power)
+                .fold(0u32, u32::saturating_add),
+        );
+        remaining_groups =
+            remaining_group_capacities(&remaining_groups, chargers_config, &charger_sessions);
+        reserved_sessions.extend(charger_sessions);
+    }
+
+    // The sessions and the chargers can only take the power on top of their reservation
+    let sessions = current_sessions
+        .iter()
+        .map(|(k, v)| {
+            let mut session = v.clone();
+            if let Some(reserved_session) = reserved_sessions.get(k) {
+                session.vehicle_max_power = session
+                    .vehicle_max_power
+                    .saturating_sub(reserved_session.allocated_power);
+            }
+            (*k, session)
+        })
+        .collect();
+    let chargers_config = chargers_config
+        .iter()
+        .map(|(k, v)| {
+            let mut charger = v.clone();
+            charger.max_power = charger.max_power.saturating_sub(
+                reserved_sessions
+                    .values()
+                    .filter(|s| s.connector_id.charger_id == *k)
+                    .map(|s| s.allocated_power)
+                    .fold(0u32, u32::saturating_add),
+            );
+            // Already served
+            charger.reserved_capacity = None;
+            (k.clone(), charger)
+        })
+        .collect();
+    let mut allocated_sessions = allocate_rest(
+        &sessions,
+        &chargers_config,
+        &remaining_groups,
+        remaining_power,
+    );
+    for session in allocated_sessions.values_mut() {
+        session.vehicle_max_power = current_sessions[&session.session_id].vehicle_max_power;
+        if let Some(reserved_session) = reserved_sessions.get(&session.session_id) {
+            session.allocated_power = session
+                .allocated_power
+                .saturating_add(reserved_session.allocated_power);
         }
     }
-    chargers_sessions
+    allocated_sessions
+}
+
+/// Return the charger of a station with a single charger, if it has no reserved capacity nor
+/// group limit, in which case the allocation is a fair share of the charger.
+fn single_plain_charger<'a>(
+    chargers_config: &'a HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+) -> Option<&'a ChargerConfig> {
+    let mut chargers = chargers_config.values();
+    let charger = chargers.next()?;
+    let grouped = charger
+        .group_id
+        .as_ref()
+        .is_some_and(|group_id| groups.contains_key(group_id));
+    (chargers.next().is_none() && charger.reserved_capacity.is_none() && !grouped)
+        .then_some(charger)
+}
+
+/// Allocate the station capacity to the sessions of a single charger.
+///
+/// This is the fair share of `allocate_fair_share` without the priority levels and the groups,
+/// as many sites have a single charger. The sessions are raised to the highest level that fits
+/// both the charger and the station, and like the general path, the remainder the station
+/// capacity leaves below that level stays unallocated.
+fn allocate_single_charger(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    charger: &ChargerConfig,
+    station_capacity: u32,
+) -> HashMap<uuid::Uuid, Session> {
+    let mut sessions = current_sessions
+        .values()
+        .filter(|s| s.connector_id.charger_id == charger.id)
+        .map(|s| {
+            let mut out = s.clone();
+            out.allocated_power = 0;
+            out
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|s| s.session_id);
+
+    let max_level = sessions
+        .iter()
+        .map(|s| s.vehicle_max_power)
+        .max()
+        .unwrap_or(0);
+    let level = highest_level(max_level, |level| {
+        power_at_level(&sessions, level).min(charger.max_power) <= station_capacity
+    });
+    fill_charger(&mut sessions, charger.max_power, level);
+    sessions.into_iter().map(|s| (s.session_id, s)).collect()
+}
+
+/// Allocate the station capacity by charger priority.
+///
+/// The chargers with the highest priority share the station capacity first, then the next
+/// priority level shares what is left, and so on. When every charger has the same priority,
+/// this is a fair share of the station capacity.
+fn allocate_power_station_by_priority(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+    station_capacity: u32,
+) -> HashMap<uuid::Uuid, Session> {
+    let mut priorities = chargers_config
         .values()
-        .flat_map(|sessions| sessions.iter().map(|s| (s.session_id, s.clone())))
+        .map(|c| c.priority)
+        .collect::<Vec<_>>();
+    priorities.sort_unstable_by(|a, b| b.cmp(a));
+    priorities.dedup();
+
+    let mut remaining_power = station_capacity;
+    let mut remaining_groups = groups.clone();
+    let mut allocated_sessions = HashMap::new();
+    for priority in priorities {
+        let priority_chargers_config = chargers_config
+            .iter()
+            .filter(|(_, c)| c.priority == priority)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let priority_sessions = allocate_fair_share(
+            current_sessions,
+            &priority_chargers_config,
+            &remaining_groups,
+            remaining_power,
+        );
+        let priority_allocated_power = priority_sessions
+            .values()
+            .map(|s| s.allocated_power)
+            .fold(0u32, u32::saturating_add);
+        debug_assert!(
+            priority_allocated_power <= remaining_power,
+            "The fair share cannot allocate more than the remaining power"
+        );
+        remaining_power = remaining_power.saturating_sub(priority_allocated_power);
+        remaining_groups =
+            remaining_group_capacities(&remaining_groups, chargers_config, &priority_sessions);
+        allocated_sessions.extend(priority_sessions);
+    }
+    allocated_sessions
+}
+
+/// Return the max power left to the groups once the sessions are allocated, by group id.
+fn remaining_group_capacities(
+    groups: &HashMap<String, u32>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    allocated_sessions: &HashMap<uuid::Uuid, Session>,
+) -> HashMap<String, u32> {
+    let mut remaining_groups = groups.clone();
+    for session in allocated_sessions.values() {
+        if let Some(remaining_power) = chargers_config
+            .get(&session.connector_id.charger_id)
+            .and_then(|charger| charger.group_id.as_ref())
+            .and_then(|group_id| remaining_groups.get_mut(group_id))
+        {
+            *remaining_power = remaining_power.saturating_sub(session.allocated_power);
+        }
+    }
+    remaining_groups
+}
+
+/// Share the station capacity fairly between the sessions of the given chargers.
+///
+/// The sessions are raised to the same power level, each capped by its vehicle max power, and
+/// the sessions of a charger share its max power the same way, as do the chargers of a group with
+/// the max power of the group. Rather than raising the level round by round, the highest level
+/// the station capacity allows is found by a binary search, so that the allocation takes a few
+/// dozen steps whatever the number of sessions.
+fn allocate_fair_share(
+    current_sessions: &HashMap<uuid::Uuid, Session>,
+    chargers_config: &HashMap<String, ChargerConfig>,
+    groups: &HashMap<String, u32>,
+    station_capacity: u32,
+) -> HashMap<uuid::Uuid, Session> {
+    // Split the sessions based on their charger, and the chargers based on their group. An
+    // ungrouped charger is alone in a group without max power.
+    let mut groups_sessions: Vec<(Option<u32>, Vec<ChargerSessions>)> = Vec::new();
+    let mut grouped_chargers: HashMap<&str, Vec<ChargerSessions>> = HashMap::new();
+    for charger in chargers_config.values() {
+        let mut charger_sessions = current_sessions
+            .values()
+            .filter(|s| s.connector_id.charger_id == charger.id)
+            .map(|s| {
+                let mut out = s.clone();
+                out.allocated_power = 0;
+                out
+            })
+            .collect::<Vec<_>>();
+        if charger_sessions.is_empty() {
+            continue;
+        }
+        // The power that cannot be split evenly within a charger goes to its sessions by id, so
+        // that the allocation is deterministic
+        charger_sessions.sort_by_key(|s| s.session_id);
+        match charger
+            .group_id
+            .as_deref()
+            .filter(|group_id| groups.contains_key(*group_id))
+        {
+            Some(group_id) => grouped_chargers
+                .entry(group_id)
+                .or_default()
+                .push((charger.max_power, charger_sessions)),
+            None => groups_sessions.push((None, vec![(charger.max_power, charger_sessions)])),
+        }
+    }
+    groups_sessions.extend(
+        grouped_chargers
+            .into_iter()
+            .map(|(group_id, chargers)| (Some(groups[group_id]), chargers)),
+    );
+
+    // The remaining power, below the number of sessions that could take one more kW, stays
+    // unallocated rather than favoring some sessions, within the station as within the groups
+    let max_level = groups_sessions
+        .iter()
+        .flat_map(|(_, chargers)| chargers.iter())
+        .flat_map(|(_, sessions)| sessions.iter().map(|s| s.vehicle_max_power))
+        .max()
+        .unwrap_or(0);
+    let level = highest_level(max_level, |level| {
+        groups_sessions
+            .iter()
+            .map(|(max_power, chargers)| {
+                let power = chargers_power_at_level(chargers, level);
+                max_power.map_or(power, |max_power| power.min(max_power))
+            })
+            .fold(0u32, u32::saturating_add)
+            <= station_capacity
+    });
+
+    for (max_power, chargers) in &mut groups_sessions {
+        // When the max power of the group binds, its chargers share it at the highest level it
+        // allows
+        let group_level = match max_power {
+            Some(max_power) => highest_level(level, |level| {
+                chargers_power_at_level(chargers, level) <= *max_power
+            }),
+            None => level,
+        };
+        for (max_power, sessions) in chargers.iter_mut() {
+            fill_charger(sessions, *max_power, group_level);
+        }
+    }
+    groups_sessions
+        .into_iter()
+        .flat_map(|(_, chargers)| chargers)
+        .flat_map(|(_, sessions)| sessions.into_iter().map(|s| (s.session_id, s)))
         .collect()
 }
 
+/// Max power of a charger, and its sessions sorted by id.
+type ChargerSessions = (u32, Vec<Session>);
+
+/// Return the power the chargers take when their sessions are raised to `level`, each charger
+/// capped by its max power.
+fn chargers_power_at_level(chargers: &[ChargerSessions], level: u32) -> u32 {
+    chargers
+        .iter()
+        .map(|(max_power, sessions)| power_at_level(sessions, level).min(*max_power))
+        .fold(0u32, u32::saturating_add)
+}
+
+/// Return the power the sessions take when raised to `level`, each capped by its vehicle max
+/// power.
+fn power_at_level(sessions: &[Session], level: u32) -> u32 {
+    sessions
+        .iter()
+        .map(|s| s.vehicle_max_power.min(level))
+        .fold(0u32, u32::saturating_add)
+}
+
+/// Return the highest level up to `max_level` that `fits`, which must hold for the lower levels
+/// as well as for 0.
+fn highest_level(max_level: u32, fits: impl Fn(u32) -> bool) -> u32 {
+    let (mut low, mut high) = (0, max_level);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+/// Allocate the sessions of a charger up to `level`, within the max power of the charger.
+///
+/// When the max power binds, the sessions share it at the highest level it allows. The power left,
+/// below the number of sessions that can take more, goes 1 kW at a time to these sessions by id.
+fn fill_charger(sessions: &mut [Session], max_power: u32, level: u32) {
+    let charger_level = highest_level(level, |level| power_at_level(sessions, level) <= max_power);
+    let mut remainder = power_at_level(sessions, level)
+        .min(max_power)
+        .saturating_sub(power_at_level(sessions, charger_level));
+    for session in sessions.iter_mut() {
+        session.allocated_power = session.vehicle_max_power.min(charger_level);
+        if remainder > 0 && session.allocated_power < session.vehicle_max_power {
+            session.allocated_power += 1;
+            remainder -= 1;
+        }
+    }
+}
+
 #[cfg(test)]
 mod test_allocate_station {
     use super::*;
@@ -214,16 +1001,22 @@ mod test_allocate_station {
                 id: "CP001".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
             ChargerConfig {
                 id: "CP002".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
         ]);
 
-        let out_sessions =
-            allocate_power_station(&vec_session_to_hashmap(&sessions), &chargers_config, 1000);
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            1000,
+        );
 
         // We expect every vehicle to be at max power
         assert_eq_allocated_power(&sessions[0], &out_sessions, 100);
@@ -261,16 +1054,22 @@ mod test_allocate_station {
                 id: "CP001".to_string(),
                 max_power: 100,
                 connectors: 2,
+                ..Default::default()
             },
             ChargerConfig {
                 id: "CP002".to_string(),
                 max_power: 100,
                 connectors: 2,
+                ..Default::default()
             },
         ]);
 
-        let out_sessions =
-            allocate_power_station(&vec_session_to_hashmap(&sessions), &chargers_config, 500);
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            500,
+        );
 
         // We expect the first charger to be at max power, and all the remaining power
         // to go to the second charger.
@@ -309,16 +1108,22 @@ mod test_allocate_station {
                 id: "CP001".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
             ChargerConfig {
                 id: "CP002".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
         ]);
 
-        let out_sessions =
-            allocate_power_station(&vec_session_to_hashmap(&sessions), &chargers_config, 300);
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            300,
+        );
 
         // The 3 vehicles should take a third each, as it is below their max power
         assert_eq_allocated_power(&sessions[0], &out_sessions, 100);
@@ -356,16 +1161,22 @@ mod test_allocate_station {
                 id: "CP001".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
             ChargerConfig {
                 id: "CP002".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
         ]);
 
-        let out_sessions =
-            allocate_power_station(&vec_session_to_hashmap(&sessions), &chargers_config, 300);
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            300,
+        );
 
         // The first charger should be at max power, and the second one taking the rest
         assert_eq_allocated_power(&sessions[0], &out_sessions, 50);
@@ -403,21 +1214,28 @@ mod test_allocate_station {
                 id: "CP001".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
             ChargerConfig {
                 id: "CP002".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
             ChargerConfig {
                 id: "CP003".to_string(),
                 max_power: 300,
                 connectors: 2,
+                ..Default::default()
             },
         ]);
 
-        let out_sessions =
-            allocate_power_station(&vec_session_to_hashmap(&sessions), &chargers_config, 300);
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            300,
+        );
 
         assert_eq_allocated_power(&sessions[0], &out_sessions, 100);
         assert_eq_allocated_power(&sessions[1], &out_sessions, 100);
@@ -454,19 +1272,1029 @@ mod test_allocate_station {
                 id: "CP001".to_string(),
                 max_power: 200,
                 connectors: 2,
+                ..Default::default()
             },
             ChargerConfig {
                 id: "CP002".to_string(),
                 max_power: 200,
                 connectors: 2,
+                ..Default::default()
             },
         ]);
 
-        let out_sessions =
-            allocate_power_station(&vec_session_to_hashmap(&sessions), &chargers_config, 330);
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            330,
+        );
 
         assert_eq_allocated_power(&sessions[0], &out_sessions, 80);
         assert_eq_allocated_power(&sessions[1], &out_sessions, 120);
         assert_eq_allocated_power(&sessions[2], &out_sessions, 130);
     }
+
+    #[test]
+    fn test_tight_deadline_preempts_relaxed() {
+        let now = Utc::now();
+        let relaxed = Session::with_options(
+            ConnectorId {
+                charger_id: "CP001".into(),
+                idx: 1,
+            },
+            150,
+            crate::SessionOptions {
+                depart_by: Some(now + chrono::Duration::hours(8)),
+                energy_needed_wh: Some(30_000),
+                ..Default::default()
+            },
+        );
+        let tight = Session::with_options(
+            ConnectorId {
+                charger_id: "CP002".into(),
+                idx: 1,
+            },
+            150,
+            crate::SessionOptions {
+                depart_by: Some(now + chrono::Duration::minutes(30)),
+                energy_needed_wh: Some(60_000),
+                ..Default::default()
+            },
+        );
+        let no_deadline = Session::new(
+            ConnectorId {
+                charger_id: "CP002".into(),
+                idx: 2,
+            },
+            150,
+        );
+        let sessions = vec![relaxed, tight, no_deadline];
+        let chargers_config = vec_chargers_to_hashmap(&[
+            ChargerConfig {
+                id: "CP001".to_string(),
+                max_power: 200,
+                connectors: 2,
+                ..Default::default()
+            },
+            ChargerConfig {
+                id: "CP002".to_string(),
+                max_power: 200,
+                connectors: 2,
+                ..Default::default()
+            },
+        ]);
+
+        let out_sessions = allocate_power_station_by_deadline(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            200,
+            now,
+        );
+
+        // The tight session is served first, the relaxed one takes the rest,
+        // and the session without deadline does not receive anything.
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 150);
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 50);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 0);
+    }
+
+    #[test]
+    fn test_deadline_mode_serves_reservations_first() {
+        let now = Utc::now();
+        let deadline = Session::with_options(
+            ConnectorId {
+                charger_id: "CP001".into(),
+                idx: 1,
+            },
+            300,
+            crate::SessionOptions {
+                depart_by: Some(now + chrono::Duration::hours(1)),
+                energy_needed_wh: Some(200_000),
+                ..Default::default()
+            },
+        );
+        let reserved = Session::new(
+            ConnectorId {
+                charger_id: "CP002".into(),
+                idx: 1,
+            },
+            300,
+        );
+        let sessions = vec![deadline, reserved];
+        let chargers_config = vec_chargers_to_hashmap(&[
+            ChargerConfig {
+                id: "CP001".to_string(),
+                max_power: 300,
+                connectors: 2,
+                ..Default::default()
+            },
+            ChargerConfig {
+                id: "CP002".to_string(),
+                max_power: 300,
+                connectors: 2,
+                reserved_capacity: Some(100),
+                ..Default::default()
+            },
+        ]);
+
+        // The reservation is guaranteed, and the deadline session takes everything else
+        let out_sessions = allocate_power_station_by_deadline(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            300,
+            now,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 200);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 100);
+
+        // With capacity left after the deadline session, the reserved charger takes more
+        let out_sessions = allocate_power_station_by_deadline(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            500,
+            now,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 300);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 200);
+    }
+
+    #[test]
+    fn test_deadline_mode_without_deadlines_is_fair() {
+        let sessions = vec![
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                80,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                150,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP002".into(),
+                    idx: 1,
+                },
+                150,
+            ),
+        ];
+        let chargers_config = vec_chargers_to_hashmap(&[
+            ChargerConfig {
+                id: "CP001".to_string(),
+                max_power: 200,
+                connectors: 2,
+                ..Default::default()
+            },
+            ChargerConfig {
+                id: "CP002".to_string(),
+                max_power: 200,
+                connectors: 2,
+                ..Default::default()
+            },
+        ]);
+
+        let out_sessions = allocate_power_station_by_deadline(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            330,
+            Utc::now(),
+        );
+
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 80);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 120);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 130);
+    }
+
+    #[test]
+    fn test_charger_priority() {
+        let sessions = vec![
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                150,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP002".into(),
+                    idx: 1,
+                },
+                150,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                150,
+            ),
+        ];
+        let chargers_config = vec_chargers_to_hashmap(&[
+            ChargerConfig {
+                id: "CP001".to_string(),
+                max_power: 200,
+                connectors: 2,
+                priority: 1,
+                ..Default::default()
+            },
+            ChargerConfig {
+                id: "CP002".to_string(),
+                max_power: 200,
+                connectors: 2,
+                ..Default::default()
+            },
+            ChargerConfig {
+                id: "CP003".to_string(),
+                max_power: 200,
+                connectors: 2,
+                ..Default::default()
+            },
+        ]);
+
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            300,
+        );
+
+        // The high priority charger is filled to its max first, then the low priority
+        // chargers share what is left.
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 100);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 100);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 50);
+        assert_eq_allocated_power(&sessions[3], &out_sessions, 50);
+
+        // Under more contention, the low priority chargers are starved
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            200,
+        );
+
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 100);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 100);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 0);
+        assert_eq_allocated_power(&sessions[3], &out_sessions, 0);
+    }
+
+    #[test]
+    fn test_new_session_allocation_failed() {
+        let current_session = Session::new(
+            ConnectorId {
+                charger_id: "CP001".into(),
+                idx: 1,
+            },
+            100,
+        );
+        // The charger of the new session is not in the configuration, it cannot be allocated
+        let new_session = Session::new(
+            ConnectorId {
+                charger_id: "CP002".into(),
+                idx: 1,
+            },
+            100,
+        );
+        let chargers_config = vec_chargers_to_hashmap(&[ChargerConfig {
+            id: "CP001".to_string(),
+            max_power: 200,
+            connectors: 2,
+            ..Default::default()
+        }]);
+
+        let result = allocate_for_new_session(
+            vec_session_to_hashmap(std::slice::from_ref(&current_session)),
+            &chargers_config,
+            &HashMap::new(),
+            400,
+            None,
+            400,
+            AllocationMode::Fair,
+            &new_session,
+        );
+        match result {
+            Err(SessionError::AllocationFailed { session_id }) => {
+                assert_eq!(session_id, new_session.session_id)
+            }
+            _ => panic!("Expected AllocationFailed error"),
+        }
+    }
+
+    #[test]
+    fn test_charger_remainder_below_session_count() {
+        let mut sessions = vec![
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                150,
+            ),
+        ];
+        sessions.sort_by_key(|s| s.session_id);
+        let chargers_config = vec_chargers_to_hashmap(&[ChargerConfig {
+            id: "CP001".to_string(),
+            max_power: 201,
+            connectors: 2,
+            ..Default::default()
+        }]);
+
+        // After the first round, the charger has 1kW left for 2 sessions: it goes to the first
+        // session by id, instead of never being allocated
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            1000,
+        );
+
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 101);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 100);
+    }
+
+    #[test]
+    fn test_station_remainder_below_session_count() {
+        let sessions = (1..=3)
+            .map(|idx| {
+                Session::new(
+                    ConnectorId {
+                        charger_id: "CP001".into(),
+                        idx,
+                    },
+                    100,
+                )
+            })
+            .collect::<Vec<_>>();
+        let chargers_config = vec_chargers_to_hashmap(&[ChargerConfig {
+            id: "CP001".to_string(),
+            max_power: 300,
+            connectors: 3,
+            ..Default::default()
+        }]);
+
+        // The fair share of 2kW between 3 sessions is 0, the remaining power stays unallocated
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            2,
+        );
+
+        for session in &sessions {
+            assert_eq_allocated_power(session, &out_sessions, 0);
+        }
+
+        // With 8kW, every session gets 2kW and the remaining 2kW stays unallocated
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::new(),
+            8,
+        );
+
+        for session in &sessions {
+            assert_eq_allocated_power(session, &out_sessions, 2);
+        }
+    }
+
+    #[test]
+    fn test_many_sessions() {
+        // 60 sessions on 20 chargers, half of them taking 10kW only
+        let sessions = (0..60)
+            .map(|i| {
+                Session::new(
+                    ConnectorId {
+                        charger_id: format!("CP{:03}", i % 20),
+                        idx: (i / 20 + 1) as u8,
+                    },
+                    if i % 2 == 0 { 10 } else { 200 },
+                )
+            })
+            .collect::<Vec<_>>();
+        let chargers_config = (0..20)
+            .map(|i| ChargerConfig {
+                id: format!("CP{:03}", i),
+                max_power: 600,
+                connectors: 3,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        // The 30 sessions taking more share 2000kW - 30 * 10kW: 56kW each, the remaining 20kW
+        // stays unallocated
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &vec_chargers_to_hashmap(&chargers_config),
+            &HashMap::new(),
+            2000,
+        );
+
+        for session in &sessions {
+            let expected = session.vehicle_max_power.min(56);
+            assert_eq_allocated_power(session, &out_sessions, expected);
+        }
+    }
+
+    #[test]
+    fn test_fair_share_rounds() {
+        // The level is found in at most 33 rounds whatever the highest level, rather than one
+        // round per kW
+        for expected_level in [0, 1, 56, 12_345, u32::MAX - 1, u32::MAX] {
+            let rounds = std::cell::Cell::new(0);
+            let level = highest_level(u32::MAX, |level| {
+                rounds.set(rounds.get() + 1);
+                level <= expected_level
+            });
+            assert_eq!(level, expected_level);
+            assert!(rounds.get() <= 33, "{} rounds", rounds.get());
+        }
+
+        // 1000 sessions with vehicles taking up to 1GW are allocated at once
+        let sessions = (0..1000)
+            .map(|i| {
+                Session::new(
+                    ConnectorId {
+                        charger_id: format!("CP{:03}", i % 100),
+                        idx: (i / 100 + 1) as u8,
+                    },
+                    if i % 2 == 0 { 1_000_000_000 } else { 10 },
+                )
+            })
+            .collect::<Vec<_>>();
+        let chargers_config = (0..100)
+            .map(|i| ChargerConfig {
+                id: format!("CP{:03}", i),
+                max_power: u32::MAX,
+                connectors: 10,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let out_sessions = allocate_fair_share(
+            &vec_session_to_hashmap(&sessions),
+            &vec_chargers_to_hashmap(&chargers_config),
+            &HashMap::new(),
+            1_000_000,
+        );
+
+        // The 500 sessions taking more share 1000000kW - 500 * 10kW
+        for session in &sessions {
+            let expected = session.vehicle_max_power.min(1990);
+            assert_eq_allocated_power(session, &out_sessions, expected);
+        }
+    }
+
+    fn sharing_sessions() -> Vec<Session> {
+        vec![
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                150,
+            ),
+        ]
+    }
+
+    fn sharing_chargers(sharing: SharingMode) -> HashMap<String, ChargerConfig> {
+        vec_chargers_to_hashmap(&[ChargerConfig {
+            id: "CP001".to_string(),
+            max_power: 200,
+            connectors: 2,
+            sharing,
+            ..Default::default()
+        }])
+    }
+
+    #[test]
+    fn test_shared_charger() {
+        let sessions = sharing_sessions();
+
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &sharing_chargers(SharingMode::Shared),
+            &HashMap::new(),
+            1000,
+            AllocationMode::Fair,
+        );
+
+        // The charger max power caps the sum of its sessions
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 100);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 100);
+    }
+
+    #[test]
+    fn test_dedicated_charger() {
+        let sessions = sharing_sessions();
+
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &sharing_chargers(SharingMode::Dedicated),
+            &HashMap::new(),
+            1000,
+            AllocationMode::Fair,
+        );
+
+        // Every connector can deliver up to the charger max power
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 150);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 150);
+
+        // A vehicle above the charger max power is capped by its connector, without losing its
+        // own max power
+        let sessions = vec![Session::new(
+            ConnectorId {
+                charger_id: "CP001".into(),
+                idx: 1,
+            },
+            300,
+        )];
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &sharing_chargers(SharingMode::Dedicated),
+            &HashMap::new(),
+            1000,
+            AllocationMode::Fair,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 200);
+        assert_eq!(out_sessions[&sessions[0].session_id].vehicle_max_power, 300);
+    }
+
+    #[test]
+    fn test_sequential_charger() {
+        let mut sessions = sharing_sessions();
+        sessions[1].started_at = sessions[0].started_at - chrono::Duration::seconds(60);
+
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &sharing_chargers(SharingMode::Sequential),
+            &HashMap::new(),
+            1000,
+            AllocationMode::Fair,
+        );
+
+        // Only the first started session gets power
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 0);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 150);
+
+        // Once it is gone, the next session gets the power
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions[..1]),
+            &sharing_chargers(SharingMode::Sequential),
+            &HashMap::new(),
+            1000,
+            AllocationMode::Fair,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 150);
+    }
+
+    fn dc_sessions() -> Vec<Session> {
+        (1..=3)
+            .map(|charger| {
+                Session::new(
+                    ConnectorId {
+                        charger_id: format!("DC00{charger}"),
+                        idx: 1,
+                    },
+                    150,
+                )
+            })
+            .collect()
+    }
+
+    fn dc_chargers(usable_min_power: Option<u32>) -> HashMap<String, ChargerConfig> {
+        vec_chargers_to_hashmap(
+            &(1..=3)
+                .map(|charger| ChargerConfig {
+                    id: format!("DC00{charger}"),
+                    max_power: 150,
+                    connectors: 1,
+                    usable_min_power,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn test_usable_min_power() {
+        let mut sessions = dc_sessions();
+        sessions[2].started_at = sessions[0].started_at + chrono::Duration::seconds(60);
+
+        // Without a usable min power, the 100kW are shared in unusable allocations
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &dc_chargers(None),
+            &HashMap::new(),
+            100,
+            AllocationMode::Fair,
+        );
+        assert!(out_sessions.values().all(|s| s.allocated_power == 33));
+
+        // With it, the last started session gets no power and the two others can charge
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &dc_chargers(Some(40)),
+            &HashMap::new(),
+            100,
+            AllocationMode::Fair,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 50);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 50);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 0);
+        assert_eq!(out_sessions[&sessions[2].session_id].vehicle_max_power, 150);
+
+        // When even one session cannot get the usable min power, no power is allocated
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &dc_chargers(Some(40)),
+            &HashMap::new(),
+            30,
+            AllocationMode::Fair,
+        );
+        assert!(out_sessions.values().all(|s| s.allocated_power == 0));
+    }
+
+    #[test]
+    fn test_warmup() {
+        let mut sessions = dc_sessions();
+        let mut chargers = dc_chargers(None);
+        let charger = chargers.get_mut("DC001").unwrap();
+        charger.warmup_secs = Some(30);
+        charger.warmup_power = Some(60);
+
+        // During the warm-up, the session gets the warm-up power and the others share the rest
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &chargers,
+            &HashMap::new(),
+            120,
+            AllocationMode::Fair,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 60);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 30);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 30);
+
+        // A fair share above the warm-up power is kept
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &chargers,
+            &HashMap::new(),
+            300,
+            AllocationMode::Fair,
+        );
+        assert!(out_sessions.values().all(|s| s.allocated_power == 100));
+
+        // After the warm-up, the power is shared fairly
+        sessions[0].started_at -= chrono::Duration::seconds(30);
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &chargers,
+            &HashMap::new(),
+            120,
+            AllocationMode::Fair,
+        );
+        assert!(out_sessions.values().all(|s| s.allocated_power == 40));
+    }
+
+    #[test]
+    fn test_usable_min_power_above_vehicle_max_power() {
+        // A tapering vehicle drawing less than the usable min power keeps charging
+        let mut sessions = dc_sessions();
+        sessions[0].vehicle_max_power = 10;
+
+        let out_sessions = allocate(
+            &vec_session_to_hashmap(&sessions),
+            &dc_chargers(Some(40)),
+            &HashMap::new(),
+            100,
+            AllocationMode::Fair,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 10);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 45);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 45);
+    }
+
+    #[test]
+    fn test_reserved_capacity() {
+        let sessions = vec![
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                300,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP002".into(),
+                    idx: 1,
+                },
+                300,
+            ),
+            Session::new(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                300,
+            ),
+        ];
+        let chargers = |reserved_capacity| {
+            vec_chargers_to_hashmap(&[
+                ChargerConfig {
+                    id: "CP001".to_string(),
+                    max_power: 300,
+                    connectors: 2,
+                    ..Default::default()
+                },
+                ChargerConfig {
+                    id: "CP002".to_string(),
+                    max_power: 300,
+                    connectors: 2,
+                    ..Default::default()
+                },
+                ChargerConfig {
+                    id: "CP003".to_string(),
+                    max_power: 300,
+                    connectors: 2,
+                    reserved_capacity,
+                    ..Default::default()
+                },
+            ])
+        };
+
+        // Without a reservation, the 300kW are shared evenly
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers(None),
+            &HashMap::new(),
+            300,
+        );
+        for session in &sessions {
+            assert_eq_allocated_power(session, &out_sessions, 100);
+        }
+
+        // The reserved charger gets its guarantee even though the others demand everything,
+        // and shares the rest fairly with them
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers(Some(180)),
+            &HashMap::new(),
+            300,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 40);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 40);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 220);
+
+        // Without demand on the reserved charger, its reserve goes to the others
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions[..2]),
+            &chargers(Some(180)),
+            &HashMap::new(),
+            300,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 150);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 150);
+
+        // A vehicle taking less than the reserve leaves the rest to the others
+        let mut sessions = sessions;
+        sessions[2].vehicle_max_power = 50;
+        let out_sessions = allocate_power_station(
+            &vec_session_to_hashmap(&sessions),
+            &chargers(Some(180)),
+            &HashMap::new(),
+            300,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 125);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 125);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 50);
+    }
+
+    #[test]
+    fn test_charger_groups() {
+        let sessions = ["CP001", "CP002", "CP003"]
+            .map(|charger_id| {
+                Session::new(
+                    ConnectorId {
+                        charger_id: charger_id.into(),
+                        idx: 1,
+                    },
+                    200,
+                )
+            })
+            .to_vec();
+        // CP001 and CP002 share a 150kW feeder, CP003 is ungrouped
+        let chargers = |configure: fn(&mut [ChargerConfig])| {
+            let mut chargers = ["CP001", "CP002", "CP003"].map(|id| ChargerConfig {
+                id: id.to_string(),
+                max_power: 200,
+                connectors: 2,
+                group_id: (id != "CP003").then(|| "FEEDER".to_string()),
+                ..Default::default()
+            });
+            configure(&mut chargers);
+            vec_chargers_to_hashmap(&chargers)
+        };
+        let groups = HashMap::from([("FEEDER".to_string(), 150)]);
+        let allocate = |chargers_config, station_capacity| {
+            allocate_power_station(
+                &vec_session_to_hashmap(&sessions),
+                &chargers_config,
+                &groups,
+                station_capacity,
+            )
+        };
+
+        // The group caps its chargers, the ungrouped charger is only capped by the station
+        let out_sessions = allocate(chargers(|_| {}), 500);
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 75);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 75);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 200);
+
+        // The station caps the ungrouped charger once the group binds
+        let out_sessions = allocate(chargers(|_| {}), 250);
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 75);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 75);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 100);
+
+        // A charger capped below its share leaves the rest of the group to the other one
+        let out_sessions = allocate(chargers(|chargers| chargers[0].max_power = 50), 500);
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 50);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 100);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 200);
+
+        // A higher priority charger takes the group first, even with station capacity left
+        let out_sessions = allocate(chargers(|chargers| chargers[0].priority = 1), 500);
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 150);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 0);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 200);
+
+        // A reservation is served within the group, which the others share afterwards
+        let out_sessions = allocate(
+            chargers(|chargers| chargers[1].reserved_capacity = Some(100)),
+            500,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 25);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 125);
+        assert_eq_allocated_power(&sessions[2], &out_sessions, 200);
+    }
+
+    #[test]
+    fn test_charger_groups_by_deadline() {
+        let now = Utc::now();
+        let mut sessions = ["CP001", "CP002"]
+            .map(|charger_id| {
+                Session::new(
+                    ConnectorId {
+                        charger_id: charger_id.into(),
+                        idx: 1,
+                    },
+                    200,
+                )
+            })
+            .to_vec();
+        sessions[0].depart_by = Some(now + chrono::Duration::hours(1));
+        sessions[0].energy_needed_wh = Some(150_000);
+        let chargers_config =
+            vec_chargers_to_hashmap(&["CP001", "CP002"].map(|id| ChargerConfig {
+                id: id.to_string(),
+                max_power: 200,
+                connectors: 2,
+                group_id: Some("FEEDER".to_string()),
+                ..Default::default()
+            }));
+
+        // The deadline session is served first within the group, the other one takes the rest
+        let out_sessions = allocate_power_station_by_deadline(
+            &vec_session_to_hashmap(&sessions),
+            &chargers_config,
+            &HashMap::from([("FEEDER".to_string(), 180)]),
+            500,
+            now,
+        );
+        assert_eq_allocated_power(&sessions[0], &out_sessions, 180);
+        assert_eq_allocated_power(&sessions[1], &out_sessions, 0);
+    }
+
+    #[test]
+    fn test_single_charger_fast_path() {
+        let vehicle_powers: [&[u32]; 4] = [&[100, 100, 100, 37], &[0, 20, 300], &[7], &[]];
+        for powers in vehicle_powers {
+            let mut sessions = powers
+                .iter()
+                .enumerate()
+                .map(|(idx, power)| {
+                    Session::new(
+                        ConnectorId {
+                            charger_id: "CP001".into(),
+                            idx: idx as u8 + 1,
+                        },
+                        *power,
+                    )
+                })
+                .collect::<Vec<_>>();
+            // A session of a charger missing from the configuration is not allocated
+            sessions.push(Session::new(
+                ConnectorId {
+                    charger_id: "CP999".into(),
+                    idx: 1,
+                },
+                100,
+            ));
+            let sessions = vec_session_to_hashmap(&sessions);
+            for max_power in [0, 50, 150, 301] {
+                let chargers_config = vec_chargers_to_hashmap(&[ChargerConfig {
+                    id: "CP001".to_string(),
+                    max_power,
+                    connectors: 4,
+                    ..Default::default()
+                }]);
+                assert!(single_plain_charger(&chargers_config, &HashMap::new()).is_some());
+                for station_capacity in [0, 7, 100, 250, 1000] {
+                    let allocated_power = |sessions: &HashMap<uuid::Uuid, Session>| {
+                        let mut powers = sessions
+                            .values()
+                            .map(|s| (s.session_id, s.allocated_power))
+                            .collect::<Vec<_>>();
+                        powers.sort();
+                        powers
+                    };
+                    // The fast path gives the same allocation as the general path
+                    assert_eq!(
+                        allocated_power(&allocate_power_station(
+                            &sessions,
+                            &chargers_config,
+                            &HashMap::new(),
+                            station_capacity,
+                        )),
+                        allocated_power(&allocate_power_station_by_priority(
+                            &sessions,
+                            &chargers_config,
+                            &HashMap::new(),
+                            station_capacity,
+                        )),
+                        "vehicle powers {powers:?}, charger {max_power}, station {station_capacity}"
+                    );
+                }
+            }
+        }
+
+        // The reserved capacities and the group limits take the general path
+        let grouped_charger = ChargerConfig {
+            id: "CP001".to_string(),
+            max_power: 100,
+            connectors: 2,
+            group_id: Some("FEEDER".to_string()),
+            ..Default::default()
+        };
+        let groups = HashMap::from([("FEEDER".to_string(), 50)]);
+        assert!(
+            single_plain_charger(&vec_chargers_to_hashmap(&[grouped_charger]), &groups).is_none()
+        );
+        let reserved_charger = ChargerConfig {
+            id: "CP001".to_string(),
+            max_power: 100,
+            connectors: 2,
+            reserved_capacity: Some(50),
+            ..Default::default()
+        };
+        assert!(
+            single_plain_charger(
+                &vec_chargers_to_hashmap(&[reserved_charger]),
+                &HashMap::new()
+            )
+            .is_none()
+        );
+    }
 }
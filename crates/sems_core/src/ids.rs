@@ -0,0 +1,56 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Source of the session ids of a `StationState`.
+pub trait IdGenerator: Send + Sync {
+    /// Return a new session id.
+    fn next_id(&self) -> uuid::Uuid;
+}
+
+/// Generate random (v4) ids, the default of a `StationState`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomIdGenerator;
+
+impl IdGenerator for RandomIdGenerator {
+    fn next_id(&self) -> uuid::Uuid {
+        uuid::Uuid::new_v4()
+    }
+}
+
+/// Generate predictable ids, e.g. for tests asserting on session ids.
+///
+/// The n-th id is `0000000n-0000-0000-0000-000000000000`, starting from 1, so that the short ids,
+/// derived from the first bits, are unique as well.
+#[derive(Debug, Default)]
+pub struct SequentialIdGenerator {
+    count: AtomicU32,
+}
+
+impl SequentialIdGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn next_id(&self) -> uuid::Uuid {
+        let n = self.count.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        uuid::Uuid::from_fields(n, 0, 0, &[0; 8])
+    }
+}
+
+/// Id generator shared by the clones of a `StationState`.
+#[derive(Clone)]
+pub(crate) struct SharedIdGenerator(pub(crate) Arc<dyn IdGenerator>);
+
+impl Default for SharedIdGenerator {
+    fn default() -> Self {
+        SharedIdGenerator(Arc::new(RandomIdGenerator))
+    }
+}
+
+impl std::fmt::Debug for SharedIdGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedIdGenerator")
+    }
+}
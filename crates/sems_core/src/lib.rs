@@ -1,8 +1,14 @@
 mod allocator;
+mod ids;
 mod models;
 
+pub use crate::ids::{IdGenerator, RandomIdGenerator, SequentialIdGenerator};
 pub use crate::models::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::ids::SharedIdGenerator;
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -13,8 +19,190 @@ pub enum SessionError {
     ConnectorAlreadyInUse { connector_id: ConnectorId },
     #[error("Connector {connector_id:?} does not exist in the station configuration")]
     ConnectorNotFound { connector_id: ConnectorId },
+    #[error("Connector {connector_id:?} is invalid, connector indexes start at {connector_base}")]
+    InvalidConnectorIndex {
+        connector_id: ConnectorId,
+        connector_base: u8,
+    },
     #[error("Session {session_id} not found")]
     SessionNotFound { session_id: uuid::Uuid },
+    #[error("Session {session_id} was stopped at {stopped_at}")]
+    SessionGone {
+        session_id: uuid::Uuid,
+        stopped_at: chrono::DateTime<chrono::Utc>,
+    },
+    #[error("Session {id} not found")]
+    SessionIdNotFound { id: String },
+    #[error("Connector label {label} not found")]
+    ConnectorLabelNotFound { label: String },
+    #[error("Session {session_id} is not in the queue")]
+    QueuedSessionNotFound { session_id: uuid::Uuid },
+    #[error("Could not allocate power to session {session_id}")]
+    AllocationFailed { session_id: uuid::Uuid },
+    #[error("The station is draining, it does not accept new sessions")]
+    StationDraining,
+    #[error("The station is emergency stopped, it does not accept new sessions")]
+    EmergencyStop,
+    #[error("The station has no capacity, it does not accept new sessions")]
+    InsufficientCapacity,
+    #[error("No unused session id could be generated in {attempts} attempts")]
+    SessionIdsExhausted { attempts: usize },
+    #[error("The vehicle max power or max current is required")]
+    MissingVehicleMaxPower,
+    #[error("The expiry {expires_at} is in the past")]
+    ExpiryInPast {
+        expires_at: chrono::DateTime<chrono::Utc>,
+    },
+    #[error("A session note cannot be empty")]
+    EmptyNote,
+    #[error("Session {session_id} already has the maximum of {max} notes")]
+    TooManyNotes { session_id: uuid::Uuid, max: usize },
+    #[error("Session {session_id} cannot go from {from:?} to {to:?}")]
+    InvalidTransition {
+        session_id: uuid::Uuid,
+        from: SessionState,
+        to: SessionState,
+    },
+}
+
+/// Number of ids drawn for a new session before giving up, when their short ids are all used.
+const SESSION_ID_ATTEMPTS: usize = 100;
+
+/// Number of finished sessions kept to reject operations on them.
+const FINISHED_SESSIONS_RETAINED: usize = 100;
+
+/// Number of stopped session ids kept, to report them as gone once their session is no longer
+/// retained.
+const STOPPED_SESSION_IDS_RETAINED: usize = 1000;
+
+/// Number of allocation samples kept per session.
+const ALLOCATION_HISTORY_RETAINED: usize = 100;
+
+/// Number of reallocations the allocation churn is averaged over.
+const ALLOCATION_CHURN_WINDOW: usize = 100;
+
+/// Number of last allocation samples of a session its flapping is measured over.
+const FLAPPING_SAMPLES: usize = 10;
+
+/// Number of allocator runs the allocation durations are kept for.
+const ALLOCATION_DURATION_WINDOW: usize = 100;
+
+/// Number of notes a session can hold.
+pub const MAX_SESSION_NOTES: usize = 50;
+
+#[derive(Error, Debug)]
+pub enum StationError {
+    #[error("Demand response fraction {fraction} must be between 0 and 1")]
+    InvalidDemandResponse { fraction: f32 },
+    #[error("Demand response duration of {duration_secs}s is too long")]
+    InvalidDemandResponseDuration { duration_secs: u64 },
+    #[error("Charger {charger_id} not found")]
+    ChargerNotFound { charger_id: String },
+    #[error("The station has no battery")]
+    BatteryNotFound,
+    #[error("Battery power {power}kW exceeds the {rating}kW battery rating")]
+    InvalidBatteryPower { power: u32, rating: u32 },
+    #[error("Safety margin {percent}% must be between 0 and {MAX_SAFETY_MARGIN_PERCENT}%")]
+    InvalidSafetyMargin { percent: u8 },
+}
+
+/// Highest safety margin, in percent of the grid capacity.
+pub const MAX_SAFETY_MARGIN_PERCENT: u8 = 50;
+
+/// Callback invoked with the active sessions after their allocations changed.
+type ReallocationCallback = dyn Fn(&[Session]) + Send + Sync;
+
+#[derive(Clone)]
+struct ReallocationObserver(Arc<ReallocationCallback>);
+
+impl std::fmt::Debug for ReallocationObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReallocationObserver")
+    }
+}
+
+/// Problem found in a station configuration by `StationConfig::validate`.
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "problem", rename_all = "camelCase")]
+pub enum ConfigProblem {
+    #[error("Charger {charger_id} is configured more than once")]
+    #[serde(rename_all = "camelCase")]
+    DuplicateChargerId { charger_id: String },
+    #[error("Charger {charger_id} has no connectors")]
+    #[serde(rename_all = "camelCase")]
+    NoConnectors { charger_id: String },
+    #[error(
+        "Charger {charger_id} max power {max_power}kW exceeds the {station_capacity}kW station capacity"
+    )]
+    #[serde(rename_all = "camelCase")]
+    ChargerExceedsStationCapacity {
+        charger_id: String,
+        max_power: u32,
+        station_capacity: u32,
+    },
+    #[error(
+        "Charger {charger_id} usable min power {usable_min_power}kW exceeds its {max_power}kW max power"
+    )]
+    #[serde(rename_all = "camelCase")]
+    UsableMinPowerAboveMaxPower {
+        charger_id: String,
+        usable_min_power: u32,
+        max_power: u32,
+    },
+    #[error("Charger {charger_id} phase {phase} must be 1, 2 or 3")]
+    #[serde(rename_all = "camelCase")]
+    InvalidPhase { charger_id: String, phase: u8 },
+    #[error(
+        "Charger {charger_id} has {connector_powers} connector power(s) for {connectors} connector(s)"
+    )]
+    #[serde(rename_all = "camelCase")]
+    ConnectorPowersMismatch {
+        charger_id: String,
+        connector_powers: usize,
+        connectors: u8,
+    },
+    #[error("Label {label} is on connector {charger_id}:{idx}, which does not exist")]
+    #[serde(rename_all = "camelCase")]
+    LabelOnMissingConnector {
+        charger_id: String,
+        idx: u8,
+        label: String,
+    },
+    #[error("Label {label} is used by more than one connector")]
+    #[serde(rename_all = "camelCase")]
+    DuplicateLabel { label: String },
+    #[error(
+        "The chargers reserve {reserved_capacity}kW, more than the {grid_capacity}kW grid capacity"
+    )]
+    #[serde(rename_all = "camelCase")]
+    ReservedCapacityExceedsGridCapacity {
+        reserved_capacity: u32,
+        grid_capacity: u32,
+    },
+    #[error("The station has chargers but no grid capacity nor battery to power them")]
+    NoStationCapacity,
+    #[error("The display smoothing factor must be above 0 and at most 1")]
+    InvalidDisplaySmoothingFactor,
+    #[error(
+        "Charger {charger_id} connectors deliver at most {connectors_max_power}kW at {connector_max_power}kW each, below its {max_power}kW max power"
+    )]
+    #[serde(rename_all = "camelCase")]
+    ConnectorsBelowChargerMaxPower {
+        charger_id: String,
+        connector_max_power: u32,
+        connectors_max_power: u32,
+        max_power: u32,
+    },
+    #[error("Group {group_id} is configured more than once")]
+    #[serde(rename_all = "camelCase")]
+    DuplicateGroupId { group_id: String },
+    #[error("Charger {charger_id} is in group {group_id}, which does not exist")]
+    #[serde(rename_all = "camelCase")]
+    UnknownGroup {
+        charger_id: String,
+        group_id: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +211,71 @@ pub struct StationState {
     config: StationConfig,
     sessions: HashMap<uuid::Uuid, Session>,
     chargers: HashMap<String, ChargerConfig>,
+    #[serde(skip)]
+    connector_labels: HashMap<String, ConnectorId>,
+    /// The session of every connector in use, kept in sync with the active sessions.
+    #[serde(skip)]
+    connector_sessions: HashMap<ConnectorId, uuid::Uuid>,
+    #[serde(skip)]
+    finished_sessions: VecDeque<Session>,
+    /// Sessions waiting for capacity when `queue_when_full` is set, from the first to start.
+    #[serde(skip)]
+    queue: VecDeque<QueuedSession>,
+    /// Queued sessions started since the last `take_promoted_sessions`.
+    #[serde(skip)]
+    promoted_sessions: Vec<Session>,
+    /// Id and stop time of the last stopped sessions, from the oldest.
+    #[serde(skip)]
+    stopped_session_ids: VecDeque<(uuid::Uuid, chrono::DateTime<chrono::Utc>)>,
+    #[serde(skip)]
+    pending_power_updates: HashSet<uuid::Uuid>,
+    #[serde(skip)]
+    reallocation_count: u64,
+    #[serde(skip)]
+    changed_allocation_count: u64,
+    /// Number of sessions whose allocation changed, for the last reallocations.
+    #[serde(skip)]
+    allocation_churn: VecDeque<usize>,
+    /// Time taken by the allocator, for its last runs.
+    #[serde(skip)]
+    allocation_durations: VecDeque<Duration>,
+    #[serde(skip)]
+    demand_response: Option<DemandResponse>,
+    #[serde(skip)]
+    demand_response_count: u64,
+    /// Ceiling of the grid capacity set by `set_power_cap`, e.g. on a utility request.
+    #[serde(skip)]
+    power_cap: Option<u32>,
+    /// Share of the grid capacity kept as headroom, in percent, set by `set_safety_margin`.
+    #[serde(skip)]
+    safety_margin_percent: u8,
+    #[serde(skip)]
+    derated_chargers: HashMap<String, u32>,
+    #[serde(skip)]
+    suspended_chargers: HashSet<String>,
+    #[serde(skip)]
+    battery_control: BatteryControl,
+    #[serde(skip)]
+    allocation_history: HashMap<uuid::Uuid, VecDeque<AllocationSample>>,
+    /// Allocation of the sessions in the last full reallocation, their target for the fairness.
+    #[serde(skip)]
+    fair_share_targets: HashMap<uuid::Uuid, u32>,
+    /// Last time the allocation of every active session changed.
+    #[serde(skip)]
+    last_allocation_changes: HashMap<uuid::Uuid, Instant>,
+    /// Smoothed allocated power of every active session, if the display smoothing is set.
+    #[serde(skip)]
+    display_powers: HashMap<uuid::Uuid, f32>,
+    #[serde(skip)]
+    session_notes: HashMap<uuid::Uuid, Vec<SessionNote>>,
+    #[serde(skip)]
+    reallocation_observers: Vec<ReallocationObserver>,
+    #[serde(skip)]
+    draining: bool,
+    #[serde(skip)]
+    emergency_stopped: bool,
+    #[serde(skip)]
+    id_generator: SharedIdGenerator,
 }
 
 impl StationState {
@@ -32,11 +285,121 @@ impl StationState {
             .iter()
             .map(|charger| (charger.id.clone(), charger.clone()))
             .collect();
+        let connector_labels = config
+            .chargers
+            .iter()
+            .flat_map(|charger| {
+                charger.labels.iter().map(|(idx, label)| {
+                    (
+                        label.clone(),
+                        ConnectorId {
+                            charger_id: charger.id.clone(),
+                            idx: *idx,
+                        },
+                    )
+                })
+            })
+            .collect();
         StationState {
             config,
             chargers,
+            connector_labels,
             sessions: HashMap::new(),
+            connector_sessions: HashMap::new(),
+            finished_sessions: VecDeque::new(),
+            queue: VecDeque::new(),
+            promoted_sessions: Vec::new(),
+            stopped_session_ids: VecDeque::new(),
+            pending_power_updates: HashSet::new(),
+            reallocation_count: 0,
+            changed_allocation_count: 0,
+            allocation_churn: VecDeque::new(),
+            allocation_durations: VecDeque::new(),
+            demand_response: None,
+            demand_response_count: 0,
+            power_cap: None,
+            safety_margin_percent: 0,
+            derated_chargers: HashMap::new(),
+            suspended_chargers: HashSet::new(),
+            battery_control: BatteryControl::default(),
+            allocation_history: HashMap::new(),
+            fair_share_targets: HashMap::new(),
+            last_allocation_changes: HashMap::new(),
+            display_powers: HashMap::new(),
+            session_notes: HashMap::new(),
+            reallocation_observers: Vec::new(),
+            draining: false,
+            emergency_stopped: false,
+            id_generator: SharedIdGenerator::default(),
+        }
+    }
+
+    /// Keep the runtime controls of the previous state of the station when its configuration is
+    /// replaced.
+    ///
+    /// The emergency stop, the drain, the power cap, the safety margin, the demand response, the
+    /// id generator and the reallocation observers are kept as is. The suspended and derated
+    /// chargers, the queued sessions and the battery control are kept if the new configuration
+    /// still has their charger, connector or battery power.
+    pub fn carry_over_controls(&mut self, previous: StationState) {
+        self.emergency_stopped = previous.emergency_stopped;
+        self.draining = previous.draining;
+        self.power_cap = previous.power_cap;
+        self.safety_margin_percent = previous.safety_margin_percent;
+        self.demand_response = previous.demand_response;
+        self.demand_response_count = previous.demand_response_count;
+        self.id_generator = previous.id_generator;
+        self.reallocation_observers = previous.reallocation_observers;
+        self.suspended_chargers = previous
+            .suspended_chargers
+            .into_iter()
+            .filter(|charger_id| self.chargers.contains_key(charger_id))
+            .collect();
+        self.derated_chargers = previous
+            .derated_chargers
+            .into_iter()
+            .filter(|(charger_id, _)| self.chargers.contains_key(charger_id))
+            .collect();
+        self.queue = previous
+            .queue
+            .into_iter()
+            .filter(|queued_session| self.charger(&queued_session.connector_id).is_ok())
+            .collect();
+        if previous.battery_control != BatteryControl::default()
+            && let Err(error) = self.set_battery_control(previous.battery_control)
+        {
+            tracing::warn!(%error, "Dropping the battery control of the previous configuration");
         }
+        // The new configuration has no active session yet
+        self.promoted_sessions = previous.promoted_sessions;
+        self.promote_queued_sessions();
+    }
+
+    /// Replace the configuration of the station, which drops the active sessions, keeping its
+    /// runtime controls as `carry_over_controls` does.
+    ///
+    /// Return the allocation changes: the dropped sessions, and the queued sessions started with
+    /// the new configuration.
+    pub fn reconfigure(&mut self, config: StationConfig) -> AllocationDiff {
+        let mut previous = std::mem::replace(self, StationState::new(config));
+        let previous_sessions = std::mem::take(&mut previous.sessions);
+        self.carry_over_controls(previous);
+        diff_sessions(&previous_sessions, &self.sessions)
+    }
+
+    /// Return a copy of the station without its reallocation observers, e.g. to preview a
+    /// `reconfigure` without notifying them of the sessions it would start.
+    pub fn clone_without_observers(&self) -> StationState {
+        let mut state = self.clone();
+        state.reallocation_observers.clear();
+        state
+    }
+
+    /// Generate the session ids with `id_generator` instead of random ids, e.g. to make the ids
+    /// predictable in tests with a `SequentialIdGenerator`.
+    pub fn with_id_generator(mut self, id_generator: impl IdGenerator + 'static) -> Self {
+        self.id_generator = SharedIdGenerator(Arc::new(id_generator));
+        self
     }
 
     pub fn get_config(&self) -> &StationConfig {
@@ -47,265 +410,4968 @@ impl StationState {
         &self.sessions
     }
 
-    /// Return the total allocated power of the station.
+    /// Return the session with the given id, among the active and the retained finished sessions.
     ///
-    /// This is the sum of all allocated power of all sessions.
-    fn station_allocated_power(&self) -> u32 {
+    /// A session stopped recently but no longer retained is reported as `SessionGone`.
+    pub fn get_session(&self, session_id: uuid::Uuid) -> Result<&Session, SessionError> {
         self.sessions
-            .values()
-            .map(|session| session.allocated_power)
-            .sum()
+            .get(&session_id)
+            .or_else(|| {
+                self.finished_sessions
+                    .iter()
+                    .find(|session| session.session_id == session_id)
+            })
+            .ok_or_else(|| self.session_not_found(session_id))
     }
 
-    /// Return the remaining capacity of the station.
-    ///
-    /// This is the difference between the grid capacity and the total allocated power.
-    fn station_remaining_capacity(&self) -> u32 {
-        self.config.grid_capacity - self.station_allocated_power()
+    /// Return when the session was stopped, if it is among the last stopped sessions.
+    pub fn recently_stopped(
+        &self,
+        session_id: uuid::Uuid,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.stopped_session_ids
+            .iter()
+            .rev()
+            .find(|(stopped_session_id, _)| *stopped_session_id == session_id)
+            .map(|(_, stopped_at)| *stopped_at)
     }
 
-    /// Return the remaining capacity of a charger.
-    ///
-    /// This is the difference between the maximum power of the charger and the total allocated power of all sessions connected to the charger.
+    /// Return the error for a session that is neither active nor retained, telling apart the
+    /// sessions stopped recently from the unknown ones.
+    fn session_not_found(&self, session_id: uuid::Uuid) -> SessionError {
+        match self.recently_stopped(session_id) {
+            Some(stopped_at) => SessionError::SessionGone {
+                session_id,
+                stopped_at,
+            },
+            None => SessionError::SessionNotFound { session_id },
+        }
+    }
+
+    /// Return the active session on the connector, if the connector is in use.
+    pub fn session_on_connector(&self, connector_id: &ConnectorId) -> Option<&Session> {
+        self.connector_sessions
+            .get(connector_id)
+            .and_then(|session_id| self.sessions.get(session_id))
+    }
+
+    /// Return the id of the session with the given id or short id.
     ///
-    /// Note: This cannot exceed the remaining capacity of the station.
-    fn charger_remaining_capacity(&self, charger_id: &str) -> u32 {
-        let station_remaining_capacity = self.station_remaining_capacity();
-        self.chargers
-            .get(charger_id)
-            .map_or(0, |charger| {
-                charger.max_power
-                    - self
-                        .sessions
-                        .values()
-                        .filter(|session| session.connector_id.charger_id == charger_id)
-                        .map(|session| session.allocated_power)
-                        .sum::<u32>()
+    /// Short ids are matched case-insensitively against the active, the retained finished and the
+    /// queued sessions.
+    pub fn resolve_session_id(&self, id: &str) -> Result<uuid::Uuid, SessionError> {
+        if let Ok(session_id) = uuid::Uuid::parse_str(id) {
+            return Ok(session_id);
+        }
+        let short_id = id.to_lowercase();
+        self.sessions
+            .values()
+            .chain(&self.finished_sessions)
+            .find(|session| session.short_id == short_id)
+            .map(|session| session.session_id)
+            .or_else(|| {
+                self.queue
+                    .iter()
+                    .find(|queued_session| queued_session.short_id == short_id)
+                    .map(|queued_session| queued_session.session_id)
             })
-            .min(station_remaining_capacity)
+            .ok_or_else(|| SessionError::SessionIdNotFound { id: id.to_string() })
     }
 
-    #[tracing::instrument(skip(self))]
-    pub fn start_session(
-        &mut self,
-        connector_id: ConnectorId,
-        vehicle_max_power: u32,
-    ) -> Result<Session, SessionError> {
-        tracing::info!("Starting session");
-        // Check if the connector exists in the station configuration
-        if let Some(charger) = self.chargers.get(&connector_id.charger_id) {
-            if connector_id.idx == 0 || connector_id.idx > charger.connectors {
-                return Err(SessionError::ConnectorNotFound {
-                    connector_id: connector_id.clone(),
-                });
-            }
-        } else {
-            return Err(SessionError::ConnectorNotFound {
-                connector_id: connector_id.clone(),
-            });
-        }
+    /// Return the allocated power of the session over time, from the oldest sample.
+    ///
+    /// A sample is recorded every time an allocation changes the power of the session, and only
+    /// the last ones are kept.
+    pub fn allocation_history(
+        &self,
+        session_id: uuid::Uuid,
+    ) -> Result<Vec<AllocationSample>, SessionError> {
+        self.get_session(session_id)?;
+        Ok(self
+            .allocation_history
+            .get(&session_id)
+            .map(|history| history.iter().copied().collect())
+            .unwrap_or_default())
+    }
 
-        // Check if the connector is already in use
-        if self
-            .sessions
-            .values()
-            .any(|session| session.connector_id == connector_id)
+    /// Record a sample in the allocation history of the session if its allocated power changed.
+    fn record_allocation(&mut self, session_id: uuid::Uuid, allocated_power: u32) {
+        self.smooth_display_power(session_id, allocated_power);
+        let history = self.allocation_history.entry(session_id).or_default();
+        if history
+            .back()
+            .is_some_and(|sample| sample.allocated_power == allocated_power)
         {
-            return Err(SessionError::ConnectorAlreadyInUse { connector_id });
+            return;
+        }
+        if history.len() == ALLOCATION_HISTORY_RETAINED {
+            history.pop_front();
         }
+        history.push_back(AllocationSample {
+            timestamp: chrono::Utc::now(),
+            allocated_power,
+        });
+        self.last_allocation_changes
+            .insert(session_id, Instant::now());
+    }
 
-        let new_session = allocator::allocate_for_new_session(
-            self.sessions.clone(),
-            &self.chargers,
-            self.config.grid_capacity,
-            self.charger_remaining_capacity(&connector_id.charger_id),
-            &Session::new(connector_id, vehicle_max_power),
-        );
+    /// Move the display power of the session towards its allocated power by the display smoothing
+    /// factor, starting at its first allocation.
+    ///
+    /// The average advances once per allocation, whatever the time between them: a burst of
+    /// reallocations moves it faster than the same change spread over minutes.
+    fn smooth_display_power(&mut self, session_id: uuid::Uuid, allocated_power: u32) {
+        let Some(factor) = self.config.display_smoothing_factor else {
+            return;
+        };
+        let allocated_power = allocated_power as f32;
+        self.display_powers
+            .entry(session_id)
+            .and_modify(|display_power| {
+                *display_power += factor * (allocated_power - *display_power);
+            })
+            .or_insert(allocated_power);
+    }
 
-        self.sessions
-            .insert(new_session.session_id, new_session.clone());
-        Ok(new_session)
+    /// Set the display power of the session to 0 at once, when its power is cut rather than
+    /// reallocated, so that a UI does not show it slowly winding down.
+    fn reset_display_power(&mut self, session_id: uuid::Uuid) {
+        if let Some(display_power) = self.display_powers.get_mut(&session_id) {
+            *display_power = 0.0;
+        }
     }
 
-    #[tracing::instrument(skip(self))]
-    pub fn stop_session(&mut self, session_id: uuid::Uuid) {
-        tracing::info!("Stopping session");
-        self.sessions.remove(&session_id);
+    /// Return the allocated power of the session smoothed for display, e.g. so that a UI does not
+    /// jitter by a few kW between updates, or `None` if the display smoothing is not set.
+    ///
+    /// This is only meant for display: the chargers must apply the allocated power.
+    pub fn display_power(&self, session_id: uuid::Uuid) -> Option<u32> {
+        self.display_powers
+            .get(&session_id)
+            .map(|display_power| display_power.round() as u32)
     }
 
-    /// If the consumed power is lower than the allocated power, then this
-    /// will set this consumed power as the `vehicle_max_power` of the session,
-    /// to free the power for other sessions.
-    #[tracing::instrument(skip(self))]
-    pub fn power_update(
-        &mut self,
-        session_id: uuid::Uuid,
-        consumed_power: u32,
-    ) -> Result<Session, SessionError> {
-        let Some(mut previous_session) = self.sessions.get(&session_id).cloned() else {
-            return Err(SessionError::SessionNotFound { session_id });
+    /// Return whether the allocation of the session changed within the reallocation cooldown, so
+    /// that its power updates keep its allocation.
+    fn in_reallocation_cooldown(&self, session_id: uuid::Uuid) -> bool {
+        let Some(cooldown_ms) = self.config.reallocation_cooldown_ms else {
+            return false;
         };
+        self.last_allocation_changes
+            .get(&session_id)
+            .is_some_and(|changed_at| changed_at.elapsed() < Duration::from_millis(cooldown_ms))
+    }
 
-        if consumed_power < previous_session.allocated_power {
-            previous_session.vehicle_max_power = consumed_power;
+    /// Record the allocated power of every active session in their allocation history.
+    fn record_allocations(&mut self) {
+        let allocations = self
+            .sessions
+            .values()
+            .map(|session| (session.session_id, session.allocated_power))
+            .collect::<Vec<_>>();
+        for (session_id, allocated_power) in allocations {
+            self.record_allocation(session_id, allocated_power);
         }
-
-        let reallocated_session = allocator::allocate_for_new_session(
-            self.sessions.clone(),
-            &self.chargers,
-            self.config.grid_capacity,
-            self.charger_remaining_capacity(&previous_session.connector_id.charger_id)
-                + previous_session.allocated_power,
-            &previous_session,
-        );
-
-        self.sessions
-            .insert(reallocated_session.session_id, reallocated_session.clone());
-        tracing::info!(
-            "Reallocated power to {}kW",
-            reallocated_session.allocated_power,
-        );
-        Ok(reallocated_session)
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
 
-    fn default_config() -> StationConfig {
-        StationConfig {
-            station_id: "ELECTRA_PARIS_15".into(),
-            grid_capacity: 400,
-            chargers: vec![
-                ChargerConfig {
-                    id: "CP001".into(),
-                    max_power: 200,
-                    connectors: 2,
-                },
-                ChargerConfig {
-                    id: "CP002".into(),
-                    max_power: 200,
-                    connectors: 2,
-                },
-                ChargerConfig {
-                    id: "CP003".into(),
-                    max_power: 300,
-                    connectors: 2,
-                },
-            ],
-            battery: None,
-        }
+    /// Return the connector with the given label.
+    pub fn resolve_label(&self, label: &str) -> Result<ConnectorId, SessionError> {
+        self.connector_labels.get(label).cloned().ok_or_else(|| {
+            SessionError::ConnectorLabelNotFound {
+                label: label.to_string(),
+            }
+        })
     }
 
-    fn default_state() -> StationState {
-        StationState::new(default_config())
+    /// Return the grid capacity, curtailed by the active demand response and the power cap if any.
+    fn available_grid_capacity(&self) -> u32 {
+        self.curtailed_grid_capacity(self.config.grid_capacity)
     }
 
-    #[test]
-    fn test_connector_already_in_use() {
-        let mut state = default_state();
-        let connector_id = ConnectorId {
-            charger_id: "CP001".into(),
-            idx: 1,
+    /// Return the given grid capacity, curtailed by the active demand response and the safety
+    /// margin, and by the power cap if any, whichever is the lowest.
+    fn curtailed_grid_capacity(&self, grid_capacity: u32) -> u32 {
+        let grid_capacity = match &self.demand_response {
+            Some(demand_response) => (grid_capacity as f32 * demand_response.fraction) as u32,
+            None => grid_capacity,
         };
+        // The margin is at most 50%, so this cannot underflow
+        let grid_capacity =
+            grid_capacity - (grid_capacity as u64 * self.safety_margin_percent as u64 / 100) as u32;
+        self.power_cap
+            .map_or(grid_capacity, |power_cap| grid_capacity.min(power_cap))
+    }
 
-        // First session should succeed
-        let result1 = state.start_session(connector_id.clone(), 100);
-        assert!(result1.is_ok());
-
-        // Second session on same connector should fail
-        let result2 = state.start_session(connector_id.clone(), 50);
-        assert!(result2.is_err());
+    /// Return the capacity available to the sessions.
+    fn effective_capacity(&self) -> u32 {
+        if self.emergency_stopped {
+            return 0;
+        }
+        self.boosted_capacity(self.available_grid_capacity())
+    }
 
-        match result2 {
-            Err(SessionError::ConnectorAlreadyInUse {
-                connector_id: err_connector_id,
-            }) => {
-                assert_eq!(err_connector_id, connector_id);
+    /// Return the capacity available to the sessions with the given available grid capacity.
+    ///
+    /// This is the available grid capacity, boosted by the discharge of the batteries if the
+    /// station has some. Under manual control, the battery charge is taken from the grid capacity
+    /// instead.
+    fn boosted_capacity(&self, grid_capacity: u32) -> u32 {
+        let power = self.battery_control.power;
+        match self.battery_control.mode {
+            BatteryMode::Auto => {
+                let boost = self
+                    .config
+                    .batteries
+                    .iter()
+                    .map(Bess::capacity_boost)
+                    .fold(0u32, u32::saturating_add);
+                grid_capacity.saturating_add(boost)
             }
-            _ => panic!("Expected ConnectorAlreadyInUse error"),
+            BatteryMode::Charge => {
+                grid_capacity.saturating_sub(self.split_battery_power(power, Bess::charge_power))
+            }
+            BatteryMode::Discharge => {
+                grid_capacity.saturating_add(self.split_battery_power(power, Bess::discharge_power))
+            }
+            BatteryMode::Idle => grid_capacity,
         }
-
-        // Different connector should still work
-        let different_connector = ConnectorId {
-            charger_id: "CP001".into(),
-            idx: 2,
-        };
-        let result3 = state.start_session(different_connector, 75);
-        assert!(result3.is_ok());
     }
 
-    #[test]
-    fn test_connector_not_found() {
-        let mut state = default_state();
+    /// Split `power` among the batteries in configuration order, each taking what `limit` allows
+    /// of the power left, and return the combined power.
+    fn split_battery_power(&self, power: u32, limit: impl Fn(&Bess, u32) -> u32) -> u32 {
+        self.config.batteries.iter().fold(0, |total, battery| {
+            total.saturating_add(limit(battery, power.saturating_sub(total)))
+        })
+    }
 
-        // Test non-existent charger
-        let invalid_charger = ConnectorId {
-            charger_id: "INVALID".into(),
-            idx: 1,
+    /// Return the combined power flow of the batteries, if the station has some.
+    ///
+    /// In the auto mode, the batteries discharge to cover the allocated power exceeding the grid
+    /// capacity. Under manual control, they charge or discharge the requested power, within
+    /// their state of charge limits.
+    pub fn battery_flow(&self) -> Option<BatteryFlow> {
+        if self.config.batteries.is_empty() {
+            return None;
+        }
+        let power = self.battery_control.power;
+        let flow = match self.battery_control.mode {
+            BatteryMode::Auto => {
+                let power = self
+                    .station_allocated_power()
+                    .saturating_sub(self.available_grid_capacity());
+                BatteryFlow::Discharging(self.split_battery_power(power, Bess::discharge_power))
+            }
+            BatteryMode::Charge => {
+                BatteryFlow::Charging(self.split_battery_power(power, Bess::charge_power))
+            }
+            BatteryMode::Discharge => {
+                BatteryFlow::Discharging(self.split_battery_power(power, Bess::discharge_power))
+            }
+            BatteryMode::Idle => BatteryFlow::Idle,
         };
+        Some(match flow {
+            BatteryFlow::Charging(0) | BatteryFlow::Discharging(0) => BatteryFlow::Idle,
+            flow => flow,
+        })
+    }
 
-        let result = state.start_session(invalid_charger.clone(), 100);
-        assert!(result.is_err());
+    /// Override the automatic behavior of the batteries, e.g. for testing or arbitrage, and
+    /// reallocate the sessions.
+    ///
+    /// The auto mode restores the automatic behavior. The power cannot exceed the combined
+    /// battery rating, and a battery does not charge when full nor discharge below its floor.
+    #[tracing::instrument(skip(self))]
+    pub fn set_battery_control(&mut self, control: BatteryControl) -> Result<(), StationError> {
+        if self.config.batteries.is_empty() {
+            return Err(StationError::BatteryNotFound);
+        }
+        let rating = self.config.battery_power_rating();
+        if control.power > rating {
+            return Err(StationError::InvalidBatteryPower {
+                power: control.power,
+                rating,
+            });
+        }
+        tracing::info!("Setting battery control");
+        self.battery_control = control;
+        self.reallocate();
+        Ok(())
+    }
 
-        match result {
-            Err(SessionError::ConnectorNotFound {
-                connector_id: err_connector_id,
-            }) => {
-                assert_eq!(err_connector_id, invalid_charger);
+    /// Return the charger of the connector, checking that the connector exists in the station
+    /// configuration.
+    ///
+    /// An index below the connector base of the charger is invalid rather than not found.
+    fn charger(&self, connector_id: &ConnectorId) -> Result<&ChargerConfig, SessionError> {
+        match self.chargers.get(&connector_id.charger_id) {
+            Some(charger) if charger.has_connector(connector_id.idx) => Ok(charger),
+            Some(charger) if connector_id.idx < charger.connector_base() => {
+                Err(SessionError::InvalidConnectorIndex {
+                    connector_id: connector_id.clone(),
+                    connector_base: charger.connector_base(),
+                })
             }
-            _ => panic!("Expected ConnectorNotFound error"),
+            _ => Err(SessionError::ConnectorNotFound {
+                connector_id: connector_id.clone(),
+            }),
         }
+    }
 
-        // Test invalid connector index (0)
-        let invalid_idx_zero = ConnectorId {
-            charger_id: "CP001".into(),
-            idx: 0,
-        };
-
-        let result = state.start_session(invalid_idx_zero.clone(), 100);
-        assert!(result.is_err());
+    /// Return the state of charge of every battery, in configuration order.
+    pub fn battery_statuses(&self) -> Vec<BatteryStatus> {
+        self.config
+            .batteries
+            .iter()
+            .map(|battery| BatteryStatus {
+                soc_percent: battery.soc_percent(),
+                discharge_floor_active: battery.discharge_floor_active(),
+                control: self.battery_control,
+            })
+            .collect()
+    }
 
-        match result {
-            Err(SessionError::ConnectorNotFound {
-                connector_id: err_connector_id,
-            }) => {
-                assert_eq!(err_connector_id, invalid_idx_zero);
-            }
-            _ => panic!("Expected ConnectorNotFound error"),
-        }
+    /// Return the power delivered by one ampere on every phase of the connector, in W.
+    fn watts_per_ampere(&self, connector_id: &ConnectorId) -> Result<u32, SessionError> {
+        self.charger(connector_id).map(|charger| {
+            self.config
+                .voltage()
+                .saturating_mul(charger.phases() as u32)
+        })
+    }
 
-        // Test invalid connector index (too high)
-        let invalid_idx_high = ConnectorId {
-            charger_id: "CP001".into(),
-            idx: 5, // CP001 only has 2 connectors
-        };
+    /// Convert a power in kW to the current per phase in A on the connector, rounded down so
+    /// that the current never exceeds the power.
+    pub fn power_to_current(
+        &self,
+        connector_id: &ConnectorId,
+        power: u32,
+    ) -> Result<u32, SessionError> {
+        let watts_per_ampere = self.watts_per_ampere(connector_id)?;
+        let current = (power as u64 * 1000)
+            .checked_div(watts_per_ampere as u64)
+            .unwrap_or(0);
+        Ok(u32::try_from(current).unwrap_or(u32::MAX))
+    }
 
-        let result = state.start_session(invalid_idx_high.clone(), 100);
-        assert!(result.is_err());
+    /// Convert a current per phase in A to a power in kW on the connector, rounded down.
+    pub fn current_to_power(
+        &self,
+        connector_id: &ConnectorId,
+        current: u32,
+    ) -> Result<u32, SessionError> {
+        let watts_per_ampere = self.watts_per_ampere(connector_id)?;
+        let power = current as u64 * watts_per_ampere as u64 / 1000;
+        Ok(u32::try_from(power).unwrap_or(u32::MAX))
+    }
 
-        match result {
-            Err(SessionError::ConnectorNotFound {
+    /// Return the total allocated power of the station.
+    ///
+    /// This is the sum of all allocated power of all sessions.
+    fn station_allocated_power(&self) -> u32 {
+        self.sessions
+            .values()
+            .map(|session| session.allocated_power)
+            .fold(0u32, u32::saturating_add)
+    }
+
+    /// Return the remaining capacity of the station.
+    ///
+    /// This is the difference between the effective capacity and the total allocated power,
+    /// or 0 if the sessions were allocated more than the current capacity (e.g. the capacity
+    /// was reduced since their last allocation).
+    fn station_remaining_capacity(&self) -> u32 {
+        self.effective_capacity()
+            .saturating_sub(self.station_allocated_power())
+    }
+
+    /// Return the remaining capacity of a charger.
+    ///
+    /// This is the difference between the maximum power of the charger and the total allocated power of all sessions connected to the charger.
+    ///
+    /// Note: This cannot exceed the remaining capacity of the station, nor the one of the charger
+    /// group, and is 0 if the sessions were allocated more than the charger maximum power. It is
+    /// also 0 for unknown chargers, so callers must check that the charger exists with `charger`
+    /// first.
+    fn charger_remaining_capacity(&self, charger_id: &str) -> u32 {
+        let station_remaining_capacity = self.station_remaining_capacity();
+        let Some(charger) = self.chargers.get(charger_id) else {
+            return 0;
+        };
+        let allocated_power = |charger_ids: &[&str]| {
+            self.sessions
+                .values()
+                .filter(|session| charger_ids.contains(&session.connector_id.charger_id.as_str()))
+                .map(|session| session.allocated_power)
+                .fold(0u32, u32::saturating_add)
+        };
+        let group_remaining_capacity = charger
+            .group_id
+            .as_ref()
+            .and_then(|group_id| {
+                let group = self
+                    .config
+                    .groups
+                    .iter()
+                    .find(|group| group.id == *group_id)?;
+                let group_chargers = self
+                    .chargers
+                    .values()
+                    .filter(|charger| charger.group_id.as_ref() == Some(group_id))
+                    .map(|charger| charger.id.as_str())
+                    .collect::<Vec<_>>();
+                Some(
+                    group
+                        .max_power
+                        .saturating_sub(allocated_power(&group_chargers)),
+                )
+            })
+            .unwrap_or(u32::MAX);
+        charger
+            .total_max_power()
+            .saturating_sub(allocated_power(&[charger_id]))
+            .min(group_remaining_capacity)
+            .min(self.phase_remaining_capacity(charger))
+            .min(station_remaining_capacity)
+    }
+
+    /// Return the power the charger can still draw before overloading a phase of the grid
+    /// connection, or `u32::MAX` if the phases are not limited.
+    fn phase_remaining_capacity(&self, charger: &ChargerConfig) -> u32 {
+        let Some(phase_capacity) = self.config.grid_phase_capacity else {
+            return u32::MAX;
+        };
+        let loads = allocator::phase_loads(self.sessions.values(), &self.chargers);
+        loads
+            .iter()
+            .zip(charger.phase_shares())
+            .filter(|(_, share)| *share > 0.0)
+            .map(|(load, share)| ((phase_capacity as f64 - load).max(0.0) / share) as u32)
+            .min()
+            .unwrap_or(u32::MAX)
+    }
+
+    /// Return a summary of the station capacity and its current usage.
+    pub fn capacity_summary(&self) -> CapacitySummary {
+        CapacitySummary {
+            grid_capacity: self.config.grid_capacity,
+            effective_capacity: self.effective_capacity(),
+            allocated_power: self.station_allocated_power(),
+            remaining_capacity: self.station_remaining_capacity(),
+            changed_allocations: self.changed_allocation_count,
+            allocation_churn: self.allocation_churn(),
+            allocation_latency: self.allocation_latency(),
+            stability_score: self.stability_score(),
+            power_cap: self.power_cap,
+            safety_margin_percent: self.safety_margin_percent,
+            demand_response: self.demand_response.as_ref().map(|demand_response| {
+                DemandResponseStatus {
+                    fraction: demand_response.fraction,
+                    remaining_secs: demand_response
+                        .expires_at
+                        .saturating_duration_since(Instant::now())
+                        .as_secs(),
+                }
+            }),
+        }
+    }
+
+    /// Reallocate the power of every session.
+    ///
+    /// Unlike `power_update`, the new allocations are applied to all sessions at once, which
+    /// is required when the station capacity changes.
+    ///
+    /// The vehicles cannot react instantly to a lower allocation, so until its next power update,
+    /// a session keeps at least the power it reported consuming, within its previous allocation,
+    /// unless its charger is suspended.
+    /// The other sessions of the reallocation, the new sessions and the power updates only get
+    /// the capacity left by these sessions, so the station goes back within its capacity as the
+    /// sessions update.
+    fn reallocate(&mut self) {
+        self.reallocation_count += 1;
+        let started_at = Instant::now();
+        let mut reallocated_sessions = allocator::allocate_within_phases(
+            &self.sessions,
+            &self.chargers,
+            &self.config.group_capacities(),
+            self.effective_capacity(),
+            self.config.grid_phase_capacity,
+            self.config.allocation_mode,
+        );
+        self.record_allocation_duration(started_at.elapsed());
+        self.record_fair_share_targets(&reallocated_sessions);
+        let mut floored = false;
+        for session in reallocated_sessions.values_mut() {
+            // The sessions of a suspended charger drop to no power right away
+            if self
+                .suspended_chargers
+                .contains(&session.connector_id.charger_id)
+            {
+                continue;
+            }
+            let floor = self
+                .sessions
+                .get(&session.session_id)
+                .map_or(0, |previous| previous.allocated_power)
+                .min(session.consumed_power);
+            floored |= floor > session.allocated_power;
+            session.allocated_power = session.allocated_power.max(floor);
+        }
+        if floored {
+            self.cap_raised_allocations(&mut reallocated_sessions);
+        }
+        self.apply_fairness_epsilon(&mut reallocated_sessions);
+        let changed_sessions = reallocated_sessions
+            .values()
+            .filter(|session| {
+                self.sessions
+                    .get(&session.session_id)
+                    .is_some_and(|previous| previous.allocated_power != session.allocated_power)
+            })
+            .count();
+        self.record_churn(changed_sessions);
+        self.sessions = reallocated_sessions;
+        self.record_allocations();
+        self.notify_reallocation();
+        // The reallocation may have freed capacity, e.g. when a capacity cut ends
+        self.promote_queued_sessions();
+    }
+
+    /// Cap the allocations raised above their previous allocation, so that the raises only take
+    /// the capacity of the station, of their charger and of their group left by the other
+    /// sessions, including the ones keeping the power they reported consuming.
+    ///
+    /// The raised sessions are served by id.
+    fn cap_raised_allocations(&self, sessions: &mut HashMap<uuid::Uuid, Session>) {
+        let previous_power = |session_id: &uuid::Uuid| {
+            self.sessions
+                .get(session_id)
+                .map_or(0, |previous| previous.allocated_power)
+        };
+        let mut raised_sessions = Vec::new();
+        for session in sessions.values_mut() {
+            let previous_power = previous_power(&session.session_id);
+            if session.allocated_power > previous_power {
+                raised_sessions.push((session.session_id, session.allocated_power));
+                session.allocated_power = previous_power;
+            }
+        }
+        raised_sessions.sort();
+
+        // The powers left once the raises are taken back
+        let mut station_power = 0u32;
+        let mut charger_powers = HashMap::<String, u32>::new();
+        let mut group_powers = HashMap::<String, u32>::new();
+        for session in sessions.values() {
+            station_power = station_power.saturating_add(session.allocated_power);
+            let charger_id = &session.connector_id.charger_id;
+            let charger_power = charger_powers.entry(charger_id.clone()).or_default();
+            *charger_power = charger_power.saturating_add(session.allocated_power);
+            if let Some(group_id) = self
+                .chargers
+                .get(charger_id)
+                .and_then(|charger| charger.group_id.clone())
+            {
+                let group_power = group_powers.entry(group_id).or_default();
+                *group_power = group_power.saturating_add(session.allocated_power);
+            }
+        }
+        let group_capacities = self.config.group_capacities();
+        let effective_capacity = self.effective_capacity();
+        for (session_id, raised_power) in raised_sessions {
+            let session = sessions
+                .get_mut(&session_id)
+                .expect("The raised sessions are in the sessions");
+            let Some(charger) = self.chargers.get(&session.connector_id.charger_id) else {
+                continue;
+            };
+            let charger_power = charger_powers
+                .get_mut(&charger.id)
+                .expect("The charger of a session has its power");
+            let mut group_power = charger
+                .group_id
+                .as_ref()
+                .and_then(|group_id| Some((group_capacities.get(group_id)?, group_id)))
+                .map(|(group_capacity, group_id)| {
+                    let group_power = group_powers
+                        .get_mut(group_id)
+                        .expect("The group of a charger with sessions has its power");
+                    (*group_capacity, group_power)
+                });
+            let raise = (raised_power - session.allocated_power)
+                .min(effective_capacity.saturating_sub(station_power))
+                .min(charger.total_max_power().saturating_sub(*charger_power))
+                .min(
+                    group_power
+                        .as_ref()
+                        .map_or(u32::MAX, |(group_capacity, group_power)| {
+                            group_capacity.saturating_sub(**group_power)
+                        }),
+                );
+            session.allocated_power += raise;
+            station_power += raise;
+            *charger_power += raise;
+            if let Some((_, group_power)) = &mut group_power {
+                **group_power += raise;
+            }
+        }
+    }
+
+    /// Return the previous allocation of a session to keep if its new allocation differs from it
+    /// by less than the fairness epsilon.
+    ///
+    /// The kept allocation is capped at the vehicle max power, which may have been lowered since
+    /// the previous allocation.
+    fn fairness_kept_power(&self, previous: &Session, session: &Session) -> Option<u32> {
+        let kept_power = previous.allocated_power.min(session.vehicle_max_power);
+        (kept_power.abs_diff(session.allocated_power) < self.config.fairness_epsilon
+            && session.pinned_power.is_none())
+        .then_some(kept_power)
+    }
+
+    /// Keep the previous allocation of the sessions whose allocation changes by less than the
+    /// fairness epsilon.
+    ///
+    /// Keeping a previous allocation higher than the new one could exceed the station, charger
+    /// or group limits. In that case, only the previous allocations lower than the new ones are
+    /// kept.
+    fn apply_fairness_epsilon(&self, reallocated_sessions: &mut HashMap<uuid::Uuid, Session>) {
+        if self.config.fairness_epsilon == 0 {
+            return;
+        }
+        let mut kept_sessions = reallocated_sessions.clone();
+        for session in kept_sessions.values_mut() {
+            if let Some(previous) = self.sessions.get(&session.session_id)
+                && let Some(kept_power) = self.fairness_kept_power(previous, session)
+            {
+                session.allocated_power = kept_power;
+            }
+        }
+        if self.within_limits(&kept_sessions) {
+            *reallocated_sessions = kept_sessions;
+            return;
+        }
+        for session in reallocated_sessions.values_mut() {
+            if let Some(previous) = self.sessions.get(&session.session_id)
+                && let Some(kept_power) = self.fairness_kept_power(previous, session)
+                && kept_power < session.allocated_power
+            {
+                session.allocated_power = kept_power;
+            }
+        }
+    }
+
+    /// Return whether the allocations of the sessions fit in the station capacity, in the
+    /// capacity of the phases, and in the max power of their chargers and charger groups.
+    fn within_limits(&self, sessions: &HashMap<uuid::Uuid, Session>) -> bool {
+        if let Some(phase_capacity) = self.config.grid_phase_capacity
+            && allocator::phase_loads(sessions.values(), &self.chargers)
+                .iter()
+                .any(|load| *load > phase_capacity as f64 + allocator::PHASE_LOAD_TOLERANCE)
+        {
+            return false;
+        }
+        let mut charger_powers = HashMap::<&str, u32>::new();
+        for session in sessions.values() {
+            let charger_power = charger_powers
+                .entry(session.connector_id.charger_id.as_str())
+                .or_default();
+            *charger_power = charger_power.saturating_add(session.allocated_power);
+        }
+        let mut group_powers = HashMap::<&str, u32>::new();
+        for (charger_id, power) in &charger_powers {
+            let Some(charger) = self.chargers.get(*charger_id) else {
+                return false;
+            };
+            if *power > charger.total_max_power() {
+                return false;
+            }
+            if let Some(group_id) = &charger.group_id {
+                let group_power = group_powers.entry(group_id.as_str()).or_default();
+                *group_power = group_power.saturating_add(*power);
+            }
+        }
+        let group_capacities = self.config.group_capacities();
+        group_powers.iter().all(|(group_id, power)| {
+            group_capacities
+                .get(*group_id)
+                .is_none_or(|max_power| power <= max_power)
+        }) && charger_powers
+            .values()
+            .copied()
+            .fold(0u32, u32::saturating_add)
+            <= self.effective_capacity()
+    }
+
+    /// Register a callback invoked with the active sessions, sorted by id, every time their
+    /// allocations change: when a session starts or stops, on power updates and on reallocations.
+    ///
+    /// The callback runs synchronously while the state is borrowed, so it must be cheap, e.g. to
+    /// push the new allocations to the chargers without polling. The callbacks are kept across
+    /// configuration updates, by `reconfigure` and `carry_over_controls`.
+    pub fn on_reallocation(&mut self, observer: impl Fn(&[Session]) + Send + Sync + 'static) {
+        self.reallocation_observers
+            .push(ReallocationObserver(Arc::new(observer)));
+    }
+
+    /// Invoke the reallocation observers with the active sessions.
+    fn notify_reallocation(&self) {
+        if self.reallocation_observers.is_empty() {
+            return;
+        }
+        let mut sessions = self.sessions.values().cloned().collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.session_id);
+        for observer in &self.reallocation_observers {
+            (observer.0)(&sessions);
+        }
+    }
+
+    /// Return the number of times the allocator ran since the station started.
+    pub fn reallocation_count(&self) -> u64 {
+        self.reallocation_count
+    }
+
+    /// Record the number of sessions whose allocation changed in a reallocation.
+    /// Keep the allocations of a full reallocation as the fair-share targets of the sessions, so
+    /// that the fairness does not run the allocator.
+    fn record_fair_share_targets(&mut self, reallocated_sessions: &HashMap<uuid::Uuid, Session>) {
+        self.fair_share_targets = reallocated_sessions
+            .values()
+            .map(|session| (session.session_id, session.allocated_power))
+            .collect();
+    }
+
+    fn record_churn(&mut self, changed_sessions: usize) {
+        self.changed_allocation_count += changed_sessions as u64;
+        if self.allocation_churn.len() == ALLOCATION_CHURN_WINDOW {
+            self.allocation_churn.pop_front();
+        }
+        self.allocation_churn.push_back(changed_sessions);
+    }
+
+    /// Return the number of times the allocation of a session changed in a reallocation since
+    /// the station started.
+    pub fn changed_allocation_count(&self) -> u64 {
+        self.changed_allocation_count
+    }
+
+    /// Return the average number of sessions whose allocation changed per reallocation, over the
+    /// last reallocations.
+    ///
+    /// A high churn means the allocations keep moving, e.g. with vehicles whose power oscillates,
+    /// which might call for limiting the ramps.
+    pub fn allocation_churn(&self) -> f32 {
+        if self.allocation_churn.is_empty() {
+            return 0.0;
+        }
+        self.allocation_churn.iter().sum::<usize>() as f32 / self.allocation_churn.len() as f32
+    }
+
+    /// Return the stability of the allocations, from 0 (thrashing) to 100 (stable), for operators
+    /// to alarm on.
+    ///
+    /// The score is the weighted average of three inputs, each from 0 (worst) to 1 (best):
+    /// - 40% for the churn: 1 minus the allocation churn per active session, floored at 0;
+    /// - 30% for the flapping: 1 minus the share of direction reversals over the last 10
+    ///   allocation changes of the active sessions, e.g. down then up again;
+    /// - 30% for the fairness: Jain's index of the allocated power of the active sessions relative
+    ///   to their fair-share target, 1 when they all get what a full reallocation would give them.
+    pub fn stability_score(&self) -> u8 {
+        let active_sessions = self.sessions.len().max(1) as f32;
+        let churn = (self.allocation_churn() / active_sessions).min(1.0);
+        let score = 0.4 * (1.0 - churn) + 0.3 * (1.0 - self.flapping()) + 0.3 * self.fairness();
+        (score * 100.0).round().clamp(0.0, 100.0) as u8
+    }
+
+    /// Return the share of direction reversals over the last allocation changes of the active
+    /// sessions, 0 if they have no consecutive changes.
+    fn flapping(&self) -> f32 {
+        let (reversals, opportunities) = self
+            .sessions
+            .keys()
+            .filter_map(|session_id| self.allocation_history.get(session_id))
+            .map(|history| {
+                let powers = history
+                    .iter()
+                    .skip(history.len().saturating_sub(FLAPPING_SAMPLES))
+                    .map(|sample| sample.allocated_power)
+                    .collect::<Vec<_>>();
+                // The samples are only recorded on changes, so consecutive ones always differ
+                let rises = powers
+                    .windows(2)
+                    .map(|pair| pair[1] > pair[0])
+                    .collect::<Vec<_>>();
+                let reversals = rises.windows(2).filter(|pair| pair[0] != pair[1]).count();
+                (reversals, rises.len().saturating_sub(1))
+            })
+            .fold((0, 0), |(reversals, opportunities), (r, o)| {
+                (reversals + r, opportunities + o)
+            });
+        if opportunities == 0 {
+            return 0.0;
+        }
+        reversals as f32 / opportunities as f32
+    }
+
+    /// Return Jain's fairness index of the allocated power of the active sessions relative to
+    /// their fair-share target, from 1/n to 1, and 1 without sessions to compare.
+    ///
+    /// The target of a session is its allocation in the last run of the allocator, before the
+    /// floors, the epsilon, the cooldown and the hardcap, so that the priorities, deadlines and
+    /// pins, which allocate unevenly on purpose, do not lower the fairness. The sessions started
+    /// since the last reallocation, which only got the capacity left, do.
+    fn fairness(&self) -> f32 {
+        let shares = self
+            .sessions
+            .values()
+            .filter_map(|session| {
+                let target = *self.fair_share_targets.get(&session.session_id)?;
+                (target > 0).then(|| session.allocated_power.min(target) as f32 / target as f32)
+            })
+            .collect::<Vec<_>>();
+        let sum_of_squares = shares.iter().map(|share| share * share).sum::<f32>();
+        if sum_of_squares == 0.0 {
+            return 1.0;
+        }
+        shares.iter().sum::<f32>().powi(2) / (shares.len() as f32 * sum_of_squares)
+    }
+
+    /// Record the time taken by a run of the allocator.
+    fn record_allocation_duration(&mut self, duration: Duration) {
+        if self.allocation_durations.len() == ALLOCATION_DURATION_WINDOW {
+            self.allocation_durations.pop_front();
+        }
+        self.allocation_durations.push_back(duration);
+    }
+
+    /// Return the min, average and max time taken by the allocator over its last runs, or `None`
+    /// if it never ran.
+    ///
+    /// The allocation runs while the state is locked, so a growing latency with the number of
+    /// sessions delays every request.
+    pub fn allocation_latency(&self) -> Option<AllocationLatency> {
+        let min = self.allocation_durations.iter().min()?;
+        let max = self.allocation_durations.iter().max()?;
+        let total = self.allocation_durations.iter().sum::<Duration>();
+        Some(AllocationLatency {
+            samples: self.allocation_durations.len(),
+            min_us: min.as_micros() as u64,
+            avg_us: (total / self.allocation_durations.len() as u32).as_micros() as u64,
+            max_us: max.as_micros() as u64,
+        })
+    }
+
+    /// Scale the grid capacity by `fraction` for `duration`, and reallocate the sessions.
+    ///
+    /// This replaces any active demand response. Return the id of the demand response,
+    /// to be given to `end_demand_response` once the duration has elapsed.
+    #[tracing::instrument(skip(self))]
+    pub fn apply_demand_response(
+        &mut self,
+        fraction: f32,
+        duration: Duration,
+    ) -> Result<u64, StationError> {
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(StationError::InvalidDemandResponse { fraction });
+        }
+        let expires_at = Instant::now().checked_add(duration).ok_or(
+            StationError::InvalidDemandResponseDuration {
+                duration_secs: duration.as_secs(),
+            },
+        )?;
+        tracing::info!("Applying demand response");
+        self.demand_response_count += 1;
+        self.demand_response = Some(DemandResponse {
+            id: self.demand_response_count,
+            fraction,
+            expires_at,
+        });
+        self.reallocate();
+        Ok(self.demand_response_count)
+    }
+
+    /// End the demand response with the given id and reallocate the sessions.
+    ///
+    /// Does nothing if that demand response has already been replaced by another one.
+    #[tracing::instrument(skip(self))]
+    pub fn end_demand_response(&mut self, id: u64) {
+        if self
+            .demand_response
+            .as_ref()
+            .is_some_and(|demand_response| demand_response.id == id)
+        {
+            tracing::info!("Ending demand response");
+            self.demand_response = None;
+            self.reallocate();
+        }
+    }
+
+    /// Cap the grid capacity to `max_power` until `clear_power_cap` is called, e.g. on a utility
+    /// request, and reallocate the sessions.
+    ///
+    /// Unlike a demand response, the cap has no duration. When both apply, the lowest capacity
+    /// wins. This replaces any previous cap.
+    #[tracing::instrument(skip(self))]
+    pub fn set_power_cap(&mut self, max_power: u32) {
+        tracing::info!("Capping the station power");
+        self.power_cap = Some(max_power);
+        self.reallocate();
+    }
+
+    /// Clear the power cap, if any, and reallocate the sessions.
+    #[tracing::instrument(skip(self))]
+    pub fn clear_power_cap(&mut self) {
+        tracing::info!("Clearing the station power cap");
+        if self.power_cap.take().is_some() {
+            self.reallocate();
+        }
+    }
+
+    /// Return the ceiling of the grid capacity set by `set_power_cap`, if any.
+    pub fn power_cap(&self) -> Option<u32> {
+        self.power_cap
+    }
+
+    /// Keep `percent` of the grid capacity as headroom, e.g. to tighten it during a heat wave,
+    /// and reallocate the sessions.
+    ///
+    /// The margin applies on top of a demand response, and the power cap still applies when it is
+    /// lower. A margin of 0 removes it. Fails if the margin exceeds 50%.
+    #[tracing::instrument(skip(self))]
+    pub fn set_safety_margin(&mut self, percent: u8) -> Result<(), StationError> {
+        if percent > MAX_SAFETY_MARGIN_PERCENT {
+            return Err(StationError::InvalidSafetyMargin { percent });
+        }
+        tracing::info!("Setting the station safety margin");
+        if self.safety_margin_percent != percent {
+            self.safety_margin_percent = percent;
+            self.reallocate();
+        }
+        Ok(())
+    }
+
+    /// Return the share of the grid capacity kept as headroom, in percent.
+    pub fn safety_margin_percent(&self) -> u8 {
+        self.safety_margin_percent
+    }
+
+    /// Cap the maximum power of a charger to `max_power` until `clear_charger_derating` is called,
+    /// e.g. when the charger reports thermal throttling, and reallocate the sessions.
+    ///
+    /// This replaces any previous derating of the charger. A derating above the configured
+    /// maximum power has no effect.
+    #[tracing::instrument(skip(self))]
+    pub fn derate_charger(&mut self, charger_id: &str, max_power: u32) -> Result<(), StationError> {
+        tracing::info!("Derating charger");
+        self.derated_chargers
+            .insert(charger_id.to_string(), max_power);
+        self.apply_charger_limits(charger_id)
+    }
+
+    /// Restore the configured maximum power of a derated charger, and reallocate the sessions.
+    #[tracing::instrument(skip(self))]
+    pub fn clear_charger_derating(&mut self, charger_id: &str) -> Result<(), StationError> {
+        tracing::info!("Clearing charger derating");
+        self.derated_chargers.remove(charger_id);
+        self.apply_charger_limits(charger_id)
+    }
+
+    /// Stop allocating power to the sessions of a charger until `resume_charger` is called, e.g.
+    /// for a partial maintenance, and reallocate the sessions.
+    ///
+    /// Unlike stopping them, the sessions stay active with no power, and the capacity they free
+    /// goes to the other chargers.
+    #[tracing::instrument(skip(self))]
+    pub fn suspend_charger(&mut self, charger_id: &str) -> Result<(), StationError> {
+        tracing::info!("Suspending charger");
+        self.suspended_chargers.insert(charger_id.to_string());
+        self.apply_charger_limits(charger_id)
+    }
+
+    /// Allocate power to the sessions of a suspended charger again, and reallocate the sessions.
+    #[tracing::instrument(skip(self))]
+    pub fn resume_charger(&mut self, charger_id: &str) -> Result<(), StationError> {
+        tracing::info!("Resuming charger");
+        self.suspended_chargers.remove(charger_id);
+        self.apply_charger_limits(charger_id)
+    }
+
+    /// Set the maximum power of the charger used by the allocation to its configured maximum
+    /// power, capped by its derating if any, or to 0 if it is suspended.
+    fn apply_charger_limits(&mut self, charger_id: &str) -> Result<(), StationError> {
+        let configured_max_power = self
+            .config
+            .chargers
+            .iter()
+            .find(|charger| charger.id == charger_id)
+            .map(|charger| charger.max_power);
+        let (Some(configured_max_power), Some(charger)) =
+            (configured_max_power, self.chargers.get_mut(charger_id))
+        else {
+            self.derated_chargers.remove(charger_id);
+            self.suspended_chargers.remove(charger_id);
+            return Err(StationError::ChargerNotFound {
+                charger_id: charger_id.to_string(),
+            });
+        };
+        let suspended = self.suspended_chargers.contains(charger_id);
+        charger.max_power = if suspended {
+            0
+        } else {
+            self.derated_chargers
+                .get(charger_id)
+                .map_or(configured_max_power, |max_power| {
+                    configured_max_power.min(*max_power)
+                })
+        };
+        self.reallocate();
+        if suspended {
+            let suspended_sessions = self
+                .sessions
+                .values()
+                .filter(|session| session.connector_id.charger_id == charger_id)
+                .map(|session| session.session_id)
+                .collect::<Vec<_>>();
+            for session_id in suspended_sessions {
+                self.reset_display_power(session_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse new sessions until `undrain` is called, e.g. before a maintenance. The active
+    /// sessions continue, and can still be updated and stopped.
+    #[tracing::instrument(skip(self))]
+    pub fn drain(&mut self) {
+        tracing::info!("Draining station");
+        self.draining = true;
+    }
+
+    /// Accept new sessions again after `drain`.
+    #[tracing::instrument(skip(self))]
+    pub fn undrain(&mut self) {
+        tracing::info!("Undraining station");
+        self.draining = false;
+        self.promote_queued_sessions();
+    }
+
+    /// Return whether the station is draining, refusing new sessions.
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Cut the power of every session at once, e.g. on a safety incident, until
+    /// `clear_emergency_stop` is called.
+    ///
+    /// The sessions stay active with no power, and the charging ones are suspended. The stop is
+    /// latched: new sessions are refused, and the power updates and reallocations give no power
+    /// until it is cleared. The allocator is not run, so the stop is immediate.
+    #[tracing::instrument(skip(self))]
+    pub fn emergency_stop(&mut self) {
+        tracing::error!(
+            sessions = self.sessions.len(),
+            "EMERGENCY STOP, cutting the power of every session"
+        );
+        self.emergency_stopped = true;
+        let mut changed_sessions = Vec::new();
+        for session in self.sessions.values_mut() {
+            if session.allocated_power > 0 {
+                changed_sessions.push(session.session_id);
+            }
+            session.allocated_power = 0;
+            // A pending session has not drawn power yet, so it has nothing to suspend
+            if session.state == SessionState::Charging {
+                session.state = SessionState::Suspended;
+            }
+        }
+        self.record_churn(changed_sessions.len());
+        for session_id in changed_sessions {
+            self.record_allocation(session_id, 0);
+            self.reset_display_power(session_id);
+        }
+        self.notify_reallocation();
+    }
+
+    /// Release the emergency stop, and reallocate the sessions.
+    ///
+    /// The suspended sessions get power again, and go back to charging at their next power
+    /// update reporting a consumption.
+    #[tracing::instrument(skip(self))]
+    pub fn clear_emergency_stop(&mut self) {
+        tracing::warn!("Clearing the emergency stop");
+        self.emergency_stopped = false;
+        self.reallocate();
+    }
+
+    /// Return whether the station is emergency stopped, giving no power to the sessions.
+    pub fn is_emergency_stopped(&self) -> bool {
+        self.emergency_stopped
+    }
+
+    /// Return the status of the chargers, in the order of the station configuration.
+    pub fn charger_statuses(&self) -> Vec<ChargerStatus> {
+        self.config
+            .chargers
+            .iter()
+            .filter_map(|config| {
+                let charger = self.chargers.get(&config.id)?;
+                let charger_sessions = self
+                    .sessions
+                    .values()
+                    .filter(|session| session.connector_id.charger_id == config.id);
+                let queued_sessions = self
+                    .queue
+                    .iter()
+                    .filter(|queued_session| queued_session.connector_id.charger_id == config.id)
+                    .count();
+                Some(ChargerStatus {
+                    id: config.id.clone(),
+                    configured_max_power: config.max_power,
+                    max_power: charger.max_power,
+                    derated_max_power: self.derated_chargers.get(&config.id).copied(),
+                    suspended: self.suspended_chargers.contains(&config.id),
+                    allocated_power: charger_sessions
+                        .clone()
+                        .map(|session| session.allocated_power)
+                        .fold(0u32, u32::saturating_add),
+                    // The connector of a queued session is taken as well
+                    available_connectors: config.connectors.saturating_sub(
+                        u8::try_from(charger_sessions.count() + queued_sessions).unwrap_or(u8::MAX),
+                    ),
+                    firmware_version: config.firmware_version.clone(),
+                    vendor: config.vendor.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn start_session(
+        &mut self,
+        connector_id: ConnectorId,
+        vehicle_max_power: u32,
+    ) -> Result<Session, SessionError> {
+        self.start_session_with_options(connector_id, vehicle_max_power, SessionOptions::default())
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn start_session_with_options(
+        &mut self,
+        connector_id: ConnectorId,
+        vehicle_max_power: u32,
+        options: SessionOptions,
+    ) -> Result<Session, SessionError> {
+        tracing::info!("Starting session");
+        self.check_connector_available(&connector_id)?;
+        self.check_capacity()?;
+        let vehicle_max_power = self.cap_vehicle_power(vehicle_max_power);
+        let session_id = self.next_session_id()?;
+        self.start_new_session(Session::with_id(
+            session_id,
+            connector_id,
+            vehicle_max_power,
+            options,
+        ))
+    }
+
+    /// Start a session, or queue it if the station has `queue_when_full` set and the session
+    /// would get no power.
+    ///
+    /// The sessions do not skip the queue: while sessions are waiting, the new ones are queued
+    /// after them.
+    #[tracing::instrument(skip(self))]
+    pub fn admit_session(
+        &mut self,
+        connector_id: ConnectorId,
+        vehicle_max_power: u32,
+        options: SessionOptions,
+    ) -> Result<SessionAdmission, SessionError> {
+        self.check_connector_available(&connector_id)?;
+        if !self.config.queue_when_full
+            || (self.queue.is_empty()
+                && self.charger_remaining_capacity(&connector_id.charger_id) > 0)
+        {
+            return self
+                .start_session_with_options(connector_id, vehicle_max_power, options)
+                .map(SessionAdmission::Started);
+        }
+        let session_id = self.next_session_id()?;
+        let queued_session = QueuedSession {
+            session_id,
+            short_id: models::short_id(&session_id),
+            connector_id,
+            vehicle_max_power: self.cap_vehicle_power(vehicle_max_power),
+            queued_at: chrono::Utc::now(),
+            options,
+        };
+        self.queue.push_back(queued_session.clone());
+        let position = self.queue.len();
+        tracing::info!(
+            session_id = %queued_session.session_id,
+            position,
+            "Station full, queueing session"
+        );
+        Ok(SessionAdmission::Queued {
+            queued_session,
+            position,
+        })
+    }
+
+    /// Return the sessions waiting for capacity, from the first to start.
+    pub fn queued_sessions(&self) -> impl Iterator<Item = &QueuedSession> {
+        self.queue.iter()
+    }
+
+    /// Remove a session from the queue, e.g. when its driver leaves before it started.
+    #[tracing::instrument(skip(self))]
+    pub fn cancel_queued_session(
+        &mut self,
+        session_id: uuid::Uuid,
+    ) -> Result<QueuedSession, SessionError> {
+        let position = self
+            .queue
+            .iter()
+            .position(|queued_session| queued_session.session_id == session_id)
+            .ok_or(SessionError::QueuedSessionNotFound { session_id })?;
+        tracing::info!("Cancelling queued session");
+        let queued_session = self
+            .queue
+            .remove(position)
+            .expect("The position is in the queue");
+        // The next sessions may wait on chargers with capacity left
+        self.promote_queued_sessions();
+        Ok(queued_session)
+    }
+
+    /// Return the queued sessions started since the last call, from the first started, e.g. to
+    /// schedule their expiry and the end of their warm-up.
+    ///
+    /// The queued sessions start when capacity frees: when a session stops, after a reallocation
+    /// or a power update, or when the station stops draining.
+    pub fn take_promoted_sessions(&mut self) -> Vec<Session> {
+        std::mem::take(&mut self.promoted_sessions)
+    }
+
+    /// Start the queued sessions in their order while the charger of the first one has capacity
+    /// left, and keep them for `take_promoted_sessions`.
+    ///
+    /// The queue waits while the station is emergency stopped or draining. A queued session that
+    /// can no longer start, e.g. because its charger was removed, is dropped from the queue.
+    fn promote_queued_sessions(&mut self) {
+        if self.emergency_stopped || self.draining {
+            return;
+        }
+        while let Some(queued_session) = self.queue.front() {
+            if self.charger_remaining_capacity(&queued_session.connector_id.charger_id) == 0 {
+                break;
+            }
+            let queued_session = self.queue.pop_front().expect("The queue is not empty");
+            let session_id = queued_session.session_id;
+            match self
+                .check_connector_available(&queued_session.connector_id)
+                .and_then(|()| {
+                    self.start_new_session(Session::with_id(
+                        session_id,
+                        queued_session.connector_id,
+                        queued_session.vehicle_max_power,
+                        queued_session.options,
+                    ))
+                }) {
+                Ok(session) => {
+                    tracing::info!(%session_id, "Starting queued session");
+                    self.promoted_sessions.push(session);
+                }
+                Err(error) => tracing::warn!(%session_id, %error, "Dropping queued session"),
+            }
+        }
+    }
+
+    /// Return a new session id, whose short id is not used by another session.
+    ///
+    /// Give up after `SESSION_ID_ATTEMPTS` ids, e.g. with an id generator repeating its ids.
+    fn next_session_id(&self) -> Result<uuid::Uuid, SessionError> {
+        // Short ids are much shorter than the session ids, so make sure they stay unique
+        let is_used = |session_id: &uuid::Uuid| {
+            // The queued sessions are resolved as well
+            self.resolve_session_id(&models::short_id(session_id))
+                .is_ok()
+        };
+        std::iter::repeat_with(|| self.id_generator.0.next_id())
+            .take(SESSION_ID_ATTEMPTS)
+            .find(|session_id| !is_used(session_id))
+            .ok_or(SessionError::SessionIdsExhausted {
+                attempts: SESSION_ID_ATTEMPTS,
+            })
+    }
+
+    /// Allocate power to a new session, and add it to the active sessions.
+    fn start_new_session(&mut self, new_session: Session) -> Result<Session, SessionError> {
+        self.reallocation_count += 1;
+        // The other sessions keep their allocations until they are reallocated
+        self.record_churn(0);
+        let started_at = Instant::now();
+        let new_session = allocator::allocate_for_new_session(
+            self.sessions.clone(),
+            &self.chargers,
+            &self.config.group_capacities(),
+            self.effective_capacity(),
+            self.config.grid_phase_capacity,
+            self.charger_remaining_capacity(&new_session.connector_id.charger_id),
+            self.config.allocation_mode,
+            &new_session,
+        );
+        self.record_allocation_duration(started_at.elapsed());
+        let (new_session, allocations) = new_session?;
+        self.fair_share_targets = allocations;
+
+        self.sessions
+            .insert(new_session.session_id, new_session.clone());
+        self.connector_sessions
+            .insert(new_session.connector_id.clone(), new_session.session_id);
+        self.record_allocation(new_session.session_id, new_session.allocated_power);
+        self.notify_reallocation();
+        Ok(new_session)
+    }
+
+    /// Clamp a vehicle max power to the `max_vehicle_power` of the station, if set.
+    ///
+    /// A warning is logged when the power is clamped.
+    fn cap_vehicle_power(&self, vehicle_max_power: u32) -> u32 {
+        match self.config.max_vehicle_power {
+            Some(max_vehicle_power) if vehicle_max_power > max_vehicle_power => {
+                tracing::warn!(
+                    requested = vehicle_max_power,
+                    max_vehicle_power,
+                    "Vehicle max power is above the station maximum, clamping it"
+                );
+                max_vehicle_power
+            }
+            _ => vehicle_max_power,
+        }
+    }
+
+    /// Check that the station has capacity to start a session.
+    ///
+    /// Without the queue, the sessions would wait with no power until the capacity is restored.
+    fn check_capacity(&self) -> Result<(), SessionError> {
+        if self.effective_capacity() == 0 {
+            return Err(SessionError::InsufficientCapacity);
+        }
+        Ok(())
+    }
+
+    /// Check that the station accepts new sessions, and that the connector exists in the station
+    /// configuration and is not in use.
+    fn check_connector_available(&self, connector_id: &ConnectorId) -> Result<(), SessionError> {
+        if self.emergency_stopped {
+            return Err(SessionError::EmergencyStop);
+        }
+        if self.draining {
+            return Err(SessionError::StationDraining);
+        }
+        self.charger(connector_id)?;
+        if self.connector_sessions.contains_key(connector_id)
+            || self
+                .queue
+                .iter()
+                .any(|queued_session| queued_session.connector_id == *connector_id)
+        {
+            return Err(SessionError::ConnectorAlreadyInUse {
+                connector_id: connector_id.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Return the change in allocated power of every active session if a session were started
+    /// with these parameters, without starting it. Negative changes are reductions.
+    ///
+    /// The changes are the ones of a full reallocation, which the sessions get at their next
+    /// power update after the session started. The changes are sorted from the largest
+    /// reduction, the session id breaking ties.
+    pub fn marginal_impact(
+        &self,
+        connector_id: ConnectorId,
+        vehicle_max_power: u32,
+        options: SessionOptions,
+    ) -> Result<Vec<(uuid::Uuid, i64)>, SessionError> {
+        let (_, reallocated_sessions) =
+            self.preview_allocation(connector_id, vehicle_max_power, options)?;
+
+        let mut impacts = self
+            .sessions
+            .values()
+            .filter_map(|session| {
+                let reallocated_session = reallocated_sessions.get(&session.session_id)?;
+                let change =
+                    reallocated_session.allocated_power as i64 - session.allocated_power as i64;
+                Some((session.session_id, change))
+            })
+            .collect::<Vec<_>>();
+        impacts.sort_by_key(|(session_id, change)| (*change, *session_id));
+        Ok(impacts)
+    }
+
+    /// Return the power a session would get if it were started with these parameters, and the
+    /// limit bounding it, without starting it.
+    ///
+    /// The power is the one of a full reallocation, like for `marginal_impact`. When several
+    /// limits are reached, the one closest to the vehicle is returned.
+    pub fn feasibility(
+        &self,
+        connector_id: ConnectorId,
+        vehicle_max_power: u32,
+        options: SessionOptions,
+    ) -> Result<Feasibility, SessionError> {
+        let (new_session, reallocated_sessions) =
+            self.preview_allocation(connector_id, vehicle_max_power, options)?;
+        let power = reallocated_sessions
+            .get(&new_session.session_id)
+            .map_or(0, |session| session.allocated_power);
+        let charger_id = &new_session.connector_id.charger_id;
+        let charger = &self.chargers[charger_id];
+        let allocated_power = |charger_ids: &HashSet<&str>| {
+            reallocated_sessions
+                .values()
+                .filter(|session| charger_ids.contains(session.connector_id.charger_id.as_str()))
+                .map(|session| session.allocated_power)
+                .fold(0u32, u32::saturating_add)
+        };
+        let charger_full = match charger.sharing {
+            SharingMode::Dedicated => power >= charger.max_power,
+            SharingMode::Sequential => {
+                power >= charger.max_power
+                    || self
+                        .sessions
+                        .values()
+                        .any(|session| &session.connector_id.charger_id == charger_id)
+            }
+            SharingMode::Shared => {
+                allocated_power(&HashSet::from([charger_id.as_str()])) >= charger.max_power
+            }
+        };
+        let group_full = charger.group_id.as_ref().is_some_and(|group_id| {
+            let Some(group_capacity) = self.config.group_capacities().get(group_id).copied() else {
+                return false;
+            };
+            let group_charger_ids = self
+                .chargers
+                .values()
+                .filter(|charger| charger.group_id.as_ref() == Some(group_id))
+                .map(|charger| charger.id.as_str())
+                .collect();
+            allocated_power(&group_charger_ids) >= group_capacity
+        });
+
+        let binding_constraint = if power >= new_session.vehicle_max_power {
+            BindingConstraint::Vehicle
+        } else if charger
+            .connector_max_power_of(new_session.connector_id.idx)
+            .is_some_and(|connector_max_power| power >= connector_max_power)
+        {
+            BindingConstraint::Connector
+        } else if charger_full {
+            BindingConstraint::Charger
+        } else if group_full {
+            BindingConstraint::Group
+        } else {
+            // The other sessions took the station capacity, or left too little to be usable
+            BindingConstraint::Station
+        };
+        Ok(Feasibility {
+            feasible: power > 0,
+            max_achievable_power: power,
+            binding_constraint,
+        })
+    }
+
+    /// Return the session that would be started with these parameters, and the allocation of the
+    /// sessions with it, without starting it.
+    fn preview_allocation(
+        &self,
+        connector_id: ConnectorId,
+        vehicle_max_power: u32,
+        options: SessionOptions,
+    ) -> Result<(Session, HashMap<uuid::Uuid, Session>), SessionError> {
+        self.check_connector_available(&connector_id)?;
+        self.check_capacity()?;
+
+        // The session is not started, so it does not take an id of the generator
+        let new_session = Session::with_id(
+            uuid::Uuid::new_v4(),
+            connector_id,
+            self.cap_vehicle_power(vehicle_max_power),
+            options,
+        );
+        let mut sessions = self.sessions.clone();
+        sessions.insert(new_session.session_id, new_session.clone());
+        let reallocated_sessions = allocator::allocate_within_phases(
+            &sessions,
+            &self.chargers,
+            &self.config.group_capacities(),
+            self.effective_capacity(),
+            self.config.grid_phase_capacity,
+            self.config.allocation_mode,
+        );
+        Ok((new_session, reallocated_sessions))
+    }
+
+    /// Return the active sessions, sorted by id, as they would be allocated with another grid
+    /// capacity, without changing the state, e.g. to size a new grid contract.
+    ///
+    /// The allocation is a full reallocation, with the current demand response and battery.
+    pub fn what_if_capacity(&self, grid_capacity: u32) -> Vec<Session> {
+        let capacity = self.boosted_capacity(self.curtailed_grid_capacity(grid_capacity));
+        let mut sessions = allocator::allocate_within_phases(
+            &self.sessions,
+            &self.chargers,
+            &self.config.group_capacities(),
+            capacity,
+            self.config.grid_phase_capacity,
+            self.config.allocation_mode,
+        )
+        .into_values()
+        .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.session_id);
+        sessions
+    }
+
+    /// Return the differences of the active sessions and their allocations from this state to
+    /// `other`, e.g. to audit a configuration change or a reallocation.
+    pub fn diff(&self, other: &StationState) -> AllocationDiff {
+        diff_sessions(&self.sessions, &other.sessions)
+    }
+
+    /// Reallocate the power of every session now, and return the allocation changes.
+    ///
+    /// The sessions are otherwise reallocated when the station capacity changes, this helps
+    /// checking that their allocations are the ones the station would give them.
+    #[tracing::instrument(skip(self))]
+    pub fn force_reallocation(&mut self) -> AllocationDiff {
+        let previous_sessions = self.sessions.clone();
+        self.reallocate();
+        diff_sessions(&previous_sessions, &self.sessions)
+    }
+
+    /// Return the active session with the given id, checking that it can go to the `to` state.
+    fn session_for_transition(
+        &self,
+        session_id: uuid::Uuid,
+        to: SessionState,
+    ) -> Result<&Session, SessionError> {
+        let Some(session) = self.sessions.get(&session_id) else {
+            return Err(
+                if self
+                    .finished_sessions
+                    .iter()
+                    .any(|session| session.session_id == session_id)
+                {
+                    SessionError::InvalidTransition {
+                        session_id,
+                        from: SessionState::Finished,
+                        to,
+                    }
+                } else {
+                    self.session_not_found(session_id)
+                },
+            );
+        };
+        if !session.state.can_transition_to(to) {
+            return Err(SessionError::InvalidTransition {
+                session_id,
+                from: session.state,
+                to,
+            });
+        }
+        Ok(session)
+    }
+
+    /// Stop the session and return it in its finished state.
+    #[tracing::instrument(skip(self))]
+    pub fn stop_session(&mut self, session_id: uuid::Uuid) -> Result<Session, SessionError> {
+        tracing::info!("Stopping session");
+        self.session_for_transition(session_id, SessionState::Finished)?;
+        let mut session = self
+            .sessions
+            .remove(&session_id)
+            .expect("The session was found above");
+        self.connector_sessions.remove(&session.connector_id);
+        session.state = SessionState::Finished;
+        session.allocated_power = 0;
+
+        self.record_allocation(session_id, 0);
+        self.last_allocation_changes.remove(&session_id);
+        self.display_powers.remove(&session_id);
+
+        if self.finished_sessions.len() == FINISHED_SESSIONS_RETAINED
+            && let Some(evicted_session) = self.finished_sessions.pop_front()
+        {
+            self.allocation_history.remove(&evicted_session.session_id);
+            self.session_notes.remove(&evicted_session.session_id);
+        }
+        self.finished_sessions.push_back(session.clone());
+        if self.stopped_session_ids.len() == STOPPED_SESSION_IDS_RETAINED {
+            self.stopped_session_ids.pop_front();
+        }
+        self.stopped_session_ids
+            .push_back((session_id, chrono::Utc::now()));
+        self.notify_reallocation();
+        self.promote_queued_sessions();
+        Ok(session)
+    }
+
+    /// Stop the session if it reached the expiry set by its client, and reallocate its power
+    /// to the other sessions.
+    ///
+    /// Return `None` if the session is no longer active or has not expired yet, e.g. when it
+    /// was stopped by its charger before its expiry.
+    #[tracing::instrument(skip(self))]
+    pub fn expire_session(&mut self, session_id: uuid::Uuid) -> Option<Session> {
+        // A session can expire before it leaves the queue
+        if let Some(position) = self.queue.iter().position(|queued_session| {
+            queued_session.session_id == session_id
+                && queued_session
+                    .options
+                    .expires_at
+                    .is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+        }) {
+            tracing::info!("Queued session reached its client-set expiry");
+            self.queue.remove(position);
+            self.promote_queued_sessions();
+            return None;
+        }
+        let expires_at = self.sessions.get(&session_id)?.expires_at?;
+        if expires_at > chrono::Utc::now() {
+            return None;
+        }
+        tracing::info!(%expires_at, "Session reached its client-set expiry");
+        let session = self.stop_session(session_id).ok()?;
+        self.reallocate();
+        Some(session)
+    }
+
+    /// Return the expiry set by the client of an active or queued session.
+    pub fn expires_at(&self, session_id: uuid::Uuid) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self.sessions.get(&session_id) {
+            Some(session) => session.expires_at,
+            None => {
+                self.queue
+                    .iter()
+                    .find(|queued_session| queued_session.session_id == session_id)?
+                    .options
+                    .expires_at
+            }
+        }
+    }
+
+    /// Return when the warm-up of the session ends, if its charger has a warm-up.
+    pub fn warmup_ends_at(&self, session_id: uuid::Uuid) -> Option<chrono::DateTime<chrono::Utc>> {
+        let session = self.sessions.get(&session_id)?;
+        self.chargers
+            .get(&session.connector_id.charger_id)?
+            .warmup_ends_at(session)
+    }
+
+    /// Reallocate the sessions once the warm-up of the session ended, so that it goes back to its
+    /// fair share without waiting for a power update.
+    ///
+    /// Return `None` if the session is no longer active or is still warming up.
+    #[tracing::instrument(skip(self))]
+    pub fn end_warmup(&mut self, session_id: uuid::Uuid) -> Option<Session> {
+        let warmup_ends_at = self.warmup_ends_at(session_id)?;
+        if warmup_ends_at > chrono::Utc::now() {
+            return None;
+        }
+        tracing::info!(%warmup_ends_at, "Session warm-up ended");
+        self.reallocate();
+        self.sessions.get(&session_id).cloned()
+    }
+
+    /// Pin the session at `power`, and reallocate the sessions.
+    ///
+    /// The allocator gives a pinned session exactly its pinned power, exempt from the fair share,
+    /// e.g. to test a charger or honor a contractual rate, and the other sessions share the rest.
+    /// The pin is still bounded by the vehicle max power and by the station, charger and group
+    /// limits.
+    #[tracing::instrument(skip(self))]
+    pub fn pin_session(
+        &mut self,
+        session_id: uuid::Uuid,
+        power: u32,
+    ) -> Result<Session, SessionError> {
+        tracing::info!("Pinning session");
+        self.set_pinned_power(session_id, Some(power))
+    }
+
+    /// Remove the pin of the session, if any, and reallocate the sessions.
+    #[tracing::instrument(skip(self))]
+    pub fn unpin_session(&mut self, session_id: uuid::Uuid) -> Result<Session, SessionError> {
+        tracing::info!("Unpinning session");
+        self.set_pinned_power(session_id, None)
+    }
+
+    fn set_pinned_power(
+        &mut self,
+        session_id: uuid::Uuid,
+        pinned_power: Option<u32>,
+    ) -> Result<Session, SessionError> {
+        let Some(session) = self.sessions.get_mut(&session_id) else {
+            return Err(self.session_not_found(session_id));
+        };
+        session.pinned_power = pinned_power;
+        self.reallocate();
+        Ok(self.sessions[&session_id].clone())
+    }
+
+    /// Merge the given key-values into the metadata of the session, overwriting existing keys.
+    ///
+    /// The metadata is not used by the allocation, so the power is not reallocated.
+    #[tracing::instrument(skip(self))]
+    pub fn update_session_metadata(
+        &mut self,
+        session_id: uuid::Uuid,
+        metadata: HashMap<String, String>,
+    ) -> Result<Session, SessionError> {
+        let Some(session) = self.sessions.get_mut(&session_id) else {
+            return Err(self.session_not_found(session_id));
+        };
+        session.metadata.extend(metadata);
+        Ok(session.clone())
+    }
+
+    /// Append a timestamped note to an active or recently finished session, e.g. for support
+    /// staff to record an investigation.
+    ///
+    /// The notes are not used by the allocation. A session holds at most `MAX_SESSION_NOTES`
+    /// notes, so that the older ones are never lost.
+    #[tracing::instrument(skip(self))]
+    pub fn add_session_note(
+        &mut self,
+        session_id: uuid::Uuid,
+        text: String,
+    ) -> Result<SessionNote, SessionError> {
+        self.get_session(session_id)?;
+        if text.trim().is_empty() {
+            return Err(SessionError::EmptyNote);
+        }
+        let notes = self.session_notes.entry(session_id).or_default();
+        if notes.len() == MAX_SESSION_NOTES {
+            return Err(SessionError::TooManyNotes {
+                session_id,
+                max: MAX_SESSION_NOTES,
+            });
+        }
+        let note = SessionNote {
+            timestamp: chrono::Utc::now(),
+            text,
+        };
+        notes.push(note.clone());
+        Ok(note)
+    }
+
+    /// Return the notes of the session, from the oldest.
+    pub fn session_notes(&self, session_id: uuid::Uuid) -> &[SessionNote] {
+        self.session_notes
+            .get(&session_id)
+            .map_or(&[], |notes| notes.as_slice())
+    }
+
+    /// If the consumed power is lower than the allocated power, then this
+    /// will set this consumed power as the `vehicle_max_power` of the session,
+    /// to free the power for other sessions. Once the vehicle consumes all its allocated
+    /// power again, its `vehicle_max_power` is restored to the `requested_max_power`, so that
+    /// a vehicle that tapered can reclaim its power when it resumes drawing more.
+    ///
+    /// The session is charging while it consumes power, and suspended once it stops consuming.
+    #[tracing::instrument(skip(self))]
+    pub fn power_update(
+        &mut self,
+        session_id: uuid::Uuid,
+        consumed_power: u32,
+    ) -> Result<Session, SessionError> {
+        let previous_session = self.sessions.get(&session_id).cloned();
+        self.record_power_update(session_id, consumed_power)?;
+        if self.flush_pending_power_updates().contains(&session_id) {
+            // Roll back the update, the session keeps its previous state and allocation
+            if let Some(previous_session) = previous_session {
+                self.sessions.insert(session_id, previous_session);
+            }
+            return Err(SessionError::AllocationFailed { session_id });
+        }
+        // The vehicle may have released power
+        self.promote_queued_sessions();
+
+        let reallocated_session = self.sessions[&session_id].clone();
+        tracing::info!(
+            "Reallocated power to {}kW",
+            reallocated_session.allocated_power,
+        );
+        Ok(reallocated_session)
+    }
+
+    /// Apply the power updates of several sessions like `power_update`, running the allocator
+    /// once for all of them.
+    ///
+    /// An update that fails, e.g. for a finished session, does not prevent the others from being
+    /// applied. Return the result of every update, in the order of `updates`.
+    #[tracing::instrument(skip(self))]
+    pub fn batch_power_update(
+        &mut self,
+        updates: &[(uuid::Uuid, u32)],
+    ) -> Vec<Result<Session, SessionError>> {
+        let mut previous_sessions = HashMap::new();
+        let mut results = Vec::with_capacity(updates.len());
+        for &(session_id, consumed_power) in updates {
+            if let Some(session) = self.sessions.get(&session_id) {
+                previous_sessions
+                    .entry(session_id)
+                    .or_insert_with(|| session.clone());
+            }
+            results.push(self.record_power_update(session_id, consumed_power));
+        }
+
+        let failed_sessions = self.flush_pending_power_updates();
+        for session_id in &failed_sessions {
+            // Roll back the updates, the session keeps its previous state and allocation
+            if let Some(previous_session) = previous_sessions.remove(session_id) {
+                self.sessions.insert(*session_id, previous_session);
+            }
+        }
+        self.promote_queued_sessions();
+
+        results
+            .into_iter()
+            .map(|result| {
+                let session_id = result?.session_id;
+                if failed_sessions.contains(&session_id) {
+                    return Err(SessionError::AllocationFailed { session_id });
+                }
+                Ok(self.sessions[&session_id].clone())
+            })
+            .collect()
+    }
+
+    /// Record the consumed power of a session like `power_update`, but without reallocating
+    /// the power. The session is reallocated on the next call to `flush_power_updates`.
+    #[tracing::instrument(skip(self))]
+    pub fn record_power_update(
+        &mut self,
+        session_id: uuid::Uuid,
+        consumed_power: u32,
+    ) -> Result<Session, SessionError> {
+        // The vehicle max power follows the consumed power, which is bounded like it
+        let consumed_power = self.cap_vehicle_power(consumed_power);
+        let state = match self.sessions.get(&session_id).map(|session| session.state) {
+            // The sessions stay halted until the emergency stop is cleared
+            Some(SessionState::Pending) if self.emergency_stopped => SessionState::Pending,
+            _ if self.emergency_stopped => SessionState::Suspended,
+            _ if consumed_power > 0 => SessionState::Charging,
+            Some(SessionState::Pending) => SessionState::Pending,
+            _ => SessionState::Suspended,
+        };
+        let mut session = self.session_for_transition(session_id, state)?.clone();
+        // The charger of the session might have been removed from the configuration, the
+        // session cannot be allocated against it anymore
+        self.charger(&session.connector_id)?;
+        session.state = state;
+        session.consumed_power = consumed_power;
+
+        if consumed_power < session.allocated_power {
+            // Without the release, a momentary dip keeps the allocation of the session
+            if self.config.auto_release_unused_power() {
+                session.vehicle_max_power = consumed_power;
+            }
+        } else if consumed_power > 0 {
+            session.vehicle_max_power = session.requested_max_power;
+        }
+
+        self.sessions.insert(session_id, session.clone());
+        self.pending_power_updates.insert(session_id);
+        Ok(session)
+    }
+
+    /// Return whether some power updates are waiting for `flush_power_updates`.
+    pub fn has_pending_power_updates(&self) -> bool {
+        !self.pending_power_updates.is_empty()
+    }
+
+    /// Reallocate the power of the sessions with a recorded power update, running the
+    /// allocator once for all of them.
+    ///
+    /// The other sessions keep their allocation until their own update, so the updated sessions
+    /// cannot take more than the remaining capacity of their charger.
+    ///
+    /// The sessions the allocator could not allocate keep their previous allocation, and the
+    /// first of them is returned as an `AllocationFailed` error.
+    #[tracing::instrument(skip(self))]
+    pub fn flush_power_updates(&mut self) -> Result<(), SessionError> {
+        let failed_sessions = self.flush_pending_power_updates();
+        self.promote_queued_sessions();
+        match failed_sessions.first() {
+            Some(&session_id) => Err(SessionError::AllocationFailed { session_id }),
+            None => Ok(()),
+        }
+    }
+
+    /// Flush the power updates like `flush_power_updates`, returning the ids of the sessions the
+    /// allocator could not allocate.
+    fn flush_pending_power_updates(&mut self) -> Vec<uuid::Uuid> {
+        if self.pending_power_updates.is_empty() {
+            return Vec::new();
+        }
+        self.reallocation_count += 1;
+        let started_at = Instant::now();
+        let mut reallocated_sessions = allocator::allocate_within_phases(
+            &self.sessions,
+            &self.chargers,
+            &self.config.group_capacities(),
+            self.effective_capacity(),
+            self.config.grid_phase_capacity,
+            self.config.allocation_mode,
+        );
+        self.record_allocation_duration(started_at.elapsed());
+        self.record_fair_share_targets(&reallocated_sessions);
+
+        // The sessions whose allocation decreases are updated first, so that the power they
+        // free is available to the other updated sessions.
+        let mut failed_sessions = Vec::new();
+        let mut pending_power_updates = self
+            .pending_power_updates
+            .drain()
+            // The session might have been stopped since its update
+            .filter_map(|session_id| {
+                let session = self.sessions.get(&session_id)?;
+                let Some(reallocated_session) = reallocated_sessions.remove(&session_id) else {
+                    failed_sessions.push(session_id);
+                    return None;
+                };
+                let delta =
+                    reallocated_session.allocated_power as i64 - session.allocated_power as i64;
+                Some((delta, reallocated_session))
+            })
+            .collect::<Vec<_>>();
+        pending_power_updates.sort_by_key(|(delta, session)| (*delta, session.session_id));
+
+        let mut changed_sessions = 0;
+        for (_, mut reallocated_session) in pending_power_updates {
+            let session = &self.sessions[&reallocated_session.session_id];
+            // The hardcap below is computed with the previous allocation, so keeping it stays
+            // within the limits
+            if let Some(kept_power) = self.fairness_kept_power(session, &reallocated_session) {
+                reallocated_session.allocated_power = kept_power;
+            } else if self.in_reallocation_cooldown(session.session_id)
+                && reallocated_session.pinned_power.is_none()
+            {
+                // A vehicle drawing less releases its unused power even within the cooldown
+                reallocated_session.allocated_power = session
+                    .allocated_power
+                    .min(reallocated_session.vehicle_max_power);
+            }
+            let hardcap_capacity = self
+                .charger_remaining_capacity(&session.connector_id.charger_id)
+                .saturating_add(session.allocated_power);
+            reallocated_session.allocated_power =
+                reallocated_session.allocated_power.min(hardcap_capacity);
+            if reallocated_session.allocated_power != session.allocated_power {
+                changed_sessions += 1;
+            }
+            self.record_allocation(
+                reallocated_session.session_id,
+                reallocated_session.allocated_power,
+            );
+            self.sessions
+                .insert(reallocated_session.session_id, reallocated_session);
+        }
+        self.record_churn(changed_sessions);
+
+        self.notify_reallocation();
+        failed_sessions.sort();
+        failed_sessions
+    }
+}
+
+/// Return the differences from the `previous` sessions to the `sessions`, sorted by session id.
+fn diff_sessions(
+    previous: &HashMap<uuid::Uuid, Session>,
+    sessions: &HashMap<uuid::Uuid, Session>,
+) -> AllocationDiff {
+    let only_in = |sessions: &HashMap<uuid::Uuid, Session>,
+                   other: &HashMap<uuid::Uuid, Session>| {
+        let mut sessions = sessions
+            .values()
+            .filter(|session| !other.contains_key(&session.session_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.session_id);
+        sessions
+    };
+    let mut changed = sessions
+        .values()
+        .filter_map(|session| {
+            let previous = previous.get(&session.session_id)?;
+            (previous.allocated_power != session.allocated_power).then_some(AllocationChange {
+                session_id: session.session_id,
+                previous_power: previous.allocated_power,
+                allocated_power: session.allocated_power,
+            })
+        })
+        .collect::<Vec<_>>();
+    changed.sort_by_key(|change| change.session_id);
+    AllocationDiff {
+        added: only_in(sessions, previous),
+        removed: only_in(previous, sessions),
+        changed,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn default_config() -> StationConfig {
+        StationConfig {
+            station_id: "ELECTRA_PARIS_15".into(),
+            grid_capacity: 400,
+            chargers: vec![
+                ChargerConfig {
+                    id: "CP001".into(),
+                    max_power: 200,
+                    connectors: 2,
+                    ..Default::default()
+                },
+                ChargerConfig {
+                    id: "CP002".into(),
+                    max_power: 200,
+                    connectors: 2,
+                    ..Default::default()
+                },
+                ChargerConfig {
+                    id: "CP003".into(),
+                    max_power: 300,
+                    connectors: 2,
+                    ..Default::default()
+                },
+            ],
+            batteries: vec![],
+            ..Default::default()
+        }
+    }
+
+    fn default_state() -> StationState {
+        StationState::new(default_config())
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert!(default_config().validate().is_ok());
+
+        let mut config = default_config();
+        config.chargers[1].id = "CP001".into();
+        config.chargers[1].connectors = 0;
+        config.chargers[2].max_power = 500;
+        config.chargers[2].usable_min_power = Some(600);
+        config.chargers[0].labels = HashMap::from([(1, "A".into()), (3, "B".into())]);
+        config.chargers[2].labels = HashMap::from([(1, "A".into())]);
+
+        // All the problems are reported, in the order of the chargers
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![
+                ConfigProblem::LabelOnMissingConnector {
+                    charger_id: "CP001".into(),
+                    idx: 3,
+                    label: "B".into(),
+                },
+                ConfigProblem::DuplicateChargerId {
+                    charger_id: "CP001".into(),
+                },
+                ConfigProblem::NoConnectors {
+                    charger_id: "CP001".into(),
+                },
+                ConfigProblem::ChargerExceedsStationCapacity {
+                    charger_id: "CP003".into(),
+                    max_power: 500,
+                    station_capacity: 400,
+                },
+                ConfigProblem::UsableMinPowerAboveMaxPower {
+                    charger_id: "CP003".into(),
+                    usable_min_power: 600,
+                    max_power: 500,
+                },
+                ConfigProblem::DuplicateLabel { label: "A".into() },
+            ]
+        );
+
+        let mut config = default_config();
+        config.chargers[0].reserved_capacity = Some(200);
+        config.chargers[1].reserved_capacity = Some(250);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![ConfigProblem::ReservedCapacityExceedsGridCapacity {
+                reserved_capacity: 450,
+                grid_capacity: 400,
+            }]
+        );
+
+        // The battery adds to the station capacity
+        let mut config = default_config();
+        config.chargers[2].max_power = 500;
+        config.batteries = vec![Bess::new(200, 100)];
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_config_warnings() {
+        assert!(default_config().warnings().is_empty());
+
+        let mut config = default_config();
+        // 2 connectors at 100kW can reach the 200kW of the charger
+        config.chargers[0].connector_max_power = Some(100);
+        config.chargers[1].connector_max_power = Some(50);
+        config.chargers[2].connector_max_power = Some(150);
+        config.chargers[2].sharing = SharingMode::Dedicated;
+
+        // The warnings do not make the configuration invalid
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.warnings(),
+            vec![
+                ConfigProblem::ConnectorsBelowChargerMaxPower {
+                    charger_id: "CP002".into(),
+                    connector_max_power: 50,
+                    connectors_max_power: 100,
+                    max_power: 200,
+                },
+                ConfigProblem::ConnectorsBelowChargerMaxPower {
+                    charger_id: "CP003".into(),
+                    connector_max_power: 150,
+                    connectors_max_power: 150,
+                    max_power: 300,
+                },
+            ]
+        );
+
+        // The connector max power caps the sessions
+        let mut state = StationState::new(config);
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP002".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        assert_eq!(session.allocated_power, 50);
+        assert_eq!(session.vehicle_max_power, 150);
+    }
+
+    #[test]
+    fn test_connector_powers() {
+        // A shared charger whose first connector is faster than the second
+        let mut config = default_config();
+        config.chargers[0].connector_max_power = Some(100);
+        config.chargers[0].connector_powers = vec![150, 50];
+        assert!(config.validate().is_ok());
+        assert!(config.warnings().is_empty());
+        assert_eq!(config.chargers[0].connectors_max_power(), Some(200));
+
+        // Each session is capped by its own connector, over the uniform connector max power
+        let mut state = StationState::new(config.clone());
+        let connector_id = |idx| ConnectorId {
+            charger_id: "CP001".into(),
+            idx,
+        };
+        let fast = state.start_session(connector_id(1), 200).unwrap();
+        assert_eq!(fast.allocated_power, 150);
+        let slow = state.start_session(connector_id(2), 200).unwrap();
+        assert_eq!(slow.allocated_power, 50);
+        state.force_reallocation();
+        assert_eq!(state.get_sessions()[&fast.session_id].allocated_power, 150);
+        assert_eq!(state.get_sessions()[&slow.session_id].allocated_power, 50);
+
+        // There must be one power per connector
+        config.chargers[0].connector_powers = vec![150];
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![ConfigProblem::ConnectorPowersMismatch {
+                charger_id: "CP001".into(),
+                connector_powers: 1,
+                connectors: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_phase_capacity() {
+        // Two single-phase chargers on L1 next to a DC charger drawing from the three phases
+        let single_phase = |id: &str| ChargerConfig {
+            id: id.into(),
+            max_power: 100,
+            connectors: 1,
+            phases: Some(1),
+            phase: Some(1),
+            ..Default::default()
+        };
+        let mut config = StationConfig {
+            station_id: "ST001".into(),
+            grid_capacity: 400,
+            grid_phase_capacity: Some(100),
+            chargers: vec![
+                single_phase("AC001"),
+                single_phase("AC002"),
+                ChargerConfig {
+                    id: "DC001".into(),
+                    max_power: 300,
+                    connectors: 1,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        let connector_id = |charger_id: &str| ConnectorId {
+            charger_id: charger_id.into(),
+            idx: 1,
+        };
+        let start_sessions = |config: &StationConfig| {
+            let mut state = StationState::new(config.clone());
+            for charger_id in ["AC001", "AC002", "DC001"] {
+                state.start_session(connector_id(charger_id), 300).unwrap();
+            }
+            state.force_reallocation();
+            state
+        };
+        let phase_loads = |state: &StationState| {
+            allocator::phase_loads(state.get_sessions().values(), &state.chargers)
+        };
+        let allocations = |state: &StationState| {
+            let mut allocations = state
+                .get_sessions()
+                .values()
+                .map(|session| {
+                    (
+                        session.connector_id.charger_id.clone(),
+                        session.allocated_power,
+                    )
+                })
+                .collect::<Vec<_>>();
+            allocations.sort();
+            allocations
+        };
+
+        // The total is under the grid capacity, but L1 would be overloaded without the limit
+        let state = start_sessions(&StationConfig {
+            grid_phase_capacity: None,
+            ..config.clone()
+        });
+        assert_eq!(
+            allocations(&state),
+            vec![
+                ("AC001".into(), 100),
+                ("AC002".into(), 100),
+                ("DC001".into(), 200)
+            ]
+        );
+        assert!(phase_loads(&state)[0] > 100.0);
+
+        // L1 is kept within its capacity, at the cost of the total
+        let state = start_sessions(&config);
+        assert_eq!(
+            allocations(&state),
+            vec![
+                ("AC001".into(), 42),
+                ("AC002".into(), 42),
+                ("DC001".into(), 42)
+            ]
+        );
+        let loads = phase_loads(&state);
+        assert!(loads[0] <= 100.0 + allocator::PHASE_LOAD_TOLERANCE);
+        assert!(loads[1] < loads[0] && loads[2] < loads[0]);
+
+        // A pinned session is capped like the others, its pin being bounded by the vehicle max
+        // power
+        let mut state = start_sessions(&config);
+        let session_id = state
+            .get_sessions()
+            .values()
+            .find(|session| session.connector_id.charger_id == "AC001")
+            .unwrap()
+            .session_id;
+        state.pin_session(session_id, 100).unwrap();
+        assert_eq!(
+            allocations(&state),
+            vec![
+                ("AC001".into(), 42),
+                ("AC002".into(), 42),
+                ("DC001".into(), 42)
+            ]
+        );
+        assert!(phase_loads(&state)[0] <= 100.0 + allocator::PHASE_LOAD_TOLERANCE);
+
+        // A single-phase charger is wired to one of the three phases
+        config.chargers[0].phase = Some(4);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![ConfigProblem::InvalidPhase {
+                charger_id: "AC001".into(),
+                phase: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_session_queue() {
+        let connector_id = |charger_id: &str, idx| ConnectorId {
+            charger_id: charger_id.into(),
+            idx,
+        };
+        let queued = |admission| match admission {
+            Ok(SessionAdmission::Queued {
+                queued_session,
+                position,
+            }) => (queued_session, position),
+            _ => panic!("Expected a queued session"),
+        };
+
+        // Without the queue, a session of a full station starts with no power
+        let mut state = default_state();
+        state.start_session(connector_id("CP001", 1), 200).unwrap();
+        state.start_session(connector_id("CP002", 1), 200).unwrap();
+        match state.admit_session(connector_id("CP003", 1), 200, SessionOptions::default()) {
+            Ok(SessionAdmission::Started(session)) => assert_eq!(session.allocated_power, 0),
+            _ => panic!("Expected a started session"),
+        }
+
+        let mut config = default_config();
+        config.queue_when_full = true;
+        let mut state = StationState::new(config);
+        let first = state.start_session(connector_id("CP001", 1), 200).unwrap();
+        state.start_session(connector_id("CP002", 1), 200).unwrap();
+
+        // The sessions that would get no power wait in their order of arrival
+        let (waiting, position) =
+            queued(state.admit_session(connector_id("CP003", 1), 200, SessionOptions::default()));
+        assert_eq!(position, 1);
+        let (cancelled, position) =
+            queued(state.admit_session(connector_id("CP003", 2), 200, SessionOptions::default()));
+        assert_eq!(position, 2);
+        assert!(state.get_session(waiting.session_id).is_err());
+
+        // The connector of a queued session is taken
+        assert!(matches!(
+            state.admit_session(connector_id("CP003", 1), 200, SessionOptions::default()),
+            Err(SessionError::ConnectorAlreadyInUse { .. })
+        ));
+
+        // A queued session can be cancelled, once
+        let cancelled = state.cancel_queued_session(cancelled.session_id).unwrap();
+        assert_eq!(cancelled.connector_id, connector_id("CP003", 2));
+        assert!(matches!(
+            state.cancel_queued_session(cancelled.session_id),
+            Err(SessionError::QueuedSessionNotFound { .. })
+        ));
+        assert_eq!(state.queued_sessions().count(), 1);
+        let charger_status = |state: &StationState, charger_id: &str| {
+            state
+                .charger_statuses()
+                .into_iter()
+                .find(|charger| charger.id == charger_id)
+                .unwrap()
+        };
+        assert_eq!(charger_status(&state, "CP003").available_connectors, 1);
+
+        // The first queued session starts with the power freed by a stopped session
+        state.stop_session(first.session_id).unwrap();
+        assert_eq!(state.queued_sessions().count(), 0);
+        let promoted = state.get_session(waiting.session_id).unwrap();
+        assert_eq!(promoted.connector_id, connector_id("CP003", 1));
+        assert_eq!(promoted.allocated_power, 200);
+        let promoted = state.take_promoted_sessions();
+        assert_eq!(promoted.len(), 1);
+        assert_eq!(promoted[0].session_id, waiting.session_id);
+        assert!(state.take_promoted_sessions().is_empty());
+
+        // A queued session reaching its expiry leaves the queue
+        let (expiring, _) = queued(state.admit_session(
+            connector_id("CP001", 1),
+            200,
+            SessionOptions {
+                expires_at: Some(chrono::Utc::now()),
+                ..Default::default()
+            },
+        ));
+        assert!(state.expires_at(expiring.session_id).is_some());
+        assert!(state.expire_session(expiring.session_id).is_none());
+        assert_eq!(state.queued_sessions().count(), 0);
+        assert!(state.expires_at(expiring.session_id).is_none());
+
+        // The queue waits while the station drains, and starts when the capacity comes back
+        let mut config = default_config();
+        config.queue_when_full = true;
+        let mut state = StationState::new(config);
+        state.start_session(connector_id("CP001", 1), 100).unwrap();
+        state.start_session(connector_id("CP002", 1), 100).unwrap();
+        state.set_power_cap(200);
+        let (waiting, _) =
+            queued(state.admit_session(connector_id("CP003", 1), 200, SessionOptions::default()));
+        state.drain();
+        state.clear_power_cap();
+        assert_eq!(state.queued_sessions().count(), 1);
+        state.undrain();
+        assert_eq!(state.queued_sessions().count(), 0);
+        assert_eq!(
+            state
+                .get_session(waiting.session_id)
+                .unwrap()
+                .allocated_power,
+            200
+        );
+        assert_eq!(state.take_promoted_sessions().len(), 1);
+
+        // Without capacity, the sessions are queued rather than refused
+        let mut config = default_config();
+        config.queue_when_full = true;
+        let mut state = StationState::new(config);
+        state.set_power_cap(0);
+        let (waiting, position) =
+            queued(state.admit_session(connector_id("CP001", 1), 200, SessionOptions::default()));
+        assert_eq!(position, 1);
+        state.clear_power_cap();
+        assert!(state.get_session(waiting.session_id).is_ok());
+    }
+
+    #[test]
+    fn test_session_queue_blocked_head() {
+        let connector_id = |charger_id: &str, idx| ConnectorId {
+            charger_id: charger_id.into(),
+            idx,
+        };
+        let queued = |admission| match admission {
+            Ok(SessionAdmission::Queued { queued_session, .. }) => queued_session,
+            _ => panic!("Expected a queued session"),
+        };
+        let mut config = default_config();
+        config.grid_capacity = 700;
+        config.queue_when_full = true;
+        let mut state = StationState::new(config);
+        state.start_session(connector_id("CP001", 1), 200).unwrap();
+
+        // The head waits on its full charger, and the next session waits behind it although its
+        // charger has capacity left
+        let head =
+            queued(state.admit_session(connector_id("CP001", 2), 200, SessionOptions::default()));
+        let next =
+            queued(state.admit_session(connector_id("CP002", 1), 200, SessionOptions::default()));
+
+        // The next session starts as soon as the head is cancelled
+        state.cancel_queued_session(head.session_id).unwrap();
+        assert_eq!(state.queued_sessions().count(), 0);
+        assert_eq!(
+            state.get_session(next.session_id).unwrap().allocated_power,
+            200
+        );
+        let promoted = state.take_promoted_sessions();
+        assert_eq!(promoted.len(), 1);
+        assert_eq!(promoted[0].session_id, next.session_id);
+
+        // Or as soon as the head reaches its expiry
+        let head = queued(state.admit_session(
+            connector_id("CP001", 2),
+            200,
+            SessionOptions {
+                expires_at: Some(chrono::Utc::now()),
+                ..Default::default()
+            },
+        ));
+        let next =
+            queued(state.admit_session(connector_id("CP003", 1), 200, SessionOptions::default()));
+        assert!(state.expire_session(head.session_id).is_none());
+        assert_eq!(state.queued_sessions().count(), 0);
+        assert!(state.get_session(next.session_id).is_ok());
+        assert_eq!(state.take_promoted_sessions().len(), 1);
+    }
+
+    #[test]
+    fn test_display_power() {
+        let connector_id = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 1,
+        };
+
+        // Without smoothing, there is no display power
+        let mut state = default_state();
+        let session = state.start_session(connector_id.clone(), 200).unwrap();
+        assert_eq!(state.display_power(session.session_id), None);
+
+        let mut config = default_config();
+        config.display_smoothing_factor = Some(0.5);
+        assert!(config.validate().is_ok());
+        let mut state = StationState::new(config.clone());
+        let session = state.start_session(connector_id, 200).unwrap();
+        assert_eq!(state.display_power(session.session_id), Some(200));
+
+        // The display power lags a step change of the allocation, closing half the gap each time
+        state.set_power_cap(100);
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            100
+        );
+        assert_eq!(state.display_power(session.session_id), Some(150));
+        state.force_reallocation();
+        assert_eq!(state.display_power(session.session_id), Some(125));
+        state.force_reallocation();
+        assert_eq!(state.display_power(session.session_id), Some(113));
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            100
+        );
+
+        // The display power drops to 0 at once when the power is cut, and is smoothed again after
+        state.emergency_stop();
+        assert_eq!(state.display_power(session.session_id), Some(0));
+        state.clear_emergency_stop();
+        assert_eq!(state.display_power(session.session_id), Some(50));
+        state.suspend_charger("CP001").unwrap();
+        assert_eq!(state.display_power(session.session_id), Some(0));
+        state.resume_charger("CP001").unwrap();
+        assert_eq!(state.display_power(session.session_id), Some(50));
+
+        // A stopped session has no display power
+        state.stop_session(session.session_id).unwrap();
+        assert_eq!(state.display_power(session.session_id), None);
+
+        // The factor must be in (0, 1]
+        for factor in [0.0, 1.5] {
+            config.display_smoothing_factor = Some(factor);
+            assert_eq!(
+                config.validate().unwrap_err(),
+                vec![ConfigProblem::InvalidDisplaySmoothingFactor]
+            );
+        }
+    }
+
+    #[test]
+    fn test_carry_over_controls() {
+        let mut previous_state = default_state();
+        previous_state.emergency_stop();
+        previous_state.drain();
+        previous_state.set_power_cap(300);
+        previous_state.set_safety_margin(10).unwrap();
+        previous_state.suspend_charger("CP002").unwrap();
+        previous_state.derate_charger("CP003", 100).unwrap();
+
+        // The new configuration no longer has CP003
+        let mut config = default_config();
+        config.chargers.pop();
+        let mut state = StationState::new(config);
+        state.carry_over_controls(previous_state);
+        assert!(state.is_emergency_stopped());
+        assert!(state.is_draining());
+        assert_eq!(state.power_cap(), Some(300));
+        assert_eq!(state.safety_margin_percent(), 10);
+        let chargers = state.charger_statuses();
+        assert!(
+            chargers
+                .iter()
+                .any(|charger| charger.id == "CP002" && charger.suspended)
+        );
+        assert_eq!(chargers.len(), 2);
+        assert!(
+            chargers
+                .iter()
+                .all(|charger| charger.derated_max_power.is_none())
+        );
+    }
+
+    #[test]
+    fn test_zero_capacity() {
+        // A station without capacity is reported once, not for every charger
+        let mut config = default_config();
+        config.grid_capacity = 0;
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![ConfigProblem::NoStationCapacity]
+        );
+        config.batteries = vec![Bess::new(300, 300)];
+        assert!(config.validate().is_ok());
+
+        // A demand response can cut the capacity at runtime, during which the new sessions are
+        // refused rather than waiting with no power
+        let mut state = default_state();
+        let connector_id = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 1,
+        };
+        let id = state
+            .apply_demand_response(0.0, Duration::from_secs(60))
+            .unwrap();
+        assert!(matches!(
+            state.start_session(connector_id.clone(), 100),
+            Err(SessionError::InsufficientCapacity)
+        ));
+        state.end_demand_response(id);
+        assert_eq!(
+            state
+                .start_session(connector_id, 100)
+                .unwrap()
+                .allocated_power,
+            100
+        );
+    }
+
+    #[test]
+    fn test_session_on_connector() {
+        let mut state = default_state();
+        let connector_id = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 1,
+        };
+        assert!(state.session_on_connector(&connector_id).is_none());
+
+        let session = state.start_session(connector_id.clone(), 100).unwrap();
+        assert_eq!(
+            state
+                .session_on_connector(&connector_id)
+                .map(|session| session.session_id),
+            Some(session.session_id)
+        );
+
+        // Failing to start a session keeps the connector index unchanged
+        assert!(state.start_session(connector_id.clone(), 50).is_err());
+        assert_eq!(
+            state
+                .session_on_connector(&connector_id)
+                .map(|session| session.session_id),
+            Some(session.session_id)
+        );
+
+        // Stopping the session frees the connector for a new session
+        state.stop_session(session.session_id).unwrap();
+        assert!(state.session_on_connector(&connector_id).is_none());
+        let new_session = state.start_session(connector_id.clone(), 100).unwrap();
+        assert_eq!(
+            state
+                .session_on_connector(&connector_id)
+                .map(|session| session.session_id),
+            Some(new_session.session_id)
+        );
+        assert_eq!(state.connector_sessions.len(), state.sessions.len());
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut state = default_state();
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .expect("Could not create the session");
+        let connector_id = ConnectorId {
+            charger_id: "CP002".into(),
+            idx: 1,
+        };
+
+        state.drain();
+        assert!(state.is_draining());
+        assert!(matches!(
+            state.start_session(connector_id.clone(), 100),
+            Err(SessionError::StationDraining)
+        ));
+        assert!(matches!(
+            state.marginal_impact(connector_id.clone(), 100, SessionOptions::default()),
+            Err(SessionError::StationDraining)
+        ));
+
+        // The active session continues
+        let updated_session = state.power_update(session.session_id, 80).unwrap();
+        assert_eq!(updated_session.allocated_power, 80);
+        state.stop_session(session.session_id).unwrap();
+
+        state.undrain();
+        assert!(!state.is_draining());
+        assert!(state.start_session(connector_id, 100).is_ok());
+    }
+
+    #[test]
+    fn test_connector_already_in_use() {
+        let mut state = default_state();
+        let connector_id = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 1,
+        };
+
+        // First session should succeed
+        let result1 = state.start_session(connector_id.clone(), 100);
+        assert!(result1.is_ok());
+
+        // Second session on same connector should fail
+        let result2 = state.start_session(connector_id.clone(), 50);
+        assert!(result2.is_err());
+
+        match result2 {
+            Err(SessionError::ConnectorAlreadyInUse {
+                connector_id: err_connector_id,
+            }) => {
+                assert_eq!(err_connector_id, connector_id);
+            }
+            _ => panic!("Expected ConnectorAlreadyInUse error"),
+        }
+
+        // Different connector should still work
+        let different_connector = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 2,
+        };
+        let result3 = state.start_session(different_connector, 75);
+        assert!(result3.is_ok());
+    }
+
+    #[test]
+    fn test_connector_not_found() {
+        let mut state = default_state();
+
+        // Test non-existent charger
+        let invalid_charger = ConnectorId {
+            charger_id: "INVALID".into(),
+            idx: 1,
+        };
+
+        let result = state.start_session(invalid_charger.clone(), 100);
+        assert!(result.is_err());
+
+        match result {
+            Err(SessionError::ConnectorNotFound {
+                connector_id: err_connector_id,
+            }) => {
+                assert_eq!(err_connector_id, invalid_charger);
+            }
+            _ => panic!("Expected ConnectorNotFound error"),
+        }
+
+        // Test invalid connector index (0)
+        let invalid_idx_zero = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 0,
+        };
+
+        let result = state.start_session(invalid_idx_zero.clone(), 100);
+        assert!(result.is_err());
+
+        match result {
+            Err(SessionError::InvalidConnectorIndex {
+                connector_id: err_connector_id,
+                connector_base,
+            }) => {
+                assert_eq!(err_connector_id, invalid_idx_zero);
+                assert_eq!(connector_base, 1);
+            }
+            _ => panic!("Expected InvalidConnectorIndex error"),
+        }
+
+        // Test invalid connector index (too high)
+        let invalid_idx_high = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 5, // CP001 only has 2 connectors
+        };
+
+        let result = state.start_session(invalid_idx_high.clone(), 100);
+        assert!(result.is_err());
+
+        match result {
+            Err(SessionError::ConnectorNotFound {
                 connector_id: err_connector_id,
             }) => {
-                assert_eq!(err_connector_id, invalid_idx_high);
+                assert_eq!(err_connector_id, invalid_idx_high);
+            }
+            _ => panic!("Expected ConnectorNotFound error"),
+        }
+
+        // Test valid connector should work
+        let valid_connector = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 1,
+        };
+
+        let result = state.start_session(valid_connector, 100);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_start_session() {
+        let mut state = default_state();
+
+        let session_1 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_1.allocated_power, 100);
+
+        // Starting a session with a greater max_power
+        // hitting the charger capacity
+        let session_2 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_2.allocated_power, 100);
+
+        // Starting a session on another charger,
+        // reaching grid capacity
+        let session_3 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .expect("Could not create the session");
+        assert_eq!(session_3.allocated_power, 200);
+
+        // Starting a session on a new charger,
+        // but no capacity left
+        let session_4 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP002".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+        assert_eq!(session_4.allocated_power, 0);
+
+        // Removing a session to free some capacity and adding a new session
+        // that will receive its fair share.
+        state
+            .stop_session(session_3.session_id)
+            .expect("Could not stop the session");
+
+        let session_5 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP002".into(),
+                    idx: 2,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_5.allocated_power, 100);
+    }
+
+    #[test]
+    fn test_power_update_session_not_found() {
+        let mut state = default_state();
+        let non_existent_session_id = uuid::Uuid::new_v4();
+
+        // Try to update power for a non-existent session
+        let result = state.power_update(non_existent_session_id, 150);
+        assert!(result.is_err());
+
+        match result {
+            Err(SessionError::SessionNotFound { session_id }) => {
+                assert_eq!(session_id, non_existent_session_id);
+            }
+            _ => panic!("Expected SessionNotFound error"),
+        }
+
+        // Test successful power update
+        let connector_id = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 1,
+        };
+        let session = state
+            .start_session(connector_id, 100)
+            .expect("Could not create session");
+
+        let result = state.power_update(session.session_id, 80);
+        assert!(result.is_ok());
+
+        if let Ok(updated_session) = result {
+            assert_eq!(updated_session.vehicle_max_power, 80);
+        }
+    }
+
+    #[test]
+    fn test_power_update() {
+        let mut state = default_state();
+
+        let session_1 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_1.allocated_power, 100);
+
+        // Starting a session with a greater max_power
+        // hitting the charger capacity
+        let session_2 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_2.allocated_power, 100);
+
+        // Then we update the power for the session_1, which should
+        // have a lower max_power and allocated value
+        let session_1 = state
+            .power_update(session_1.session_id, 80)
+            .expect("Error while updating power");
+        assert_eq!(session_1.vehicle_max_power, 80);
+        assert_eq!(session_1.allocated_power, 80);
+    }
+
+    #[test]
+    fn test_allocation_history() {
+        let mut state = default_state();
+        let session_1 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+            )
+            .expect("Could not create the session");
+        let session_2 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+
+        // The history grows as the power updates change the allocations
+        state.power_update(session_1.session_id, 80).unwrap();
+        state.power_update(session_2.session_id, 200).unwrap();
+        // An update that does not change the allocation is not recorded
+        state.power_update(session_2.session_id, 120).unwrap();
+        state.stop_session(session_1.session_id).unwrap();
+
+        let allocated_powers = |session_id| {
+            state
+                .allocation_history(session_id)
+                .unwrap()
+                .iter()
+                .map(|sample| sample.allocated_power)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(allocated_powers(session_1.session_id), vec![100, 80, 0]);
+        assert_eq!(allocated_powers(session_2.session_id), vec![100, 120]);
+
+        let history = state.allocation_history(session_2.session_id).unwrap();
+        assert!(history[0].timestamp <= history[1].timestamp);
+
+        assert!(matches!(
+            state.allocation_history(uuid::Uuid::new_v4()),
+            Err(SessionError::SessionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_session_notes() {
+        let mut state = default_state();
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+
+        state
+            .add_session_note(session.session_id, "Customer reported slow charging".into())
+            .unwrap();
+        assert!(matches!(
+            state.add_session_note(session.session_id, " ".into()),
+            Err(SessionError::EmptyNote)
+        ));
+        // Notes can still be added once the session is finished
+        state.stop_session(session.session_id).unwrap();
+        state
+            .add_session_note(session.session_id, "Investigated".into())
+            .unwrap();
+
+        assert_eq!(
+            state
+                .session_notes(session.session_id)
+                .iter()
+                .map(|note| note.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Customer reported slow charging", "Investigated"]
+        );
+
+        for _ in 2..MAX_SESSION_NOTES {
+            state
+                .add_session_note(session.session_id, "Note".into())
+                .unwrap();
+        }
+        assert!(matches!(
+            state.add_session_note(session.session_id, "Note".into()),
+            Err(SessionError::TooManyNotes { max, .. }) if max == MAX_SESSION_NOTES
+        ));
+        assert!(matches!(
+            state.add_session_note(uuid::Uuid::new_v4(), "Note".into()),
+            Err(SessionError::SessionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_allocation_history_is_bounded() {
+        let mut state = default_state();
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .expect("Could not create the session");
+
+        for update in 0..ALLOCATION_HISTORY_RETAINED {
+            state.power_update(session.session_id, 80).unwrap();
+            let session = state.power_update(session.session_id, 150).unwrap();
+            assert_eq!(session.allocated_power, 150, "update {update}");
+        }
+
+        let history = state.allocation_history(session.session_id).unwrap();
+        assert_eq!(history.len(), ALLOCATION_HISTORY_RETAINED);
+        assert_eq!(history.last().unwrap().allocated_power, 150);
+    }
+
+    #[test]
+    fn test_batch_power_update() {
+        let mut state = default_state();
+        let session_1 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+            )
+            .expect("Could not create the session");
+        let session_2 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+        let reallocation_count = state.reallocation_count();
+        let unknown_session_id = uuid::Uuid::new_v4();
+
+        let results = state.batch_power_update(&[
+            (session_1.session_id, 80),
+            (unknown_session_id, 50),
+            (session_2.session_id, 200),
+        ]);
+
+        // The valid updates are applied with a single reallocation
+        assert_eq!(state.reallocation_count(), reallocation_count + 1);
+        assert_eq!(results[0].as_ref().unwrap().allocated_power, 80);
+        assert!(matches!(
+            results[1],
+            Err(SessionError::SessionNotFound { session_id }) if session_id == unknown_session_id
+        ));
+        assert_eq!(results[2].as_ref().unwrap().allocated_power, 120);
+        assert_eq!(state.sessions[&session_2.session_id].allocated_power, 120);
+    }
+
+    #[test]
+    fn test_on_reallocation() {
+        let mut state = default_state();
+        let notifications = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let observer_notifications = notifications.clone();
+        state.on_reallocation(move |sessions| {
+            observer_notifications.lock().unwrap().push(
+                sessions
+                    .iter()
+                    .map(|session| session.allocated_power)
+                    .collect::<Vec<_>>(),
+            );
+        });
+
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .expect("Could not create the session");
+        state.power_update(session.session_id, 80).unwrap();
+        state.stop_session(session.session_id).unwrap();
+
+        assert_eq!(
+            *notifications.lock().unwrap(),
+            vec![vec![150], vec![80], vec![]]
+        );
+    }
+
+    #[test]
+    fn test_what_if_capacity() {
+        let mut state = default_state();
+        for charger_id in ["CP001", "CP002"] {
+            state
+                .start_session(
+                    ConnectorId {
+                        charger_id: charger_id.into(),
+                        idx: 1,
+                    },
+                    150,
+                )
+                .unwrap();
+        }
+        let allocated_power = |sessions: &[Session]| {
+            sessions
+                .iter()
+                .map(|session| session.allocated_power)
+                .collect::<Vec<_>>()
+        };
+
+        // Less capacity is shared, more capacity is capped by the vehicles
+        assert_eq!(
+            allocated_power(&state.what_if_capacity(200)),
+            vec![100, 100]
+        );
+        assert_eq!(
+            allocated_power(&state.what_if_capacity(1000)),
+            vec![150, 150]
+        );
+
+        // The sessions keep their allocations
+        assert!(
+            state
+                .get_sessions()
+                .values()
+                .all(|session| session.allocated_power == 150)
+        );
+    }
+
+    #[test]
+    fn test_validate_groups() {
+        let mut config = default_config();
+        config.groups = vec![
+            ChargerGroup {
+                id: "FEEDER".into(),
+                max_power: 150,
+            },
+            ChargerGroup {
+                id: "FEEDER".into(),
+                max_power: 100,
+            },
+        ];
+        config.chargers[0].group_id = Some("FEEDER".into());
+        config.chargers[1].group_id = Some("SWITCHGEAR".into());
+
+        assert_eq!(
+            config.validate().unwrap_err(),
+            vec![
+                ConfigProblem::DuplicateGroupId {
+                    group_id: "FEEDER".into(),
+                },
+                ConfigProblem::UnknownGroup {
+                    charger_id: "CP002".into(),
+                    group_id: "SWITCHGEAR".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_charger_groups() {
+        let mut config = default_config();
+        config.groups = vec![ChargerGroup {
+            id: "FEEDER".into(),
+            max_power: 150,
+        }];
+        config.chargers[0].group_id = Some("FEEDER".into());
+        config.chargers[1].group_id = Some("FEEDER".into());
+        let mut state = StationState::new(config);
+        let connector = |charger_id: &str| ConnectorId {
+            charger_id: charger_id.into(),
+            idx: 1,
+        };
+
+        let first = state.start_session(connector("CP001"), 200).unwrap();
+        assert_eq!(first.allocated_power, 150);
+
+        // Like the station capacity, the group capacity taken by the other sessions is only
+        // shared once they are reallocated
+        let second = state.start_session(connector("CP002"), 200).unwrap();
+        assert_eq!(second.allocated_power, 0);
+        let third = state.start_session(connector("CP003"), 200).unwrap();
+        assert_eq!(third.allocated_power, 200);
+
+        state.force_reallocation();
+        let allocated_power =
+            |session: &Session| state.get_sessions()[&session.session_id].allocated_power;
+        assert_eq!(allocated_power(&first), 75);
+        assert_eq!(allocated_power(&second), 75);
+        assert_eq!(allocated_power(&third), 200);
+    }
+
+    /// Log writer keeping the logs of a test, to check its warnings
+    #[derive(Clone, Default)]
+    struct LogCapture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogCapture {
+        fn logs(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[test]
+    fn test_max_vehicle_power() {
+        let mut config = default_config();
+        config.max_vehicle_power = Some(120);
+        let mut state = StationState::new(config);
+        let capture = LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let capture = capture.clone();
+                move || capture.clone()
+            })
+            .with_ansi(false)
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let session = state
+                .start_session(
+                    ConnectorId {
+                        charger_id: "CP001".into(),
+                        idx: 1,
+                    },
+                    100,
+                )
+                .unwrap();
+            assert_eq!(session.vehicle_max_power, 100);
+            assert!(!capture.logs().contains("WARN"));
+
+            let session = state
+                .start_session(
+                    ConnectorId {
+                        charger_id: "CP002".into(),
+                        idx: 1,
+                    },
+                    1_000_000,
+                )
+                .unwrap();
+            assert_eq!(session.vehicle_max_power, 120);
+            assert_eq!(session.requested_max_power, 120);
+            assert_eq!(session.allocated_power, 120);
+            let logs = capture.logs();
+            assert!(logs.contains("WARN"));
+            assert!(logs.contains("requested=1000000"));
+            assert!(logs.contains("max_vehicle_power=120"));
+
+            // Absurd consumed powers are clamped as well
+            let session = state.power_update(session.session_id, 500_000).unwrap();
+            assert_eq!(session.consumed_power, 120);
+            assert_eq!(session.vehicle_max_power, 120);
+            assert_eq!(session.allocated_power, 120);
+            assert!(capture.logs().contains("requested=500000"));
+        });
+    }
+
+    #[test]
+    fn test_auto_release_unused_power() {
+        let dip = |auto_release_unused_power: Option<bool>| {
+            let mut config = default_config();
+            config.grid_capacity = 250;
+            config.auto_release_unused_power = auto_release_unused_power;
+            let mut state = StationState::new(config);
+            let connector = |charger_id: &str| ConnectorId {
+                charger_id: charger_id.into(),
+                idx: 1,
+            };
+            let session = state.start_session(connector("CP001"), 200).unwrap();
+            let other_session = state.start_session(connector("CP002"), 200).unwrap();
+            // The sessions share the capacity, 125kW each
+            state.force_reallocation();
+
+            let session = state.power_update(session.session_id, 50).unwrap();
+            let other_session = state.power_update(other_session.session_id, 200).unwrap();
+            (session, other_session)
+        };
+
+        // By default, the power the vehicle does not consume goes to the other session
+        let (session, other_session) = dip(None);
+        assert_eq!(session.vehicle_max_power, 50);
+        assert_eq!(session.allocated_power, 50);
+        assert_eq!(session.consumed_power, 50);
+        assert_eq!(other_session.allocated_power, 200);
+
+        // Without the release, the dip is recorded but the allocations stay stable
+        let (session, other_session) = dip(Some(false));
+        assert_eq!(session.vehicle_max_power, 200);
+        assert_eq!(session.allocated_power, 125);
+        assert_eq!(session.consumed_power, 50);
+        assert_eq!(other_session.allocated_power, 125);
+    }
+
+    #[test]
+    fn test_reallocation_cooldown() {
+        let ping_pong = |reallocation_cooldown_ms: Option<u64>| {
+            let mut config = default_config();
+            config.grid_capacity = 250;
+            config.reallocation_cooldown_ms = reallocation_cooldown_ms;
+            let mut state = StationState::new(config);
+            let sessions = ["CP001", "CP002"].map(|charger_id| {
+                state
+                    .start_session(
+                        ConnectorId {
+                            charger_id: charger_id.into(),
+                            idx: 1,
+                        },
+                        200,
+                    )
+                    .unwrap()
+                    .session_id
+            });
+
+            // The sessions alternate their consumption, each update moving the power of the other
+            let changed_allocations = state.changed_allocation_count();
+            for round in 0..10 {
+                let (low, high) = if round % 2 == 0 {
+                    (sessions[0], sessions[1])
+                } else {
+                    (sessions[1], sessions[0])
+                };
+                state.power_update(low, 50).unwrap();
+                state.power_update(high, 200).unwrap();
+            }
+            (state, changed_allocations)
+        };
+
+        let (state, changed_allocations) = ping_pong(None);
+        assert!(state.changed_allocation_count() - changed_allocations >= 20);
+
+        // Within the cooldown, the allocations only follow the vehicles drawing less: the first
+        // session releases its unused power once, and no session gets more
+        let (mut state, changed_allocations) = ping_pong(Some(60_000));
+        assert_eq!(state.changed_allocation_count(), changed_allocations + 1);
+        let sessions = state.get_sessions();
+        assert!(
+            sessions
+                .values()
+                .all(|session| session.allocated_power <= session.vehicle_max_power)
+        );
+        let mut allocations = sessions
+            .values()
+            .map(|session| session.allocated_power)
+            .collect::<Vec<_>>();
+        allocations.sort();
+        assert_eq!(allocations, vec![50, 50]);
+
+        // A capacity change still reallocates the sessions
+        state
+            .apply_demand_response(0.5, Duration::from_secs(60))
+            .unwrap();
+        assert!(state.changed_allocation_count() > changed_allocations);
+    }
+
+    #[test]
+    fn test_emergency_stop() {
+        let mut state = default_state();
+        let connector = |charger_id: &str| ConnectorId {
+            charger_id: charger_id.into(),
+            idx: 1,
+        };
+        let charging = state.start_session(connector("CP001"), 150).unwrap();
+        state.power_update(charging.session_id, 150).unwrap();
+        let pending = state.start_session(connector("CP002"), 100).unwrap();
+
+        state.emergency_stop();
+        assert!(state.is_emergency_stopped());
+        let sessions = state.get_sessions();
+        assert_eq!(sessions.len(), 2);
+        assert!(
+            sessions
+                .values()
+                .all(|session| session.allocated_power == 0)
+        );
+        assert_eq!(
+            sessions[&charging.session_id].state,
+            SessionState::Suspended
+        );
+        assert_eq!(sessions[&pending.session_id].state, SessionState::Pending);
+
+        // The stop is latched until cleared
+        let session = state.power_update(charging.session_id, 150).unwrap();
+        assert_eq!(session.allocated_power, 0);
+        assert_eq!(session.state, SessionState::Suspended);
+        state.force_reallocation();
+        assert_eq!(
+            state.get_sessions()[&charging.session_id].allocated_power,
+            0
+        );
+        assert!(matches!(
+            state.start_session(connector("CP003"), 100),
+            Err(SessionError::EmergencyStop)
+        ));
+
+        state.clear_emergency_stop();
+        assert!(!state.is_emergency_stopped());
+        assert_eq!(
+            state.get_sessions()[&charging.session_id].allocated_power,
+            150
+        );
+        assert_eq!(
+            state.get_sessions()[&pending.session_id].allocated_power,
+            100
+        );
+        let session = state.power_update(charging.session_id, 150).unwrap();
+        assert_eq!(session.state, SessionState::Charging);
+        state.start_session(connector("CP003"), 100).unwrap();
+    }
+
+    #[test]
+    fn test_allocation_latency() {
+        let mut state = default_state();
+        assert_eq!(state.allocation_latency(), None);
+        assert_eq!(state.capacity_summary().allocation_latency, None);
+
+        state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        assert_eq!(state.allocation_latency().unwrap().samples, 1);
+
+        state.force_reallocation();
+        let latency = state.allocation_latency().unwrap();
+        assert_eq!(latency.samples, 2);
+        assert!(latency.min_us <= latency.avg_us && latency.avg_us <= latency.max_us);
+        assert_eq!(state.capacity_summary().allocation_latency, Some(latency));
+
+        // Previews do not change the station, so they are not recorded
+        state.what_if_capacity(200);
+        assert_eq!(state.allocation_latency().unwrap().samples, 2);
+    }
+
+    #[test]
+    fn test_fairness_epsilon() {
+        let allocations = |fairness_epsilon: u32| {
+            let mut config = default_config();
+            config.fairness_epsilon = fairness_epsilon;
+            let mut state = StationState::new(config);
+            let sessions = ["CP001", "CP002", "CP003"].map(|charger_id| {
+                state
+                    .start_session(
+                        ConnectorId {
+                            charger_id: charger_id.into(),
+                            idx: 1,
+                        },
+                        200,
+                    )
+                    .unwrap()
+            });
+            let allocated_powers = |state: &StationState| {
+                sessions
+                    .iter()
+                    .map(|session| state.get_sessions()[&session.session_id].allocated_power)
+                    .collect::<Vec<_>>()
+            };
+
+            // The 4kW cut is shared by the sessions, and applied even below the epsilon to stay
+            // within the capacity
+            let id = state
+                .apply_demand_response(0.99, Duration::from_secs(60))
+                .unwrap();
+            let curtailed = allocated_powers(&state);
+            assert_eq!(curtailed.iter().sum::<u32>(), 396);
+            let changed_allocations = state.changed_allocation_count();
+
+            state.end_demand_response(id);
+            let restored = allocated_powers(&state);
+            (
+                curtailed,
+                restored,
+                state.changed_allocation_count() - changed_allocations,
+            )
+        };
+
+        // Without epsilon, the sessions get their share of the restored capacity back
+        let (curtailed, restored, changed_allocations) = allocations(0);
+        assert!(restored.iter().sum::<u32>() > curtailed.iter().sum::<u32>());
+        assert!(changed_allocations > 0);
+
+        // Changes of 1 or 2kW are below the epsilon, the sessions keep their allocation
+        let (curtailed, restored, changed_allocations) = allocations(5);
+        assert_eq!(restored, curtailed);
+        assert_eq!(changed_allocations, 0);
+
+        // A vehicle lowering its consumption within the epsilon is not kept above it
+        let mut config = default_config();
+        config.fairness_epsilon = 5;
+        let mut state = StationState::new(config);
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .unwrap();
+        let session = state.power_update(session.session_id, 197).unwrap();
+        assert_eq!(session.vehicle_max_power, 197);
+        assert_eq!(session.allocated_power, 197);
+    }
+
+    #[test]
+    fn test_allocation_churn() {
+        let mut state = default_state();
+        for charger_id in ["CP001", "CP002"] {
+            state
+                .start_session(
+                    ConnectorId {
+                        charger_id: charger_id.into(),
+                        idx: 1,
+                    },
+                    150,
+                )
+                .unwrap();
+        }
+        // Starting sessions does not change the others
+        assert_eq!(state.changed_allocation_count(), 0);
+        assert_eq!(state.allocation_churn(), 0.0);
+
+        // The contended capacity changes both sessions, and changes them back when it ends
+        let id = state
+            .apply_demand_response(0.5, Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(state.changed_allocation_count(), 2);
+        assert_eq!(state.allocation_churn(), 2.0 / 3.0);
+        state.end_demand_response(id);
+        assert_eq!(state.changed_allocation_count(), 4);
+        assert_eq!(state.allocation_churn(), 1.0);
+
+        // A reallocation without change lowers the churn
+        state.force_reallocation();
+        assert_eq!(state.changed_allocation_count(), 4);
+        assert_eq!(state.allocation_churn(), 0.8);
+        let summary = state.capacity_summary();
+        assert_eq!(summary.changed_allocations, 4);
+        assert_eq!(summary.allocation_churn, 0.8);
+    }
+
+    #[test]
+    fn test_stability_score() {
+        let start_sessions = |state: &mut StationState| {
+            for charger_id in ["CP001", "CP002"] {
+                state
+                    .start_session(
+                        ConnectorId {
+                            charger_id: charger_id.into(),
+                            idx: 1,
+                        },
+                        150,
+                    )
+                    .unwrap();
+            }
+        };
+
+        // Sessions getting all they can take, with allocations that do not move
+        let mut state = default_state();
+        assert_eq!(state.stability_score(), 100);
+        start_sessions(&mut state);
+        state.force_reallocation();
+        assert_eq!(state.stability_score(), 100);
+        assert_eq!(state.capacity_summary().stability_score, 100);
+
+        // Allocations going down and up again on every reallocation
+        let mut state = default_state();
+        start_sessions(&mut state);
+        for _ in 0..5 {
+            let id = state
+                .apply_demand_response(0.5, Duration::from_secs(60))
+                .unwrap();
+            state.end_demand_response(id);
+        }
+        assert_eq!(state.flapping(), 1.0);
+        assert!(state.stability_score() < 50);
+
+        // Sessions sharing a charger get less than the others, which is their fair share
+        let mut state = default_state();
+        start_sessions(&mut state);
+        state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                150,
+            )
+            .unwrap();
+        state.force_reallocation();
+        assert_eq!(state.fairness(), 1.0);
+
+        // A session started with the capacity left by the others is below its fair share until
+        // the next reallocation
+        let mut state = default_state();
+        for charger_id in ["CP001", "CP002", "CP003"] {
+            state
+                .start_session(
+                    ConnectorId {
+                        charger_id: charger_id.into(),
+                        idx: 1,
+                    },
+                    190,
+                )
+                .unwrap();
+        }
+        assert!(state.fairness() < 1.0);
+        assert!(state.stability_score() < 100);
+        state.force_reallocation();
+        assert_eq!(state.fairness(), 1.0);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut state = default_state();
+        let connector = |charger_id: &str| ConnectorId {
+            charger_id: charger_id.into(),
+            idx: 1,
+        };
+        let session_ids = |sessions: &[Session]| {
+            sessions
+                .iter()
+                .map(|session| session.session_id)
+                .collect::<Vec<_>>()
+        };
+        let first = state.start_session(connector("CP001"), 150).unwrap();
+        assert!(state.diff(&state).is_empty());
+
+        // Added
+        let previous = state.clone();
+        let second = state.start_session(connector("CP002"), 150).unwrap();
+        let diff = previous.diff(&state);
+        assert_eq!(session_ids(&diff.added), vec![second.session_id]);
+        assert!(diff.removed.is_empty());
+        assert!(diff.changed.is_empty());
+
+        // Changed
+        let previous = state.clone();
+        state
+            .apply_demand_response(0.5, Duration::from_secs(60))
+            .unwrap();
+        let mut expected = vec![
+            AllocationChange {
+                session_id: first.session_id,
+                previous_power: 150,
+                allocated_power: 100,
+            },
+            AllocationChange {
+                session_id: second.session_id,
+                previous_power: 150,
+                allocated_power: 100,
+            },
+        ];
+        expected.sort_by_key(|change| change.session_id);
+        let diff = previous.diff(&state);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.changed, expected);
+
+        // Removed, which is added the other way round
+        let previous = state.clone();
+        state.stop_session(first.session_id).unwrap();
+        let diff = previous.diff(&state);
+        assert!(diff.added.is_empty());
+        assert_eq!(session_ids(&diff.removed), vec![first.session_id]);
+        assert!(diff.changed.is_empty());
+        assert_eq!(
+            session_ids(&state.diff(&previous).added),
+            vec![first.session_id]
+        );
+    }
+
+    #[test]
+    fn test_force_reallocation() {
+        let mut state = default_state();
+        state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        let count = state.reallocation_count();
+
+        // The allocations are already up to date
+        assert!(state.force_reallocation().is_empty());
+        assert_eq!(state.reallocation_count(), count + 1);
+    }
+
+    #[test]
+    fn test_sequential_id_generator() {
+        let mut state = default_state().with_id_generator(SequentialIdGenerator::new());
+        let first = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .unwrap();
+        // A preview does not take an id of the generator
+        let connector_id = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 2,
+        };
+        state
+            .marginal_impact(connector_id.clone(), 150, SessionOptions::default())
+            .unwrap();
+        state
+            .feasibility(connector_id.clone(), 150, SessionOptions::default())
+            .unwrap();
+        let second = state.start_session(connector_id, 150).unwrap();
+
+        assert_eq!(
+            first.session_id,
+            uuid::Uuid::parse_str("00000001-0000-0000-0000-000000000000").unwrap()
+        );
+        assert_eq!(
+            second.session_id,
+            uuid::Uuid::parse_str("00000002-0000-0000-0000-000000000000").unwrap()
+        );
+        assert_eq!(
+            state.resolve_session_id(&second.short_id).unwrap(),
+            second.session_id
+        );
+    }
+
+    #[test]
+    fn test_repeating_id_generator() {
+        struct ConstantIdGenerator;
+        impl IdGenerator for ConstantIdGenerator {
+            fn next_id(&self) -> uuid::Uuid {
+                uuid::Uuid::nil()
+            }
+        }
+        let mut state = default_state().with_id_generator(ConstantIdGenerator);
+        let connector_id = |idx| ConnectorId {
+            charger_id: "CP001".into(),
+            idx,
+        };
+        state.start_session(connector_id(1), 150).unwrap();
+
+        // The only id is taken, so the retries give up rather than spin forever
+        assert!(matches!(
+            state.start_session(connector_id(2), 150),
+            Err(SessionError::SessionIdsExhausted { .. })
+        ));
+        assert_eq!(state.get_sessions().len(), 1);
+    }
+
+    #[test]
+    fn test_power_update_restores_requested_max_power() {
+        let mut state = default_state();
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .expect("Could not create the session");
+        assert_eq!(session.allocated_power, 150);
+        assert_eq!(session.requested_max_power, 150);
+
+        // The vehicle tapers, freeing the power it does not use
+        let session = state
+            .power_update(session.session_id, 80)
+            .expect("Could not update power");
+        assert_eq!(session.vehicle_max_power, 80);
+        assert_eq!(session.allocated_power, 80);
+
+        // It then draws more than its allocation, and climbs back up to its requested power
+        let session = state
+            .power_update(session.session_id, 130)
+            .expect("Could not update power");
+        assert_eq!(session.vehicle_max_power, 150);
+        assert_eq!(session.allocated_power, 150);
+        assert_eq!(session.requested_max_power, 150);
+
+        // A suspended vehicle does not reclaim its power
+        let session = state
+            .power_update(session.session_id, 0)
+            .expect("Could not update power");
+        assert_eq!(session.allocated_power, 0);
+        let session = state
+            .power_update(session.session_id, 0)
+            .expect("Could not update power");
+        assert_eq!(session.vehicle_max_power, 0);
+        assert_eq!(session.allocated_power, 0);
+    }
+
+    #[test]
+    fn test_power_update_full_charger() {
+        let mut state = default_state();
+
+        let session_1 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_1.allocated_power, 200);
+
+        // Starting a session with a greater max_power
+        // hitting the charger capacity
+        let session_2 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_2.allocated_power, 0);
+
+        // Then we update the power for the session_1, which consume
+        // less than its allocated power.
+        let session_1 = state
+            .power_update(session_1.session_id, 80)
+            .expect("Error while updating power");
+        assert_eq!(session_1.vehicle_max_power, 80);
+        assert_eq!(session_1.allocated_power, 80);
+
+        // And we update session_2, which should receive more power
+        // as some have been freed.
+        let session_2 = state
+            .power_update(session_2.session_id, 0)
+            .expect("Error while updating power");
+        assert_eq!(session_2.vehicle_max_power, 200);
+        assert_eq!(session_2.allocated_power, 120);
+    }
+
+    #[test]
+    fn test_power_update_accross_chargers() {
+        let mut state = default_state();
+
+        let session_1 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_1.allocated_power, 100);
+
+        // Starting a session with a greater max_power
+        // hitting the charger capacity
+        let session_2 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 2,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session_2.allocated_power, 100);
+
+        // Starting a session on another charger,
+        // reaching grid capacity
+        let session_3 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .expect("Could not create the session");
+        assert_eq!(session_3.allocated_power, 200);
+
+        // Lowering the power on the first session
+        let session_1 = state
+            .power_update(session_1.session_id, 80)
+            .expect("Could not update power");
+        assert_eq!(session_1.vehicle_max_power, 80);
+        assert_eq!(session_1.allocated_power, 80);
+
+        // Then session_3 update its power usage, and should receive more power
+        let session_3 = state
+            .power_update(session_3.session_id, 200)
+            .expect("Could not update power");
+        assert_eq!(session_3.vehicle_max_power, 300);
+        assert_eq!(session_3.allocated_power, 200);
+    }
+
+    fn battery_config(battery: Bess) -> StationConfig {
+        StationConfig {
+            station_id: "ELECTRA_PARIS_15".into(),
+            grid_capacity: 100,
+            chargers: vec![ChargerConfig {
+                id: "CP001".into(),
+                max_power: 300,
+                connectors: 2,
+                ..Default::default()
+            }],
+            batteries: vec![battery],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_battery_power_limits_boost() {
+        // The battery holds enough energy for 200kW, but is rated for 50kW only
+        let mut state = StationState::new(battery_config(Bess::new(200, 50)));
+
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session.allocated_power, 150);
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Discharging(50)));
+    }
+
+    #[test]
+    fn test_battery_energy_limits_boost() {
+        // The battery is rated for 50kW but only holds enough energy for 30kW
+        let mut state = StationState::new(battery_config(Bess::new(30, 50)));
+
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session.allocated_power, 130);
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Discharging(30)));
+    }
+
+    #[test]
+    fn test_batteries_combined() {
+        let mut state = StationState::new(StationConfig {
+            batteries: vec![
+                // At its discharge floor, the first battery does not contribute
+                Bess::new(40, 100).with_discharge_floor(200, 20),
+                // Limited by its energy
+                Bess::new(30, 50),
+                // Limited by its power rating
+                Bess::new(200, 20),
+            ],
+            ..battery_config(Bess::new(0, 0))
+        });
+
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .expect("Could not create the session");
+
+        assert_eq!(session.allocated_power, 150);
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Discharging(50)));
+        assert_eq!(
+            state
+                .battery_statuses()
+                .iter()
+                .map(|status| status.discharge_floor_active)
+                .collect::<Vec<_>>(),
+            vec![true, false, false]
+        );
+
+        // The manual control is limited by the combined rating, and split within the limits of
+        // every battery
+        assert!(matches!(
+            state.set_battery_control(BatteryControl {
+                mode: BatteryMode::Discharge,
+                power: 171,
+            }),
+            Err(StationError::InvalidBatteryPower {
+                power: 171,
+                rating: 170
+            })
+        ));
+        state
+            .set_battery_control(BatteryControl {
+                mode: BatteryMode::Discharge,
+                power: 170,
+            })
+            .unwrap();
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Discharging(50)));
+    }
+
+    #[test]
+    fn test_battery_idle_under_grid_capacity() {
+        let mut state = StationState::new(battery_config(Bess::new(200, 50)));
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Idle));
+
+        state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                80,
+            )
+            .expect("Could not create the session");
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Idle));
+        assert_eq!(default_state().battery_flow(), None);
+    }
+
+    #[test]
+    fn test_battery_discharge_floor() {
+        // The battery holds 60kWh out of 200kWh, and keeps a 20% (40kWh) reserve
+        let mut state = StationState::new(battery_config(
+            Bess::new(60, 100).with_discharge_floor(200, 20),
+        ));
+        assert_eq!(
+            state.battery_statuses(),
+            vec![BatteryStatus {
+                soc_percent: Some(30),
+                discharge_floor_active: false,
+                control: BatteryControl::default(),
+            }]
+        );
+
+        // Only the 20kWh above the floor boost the capacity
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+        assert_eq!(session.allocated_power, 120);
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Discharging(20)));
+    }
+
+    #[test]
+    fn test_battery_discharge_floor_boundary() {
+        let start_session = |battery: Bess| {
+            StationState::new(battery_config(battery))
+                .start_session(
+                    ConnectorId {
+                        charger_id: "CP001".into(),
+                        idx: 1,
+                    },
+                    200,
+                )
+                .expect("Could not create the session")
+                .allocated_power
+        };
+
+        // At the floor, the battery does not contribute
+        let battery = Bess::new(40, 100).with_discharge_floor(200, 20);
+        assert!(battery.discharge_floor_active());
+        assert_eq!(start_session(battery), 100);
+
+        // Below the floor either
+        let battery = Bess::new(30, 100).with_discharge_floor(200, 20);
+        assert!(battery.discharge_floor_active());
+        assert_eq!(battery.soc_percent(), Some(15));
+        assert_eq!(start_session(battery), 100);
+
+        // Just above the floor, only the energy above it is usable
+        let battery = Bess::new(41, 100).with_discharge_floor(200, 20);
+        assert!(!battery.discharge_floor_active());
+        assert_eq!(start_session(battery), 101);
+
+        // Without a floor, the whole stored energy is usable
+        let battery = Bess::new(40, 100);
+        assert!(!battery.discharge_floor_active());
+        assert_eq!(battery.soc_percent(), None);
+        assert_eq!(start_session(battery), 140);
+    }
+
+    #[test]
+    fn test_bess_clamp_power() {
+        let battery = Bess::new(200, 50);
+        assert_eq!(battery.clamp_power(20), 20);
+        assert_eq!(battery.clamp_power(50), 50);
+        assert_eq!(battery.clamp_power(80), 50);
+    }
+
+    #[test]
+    fn test_battery_manual_control() {
+        // The battery holds 60kWh out of 200kWh, and keeps a 20% (40kWh) reserve
+        let mut state = StationState::new(battery_config(
+            Bess::new(60, 50).with_discharge_floor(200, 20),
+        ));
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .expect("Could not create the session");
+        assert_eq!(session.allocated_power, 120);
+
+        // Charging takes the battery power from the grid capacity
+        let charge = |power| BatteryControl {
+            mode: BatteryMode::Charge,
+            power,
+        };
+        state.set_battery_control(charge(30)).unwrap();
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Charging(30)));
+        assert_eq!(state.capacity_summary().effective_capacity, 70);
+        assert_eq!(
+            state
+                .get_session(session.session_id)
+                .unwrap()
+                .allocated_power,
+            70
+        );
+
+        // Discharging is limited by the 20kWh above the floor
+        state
+            .set_battery_control(BatteryControl {
+                mode: BatteryMode::Discharge,
+                power: 50,
+            })
+            .unwrap();
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Discharging(20)));
+        assert_eq!(state.capacity_summary().effective_capacity, 120);
+
+        state
+            .set_battery_control(BatteryControl {
+                mode: BatteryMode::Idle,
+                power: 50,
+            })
+            .unwrap();
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Idle));
+        assert_eq!(state.capacity_summary().effective_capacity, 100);
+
+        // The power rating cannot be exceeded
+        assert!(matches!(
+            state.set_battery_control(charge(60)),
+            Err(StationError::InvalidBatteryPower {
+                power: 60,
+                rating: 50
+            })
+        ));
+        assert_eq!(state.battery_statuses()[0].control.mode, BatteryMode::Idle);
+
+        // The auto mode restores the automatic discharge
+        state
+            .set_battery_control(BatteryControl::default())
+            .unwrap();
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Discharging(20)));
+        assert_eq!(
+            state
+                .get_session(session.session_id)
+                .unwrap()
+                .allocated_power,
+            120
+        );
+
+        assert!(matches!(
+            default_state().set_battery_control(charge(10)),
+            Err(StationError::BatteryNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_battery_charge_limited_when_full() {
+        // The battery can only store 10kWh more
+        let mut state = StationState::new(battery_config(
+            Bess::new(190, 50).with_discharge_floor(200, 20),
+        ));
+        state
+            .set_battery_control(BatteryControl {
+                mode: BatteryMode::Charge,
+                power: 50,
+            })
+            .unwrap();
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Charging(10)));
+        assert_eq!(state.capacity_summary().effective_capacity, 90);
+
+        // A full battery does not charge
+        let mut state = StationState::new(battery_config(
+            Bess::new(200, 50).with_discharge_floor(200, 20),
+        ));
+        state
+            .set_battery_control(BatteryControl {
+                mode: BatteryMode::Charge,
+                power: 50,
+            })
+            .unwrap();
+        assert_eq!(state.battery_flow(), Some(BatteryFlow::Idle));
+        assert_eq!(state.capacity_summary().effective_capacity, 100);
+    }
+
+    #[test]
+    fn test_power_cap() {
+        let mut state = default_state();
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .unwrap();
+        assert_eq!(session.allocated_power, 300);
+
+        // The cap reallocates the sessions immediately
+        state.set_power_cap(250);
+        assert_eq!(state.capacity_summary().effective_capacity, 250);
+        assert_eq!(state.capacity_summary().power_cap, Some(250));
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            250
+        );
+
+        // With a demand response, the lowest capacity wins
+        let id = state
+            .apply_demand_response(0.5, Duration::from_secs(900))
+            .unwrap();
+        assert_eq!(state.capacity_summary().effective_capacity, 200);
+        state.set_power_cap(150);
+        assert_eq!(state.capacity_summary().effective_capacity, 150);
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            150
+        );
+
+        state.clear_power_cap();
+        assert_eq!(state.capacity_summary().power_cap, None);
+        assert_eq!(state.capacity_summary().effective_capacity, 200);
+        state.end_demand_response(id);
+        assert_eq!(state.capacity_summary().effective_capacity, 400);
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            300
+        );
+
+        // A cap above the grid capacity has no effect
+        state.set_power_cap(1000);
+        assert_eq!(state.capacity_summary().effective_capacity, 400);
+    }
+
+    #[test]
+    fn test_safety_margin() {
+        let mut state = default_state();
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .unwrap();
+        assert_eq!(session.allocated_power, 300);
+
+        // The margin reallocates the sessions immediately
+        state.set_safety_margin(50).unwrap();
+        assert_eq!(state.capacity_summary().safety_margin_percent, 50);
+        assert_eq!(state.capacity_summary().effective_capacity, 200);
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            200
+        );
+
+        // It applies on top of a demand response, and a lower power cap wins
+        state.set_safety_margin(10).unwrap();
+        let id = state
+            .apply_demand_response(0.5, Duration::from_secs(900))
+            .unwrap();
+        assert_eq!(state.capacity_summary().effective_capacity, 180);
+        state.set_power_cap(150);
+        assert_eq!(state.capacity_summary().effective_capacity, 150);
+        state.clear_power_cap();
+        state.end_demand_response(id);
+
+        // Above 50%, the margin is refused and the current one kept
+        assert!(matches!(
+            state.set_safety_margin(51),
+            Err(StationError::InvalidSafetyMargin { percent: 51 })
+        ));
+        assert_eq!(state.safety_margin_percent(), 10);
+        assert_eq!(state.capacity_summary().effective_capacity, 360);
+
+        state.set_safety_margin(0).unwrap();
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            300
+        );
+    }
+
+    #[test]
+    fn test_demand_response() {
+        let mut state = default_state();
+
+        let session_1 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .expect("Could not create the session");
+        let session_2 = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                300,
+            )
+            .expect("Could not create the session");
+        assert_eq!(session_1.allocated_power, 200);
+        assert_eq!(session_2.allocated_power, 200);
+
+        // Curtailing to 60% of the grid capacity reallocates the sessions immediately
+        let id = state
+            .apply_demand_response(0.6, Duration::from_secs(900))
+            .expect("Could not apply demand response");
+        assert_eq!(
+            state.get_sessions()[&session_1.session_id].allocated_power,
+            120
+        );
+        assert_eq!(
+            state.get_sessions()[&session_2.session_id].allocated_power,
+            120
+        );
+
+        let summary = state.capacity_summary();
+        assert_eq!(summary.effective_capacity, 240);
+        assert_eq!(summary.remaining_capacity, 0);
+        let demand_response = summary.demand_response.expect("Demand response is active");
+        assert_eq!(demand_response.fraction, 0.6);
+        assert!(demand_response.remaining_secs <= 900);
+
+        // A stale id does not end the demand response
+        state.end_demand_response(id + 1);
+        assert!(state.capacity_summary().demand_response.is_some());
+
+        // Ending it restores the full capacity
+        state.end_demand_response(id);
+        assert!(state.capacity_summary().demand_response.is_none());
+        assert_eq!(
+            state.get_sessions()[&session_1.session_id].allocated_power,
+            200
+        );
+        assert_eq!(
+            state.get_sessions()[&session_2.session_id].allocated_power,
+            200
+        );
+    }
+
+    #[test]
+    fn test_capacity_cut_mid_session() {
+        let mut state = default_state();
+        let connector_id = |charger_id: &str| ConnectorId {
+            charger_id: charger_id.into(),
+            idx: 1,
+        };
+        let allocated_power = |state: &StationState, session: &Session| {
+            state.get_sessions()[&session.session_id].allocated_power
+        };
+
+        let session_1 = state.start_session(connector_id("CP001"), 200).unwrap();
+        let session_2 = state.start_session(connector_id("CP003"), 300).unwrap();
+        state.power_update(session_1.session_id, 200).unwrap();
+        state.power_update(session_2.session_id, 150).unwrap();
+        assert_eq!(allocated_power(&state, &session_1), 200);
+        assert_eq!(allocated_power(&state, &session_2), 150);
+
+        // The capacity is cut to 200kW, but the sessions keep the power they are drawing until
+        // their next power update
+        state
+            .apply_demand_response(0.5, Duration::from_secs(900))
+            .unwrap();
+        assert_eq!(allocated_power(&state, &session_1), 200);
+        assert_eq!(allocated_power(&state, &session_2), 150);
+        assert_eq!(state.capacity_summary().remaining_capacity, 0);
+
+        // A new session is capped by what is left, that is nothing
+        let session_3 = state.start_session(connector_id("CP002"), 100).unwrap();
+        assert_eq!(session_3.allocated_power, 0);
+
+        // The sessions go back within the capacity as they update
+        state.power_update(session_1.session_id, 200).unwrap();
+        assert_eq!(allocated_power(&state, &session_1), 66);
+        state.power_update(session_2.session_id, 150).unwrap();
+        assert_eq!(allocated_power(&state, &session_2), 66);
+        state.power_update(session_3.session_id, 100).unwrap();
+        assert_eq!(allocated_power(&state, &session_3), 66);
+        assert!(state.capacity_summary().allocated_power <= 200);
+    }
+
+    #[test]
+    fn test_capacity_cut_does_not_raise_over_floors() {
+        let mut state = StationState::new(StationConfig {
+            grid_capacity: 300,
+            ..default_config()
+        });
+        let connector_id = |charger_id: &str| ConnectorId {
+            charger_id: charger_id.into(),
+            idx: 1,
+        };
+
+        let session_1 = state.start_session(connector_id("CP001"), 200).unwrap();
+        let session_2 = state.start_session(connector_id("CP003"), 300).unwrap();
+        state.force_reallocation();
+        state.power_update(session_1.session_id, 200).unwrap();
+        let sessions = state.get_sessions();
+        assert_eq!(sessions[&session_1.session_id].allocated_power, 150);
+        assert_eq!(sessions[&session_2.session_id].allocated_power, 150);
+
+        // The first session keeps the power it draws, so the second one cannot take the power
+        // its derated charger leaves
+        state.derate_charger("CP001", 50).unwrap();
+        let sessions = state.get_sessions();
+        assert_eq!(sessions[&session_1.session_id].allocated_power, 150);
+        assert_eq!(sessions[&session_2.session_id].allocated_power, 150);
+        assert!(state.capacity_summary().allocated_power <= 300);
+
+        // It is raised once the first session updates
+        state.power_update(session_1.session_id, 200).unwrap();
+        state.force_reallocation();
+        let sessions = state.get_sessions();
+        assert_eq!(sessions[&session_1.session_id].allocated_power, 50);
+        assert_eq!(sessions[&session_2.session_id].allocated_power, 250);
+    }
+
+    #[test]
+    fn test_capacity_cut_before_any_update() {
+        let mut state = default_state();
+
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+            )
+            .unwrap();
+        assert_eq!(session.allocated_power, 200);
+
+        // Without a reported consumption, the session is reallocated immediately
+        state
+            .apply_demand_response(0.25, Duration::from_secs(900))
+            .unwrap();
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            100
+        );
+    }
+
+    #[test]
+    fn test_marginal_impact() {
+        let mut state = StationState::new(StationConfig {
+            grid_capacity: 300,
+            ..default_config()
+        });
+        let connector_id = |charger_id: &str, idx| ConnectorId {
+            charger_id: charger_id.into(),
+            idx,
+        };
+
+        // The station is saturated by two sessions on different chargers
+        let session_1 = state.start_session(connector_id("CP001", 1), 200).unwrap();
+        let session_2 = state.start_session(connector_id("CP003", 1), 200).unwrap();
+        assert_eq!(session_1.allocated_power, 200);
+        assert_eq!(session_2.allocated_power, 100);
+        state.power_update(session_1.session_id, 200).unwrap();
+        state.power_update(session_2.session_id, 200).unwrap();
+        assert_eq!(state.capacity_summary().remaining_capacity, 0);
+        let allocations = state
+            .get_sessions()
+            .iter()
+            .map(|(id, session)| (*id, session.allocated_power))
+            .collect::<HashMap<_, _>>();
+
+        // A third session would take a third of the capacity from the two others
+        let impacts = state
+            .marginal_impact(connector_id("CP002", 1), 200, SessionOptions::default())
+            .unwrap();
+        assert_eq!(impacts.len(), 2);
+        for (session_id, change) in &impacts {
+            assert_eq!(allocations[session_id] as i64 + change, 100);
+        }
+        assert!(impacts.iter().all(|(_, change)| *change <= 0));
+
+        // The state is not changed
+        assert_eq!(state.get_sessions().len(), 2);
+        for (session_id, session) in state.get_sessions() {
+            assert_eq!(session.allocated_power, allocations[session_id]);
+        }
+
+        // A connector in use cannot be previewed
+        match state.marginal_impact(connector_id("CP001", 1), 200, SessionOptions::default()) {
+            Err(SessionError::ConnectorAlreadyInUse { .. }) => {}
+            _ => panic!("Expected ConnectorAlreadyInUse error"),
+        }
+    }
+
+    #[test]
+    fn test_feasibility() {
+        let connector_id = |charger_id: &str, idx| ConnectorId {
+            charger_id: charger_id.into(),
+            idx,
+        };
+        let feasibility = |state: &StationState, charger_id, idx, vehicle_max_power| {
+            state
+                .feasibility(
+                    connector_id(charger_id, idx),
+                    vehicle_max_power,
+                    SessionOptions::default(),
+                )
+                .unwrap()
+        };
+
+        // On an empty station, the vehicle is the limit
+        let state = default_state();
+        assert_eq!(
+            feasibility(&state, "CP001", 1, 100),
+            Feasibility {
+                feasible: true,
+                max_achievable_power: 100,
+                binding_constraint: BindingConstraint::Vehicle,
+            }
+        );
+
+        // The cable of the connector
+        let mut config = default_config();
+        config.chargers[0].connector_max_power = Some(50);
+        let state = StationState::new(config);
+        let result = feasibility(&state, "CP001", 1, 100);
+        assert_eq!(result.max_achievable_power, 50);
+        assert_eq!(result.binding_constraint, BindingConstraint::Connector);
+
+        // The charger shared with another session, or suspended
+        let mut state = default_state();
+        state.start_session(connector_id("CP001", 1), 200).unwrap();
+        let result = feasibility(&state, "CP001", 2, 200);
+        assert_eq!(result.max_achievable_power, 100);
+        assert_eq!(result.binding_constraint, BindingConstraint::Charger);
+        state.suspend_charger("CP002").unwrap();
+        let result = feasibility(&state, "CP002", 1, 200);
+        assert!(!result.feasible);
+        assert_eq!(result.binding_constraint, BindingConstraint::Charger);
+
+        // The group of the charger
+        let mut config = default_config();
+        config.groups = vec![ChargerGroup {
+            id: "FEEDER".into(),
+            max_power: 150,
+        }];
+        config.chargers[0].group_id = Some("FEEDER".into());
+        let state = StationState::new(config);
+        let result = feasibility(&state, "CP001", 1, 200);
+        assert_eq!(result.max_achievable_power, 150);
+        assert_eq!(result.binding_constraint, BindingConstraint::Group);
+
+        // The station saturated by the other sessions
+        let mut state = StationState::new(StationConfig {
+            grid_capacity: 300,
+            ..default_config()
+        });
+        state.start_session(connector_id("CP001", 1), 200).unwrap();
+        state.start_session(connector_id("CP003", 1), 200).unwrap();
+        let result = feasibility(&state, "CP002", 1, 200);
+        assert_eq!(result.max_achievable_power, 100);
+        assert_eq!(result.binding_constraint, BindingConstraint::Station);
+
+        // The connector must still be available
+        assert!(matches!(
+            state.feasibility(connector_id("CP001", 1), 200, SessionOptions::default()),
+            Err(SessionError::ConnectorAlreadyInUse { .. })
+        ));
+    }
+
+    #[test]
+    fn test_demand_response_invalid_fraction() {
+        let mut state = default_state();
+
+        let result = state.apply_demand_response(1.5, Duration::from_secs(900));
+        match result {
+            Err(StationError::InvalidDemandResponse { fraction }) => assert_eq!(fraction, 1.5),
+            _ => panic!("Expected InvalidDemandResponse error"),
+        }
+        assert!(state.capacity_summary().demand_response.is_none());
+
+        // A duration overflowing the clock is refused rather than panicking
+        assert!(matches!(
+            state.apply_demand_response(0.5, Duration::from_secs(u64::MAX)),
+            Err(StationError::InvalidDemandResponseDuration {
+                duration_secs: u64::MAX
+            })
+        ));
+        assert!(state.capacity_summary().demand_response.is_none());
+    }
+
+    #[test]
+    fn test_deadline_mode() {
+        let mut state = StationState::new(StationConfig {
+            grid_capacity: 200,
+            allocation_mode: AllocationMode::Deadline,
+            ..default_config()
+        });
+
+        let relaxed = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                150,
+            )
+            .expect("Could not create the session");
+        assert_eq!(relaxed.allocated_power, 150);
+
+        // The session with a tight deadline gets all the power it can take,
+        // to be applied as soon as the other session lowers its consumption.
+        let tight = state
+            .start_session_with_options(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                150,
+                SessionOptions {
+                    depart_by: Some(chrono::Utc::now() + chrono::Duration::minutes(30)),
+                    energy_needed_wh: Some(60_000),
+                    ..Default::default()
+                },
+            )
+            .expect("Could not create the session");
+        assert_eq!(tight.allocated_power, 50);
+
+        let relaxed = state
+            .power_update(relaxed.session_id, 150)
+            .expect("Could not update power");
+        assert_eq!(relaxed.allocated_power, 50);
+        let tight = state
+            .power_update(tight.session_id, 50)
+            .expect("Could not update power");
+        assert_eq!(tight.allocated_power, 150);
+    }
+
+    #[test]
+    fn test_session_state_transitions() {
+        let mut state = default_state();
+
+        let session = state
+            .start_session(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+            )
+            .expect("Could not create the session");
+        assert_eq!(session.state, SessionState::Pending);
+
+        // Not drawing power yet keeps the session pending
+        let session = state
+            .power_update(session.session_id, 0)
+            .expect("Could not update power");
+        assert_eq!(session.state, SessionState::Pending);
+
+        let session = state
+            .power_update(session.session_id, 100)
+            .expect("Could not update power");
+        assert_eq!(session.state, SessionState::Charging);
+
+        let session = state
+            .power_update(session.session_id, 0)
+            .expect("Could not update power");
+        assert_eq!(session.state, SessionState::Suspended);
+
+        let session = state
+            .power_update(session.session_id, 50)
+            .expect("Could not update power");
+        assert_eq!(session.state, SessionState::Charging);
+
+        let session = state
+            .stop_session(session.session_id)
+            .expect("Could not stop the session");
+        assert_eq!(session.state, SessionState::Finished);
+        assert_eq!(session.allocated_power, 0);
+        assert!(state.get_sessions().is_empty());
+
+        // A finished session cannot be updated nor stopped again
+        match state.power_update(session.session_id, 50) {
+            Err(SessionError::InvalidTransition { from, to, .. }) => {
+                assert_eq!(from, SessionState::Finished);
+                assert_eq!(to, SessionState::Charging);
+            }
+            _ => panic!("Expected InvalidTransition error"),
+        }
+        match state.stop_session(session.session_id) {
+            Err(SessionError::InvalidTransition { from, to, .. }) => {
+                assert_eq!(from, SessionState::Finished);
+                assert_eq!(to, SessionState::Finished);
+            }
+            _ => panic!("Expected InvalidTransition error"),
+        }
+    }
+
+    #[test]
+    fn test_stop_session_not_found() {
+        let mut state = default_state();
+        let non_existent_session_id = uuid::Uuid::new_v4();
+
+        match state.stop_session(non_existent_session_id) {
+            Err(SessionError::SessionNotFound { session_id }) => {
+                assert_eq!(session_id, non_existent_session_id);
+            }
+            _ => panic!("Expected SessionNotFound error"),
+        }
+    }
+
+    #[test]
+    fn test_stopped_session_gone() {
+        let mut state = default_state();
+        let connector = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 1,
+        };
+        let first_session = state.start_session(connector.clone(), 100).unwrap();
+        state.stop_session(first_session.session_id).unwrap();
+        let stopped_at = state.recently_stopped(first_session.session_id).unwrap();
+
+        // Push the first session out of the retained finished sessions
+        for _ in 0..FINISHED_SESSIONS_RETAINED {
+            let session = state.start_session(connector.clone(), 100).unwrap();
+            state.stop_session(session.session_id).unwrap();
+        }
+
+        match state.get_session(first_session.session_id) {
+            Err(SessionError::SessionGone {
+                session_id,
+                stopped_at: gone_at,
+            }) => {
+                assert_eq!(session_id, first_session.session_id);
+                assert_eq!(gone_at, stopped_at);
             }
-            _ => panic!("Expected ConnectorNotFound error"),
+            _ => panic!("Expected SessionGone error"),
         }
+        assert!(matches!(
+            state.power_update(first_session.session_id, 50),
+            Err(SessionError::SessionGone { .. })
+        ));
+
+        // A session that never existed is still not found
+        assert!(state.recently_stopped(uuid::Uuid::new_v4()).is_none());
+        assert!(matches!(
+            state.get_session(uuid::Uuid::new_v4()),
+            Err(SessionError::SessionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_pin_session() {
+        let mut state = default_state();
+        let connector = |charger_id: &str, idx| ConnectorId {
+            charger_id: charger_id.into(),
+            idx,
+        };
+        let first = state.start_session(connector("CP001", 1), 200).unwrap();
+        let second = state.start_session(connector("CP002", 1), 200).unwrap();
+        let pinned = state.start_session(connector("CP003", 1), 300).unwrap();
+        let allocated_power = |state: &StationState, session: &Session| {
+            state.sessions[&session.session_id].allocated_power
+        };
+
+        // The pinned session keeps its power, the others share the rest of the 400kW
+        let session = state.pin_session(pinned.session_id, 250).unwrap();
+        assert_eq!(session.pinned_power, Some(250));
+        assert_eq!(session.allocated_power, 250);
+        assert_eq!(allocated_power(&state, &first), 75);
+        assert_eq!(allocated_power(&state, &second), 75);
+
+        // It is not reduced by a new session nor by a reallocation
+        let third = state.start_session(connector("CP002", 2), 200).unwrap();
+        state.force_reallocation();
+        assert_eq!(allocated_power(&state, &pinned), 250);
+        assert_eq!(allocated_power(&state, &first), 50);
+        assert_eq!(allocated_power(&state, &second), 50);
+        assert_eq!(allocated_power(&state, &third), 50);
+
+        // A pin is still bounded by the vehicle max power
+        state.pin_session(pinned.session_id, 1000).unwrap();
+        assert_eq!(allocated_power(&state, &pinned), 300);
+        assert_eq!(allocated_power(&state, &first), 33);
+
+        // Unpinned, the session gets its fair share again
+        let session = state.unpin_session(pinned.session_id).unwrap();
+        assert_eq!(session.pinned_power, None);
+        assert_eq!(session.allocated_power, 100);
+
+        assert!(matches!(
+            state.pin_session(uuid::Uuid::new_v4(), 100),
+            Err(SessionError::SessionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_expire_session() {
+        let mut state = default_state();
+        let expiring = state
+            .start_session_with_options(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                200,
+                SessionOptions {
+                    expires_at: Some(chrono::Utc::now() - chrono::Duration::seconds(1)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let later = state
+            .start_session_with_options(
+                ConnectorId {
+                    charger_id: "CP003".into(),
+                    idx: 1,
+                },
+                300,
+                SessionOptions {
+                    expires_at: Some(chrono::Utc::now() + chrono::Duration::hours(1)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(state.get_session(later.session_id).unwrap().allocated_power < 300);
+
+        // A session is not stopped before its expiry
+        assert!(state.expire_session(later.session_id).is_none());
+
+        let expired = state.expire_session(expiring.session_id).unwrap();
+        assert_eq!(expired.state, SessionState::Finished);
+        // The power of the expired session is reallocated
+        assert_eq!(
+            state.get_session(later.session_id).unwrap().allocated_power,
+            300
+        );
+
+        // Expiring a session twice is a no-op
+        assert!(state.expire_session(expiring.session_id).is_none());
+    }
+
+    #[test]
+    fn test_current_conversions() {
+        let mut config = default_config();
+        config.chargers[0].phases = Some(1);
+        let state = StationState::new(config);
 
-        // Test valid connector should work
-        let valid_connector = ConnectorId {
+        let single_phase = ConnectorId {
             charger_id: "CP001".into(),
             idx: 1,
         };
+        let three_phase = ConnectorId {
+            charger_id: "CP002".into(),
+            idx: 1,
+        };
 
-        let result = state.start_session(valid_connector, 100);
-        assert!(result.is_ok());
+        // 7kW on 230V single-phase is 30.4A
+        assert_eq!(state.power_to_current(&single_phase, 7).unwrap(), 30);
+        // 32A on 230V single-phase is 7.36kW
+        assert_eq!(state.current_to_power(&single_phase, 32).unwrap(), 7);
+        // 22kW on 230V three-phase is 31.9A
+        assert_eq!(state.power_to_current(&three_phase, 22).unwrap(), 31);
+        // 32A on 230V three-phase is 22.08kW
+        assert_eq!(state.current_to_power(&three_phase, 32).unwrap(), 22);
+
+        let unknown = ConnectorId {
+            charger_id: "INVALID".into(),
+            idx: 1,
+        };
+        assert!(matches!(
+            state.power_to_current(&unknown, 7),
+            Err(SessionError::ConnectorNotFound { .. })
+        ));
     }
 
     #[test]
-    fn test_start_session() {
+    fn test_coalesced_power_updates() {
         let mut state = default_state();
 
         let session_1 = state
@@ -314,14 +5380,9 @@ mod test {
                     charger_id: "CP001".into(),
                     idx: 1,
                 },
-                100,
+                200,
             )
             .expect("Could not create the session");
-
-        assert_eq!(session_1.allocated_power, 100);
-
-        // Starting a session with a greater max_power
-        // hitting the charger capacity
         let session_2 = state
             .start_session(
                 ConnectorId {
@@ -331,88 +5392,244 @@ mod test {
                 200,
             )
             .expect("Could not create the session");
+        assert_eq!(session_1.allocated_power, 200);
+        assert_eq!(session_2.allocated_power, 0);
+        let reallocation_count = state.reallocation_count();
 
-        assert_eq!(session_2.allocated_power, 100);
+        // Recording updates does not reallocate the power
+        let session_1 = state
+            .record_power_update(session_1.session_id, 80)
+            .expect("Could not record power update");
+        assert_eq!(session_1.allocated_power, 200);
+        let session_2 = state
+            .record_power_update(session_2.session_id, 0)
+            .expect("Could not record power update");
+        assert_eq!(session_2.allocated_power, 0);
+        assert!(state.has_pending_power_updates());
+        assert_eq!(state.reallocation_count(), reallocation_count);
 
-        // Starting a session on another charger,
-        // reaching grid capacity
-        let session_3 = state
+        // Flushing reallocates both sessions at once
+        state.flush_power_updates().unwrap();
+        assert!(!state.has_pending_power_updates());
+        assert_eq!(state.reallocation_count(), reallocation_count + 1);
+        assert_eq!(
+            state.get_sessions()[&session_1.session_id].allocated_power,
+            80
+        );
+        assert_eq!(
+            state.get_sessions()[&session_2.session_id].allocated_power,
+            120
+        );
+
+        // Flushing without pending updates does not run the allocator
+        state.flush_power_updates().unwrap();
+        assert_eq!(state.reallocation_count(), reallocation_count + 1);
+    }
+
+    #[test]
+    fn test_flush_power_updates_allocation_failed() {
+        let mut state = default_state();
+
+        let session = state
             .start_session(
                 ConnectorId {
-                    charger_id: "CP003".into(),
+                    charger_id: "CP001".into(),
                     idx: 1,
                 },
-                300,
+                200,
             )
             .expect("Could not create the session");
-        assert_eq!(session_3.allocated_power, 200);
+        state
+            .record_power_update(session.session_id, 80)
+            .expect("Could not record power update");
 
-        // Starting a session on a new charger,
-        // but no capacity left
-        let session_4 = state
+        // The allocator cannot allocate a session whose charger is not in the configuration
+        state.chargers.remove("CP001");
+        match state.flush_power_updates() {
+            Err(SessionError::AllocationFailed { session_id }) => {
+                assert_eq!(session_id, session.session_id)
+            }
+            _ => panic!("Expected AllocationFailed error"),
+        }
+
+        // The session keeps its previous allocation
+        assert!(!state.has_pending_power_updates());
+        assert_eq!(
+            state.get_sessions()[&session.session_id].allocated_power,
+            200
+        );
+    }
+
+    #[test]
+    fn test_resolve_label() {
+        let mut config = default_config();
+        config.chargers[1].labels = HashMap::from([(1, "A1".into()), (2, "A2".into())]);
+        let state = StationState::new(config);
+
+        assert_eq!(
+            state.resolve_label("A2").unwrap(),
+            ConnectorId {
+                charger_id: "CP002".into(),
+                idx: 2,
+            }
+        );
+        match state.resolve_label("B1") {
+            Err(SessionError::ConnectorLabelNotFound { label }) => assert_eq!(label, "B1"),
+            _ => panic!("Expected ConnectorLabelNotFound error"),
+        }
+    }
+
+    #[test]
+    fn test_capacity_shrink_below_allocation() {
+        let mut state = default_state();
+
+        let session_1 = state
             .start_session(
                 ConnectorId {
-                    charger_id: "CP002".into(),
+                    charger_id: "CP001".into(),
                     idx: 1,
                 },
                 200,
             )
             .expect("Could not create the session");
-        assert_eq!(session_4.allocated_power, 0);
-
-        // Removing a session to free some capacity and adding a new session
-        // that will receive its fair share.
-        state.stop_session(session_3.session_id);
-
-        let session_5 = state
+        let session_2 = state
             .start_session(
                 ConnectorId {
-                    charger_id: "CP002".into(),
-                    idx: 2,
+                    charger_id: "CP003".into(),
+                    idx: 1,
                 },
                 200,
             )
             .expect("Could not create the session");
+        assert_eq!(session_1.allocated_power, 200);
+        assert_eq!(session_2.allocated_power, 200);
 
-        assert_eq!(session_5.allocated_power, 100);
+        // Shrink the capacities below what is already allocated, which used to underflow
+        state.config.grid_capacity = 150;
+        state.chargers.get_mut("CP001").unwrap().max_power = 100;
+        assert_eq!(state.station_remaining_capacity(), 0);
+        assert_eq!(state.charger_remaining_capacity("CP001"), 0);
+
+        // The reallocated session is brought back to its fair share of the new capacity
+        let session_1 = state
+            .power_update(session_1.session_id, 200)
+            .expect("Could not update power");
+        assert_eq!(session_1.allocated_power, 75);
     }
 
     #[test]
-    fn test_power_update_session_not_found() {
+    fn test_extreme_values() {
+        let mut state = StationState::new(StationConfig {
+            grid_capacity: u32::MAX,
+            chargers: vec![ChargerConfig {
+                id: "CP001".into(),
+                max_power: u32::MAX,
+                connectors: 2,
+                ..Default::default()
+            }],
+            batteries: vec![Bess::new(u32::MAX, u32::MAX)],
+            voltage: Some(u32::MAX),
+            ..default_config()
+        });
+        let connector_id = ConnectorId {
+            charger_id: "CP001".into(),
+            idx: 1,
+        };
+
+        // Adding the battery boost and converting units used to overflow
+        let session = state
+            .start_session(connector_id.clone(), u32::MAX)
+            .expect("Could not create the session");
+        assert_eq!(session.allocated_power, u32::MAX);
+        assert_eq!(
+            state.current_to_power(&connector_id, u32::MAX).unwrap(),
+            u32::MAX
+        );
+        assert_eq!(
+            state.power_to_current(&connector_id, u32::MAX).unwrap(),
+            1000
+        );
+    }
+
+    #[test]
+    fn test_update_session_metadata() {
         let mut state = default_state();
-        let non_existent_session_id = uuid::Uuid::new_v4();
+        let session = state
+            .start_session_with_options(
+                ConnectorId {
+                    charger_id: "CP001".into(),
+                    idx: 1,
+                },
+                100,
+                SessionOptions {
+                    metadata: HashMap::from([
+                        ("userId".into(), "U42".into()),
+                        ("rfid".into(), "04A1B2".into()),
+                    ]),
+                    ..Default::default()
+                },
+            )
+            .expect("Could not create the session");
+        assert_eq!(session.metadata["userId"], "U42");
 
-        // Try to update power for a non-existent session
-        let result = state.power_update(non_existent_session_id, 150);
-        assert!(result.is_err());
+        let session = state
+            .update_session_metadata(
+                session.session_id,
+                HashMap::from([
+                    ("userId".into(), "U43".into()),
+                    ("fleetId".into(), "F1".into()),
+                ]),
+            )
+            .expect("Could not update the metadata");
+        assert_eq!(session.metadata.len(), 3);
+        assert_eq!(session.metadata["userId"], "U43");
+        assert_eq!(session.metadata["rfid"], "04A1B2");
+        assert_eq!(session.metadata["fleetId"], "F1");
+        assert_eq!(session.allocated_power, 100);
 
-        match result {
+        let unknown_session_id = uuid::Uuid::new_v4();
+        match state.update_session_metadata(unknown_session_id, HashMap::new()) {
             Err(SessionError::SessionNotFound { session_id }) => {
-                assert_eq!(session_id, non_existent_session_id);
+                assert_eq!(session_id, unknown_session_id);
             }
             _ => panic!("Expected SessionNotFound error"),
         }
+    }
 
-        // Test successful power update
+    #[test]
+    fn test_power_update_removed_charger() {
+        let mut state = default_state();
         let connector_id = ConnectorId {
-            charger_id: "CP001".into(),
+            charger_id: "CP003".into(),
             idx: 1,
         };
         let session = state
-            .start_session(connector_id, 100)
-            .expect("Could not create session");
+            .start_session(connector_id.clone(), 100)
+            .expect("Could not create the session");
 
-        let result = state.power_update(session.session_id, 80);
-        assert!(result.is_ok());
+        state.chargers.remove("CP003");
 
-        if let Ok(updated_session) = result {
-            assert_eq!(updated_session.vehicle_max_power, 80);
+        match state.power_update(session.session_id, 50) {
+            Err(SessionError::ConnectorNotFound {
+                connector_id: err_connector_id,
+            }) => {
+                assert_eq!(err_connector_id, connector_id);
+            }
+            _ => panic!("Expected ConnectorNotFound error"),
         }
+        // The update was not recorded, so the session keeps its state and allocation
+        assert!(!state.has_pending_power_updates());
+        let session = &state.get_sessions()[&session.session_id];
+        assert_eq!(session.state, SessionState::Pending);
+        assert_eq!(session.allocated_power, 100);
     }
 
     #[test]
-    fn test_power_update() {
-        let mut state = default_state();
+    fn test_suspend_charger() {
+        let mut state = StationState::new(StationConfig {
+            grid_capacity: 300,
+            ..default_config()
+        });
 
         let session_1 = state
             .start_session(
@@ -420,37 +5637,69 @@ mod test {
                     charger_id: "CP001".into(),
                     idx: 1,
                 },
-                100,
+                200,
             )
             .expect("Could not create the session");
-
-        assert_eq!(session_1.allocated_power, 100);
-
-        // Starting a session with a greater max_power
-        // hitting the charger capacity
         let session_2 = state
             .start_session(
                 ConnectorId {
-                    charger_id: "CP001".into(),
-                    idx: 2,
+                    charger_id: "CP003".into(),
+                    idx: 1,
                 },
-                200,
+                300,
             )
             .expect("Could not create the session");
-
+        assert_eq!(session_1.allocated_power, 200);
         assert_eq!(session_2.allocated_power, 100);
+        state.power_update(session_1.session_id, 200).unwrap();
 
-        // Then we update the power for the session_1, which should
-        // have a lower max_power and allocated value
-        let session_1 = state
-            .power_update(session_1.session_id, 80)
-            .expect("Error while updating power");
-        assert_eq!(session_1.vehicle_max_power, 80);
-        assert_eq!(session_1.allocated_power, 80);
+        // The sessions of the suspended charger get no power, even if they were drawing power,
+        // and stay active
+        state
+            .suspend_charger("CP001")
+            .expect("Could not suspend the charger");
+        assert_eq!(
+            state.get_sessions()[&session_1.session_id].allocated_power,
+            0
+        );
+        assert_eq!(state.get_sessions().len(), 2);
+        let status = &state.charger_statuses()[0];
+        assert!(status.suspended);
+        assert_eq!(status.max_power, 0);
+        assert_eq!(status.configured_max_power, 200);
+
+        // The freed capacity goes to the other chargers
+        assert_eq!(
+            state.get_sessions()[&session_2.session_id].allocated_power,
+            300
+        );
+
+        // Resuming restores the derated max power, not the configured one
+        state
+            .derate_charger("CP001", 150)
+            .expect("Could not derate the charger");
+        assert_eq!(state.charger_statuses()[0].max_power, 0);
+        state
+            .resume_charger("CP001")
+            .expect("Could not resume the charger");
+        let status = &state.charger_statuses()[0];
+        assert!(!status.suspended);
+        assert_eq!(status.max_power, 150);
+        assert_eq!(
+            state.get_sessions()[&session_1.session_id].allocated_power,
+            150
+        );
+
+        match state.suspend_charger("CP999") {
+            Err(StationError::ChargerNotFound { charger_id }) => {
+                assert_eq!(charger_id, "CP999");
+            }
+            _ => panic!("Expected ChargerNotFound error"),
+        }
     }
 
     #[test]
-    fn test_power_update_full_charger() {
+    fn test_derate_charger() {
         let mut state = default_state();
 
         let session_1 = state
@@ -459,48 +5708,73 @@ mod test {
                     charger_id: "CP001".into(),
                     idx: 1,
                 },
-                200,
+                100,
             )
             .expect("Could not create the session");
-
-        assert_eq!(session_1.allocated_power, 200);
-
-        // Starting a session with a greater max_power
-        // hitting the charger capacity
         let session_2 = state
             .start_session(
                 ConnectorId {
                     charger_id: "CP001".into(),
                     idx: 2,
                 },
-                200,
+                100,
             )
             .expect("Could not create the session");
+        assert_eq!(session_1.allocated_power, 100);
+        assert_eq!(session_2.allocated_power, 100);
 
-        assert_eq!(session_2.allocated_power, 0);
+        // The derating binds below the configured max power of 200kW
+        state
+            .derate_charger("CP001", 120)
+            .expect("Could not derate the charger");
+        assert_eq!(
+            state.get_sessions()[&session_1.session_id].allocated_power,
+            60
+        );
+        assert_eq!(
+            state.get_sessions()[&session_2.session_id].allocated_power,
+            60
+        );
+        let status = &state.charger_statuses()[0];
+        assert_eq!(status.id, "CP001");
+        assert_eq!(status.configured_max_power, 200);
+        assert_eq!(status.max_power, 120);
+        assert_eq!(status.derated_max_power, Some(120));
+        assert_eq!(status.allocated_power, 120);
 
-        // Then we update the power for the session_1, which consume
-        // less than its allocated power.
-        let session_1 = state
-            .power_update(session_1.session_id, 80)
-            .expect("Error while updating power");
-        assert_eq!(session_1.vehicle_max_power, 80);
-        assert_eq!(session_1.allocated_power, 80);
+        // A derating above the configured max power has no effect
+        state
+            .derate_charger("CP001", 500)
+            .expect("Could not derate the charger");
+        assert_eq!(state.charger_statuses()[0].max_power, 200);
 
-        // And we update session_2, which should receive more power
-        // as some have been freed.
-        let session_2 = state
-            .power_update(session_2.session_id, 0)
-            .expect("Error while updating power");
-        assert_eq!(session_2.vehicle_max_power, 200);
-        assert_eq!(session_2.allocated_power, 120);
+        state
+            .derate_charger("CP001", 120)
+            .expect("Could not derate the charger");
+        state
+            .clear_charger_derating("CP001")
+            .expect("Could not clear the derating");
+        assert_eq!(
+            state.get_sessions()[&session_1.session_id].allocated_power,
+            100
+        );
+        let status = &state.charger_statuses()[0];
+        assert_eq!(status.max_power, 200);
+        assert_eq!(status.derated_max_power, None);
+
+        match state.derate_charger("CP999", 100) {
+            Err(StationError::ChargerNotFound { charger_id }) => {
+                assert_eq!(charger_id, "CP999");
+            }
+            _ => panic!("Expected ChargerNotFound error"),
+        }
+        assert_eq!(state.charger_statuses().len(), 3);
     }
 
     #[test]
-    fn test_power_update_accross_chargers() {
+    fn test_resolve_session_id() {
         let mut state = default_state();
-
-        let session_1 = state
+        let session = state
             .start_session(
                 ConnectorId {
                     charger_id: "CP001".into(),
@@ -509,48 +5783,89 @@ mod test {
                 100,
             )
             .expect("Could not create the session");
+        assert_eq!(session.short_id.len(), 8);
+        assert_eq!(session.short_id, models::short_id(&session.session_id));
 
-        assert_eq!(session_1.allocated_power, 100);
+        let session_id = session.session_id;
+        assert_eq!(
+            state.resolve_session_id(&session_id.to_string()).unwrap(),
+            session_id
+        );
+        assert_eq!(
+            state.resolve_session_id(&session.short_id).unwrap(),
+            session_id
+        );
+        assert_eq!(
+            state
+                .resolve_session_id(&session.short_id.to_uppercase())
+                .unwrap(),
+            session_id
+        );
 
-        // Starting a session with a greater max_power
-        // hitting the charger capacity
-        let session_2 = state
-            .start_session(
-                ConnectorId {
-                    charger_id: "CP001".into(),
-                    idx: 2,
-                },
-                200,
-            )
-            .expect("Could not create the session");
+        assert_eq!(
+            state.get_session(session_id).unwrap().state,
+            SessionState::Pending
+        );
 
-        assert_eq!(session_2.allocated_power, 100);
+        // The finished sessions can still be resolved, to report them as finished
+        state.stop_session(session_id).unwrap();
+        assert_eq!(
+            state.resolve_session_id(&session.short_id).unwrap(),
+            session_id
+        );
+        assert_eq!(
+            state.get_session(session_id).unwrap().state,
+            SessionState::Finished
+        );
 
-        // Starting a session on another charger,
-        // reaching grid capacity
-        let session_3 = state
-            .start_session(
-                ConnectorId {
-                    charger_id: "CP003".into(),
-                    idx: 1,
-                },
-                300,
-            )
-            .expect("Could not create the session");
-        assert_eq!(session_3.allocated_power, 200);
+        match state.resolve_session_id("zzzzzzzz") {
+            Err(SessionError::SessionIdNotFound { id }) => assert_eq!(id, "zzzzzzzz"),
+            _ => panic!("Expected SessionIdNotFound error"),
+        }
+    }
 
-        // Lowering the power on the first session
-        let session_1 = state
-            .power_update(session_1.session_id, 80)
-            .expect("Could not update power");
-        assert_eq!(session_1.vehicle_max_power, 80);
-        assert_eq!(session_1.allocated_power, 80);
+    #[test]
+    fn test_short_id() {
+        let session_id = uuid::Uuid::parse_str("d93f53f5-a853-430b-b33e-281a9f706a51").unwrap();
+        // 0xd93f53f5a8 in base32
+        assert_eq!(models::short_id(&session_id), "v4zn7xd8");
+        assert_eq!(models::short_id(&uuid::Uuid::nil()), "00000000");
+    }
 
-        // Then session_3 update its power usage, and should receive more power
-        let session_3 = state
-            .power_update(session_3.session_id, 200)
-            .expect("Could not update power");
-        assert_eq!(session_3.vehicle_max_power, 300);
-        assert_eq!(session_3.allocated_power, 200);
+    #[test]
+    fn test_connector_base() {
+        let mut config = default_config();
+        config.chargers[0].connector_base = Some(0);
+        let mut state = StationState::new(config);
+        let connector_id = |charger_id: &str, idx| ConnectorId {
+            charger_id: charger_id.into(),
+            idx,
+        };
+
+        // CP001 has 2 connectors numbered from 0
+        state.start_session(connector_id("CP001", 0), 100).unwrap();
+        state.start_session(connector_id("CP001", 1), 100).unwrap();
+        match state.start_session(connector_id("CP001", 2), 100) {
+            Err(SessionError::ConnectorNotFound { connector_id }) => {
+                assert_eq!(connector_id.idx, 2)
+            }
+            _ => panic!("Expected ConnectorNotFound error"),
+        }
+
+        // CP002 keeps the default base, with 2 connectors numbered from 1
+        match state.start_session(connector_id("CP002", 0), 100) {
+            Err(SessionError::InvalidConnectorIndex { connector_base, .. }) => {
+                assert_eq!(connector_base, 1)
+            }
+            _ => panic!("Expected InvalidConnectorIndex error"),
+        }
+        state.start_session(connector_id("CP002", 1), 100).unwrap();
+        state.start_session(connector_id("CP002", 2), 100).unwrap();
+        match state.start_session(connector_id("CP002", 3), 100) {
+            Err(SessionError::ConnectorNotFound { connector_id }) => {
+                assert_eq!(connector_id.idx, 3)
+            }
+            _ => panic!("Expected ConnectorNotFound error"),
+        }
     }
 }
@@ -1,52 +1,913 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+use crate::ConfigProblem;
+
+/// The deserialization converts the powers to kW, see `StationConfig::power_unit`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(remote = "Self", rename_all = "camelCase")]
 pub struct StationConfig {
     pub station_id: String,
     pub grid_capacity: u32,
+    /// Capacity of each of the three phases of the grid connection, in the unit of the
+    /// configuration. The allocation keeps every phase within it, even when the total is under
+    /// the grid capacity. Only the grid capacity applies if not set.
+    #[serde(default)]
+    pub grid_phase_capacity: Option<u32>,
     pub chargers: Vec<ChargerConfig>,
-    pub battery: Option<Bess>,
+    /// Battery units of the station, combining their power and energy. The former single
+    /// `battery` is still accepted.
+    #[serde(default, alias = "battery", deserialize_with = "deserialize_batteries")]
+    pub batteries: Vec<Bess>,
+    #[serde(default)]
+    pub allocation_mode: AllocationMode,
+    /// Phase-to-neutral voltage of the station, in V.
+    #[serde(default)]
+    pub voltage: Option<u32>,
+    /// If set, the power updates received within this window are reallocated together.
+    #[serde(default)]
+    pub coalesce_window_ms: Option<u64>,
+    /// Unit of the grid capacity and of the charger max powers in the configuration. They are
+    /// converted to kW when the configuration is loaded, so this is always kW afterwards.
+    #[serde(default)]
+    pub power_unit: ConfigPowerUnit,
+    /// Groups of chargers behind a shared cable or switchgear, with their own power limit.
+    #[serde(default)]
+    pub groups: Vec<ChargerGroup>,
+    /// Highest vehicle max power accepted, in the unit of the configuration. Higher requests are
+    /// clamped to it, so that a single absurd request does not distort the sharing. Unbounded if
+    /// not set.
+    #[serde(default)]
+    pub max_vehicle_power: Option<u32>,
+    /// Smallest change of allocated power applied to a session, in kW whatever the unit of the
+    /// configuration. Smaller changes keep the previous allocation, to avoid pushing
+    /// insignificant changes to the chargers. 0, the default, applies every change.
+    #[serde(default)]
+    pub fairness_epsilon: u32,
+    /// Whether a session consuming less than its allocation has its vehicle max power lowered to
+    /// its consumption, freeing the rest for the other sessions. True by default.
+    #[serde(default)]
+    pub auto_release_unused_power: Option<bool>,
+    /// If set, the power updates of a session change its allocation at most once within this
+    /// interval, in ms, except to lower it to the vehicle max power. The reallocations on capacity
+    /// changes are applied regardless.
+    #[serde(default)]
+    pub reallocation_cooldown_ms: Option<u64>,
+    /// Whether the new sessions that would get no power wait in a queue, and start in their
+    /// order of arrival once capacity frees, instead of starting with no power. False by default.
+    #[serde(default)]
+    pub queue_when_full: bool,
+    /// If set, the responses have a `display_power` smoothing the allocated power of every
+    /// session for the UIs, each allocation moving it by this factor of the gap, from 0 excluded
+    /// to 1, however long apart the allocations are. The chargers still get the allocated power.
+    #[serde(default)]
+    pub display_smoothing_factor: Option<f32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Serialize for StationConfig {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        StationConfig::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StationConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        StationConfig::deserialize(deserializer)?
+            .into_kw()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Unit of the powers of a station configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum ConfigPowerUnit {
+    #[default]
+    #[serde(rename = "kW")]
+    Kw,
+    /// Megawatts, for large hubs. The powers are still whole numbers.
+    #[serde(rename = "MW")]
+    Mw,
+}
+
+impl ConfigPowerUnit {
+    /// Return the number of kW in one unit.
+    fn kw(self) -> u32 {
+        match self {
+            ConfigPowerUnit::Kw => 1,
+            ConfigPowerUnit::Mw => 1000,
+        }
+    }
+}
+
+impl StationConfig {
+    /// Return the phase-to-neutral voltage of the station, 230V by default.
+    pub fn voltage(&self) -> u32 {
+        self.voltage.unwrap_or(230)
+    }
+
+    /// Return whether the power a session does not consume is released to the other sessions,
+    /// true by default.
+    pub fn auto_release_unused_power(&self) -> bool {
+        self.auto_release_unused_power.unwrap_or(true)
+    }
+
+    /// Convert the grid and phase capacities, the charger and group max powers and the max vehicle
+    /// power from the configured unit to kW.
+    fn into_kw(mut self) -> Result<Self, String> {
+        let unit = self.power_unit;
+        // Only the MW can overflow
+        let to_kw = |name: &str, power: u32| {
+            power
+                .checked_mul(unit.kw())
+                .ok_or_else(|| format!("{} of {}MW exceeds the maximum power", name, power))
+        };
+        self.grid_capacity = to_kw("gridCapacity", self.grid_capacity)?;
+        if let Some(grid_phase_capacity) = self.grid_phase_capacity {
+            self.grid_phase_capacity = Some(to_kw("gridPhaseCapacity", grid_phase_capacity)?);
+        }
+        for charger in &mut self.chargers {
+            charger.max_power = to_kw(&format!("maxPower of {}", charger.id), charger.max_power)?;
+        }
+        for group in &mut self.groups {
+            group.max_power = to_kw(&format!("maxPower of group {}", group.id), group.max_power)?;
+        }
+        if let Some(max_vehicle_power) = self.max_vehicle_power {
+            self.max_vehicle_power = Some(to_kw("maxVehiclePower", max_vehicle_power)?);
+        }
+        self.power_unit = ConfigPowerUnit::Kw;
+        Ok(self)
+    }
+
+    /// Return the max power of every charger group, by group id.
+    pub(crate) fn group_capacities(&self) -> HashMap<String, u32> {
+        self.groups
+            .iter()
+            .map(|group| (group.id.clone(), group.max_power))
+            .collect()
+    }
+
+    /// Return the combined power rating of the batteries, in kW.
+    pub fn battery_power_rating(&self) -> u32 {
+        self.batteries
+            .iter()
+            .map(Bess::power_rating)
+            .fold(0u32, u32::saturating_add)
+    }
+
+    /// Check the consistency of the configuration, returning all its problems at once.
+    ///
+    /// A charger exceeds the station capacity when its max power is above the grid capacity and
+    /// the battery power rating combined.
+    pub fn validate(&self) -> Result<(), Vec<ConfigProblem>> {
+        let station_capacity = self
+            .grid_capacity
+            .saturating_add(self.battery_power_rating());
+        let mut problems = Vec::new();
+        let mut group_ids = HashSet::new();
+        for group in &self.groups {
+            if !group_ids.insert(group.id.as_str()) {
+                problems.push(ConfigProblem::DuplicateGroupId {
+                    group_id: group.id.clone(),
+                });
+            }
+        }
+        let mut charger_ids = HashSet::new();
+        let mut labels = HashSet::new();
+        for charger in &self.chargers {
+            if !charger_ids.insert(charger.id.as_str()) {
+                problems.push(ConfigProblem::DuplicateChargerId {
+                    charger_id: charger.id.clone(),
+                });
+            }
+            if charger.connectors == 0 {
+                problems.push(ConfigProblem::NoConnectors {
+                    charger_id: charger.id.clone(),
+                });
+            }
+            // A station without capacity is reported once below, rather than for every charger
+            if station_capacity > 0 && charger.max_power > station_capacity {
+                problems.push(ConfigProblem::ChargerExceedsStationCapacity {
+                    charger_id: charger.id.clone(),
+                    max_power: charger.max_power,
+                    station_capacity,
+                });
+            }
+            if let Some(group_id) = &charger.group_id
+                && !group_ids.contains(group_id.as_str())
+            {
+                problems.push(ConfigProblem::UnknownGroup {
+                    charger_id: charger.id.clone(),
+                    group_id: group_id.clone(),
+                });
+            }
+            if let Some(usable_min_power) = charger.usable_min_power
+                && usable_min_power > charger.max_power
+            {
+                problems.push(ConfigProblem::UsableMinPowerAboveMaxPower {
+                    charger_id: charger.id.clone(),
+                    usable_min_power,
+                    max_power: charger.max_power,
+                });
+            }
+            if let Some(phase) = charger.phase
+                && !(1..=3).contains(&phase)
+            {
+                problems.push(ConfigProblem::InvalidPhase {
+                    charger_id: charger.id.clone(),
+                    phase,
+                });
+            }
+            if !charger.connector_powers.is_empty()
+                && charger.connector_powers.len() != charger.connectors as usize
+            {
+                problems.push(ConfigProblem::ConnectorPowersMismatch {
+                    charger_id: charger.id.clone(),
+                    connector_powers: charger.connector_powers.len(),
+                    connectors: charger.connectors,
+                });
+            }
+            // Sorted by connector, for the problems to be reported in a stable order
+            let mut charger_labels = charger.labels.iter().collect::<Vec<_>>();
+            charger_labels.sort();
+            for (idx, label) in charger_labels {
+                if !charger.has_connector(*idx) {
+                    problems.push(ConfigProblem::LabelOnMissingConnector {
+                        charger_id: charger.id.clone(),
+                        idx: *idx,
+                        label: label.clone(),
+                    });
+                }
+                if !labels.insert(label.as_str()) {
+                    problems.push(ConfigProblem::DuplicateLabel {
+                        label: label.clone(),
+                    });
+                }
+            }
+        }
+
+        let reserved_capacity = self
+            .chargers
+            .iter()
+            .filter_map(|charger| charger.reserved_capacity)
+            .fold(0u32, u32::saturating_add);
+        if reserved_capacity > self.grid_capacity {
+            problems.push(ConfigProblem::ReservedCapacityExceedsGridCapacity {
+                reserved_capacity,
+                grid_capacity: self.grid_capacity,
+            });
+        }
+
+        if station_capacity == 0 && !self.chargers.is_empty() {
+            problems.push(ConfigProblem::NoStationCapacity);
+        }
+
+        if self
+            .display_smoothing_factor
+            .is_some_and(|factor| !(factor > 0.0 && factor <= 1.0))
+        {
+            problems.push(ConfigProblem::InvalidDisplaySmoothingFactor);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Return the problems that do not prevent the configuration from being applied, but are
+    /// likely configuration errors, in the order of the chargers.
+    pub fn warnings(&self) -> Vec<ConfigProblem> {
+        self.chargers
+            .iter()
+            // The warning is about the uniform connector max power, which the connector powers
+            // override
+            .filter(|charger| charger.connector_powers.is_empty())
+            .filter_map(|charger| {
+                let connector_max_power = charger.connector_max_power?;
+                let connectors_max_power = charger.connectors_max_power()?;
+                (connectors_max_power < charger.max_power).then(|| {
+                    ConfigProblem::ConnectorsBelowChargerMaxPower {
+                        charger_id: charger.id.clone(),
+                        connector_max_power,
+                        connectors_max_power,
+                        max_power: charger.max_power,
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+/// How the station capacity is shared between the sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum AllocationMode {
+    /// Every session is offered the same share of the capacity.
+    #[default]
+    Fair,
+    /// Sessions at risk of missing their departure deadline are served first.
+    Deadline,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct ChargerConfig {
     pub id: String,
     pub max_power: u32,
     pub connectors: u8,
+    /// Number of phases of the connectors.
+    #[serde(default)]
+    pub phases: Option<u8>,
+    /// Phase of the grid connection a single-phase charger is wired to, from 1 to 3, L1 if not
+    /// set. A two-phase charger is wired to this phase and the next one, and the other chargers
+    /// draw evenly from the three phases.
+    #[serde(default)]
+    pub phase: Option<u8>,
+    /// Human-friendly labels of the connectors, by connector index.
+    #[serde(default)]
+    pub labels: HashMap<u8, String>,
+    /// Chargers with a higher priority are served first when the station capacity is binding.
+    #[serde(default)]
+    pub priority: u8,
+    /// How the power of the charger is shared between its connectors.
+    #[serde(default)]
+    pub sharing: SharingMode,
+    /// Index of the first connector, 0 or 1.
+    #[serde(default, deserialize_with = "deserialize_connector_base")]
+    pub connector_base: Option<u8>,
+    /// Max power in kW of each connector, e.g. the rating of its cable, which caps the session
+    /// on it.
+    #[serde(default)]
+    pub connector_max_power: Option<u32>,
+    /// Max power in kW of every connector, by connector from the first one, for chargers whose
+    /// connectors differ, e.g. DC chargers with a faster connector. Overrides
+    /// `connector_max_power` when set, and must have one power per connector.
+    #[serde(default)]
+    pub connector_powers: Vec<u32>,
+    /// Minimum power in kW a vehicle can charge with, e.g. on DC chargers. Sessions that would be
+    /// allocated less get no power, which goes to the other sessions instead.
+    #[serde(default)]
+    pub usable_min_power: Option<u32>,
+    /// Duration in seconds of the warm-up some hardware needs at the start of a session, during
+    /// which the session gets at least `warmup_power` before sharing the power fairly.
+    #[serde(default)]
+    pub warmup_secs: Option<u64>,
+    /// Minimum power in kW of a session during the warm-up, bounded by the capacity left.
+    #[serde(default)]
+    pub warmup_power: Option<u32>,
+    /// Capacity in kW of the station reserved to the charger, e.g. by contract, that its sessions
+    /// get before the other sessions share the rest.
+    #[serde(default)]
+    pub reserved_capacity: Option<u32>,
+    /// Firmware version of the charger, e.g. to track rollouts.
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    /// Manufacturer of the charger.
+    #[serde(default)]
+    pub vendor: Option<String>,
+    /// Group of the charger, whose max power its sessions share with the other chargers of the
+    /// group. An ungrouped charger is only limited by the station capacity.
+    #[serde(default)]
+    pub group_id: Option<String>,
+}
+
+/// Chargers behind a shared cable or switchgear, e.g. a feeder, which limits their combined
+/// power between the charger and station levels.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ChargerGroup {
+    pub id: String,
+    /// Max power of the chargers of the group combined, in the unit of the configuration.
+    pub max_power: u32,
+}
+
+/// Deserialize the connector base, which can only be 0 or 1.
+fn deserialize_connector_base<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<u8>::deserialize(deserializer)? {
+        base @ (None | Some(0 | 1)) => Ok(base),
+        Some(base) => Err(serde::de::Error::custom(format!(
+            "connector base must be 0 or 1, got {}",
+            base
+        ))),
+    }
+}
+
+impl ChargerConfig {
+    /// Return the number of phases of the connectors, three by default.
+    pub fn phases(&self) -> u8 {
+        self.phases.unwrap_or(3)
+    }
+
+    /// Return the index of the first connector, 1 by default as in OCPP 1.6 where 0 addresses
+    /// the whole charger.
+    pub fn connector_base(&self) -> u8 {
+        self.connector_base.unwrap_or(1)
+    }
+
+    /// Return whether the charger has a connector with this index.
+    pub fn has_connector(&self, idx: u8) -> bool {
+        idx >= self.connector_base() && idx - self.connector_base() < self.connectors
+    }
+
+    /// Return the max power of the connector with this index, if set.
+    ///
+    /// The power of the connector in `connector_powers` wins over the uniform
+    /// `connector_max_power`.
+    pub fn connector_max_power_of(&self, idx: u8) -> Option<u32> {
+        if self.connector_powers.is_empty() {
+            return self.connector_max_power;
+        }
+        let position = idx.checked_sub(self.connector_base())?;
+        self.connector_powers.get(position as usize).copied()
+    }
+
+    /// Return the max power the connectors can deliver together, if their max power is set.
+    ///
+    /// A sequential charger delivers to one connector at a time, and a dedicated charger's max
+    /// power applies to each of its connectors.
+    pub fn connectors_max_power(&self) -> Option<u32> {
+        if !self.connector_powers.is_empty() {
+            return Some(match self.sharing {
+                SharingMode::Shared => self
+                    .connector_powers
+                    .iter()
+                    .fold(0u32, |sum, power| sum.saturating_add(*power)),
+                SharingMode::Dedicated | SharingMode::Sequential => {
+                    self.connector_powers.iter().copied().max().unwrap_or(0)
+                }
+            });
+        }
+        let connector_max_power = self.connector_max_power?;
+        Some(match self.sharing {
+            SharingMode::Shared => connector_max_power.saturating_mul(self.connectors as u32),
+            SharingMode::Dedicated | SharingMode::Sequential => connector_max_power,
+        })
+    }
+
+    /// Return when the warm-up of the session on the charger ends, if the charger has a warm-up.
+    pub fn warmup_ends_at(&self, session: &Session) -> Option<DateTime<Utc>> {
+        self.warmup_power?;
+        let warmup_secs = chrono::TimeDelta::try_seconds(i64::try_from(self.warmup_secs?).ok()?)?;
+        session.started_at.checked_add_signed(warmup_secs)
+    }
+
+    /// Return the power guaranteed to the session if it is still warming up on the charger.
+    pub(crate) fn warmup_power_at(&self, session: &Session, now: DateTime<Utc>) -> Option<u32> {
+        let warmup_power = self.warmup_power?;
+        let warmup_secs = i64::try_from(self.warmup_secs?).unwrap_or(i64::MAX);
+        (session.age(now).num_seconds() < warmup_secs).then_some(warmup_power)
+    }
+
+    /// Return the share of the power of the charger drawn from each phase of the grid connection.
+    pub fn phase_shares(&self) -> [f64; 3] {
+        let first = (self.phase.unwrap_or(1).clamp(1, 3) - 1) as usize;
+        let mut shares = [0.0; 3];
+        match self.phases {
+            Some(1) => shares[first] = 1.0,
+            Some(2) => {
+                shares[first] = 0.5;
+                shares[(first + 1) % 3] = 0.5;
+            }
+            // Three-phase AC and DC chargers are balanced
+            _ => shares = [1.0 / 3.0; 3],
+        }
+        shares
+    }
+
+    /// Return the max power the charger can deliver over all its connectors.
+    pub fn total_max_power(&self) -> u32 {
+        match self.sharing {
+            SharingMode::Dedicated => self.max_power.saturating_mul(self.connectors as u32),
+            SharingMode::Shared | SharingMode::Sequential => self.max_power,
+        }
+    }
+}
+
+/// How the power of a charger is shared between its connectors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum SharingMode {
+    /// The connectors share one power module, the max power caps the sum of their sessions.
+    #[default]
+    Shared,
+    /// Every connector has its own power module, the max power caps each of their sessions.
+    Dedicated,
+    /// The connectors share one power module that delivers to one connector at a time, the
+    /// first started session gets the power while the others wait.
+    Sequential,
+}
+
+/// Deserialize the batteries from a list, or from the former single (possibly null) battery.
+fn deserialize_batteries<'de, D>(deserializer: D) -> Result<Vec<Bess>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Batteries {
+        Many(Vec<Bess>),
+        One(Option<Bess>),
+    }
+
+    Ok(match Batteries::deserialize(deserializer)? {
+        Batteries::Many(batteries) => batteries,
+        Batteries::One(battery) => battery.into_iter().collect(),
+    })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct Bess {
     initial_capacity: u32,
     power: u32,
+    /// Energy the battery holds when full, in kWh, to compute its state of charge.
+    #[serde(default)]
+    energy_capacity: Option<u32>,
+    /// State of charge in percent below which the battery does not discharge, to preserve its
+    /// health and an emergency reserve. Requires the energy capacity.
+    #[serde(default)]
+    discharge_floor_percent: Option<u8>,
+}
+
+impl Bess {
+    pub fn new(initial_capacity: u32, power: u32) -> Self {
+        Bess {
+            initial_capacity,
+            power,
+            energy_capacity: None,
+            discharge_floor_percent: None,
+        }
+    }
+
+    /// Only allow the battery to discharge above `discharge_floor_percent` of its
+    /// `energy_capacity`.
+    pub fn with_discharge_floor(
+        mut self,
+        energy_capacity: u32,
+        discharge_floor_percent: u8,
+    ) -> Self {
+        self.energy_capacity = Some(energy_capacity);
+        self.discharge_floor_percent = Some(discharge_floor_percent);
+        self
+    }
+
+    /// Return the state of charge of the battery in percent, if its energy capacity is known.
+    pub fn soc_percent(&self) -> Option<u8> {
+        let energy_capacity = self.energy_capacity.filter(|capacity| *capacity > 0)?;
+        Some((self.initial_capacity as u64 * 100 / energy_capacity as u64).min(100) as u8)
+    }
+
+    /// Return the stored energy the battery can discharge, that is the energy above its
+    /// discharge floor if it has one.
+    fn usable_energy(&self) -> u32 {
+        match (self.energy_capacity, self.discharge_floor_percent) {
+            (Some(energy_capacity), Some(floor_percent)) => {
+                let floor_energy = energy_capacity as u64 * floor_percent.min(100) as u64 / 100;
+                // The floor energy is at most the energy capacity, so it fits in a u32
+                self.initial_capacity.saturating_sub(floor_energy as u32)
+            }
+            _ => self.initial_capacity,
+        }
+    }
+
+    /// Return whether the battery reached its discharge floor, so that it cannot discharge.
+    pub fn discharge_floor_active(&self) -> bool {
+        self.discharge_floor_percent.is_some()
+            && self.energy_capacity.is_some()
+            && self.usable_energy() == 0
+    }
+
+    /// Clamp a requested charge or discharge power to the battery power rating.
+    ///
+    /// A warning is logged when the rating is the binding constraint.
+    pub fn clamp_power(&self, requested: u32) -> u32 {
+        if requested > self.power {
+            tracing::warn!(
+                requested,
+                rating = self.power,
+                "Battery power rating is limiting the requested power"
+            );
+            self.power
+        } else {
+            requested
+        }
+    }
+
+    /// Return the power the battery can add on top of the grid capacity.
+    ///
+    /// The usable energy is considered over a one hour horizon, so a battery holding
+    /// 200kWh above its discharge floor can sustain 200kW, but never more than its power rating.
+    ///
+    /// Unlike `clamp_power`, no warning is logged, as the boost is computed at every allocation.
+    pub(crate) fn capacity_boost(&self) -> u32 {
+        self.usable_energy().min(self.power)
+    }
+
+    /// Return the power rating of the battery, in kW.
+    pub fn power_rating(&self) -> u32 {
+        self.power
+    }
+
+    /// Return the power the battery can discharge when requested `requested`, limited by its
+    /// power rating and its usable energy over a one hour horizon.
+    ///
+    /// Unlike `clamp_power`, no warning is logged, as the requested power may be split among
+    /// several batteries.
+    pub(crate) fn discharge_power(&self, requested: u32) -> u32 {
+        requested.min(self.power).min(self.usable_energy())
+    }
+
+    /// Return the power the battery can charge when requested `requested`, limited by its power
+    /// rating and the energy it can still store over a one hour horizon if its energy capacity
+    /// is known.
+    pub(crate) fn charge_power(&self, requested: u32) -> u32 {
+        let storable_energy = self.energy_capacity.map_or(u32::MAX, |capacity| {
+            capacity.saturating_sub(self.initial_capacity)
+        });
+        requested.min(self.power).min(storable_energy)
+    }
+}
+
+/// Manual control of the batteries, overriding their automatic discharge.
+///
+/// The power is split among the batteries in configuration order, each within its own limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct BatteryControl {
+    pub mode: BatteryMode,
+    /// Combined power to charge or discharge, in kW. Ignored in the auto and idle modes.
+    #[serde(default)]
+    pub power: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum BatteryMode {
+    /// The battery discharges to cover the allocated power exceeding the grid capacity.
+    #[default]
+    Auto,
+    /// The battery charges from the grid, reducing the capacity of the sessions.
+    Charge,
+    /// The battery discharges, adding to the capacity of the sessions.
+    Discharge,
+    /// The battery neither charges nor discharges.
+    Idle,
+}
+
+/// State of charge of a battery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct BatteryStatus {
+    /// State of charge in percent, if the energy capacity of the battery is known.
+    pub soc_percent: Option<u8>,
+    /// Whether the battery reached its discharge floor, so that it does not boost the capacity.
+    pub discharge_floor_active: bool,
+    /// Manual control of the batteries, in the auto mode unless overridden.
+    #[serde(default)]
+    pub control: BatteryControl,
+}
+
+/// Power flowing in or out of the batteries, in kW.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", tag = "mode", content = "power")]
+pub enum BatteryFlow {
+    Idle,
+    Charging(u32),
+    Discharging(u32),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct Session {
     pub session_id: uuid::Uuid,
+    /// Short id derived from the session id, easier to read in logs and QR codes.
+    #[serde(default)]
+    pub short_id: String,
     pub connector_id: ConnectorId,
     pub allocated_power: u32,
     pub vehicle_max_power: u32,
+    /// Max power of the vehicle given when the session started, which `vehicle_max_power` is
+    /// restored to when the vehicle draws more power after tapering.
+    #[serde(default)]
+    pub requested_max_power: u32,
+    /// Power the vehicle reported consuming at its last power update.
+    #[serde(default)]
+    pub consumed_power: u32,
+    pub depart_by: Option<DateTime<Utc>>,
+    pub energy_needed_wh: Option<u64>,
+    /// When the session is stopped automatically, as set by the client when starting it.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Power the session is pinned at by an operator, exempt from the fair share.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pinned_power: Option<u32>,
+    /// When the session started, which orders the sessions of sequential chargers.
+    #[serde(default)]
+    pub started_at: DateTime<Utc>,
+    #[serde(default)]
+    pub state: SessionState,
+    /// Key-values attached by the integrators, which are not used by the allocation.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Lifecycle of a session, which chargers can map to OCPP connector states.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum SessionState {
+    /// The session started, but the vehicle has not drawn power yet.
+    #[default]
+    Pending,
+    /// The vehicle is drawing power.
+    Charging,
+    /// The vehicle stopped drawing power, but is still plugged in.
+    Suspended,
+    /// The session was stopped, this state is final.
+    Finished,
+}
+
+impl SessionState {
+    /// Return whether a session in this state can go to the `to` state.
+    pub fn can_transition_to(self, to: SessionState) -> bool {
+        use SessionState::*;
+        matches!(
+            (self, to),
+            (Pending, Pending | Charging | Finished)
+                | (Charging | Suspended, Charging | Suspended | Finished)
+        )
+    }
+}
+
+/// Power allocated to a session from a given time, in kW.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationSample {
+    pub timestamp: DateTime<Utc>,
+    pub allocated_power: u32,
+}
+
+/// Note left by an operator on a session, e.g. about a customer report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct SessionNote {
+    pub timestamp: DateTime<Utc>,
+    pub text: String,
+}
+
+/// Session waiting in the queue for the station to have capacity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedSession {
+    /// Id of the session once it started, also used to cancel it while it waits.
+    pub session_id: uuid::Uuid,
+    /// Short id of the session, which it keeps once it started.
+    #[serde(default)]
+    pub short_id: String,
+    pub connector_id: ConnectorId,
+    pub vehicle_max_power: u32,
+    pub queued_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub options: SessionOptions,
+}
+
+/// Outcome of a new session, which starts or waits for capacity.
+#[derive(Debug, Clone)]
+pub enum SessionAdmission {
+    Started(Session),
+    /// The session waits in the queue, at this position from 1.
+    Queued {
+        queued_session: QueuedSession,
+        position: usize,
+    },
+}
+
+/// Optional parameters given when starting a session.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct SessionOptions {
+    /// When the vehicle needs to be ready.
+    #[serde(default)]
+    pub depart_by: Option<DateTime<Utc>>,
+    /// Energy the vehicle needs before its departure.
+    #[serde(default)]
+    pub energy_needed_wh: Option<u64>,
+    /// When to stop the session automatically, e.g. at the end of a prepaid slot.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Key-values to attach to the session, e.g. to correlate it with external systems.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
 }
 
 impl Session {
+    #[cfg(test)]
     pub(crate) fn new(connector_id: ConnectorId, vehicle_max_power: u32) -> Self {
+        Self::with_options(connector_id, vehicle_max_power, SessionOptions::default())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_options(
+        connector_id: ConnectorId,
+        vehicle_max_power: u32,
+        options: SessionOptions,
+    ) -> Self {
+        Self::with_id(
+            uuid::Uuid::new_v4(),
+            connector_id,
+            vehicle_max_power,
+            options,
+        )
+    }
+
+    pub(crate) fn with_id(
+        session_id: uuid::Uuid,
+        connector_id: ConnectorId,
+        vehicle_max_power: u32,
+        options: SessionOptions,
+    ) -> Self {
         Session {
-            session_id: uuid::Uuid::new_v4(),
+            session_id,
+            short_id: short_id(&session_id),
             connector_id,
             allocated_power: 0,
             vehicle_max_power,
+            requested_max_power: vehicle_max_power,
+            consumed_power: 0,
+            depart_by: options.depart_by,
+            energy_needed_wh: options.energy_needed_wh,
+            expires_at: options.expires_at,
+            pinned_power: None,
+            started_at: Utc::now(),
+            state: SessionState::Pending,
+            metadata: options.metadata,
         }
     }
+
+    /// Return the time elapsed since the session started.
+    pub(crate) fn age(&self, now: DateTime<Utc>) -> chrono::TimeDelta {
+        now - self.started_at
+    }
+
+    /// Return the laxity of the session in seconds, that is the time left before its departure
+    /// minus the time needed to deliver the energy it needs at its max power.
+    ///
+    /// Return `None` if the session does not have both a departure time and an energy need.
+    pub(crate) fn laxity(&self, now: DateTime<Utc>) -> Option<i64> {
+        let depart_by = self.depart_by?;
+        let energy_needed_wh = self.energy_needed_wh?;
+        let charging_secs = match self.vehicle_max_power {
+            0 => i64::MAX,
+            power => i64::try_from(energy_needed_wh.saturating_mul(3600) / (power as u64 * 1000))
+                .unwrap_or(i64::MAX),
+        };
+        Some(
+            (depart_by - now)
+                .num_seconds()
+                .saturating_sub(charging_secs),
+        )
+    }
+}
+
+/// Crockford base32 alphabet, which avoids the letters that can be confused with digits.
+const SHORT_ID_ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+/// Return the short id of a session id: its first 40 bits, encoded as 8 base32 characters.
+pub(crate) fn short_id(session_id: &uuid::Uuid) -> String {
+    let bits = session_id.as_bytes()[..5]
+        .iter()
+        .fold(0u64, |bits, byte| (bits << 8) | *byte as u64);
+    (0..8)
+        .rev()
+        .map(|i| SHORT_ID_ALPHABET[((bits >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(rename_all = "camelCase")]
 pub struct ConnectorId {
     pub charger_id: String,
@@ -58,3 +919,153 @@ impl Display for ConnectorId {
         write!(f, "{}:{}", self.charger_id, self.idx)
     }
 }
+
+/// A temporary curtailment of the grid capacity requested by the grid operator.
+#[derive(Debug, Clone)]
+pub(crate) struct DemandResponse {
+    pub(crate) id: u64,
+    pub(crate) fraction: f32,
+    pub(crate) expires_at: std::time::Instant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct DemandResponseStatus {
+    pub fraction: f32,
+    pub remaining_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct CapacitySummary {
+    pub grid_capacity: u32,
+    pub effective_capacity: u32,
+    pub allocated_power: u32,
+    pub remaining_capacity: u32,
+    /// Number of times the allocation of a session changed in a reallocation.
+    #[serde(default)]
+    pub changed_allocations: u64,
+    /// Average number of sessions whose allocation changed per reallocation, over the last 100
+    /// reallocations. A high churn means the allocations are unstable.
+    #[serde(default)]
+    pub allocation_churn: f32,
+    /// Time taken by the allocator over its last 100 runs, if it ran.
+    #[serde(default)]
+    pub allocation_latency: Option<AllocationLatency>,
+    /// Stability of the allocations, from 0 (thrashing) to 100 (stable), combining the churn,
+    /// the flapping and the fairness of the allocations.
+    #[serde(default)]
+    pub stability_score: u8,
+    /// Ceiling of the grid capacity in kW set by an operator, if any.
+    #[serde(default)]
+    pub power_cap: Option<u32>,
+    /// Share of the grid capacity kept as headroom, in percent.
+    #[serde(default)]
+    pub safety_margin_percent: u8,
+    pub demand_response: Option<DemandResponseStatus>,
+}
+
+/// Time taken by the allocator over its last runs, in microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationLatency {
+    /// Number of runs the latency is computed over.
+    pub samples: usize,
+    pub min_us: u64,
+    pub avg_us: u64,
+    pub max_us: u64,
+}
+
+/// Status of a charger, with its derating if any.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ChargerStatus {
+    pub id: String,
+    /// Maximum power of the charger in the station configuration.
+    pub configured_max_power: u32,
+    /// Maximum power used by the allocation, lower than the configured one when derated.
+    pub max_power: u32,
+    /// Maximum power the charger was derated to, if it is.
+    pub derated_max_power: Option<u32>,
+    /// Whether the charger is suspended, so that its sessions get no power.
+    pub suspended: bool,
+    pub allocated_power: u32,
+    /// Connectors of the charger without an active session.
+    #[serde(default)]
+    pub available_connectors: u8,
+    /// Firmware version of the charger, from the station configuration.
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    /// Manufacturer of the charger, from the station configuration.
+    #[serde(default)]
+    pub vendor: Option<String>,
+}
+
+/// Limit bounding the power a session can get, from the closest to the vehicle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum BindingConstraint {
+    /// The vehicle max power, the session gets all the power it can take.
+    Vehicle,
+    /// The max power of the connector.
+    Connector,
+    /// The max power of the charger, taken by its sessions or suspended, or the session ahead on
+    /// a sequential charger.
+    Charger,
+    /// The max power of the group of the charger.
+    Group,
+    /// The station capacity, taken by the other sessions.
+    Station,
+}
+
+/// Power a session would get if it started, and the limit bounding it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Feasibility {
+    /// Whether the session would get some power.
+    pub feasible: bool,
+    /// Power in kW the session would get after a full reallocation.
+    pub max_achievable_power: u32,
+    pub binding_constraint: BindingConstraint,
+}
+
+/// Differences between the sessions of two station states, from the first to the second.
+///
+/// The sessions are sorted by id, and the sessions active in both states with the same
+/// allocation are left out.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationDiff {
+    /// Sessions only active in the second state.
+    pub added: Vec<Session>,
+    /// Sessions only active in the first state.
+    pub removed: Vec<Session>,
+    /// Sessions active in both states, whose allocation changed.
+    pub changed: Vec<AllocationChange>,
+}
+
+impl AllocationDiff {
+    /// Return whether the sessions and their allocations are the same in both states.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Allocation change of a session active in both states of an `AllocationDiff`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationChange {
+    pub session_id: uuid::Uuid,
+    /// Power allocated in the first state, in kW.
+    pub previous_power: u32,
+    /// Power allocated in the second state, in kW.
+    pub allocated_power: u32,
+}